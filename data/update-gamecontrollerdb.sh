@@ -0,0 +1,14 @@
+#!/bin/sh
+# Install the Linux mappings of the latest SDL_GameControllerDB as
+# gamecontrollerdb.txt in the given directory, /usr/local/share/hidraw by
+# default, where `MappingDatabase::load` finds them.
+set -e
+dir=${1:-/usr/local/share/hidraw}
+url=https://raw.githubusercontent.com/mdqinc/SDL_GameControllerDB/master/gamecontrollerdb.txt
+mkdir -p "$dir"
+{
+    echo "# Linux mappings from SDL_GameControllerDB."
+    echo "# Fetched $(date -u +%Y-%m-%d) from $url"
+    curl -fsSL "$url" | grep 'platform:Linux,'
+} > "$dir/gamecontrollerdb.txt.new"
+mv "$dir/gamecontrollerdb.txt.new" "$dir/gamecontrollerdb.txt"
@@ -0,0 +1,78 @@
+//! A driver for a device this crate doesn't know about, added from outside the
+//! crate with `drivers::register`.
+//!
+//! The "Acme Pad" sends 4-byte reports: a button bitmask, then X and Y as
+//! unsigned bytes centered on 0x80, then a battery level.
+
+use anyhow::{bail, Result};
+use hidraw::device_monitor::{Bus, DeviceInfo};
+use hidraw::drivers::{self, HidDriver};
+use hidraw::report::{Axis, Button, Capabilities, GamepadInput};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+const VENDOR_ID: u16 = 0x1234;
+const PRODUCT_ID: u16 = 0x5678;
+
+const BUTTONS: [Button; 4] = [Button::South, Button::East, Button::West, Button::North];
+
+struct AcmePad;
+
+impl HidDriver for AcmePad {
+    fn name(&self) -> &str {
+        "acme"
+    }
+
+    fn probe(&self, info: &DeviceInfo) -> bool {
+        (info.vendor_id, info.product_id) == (VENDOR_ID, PRODUCT_ID)
+    }
+
+    fn decode(&mut self, report: &[u8]) -> Result<Option<GamepadInput>> {
+        let [buttons, x, y, _battery] = report else {
+            bail!("Unexpected report: {report:x?}");
+        };
+        let mut input = GamepadInput::default();
+        for (i, button) in BUTTONS.iter().enumerate() {
+            input.set_button(*button, buttons & (1 << i) != 0);
+        }
+        input.set_axis(Axis::LeftX, (*x as f32 - 128.0) / 127.0);
+        input.set_axis(Axis::LeftY, (*y as f32 - 128.0) / 127.0);
+        Ok(Some(input))
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            buttons: BUTTONS.to_vec(),
+            axes: vec![Axis::LeftX, Axis::LeftY],
+            ..Default::default()
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    drivers::register(|| Box::new(AcmePad));
+
+    let info = DeviceInfo {
+        sys_path: PathBuf::from("/sys/devices/virtual/input/input99"),
+        device_node: PathBuf::from("/dev/input/event99"),
+        hidraw_node: Some(PathBuf::from("/dev/hidraw99")),
+        parser: None,
+        bus: Bus::Usb,
+        name: "Acme Pad".to_owned(),
+        uniq: None,
+        phys: None,
+        version: 1,
+        vendor_id: VENDOR_ID,
+        product_id: PRODUCT_ID,
+        input_id: None,
+        usb_address: None,
+        connected_at: SystemTime::now(),
+    };
+    let Some(mut driver) = drivers::probe(&info) else {
+        bail!("No driver for `{}`", info.name);
+    };
+    println!("`{}` uses the {} driver", info.name, driver.name());
+    println!("{:?}", driver.capabilities());
+    println!("{:?}", driver.decode(&[0b0101, 0xFF, 0x80, 90])?);
+    Ok(())
+}
@@ -0,0 +1,89 @@
+use anyhow::{Context as ErrorContext, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How often to check batteries for changes. The kernel drivers only update
+/// them when the controller reports a change, so there's no point polling fast.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+const SYS_CLASS_POWER_SUPPLY: &str = "/sys/class/power_supply";
+
+/// A battery's charging state, from its `power_supply` `status` attribute.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ChargeStatus {
+    Charging,
+    Discharging,
+    Full,
+    /// Plugged in but not charging, e.g. because it's too warm.
+    NotCharging,
+    Unknown,
+}
+
+impl ChargeStatus {
+    fn from_sysfs(status: &str) -> ChargeStatus {
+        match status {
+            "Charging" => ChargeStatus::Charging,
+            "Discharging" => ChargeStatus::Discharging,
+            "Full" => ChargeStatus::Full,
+            "Not charging" => ChargeStatus::NotCharging,
+            _ => ChargeStatus::Unknown,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct BatteryLevel {
+    /// Percent, if the driver reports one. Some only report a coarse level.
+    pub capacity: Option<u8>,
+    pub status: ChargeStatus,
+}
+
+/// A controller's battery, as exposed by its kernel driver through the
+/// `power_supply` class, e.g. `<hid device>/power_supply/ps-controller-battery-<mac>`.
+#[derive(Clone, Debug)]
+pub struct Battery {
+    dir: PathBuf,
+}
+
+impl Battery {
+    /// Find the battery of the HID device behind the evdev device at
+    /// `sys_path`, if its driver exposes one.
+    pub fn find(sys_path: &Path) -> Option<Battery> {
+        // eventN/device is the input device, and its device the HID device.
+        let supplies = sys_path.join("device/device/power_supply");
+        let entry = fs::read_dir(supplies).ok()?.flatten().next()?;
+        Some(Battery { dir: entry.path() })
+    }
+
+    /// Find a battery named after `uniq`, the MAC address of a Bluetooth
+    /// controller, for drivers that don't put it under the HID device.
+    pub fn find_by_uniq(uniq: &str) -> Option<Battery> {
+        let uniq = uniq.to_lowercase();
+        if uniq.is_empty() {
+            return None;
+        }
+        let supplies = fs::read_dir(SYS_CLASS_POWER_SUPPLY).ok()?;
+        let entry = supplies.flatten().find(|entry| {
+            entry
+                .file_name()
+                .to_string_lossy()
+                .to_lowercase()
+                .contains(&uniq)
+        })?;
+        Some(Battery { dir: entry.path() })
+    }
+
+    pub fn read(&self) -> Result<BatteryLevel> {
+        let read = |attr: &str| -> Result<String> {
+            let path = self.dir.join(attr);
+            let value =
+                fs::read_to_string(&path).with_context(|| format!("Failed to read {path:?}"))?;
+            Ok(value.trim().to_owned())
+        };
+        Ok(BatteryLevel {
+            capacity: read("capacity").ok().and_then(|c| c.parse().ok()),
+            status: ChargeStatus::from_sysfs(&read("status")?),
+        })
+    }
+}
@@ -0,0 +1,36 @@
+use anyhow::{Context as ErrorContext, Result};
+use std::path::PathBuf;
+
+use crate::storage::{self, FileStorage, Storage};
+
+/// Where to keep data read from devices that's slow to read again, like
+/// calibration: `$HIDRAW_CACHE`, or `hidraw` in `$XDG_CACHE_HOME` or
+/// `~/.cache`.
+pub fn cache_dir() -> Option<PathBuf> {
+    if let Some(dir) = std::env::var_os("HIDRAW_CACHE") {
+        return Some(PathBuf::from(dir));
+    }
+    let base = std::env::var_os("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))?;
+    Some(base.join("hidraw"))
+}
+
+/// Read the entry `name`, a path relative to the cache directory, or under
+/// `cache/` in the configured storage. Anything that stops it being read is a
+/// miss.
+pub fn read(name: &str) -> Option<Vec<u8>> {
+    if let Some(storage) = storage::configured() {
+        return storage.read(&format!("cache/{name}")).ok()?;
+    }
+    FileStorage::new(&cache_dir()?).read(name).ok()?
+}
+
+/// Write the entry `name`, replacing it whole so readers never see part of
+/// one.
+pub fn write(name: &str, data: &[u8]) -> Result<()> {
+    if let Some(storage) = storage::configured() {
+        return storage.write(&format!("cache/{name}"), data);
+    }
+    FileStorage::new(&cache_dir().context("No cache directory")?).write(name, data)
+}
@@ -0,0 +1,213 @@
+use crate::device::AbsInfo;
+use crate::report::Axis;
+
+/// The 0..=255 range most HID gamepads use, for axes whose range the kernel
+/// won't tell us.
+const FALLBACK_RANGE: (i32, i32) = (0, 255);
+
+/// Where an axis's raw range comes from.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum AxisRange {
+    /// The range the kernel reports for the axis, from `EVIOCGABS`, or 0..=255
+    /// if it doesn't.
+    #[default]
+    Kernel,
+    /// The widest range seen so far, for worn sticks that don't reach the
+    /// range they claim. The kernel's range is used until the axis has moved
+    /// across at least half of it.
+    Observed,
+    /// A range known from elsewhere, e.g. measured by a calibration tool.
+    Fixed { min: i32, max: i32 },
+}
+
+/// How an axis responds between its rest position and its ends.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum ResponseCurve {
+    #[default]
+    Linear,
+    /// `|value|^exponent`, keeping the sign. Exponents above 1 give finer
+    /// control near the center.
+    Power(f32),
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct AxisCalibration {
+    pub range: AxisRange,
+    /// Values closer than this to rest are 0, and the rest is stretched to
+    /// cover the whole range again. `None` uses the flat the kernel reports
+    /// for the axis.
+    pub deadzone: Option<f32>,
+    pub curve: ResponseCurve,
+}
+
+/// How a device's raw axis values become normalized ones: scaled from their
+/// range, then the sticks' radial deadzones, then each axis's own deadzone and
+/// curve. The default scales from the range the kernel reports, with its flat
+/// as the deadzone, and does nothing else.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CalibrationConfig {
+    /// Indexed by `Axis as usize`.
+    pub axes: [AxisCalibration; 6],
+    /// Deadzones on the distance of the left and right sticks from center.
+    pub radial_deadzones: [f32; 2],
+}
+
+impl CalibrationConfig {
+    pub fn new() -> CalibrationConfig {
+        CalibrationConfig::default()
+    }
+
+    pub fn range(mut self, axis: Axis, range: AxisRange) -> CalibrationConfig {
+        self.axes[axis as usize].range = range;
+        self
+    }
+
+    /// Use `range` for every axis.
+    pub fn ranges(mut self, range: AxisRange) -> CalibrationConfig {
+        for axis in &mut self.axes {
+            axis.range = range;
+        }
+        self
+    }
+
+    pub fn deadzone(mut self, axis: Axis, deadzone: f32) -> CalibrationConfig {
+        self.axes[axis as usize].deadzone = Some(deadzone);
+        self
+    }
+
+    /// Set the radial deadzone of the stick `axis` is part of. Triggers have
+    /// none.
+    pub fn radial_deadzone(mut self, axis: Axis, deadzone: f32) -> CalibrationConfig {
+        if !axis.is_trigger() {
+            self.radial_deadzones[axis as usize / 2] = deadzone;
+        }
+        self
+    }
+
+    pub fn curve(mut self, axis: Axis, curve: ResponseCurve) -> CalibrationConfig {
+        self.axes[axis as usize].curve = curve;
+        self
+    }
+}
+
+/// Applies a `CalibrationConfig` to the axes of one device.
+#[derive(Clone, Debug, Default)]
+pub struct AxisCalibrator {
+    config: CalibrationConfig,
+    kernel: [Option<AbsInfo>; 6],
+    observed: [Option<(i32, i32)>; 6],
+    /// Each axis's latest value once scaled from its range, for the radial
+    /// deadzone of the stick it's part of.
+    scaled: [f32; 6],
+}
+
+impl AxisCalibrator {
+    pub fn new(config: CalibrationConfig) -> AxisCalibrator {
+        AxisCalibrator {
+            config,
+            ..Default::default()
+        }
+    }
+
+    pub fn config(&self) -> &CalibrationConfig {
+        &self.config
+    }
+
+    /// Replace the config, keeping the ranges seen so far.
+    pub fn set_config(&mut self, config: CalibrationConfig) {
+        self.config = config;
+    }
+
+    /// What the kernel reports about each axis, indexed by `Axis as usize`.
+    pub fn set_kernel_info(&mut self, info: [Option<AbsInfo>; 6]) {
+        self.kernel = info;
+    }
+
+    /// Normalize `raw`, read from `axis`, to -1.0..=1.0 for sticks or
+    /// 0.0..=1.0 for triggers. Sticks use the latest value of their other axis
+    /// for their radial deadzone.
+    pub fn normalize(&mut self, axis: Axis, raw: i32) -> f32 {
+        let i = axis as usize;
+        let settings = self.config.axes[i];
+        let kernel = self.kernel[i];
+        let nominal = kernel.map_or(FALLBACK_RANGE, |info| (info.minimum, info.maximum));
+        let observed = match self.observed[i] {
+            Some((min, max)) => (min.min(raw), max.max(raw)),
+            None => (raw, raw),
+        };
+        self.observed[i] = Some(observed);
+        let (min, max) = match settings.range {
+            AxisRange::Kernel => nominal,
+            AxisRange::Observed => {
+                let spread = observed.1 as i64 - observed.0 as i64;
+                let nominal_spread = nominal.1 as i64 - nominal.0 as i64;
+                if spread * 2 >= nominal_spread {
+                    observed
+                } else {
+                    nominal
+                }
+            }
+            AxisRange::Fixed { min, max } => (min, max),
+        };
+        let low = if axis.is_trigger() { 0.0 } else { -1.0 };
+        let value = if max > min {
+            let t = (raw.clamp(min, max) - min) as f32 / (max - min) as f32;
+            low + t * (1.0 - low)
+        } else {
+            0.0
+        };
+        self.adjust(axis, value)
+    }
+
+    /// Apply the deadzones and response curve to `value`, already in
+    /// -1.0..=1.0 for sticks or 0.0..=1.0 for triggers, e.g. by a driver.
+    pub fn adjust(&mut self, axis: Axis, value: f32) -> f32 {
+        let i = axis as usize;
+        let settings = self.config.axes[i];
+        let kernel = self.kernel[i];
+        let low = if axis.is_trigger() { 0.0 } else { -1.0 };
+        let mut value = value.clamp(low, 1.0);
+        self.scaled[i] = value;
+        if !axis.is_trigger() {
+            let deadzone = self.config.radial_deadzones[i / 2];
+            // LeftX and LeftY, RightX and RightY, are neighbours.
+            let other = self.scaled[i ^ 1];
+            let distance = value.hypot(other);
+            if distance <= deadzone {
+                value = 0.0;
+            } else if deadzone > 0.0 {
+                let stretched = ((distance - deadzone) / (1.0 - deadzone)).min(1.0);
+                value *= stretched / distance;
+            }
+        }
+        let deadzone = settings.deadzone.unwrap_or_else(|| {
+            // Flat is in raw units from rest, which for sticks is the center,
+            // half way along the range.
+            let info = kernel.unwrap_or_default();
+            let span = (info.maximum as f32 - info.minimum as f32).max(0.0);
+            let span = if axis.is_trigger() { span } else { span / 2.0 };
+            if span > 0.0 {
+                info.flat as f32 / span
+            } else {
+                0.0
+            }
+        });
+        let magnitude = apply_deadzone(value.abs(), deadzone);
+        let magnitude = match settings.curve {
+            ResponseCurve::Linear => magnitude,
+            ResponseCurve::Power(exponent) => magnitude.powf(exponent),
+        };
+        magnitude.copysign(value).clamp(low, 1.0)
+    }
+}
+
+/// Zero `magnitude` inside `deadzone`, stretching what's left to 0.0..=1.0.
+fn apply_deadzone(magnitude: f32, deadzone: f32) -> f32 {
+    if deadzone <= 0.0 {
+        magnitude
+    } else if magnitude <= deadzone || deadzone >= 1.0 {
+        0.0
+    } else {
+        (magnitude - deadzone) / (1.0 - deadzone)
+    }
+}
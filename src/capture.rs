@@ -0,0 +1,420 @@
+use anyhow::{anyhow, bail, Context as ErrorContext, Result};
+use log::{debug, info};
+use std::fs::{self, File, OpenOptions};
+use std::io::{LineWriter, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::device;
+use crate::device_monitor::{Bus, DeviceInfo};
+use crate::handle::MockHandle;
+#[cfg(feature = "emulation")]
+use crate::uhid::{UhidConfig, UhidDevice};
+
+/// The size at which a capture file is rotated. One previous file is kept, so
+/// a device's captures take at most twice this.
+pub const DEFAULT_MAX_BYTES: u64 = 1 << 20;
+
+/// Where to write captures, `$HIDRAW_CAPTURE` if set. Capturing is off otherwise.
+pub fn capture_dir() -> Option<PathBuf> {
+    std::env::var_os("HIDRAW_CAPTURE").map(PathBuf::from)
+}
+
+/// What a capture line records.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CaptureEntry {
+    /// The captured device, at the start of each file, with its report
+    /// descriptor if it has a hidraw node.
+    Device {
+        bus: Bus,
+        vendor_id: u16,
+        product_id: u16,
+        descriptor: Vec<u8>,
+    },
+    /// A raw input report.
+    Report(Vec<u8>),
+    /// What a report decoded to.
+    Decoded(String),
+    /// Why a report couldn't be decoded, or another error.
+    Error(String),
+}
+
+/// One line of a capture, which is also the recording format for replaying a
+/// device's reports.
+///
+/// Wire format: `<unix time in ms> DEVICE <bus>:<vendor>:<product> <hex>`,
+/// `<time> REPORT <hex>`, `<time> DECODED <text>` or `<time> ERROR <text>`,
+/// with IDs in hex as in the kernel's HID device names. Lines starting with
+/// `#` are comments.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CaptureLine {
+    /// Since the Unix epoch, so captures can be matched up with user reports.
+    pub time: Duration,
+    pub entry: CaptureEntry,
+}
+
+impl CaptureLine {
+    pub fn encode(&self) -> String {
+        let time = self.time.as_millis();
+        match &self.entry {
+            CaptureEntry::Device {
+                bus,
+                vendor_id,
+                product_id,
+                descriptor,
+            } => format!(
+                "{time} DEVICE {:04x}:{vendor_id:04x}:{product_id:04x} {}\n",
+                *bus as u16,
+                to_hex(descriptor)
+            ),
+            CaptureEntry::Report(data) => format!("{time} REPORT {}\n", to_hex(data)),
+            CaptureEntry::Decoded(text) => format!("{time} DECODED {}\n", text.replace('\n', " ")),
+            CaptureEntry::Error(text) => format!("{time} ERROR {}\n", text.replace('\n', " ")),
+        }
+    }
+
+    /// Parse a line, returning `Ok(None)` for comments and blank lines.
+    pub fn decode(line: &str) -> Result<Option<CaptureLine>> {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(None);
+        }
+        let mut parts = line.splitn(3, ' ');
+        let time = parts.next().unwrap_or("");
+        let time: u64 = time
+            .parse()
+            .with_context(|| anyhow!("Bad time: {time:?}"))?;
+        let kind = parts.next().unwrap_or("");
+        let rest = parts.next().unwrap_or("");
+        let entry = match kind {
+            "DEVICE" => {
+                let (ids, descriptor) = rest.split_once(' ').unwrap_or((rest, ""));
+                let bad_ids = || anyhow!("Bad device IDs: {ids:?}");
+                let ids = ids
+                    .split(':')
+                    .map(|id| u16::from_str_radix(id, 16))
+                    .collect::<Result<Vec<u16>, _>>()
+                    .with_context(bad_ids)?;
+                let [bus, vendor_id, product_id] = ids[..] else {
+                    return Err(bad_ids());
+                };
+                CaptureEntry::Device {
+                    bus: Bus::from_id(bus),
+                    vendor_id,
+                    product_id,
+                    descriptor: from_hex(descriptor).context("Bad descriptor")?,
+                }
+            }
+            "REPORT" => CaptureEntry::Report(from_hex(rest).context("Bad report data")?),
+            "DECODED" => CaptureEntry::Decoded(rest.to_owned()),
+            "ERROR" => CaptureEntry::Error(rest.to_owned()),
+            _ => bail!("Bad capture line: {line:?}"),
+        };
+        Ok(Some(CaptureLine {
+            time: Duration::from_millis(time),
+            entry,
+        }))
+    }
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn from_hex(hex: &str) -> Result<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        bail!("Odd length: {hex:?}");
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+        .collect::<Result<Vec<u8>, _>>()
+        .with_context(|| anyhow!("Bad hex: {hex:?}"))
+}
+
+/// Read a capture file.
+pub fn read(path: &Path) -> Result<Vec<CaptureLine>> {
+    let text = fs::read_to_string(path).with_context(|| format!("Failed to read {path:?}"))?;
+    let mut lines = vec![];
+    for (i, line) in text.lines().enumerate() {
+        if let Some(line) = CaptureLine::decode(line).with_context(|| format!("Line {}", i + 1))? {
+            lines.push(line);
+        }
+    }
+    Ok(lines)
+}
+
+/// A rolling capture of one device's activity, rotated to `<file>.1` when it
+/// reaches `max_bytes`. Lines are flushed as they're written so nothing is
+/// lost if we crash.
+pub struct Capture {
+    path: PathBuf,
+    header: String,
+    /// Written after the header, so every file can be replayed.
+    device: CaptureEntry,
+    writer: LineWriter<File>,
+    written: u64,
+    max_bytes: u64,
+}
+
+fn open_append(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {path:?}"))
+}
+
+impl Capture {
+    /// Start or continue capturing a device in `dir`.
+    pub fn open(dir: &Path, info: &DeviceInfo, max_bytes: u64) -> Result<Capture> {
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {dir:?}"))?;
+        let sysname = info.sys_path.file_name().context("Bad sys path")?;
+        let path = dir.join(format!(
+            "{:04x}:{:04x}-{}.log",
+            info.vendor_id,
+            info.product_id,
+            sysname.to_string_lossy()
+        ));
+        let file = open_append(&path)?;
+        let written = file.metadata()?.len();
+        info!("Capturing `{}` to {path:?}", info.name);
+        let descriptor = info.hidraw_node.as_ref().and_then(|node| {
+            let file = File::open(node).ok()?;
+            device::read_report_descriptor(file.as_raw_fd())
+                .map_err(|e| debug!("Not capturing the descriptor of {node:?}: {e}"))
+                .ok()
+        });
+        let mut capture = Capture {
+            path,
+            header: format!(
+                "# hidraw capture of `{}` {:04x}:{:04x} at {:?}\n",
+                info.name, info.vendor_id, info.product_id, info.sys_path
+            ),
+            device: CaptureEntry::Device {
+                bus: info.bus,
+                vendor_id: info.vendor_id,
+                product_id: info.product_id,
+                descriptor: descriptor.unwrap_or_default(),
+            },
+            writer: LineWriter::new(file),
+            written,
+            max_bytes,
+        };
+        capture.write_header()?;
+        Ok(capture)
+    }
+
+    fn write_header(&mut self) -> Result<()> {
+        let header = self.header.clone();
+        self.write_line(&header)?;
+        self.write_entry(self.device.clone())
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<()> {
+        self.writer.write_all(line.as_bytes())?;
+        self.written += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        let mut old = self.path.clone().into_os_string();
+        old.push(".1");
+        fs::rename(&self.path, &old)?;
+        self.writer = LineWriter::new(open_append(&self.path)?);
+        self.written = 0;
+        self.write_header()
+    }
+
+    /// Append an entry, timestamped now.
+    pub fn write(&mut self, entry: CaptureEntry) -> Result<()> {
+        if self.written >= self.max_bytes {
+            self.rotate()
+                .with_context(|| format!("Failed to rotate {:?}", self.path))?;
+        }
+        self.write_entry(entry)
+    }
+
+    fn write_entry(&mut self, entry: CaptureEntry) -> Result<()> {
+        let line = CaptureLine {
+            time: SystemTime::now().duration_since(UNIX_EPOCH)?,
+            entry,
+        };
+        self.write_line(&line.encode())
+    }
+}
+
+/// A captured device and the reports it sent, to feed them back through the
+/// drivers, e.g. to reproduce a bug report.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Recording {
+    pub bus: Bus,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// Empty if the device had no hidraw node.
+    pub descriptor: Vec<u8>,
+    /// Each report, with when it was read.
+    pub reports: Vec<(Duration, Vec<u8>)>,
+}
+
+impl Recording {
+    /// The first device in a capture and every report after it.
+    pub fn from_lines(lines: &[CaptureLine]) -> Result<Recording> {
+        let mut lines = lines
+            .iter()
+            .skip_while(|line| !matches!(line.entry, CaptureEntry::Device { .. }));
+        let Some(CaptureEntry::Device {
+            bus,
+            vendor_id,
+            product_id,
+            descriptor,
+        }) = lines.next().map(|line| line.entry.clone())
+        else {
+            bail!("The capture doesn't say what device it's of");
+        };
+        let reports = lines
+            .filter_map(|line| match &line.entry {
+                CaptureEntry::Report(data) => Some((line.time, data.clone())),
+                _ => None,
+            })
+            .collect();
+        Ok(Recording {
+            bus,
+            vendor_id,
+            product_id,
+            descriptor,
+            reports,
+        })
+    }
+
+    pub fn read(path: &Path) -> Result<Recording> {
+        Recording::from_lines(&read(path)?)
+    }
+
+    /// A handle that reads the recorded reports in order, then reads as
+    /// unplugged, for running them through a driver or parser again.
+    pub fn mock_handle(&self) -> MockHandle {
+        MockHandle::new(self.reports.iter().map(|(_, data)| data.clone()))
+    }
+
+    /// A virtual device like the captured one, which the daemon picks up as
+    /// if it had been plugged in.
+    #[cfg(feature = "emulation")]
+    pub fn uhid_config(&self) -> UhidConfig {
+        UhidConfig {
+            name: format!(
+                "hidraw replay {:04x}:{:04x}",
+                self.vendor_id, self.product_id
+            ),
+            phys: "hidraw-replay".to_owned(),
+            uniq: String::new(),
+            bus: self.bus,
+            vendor_id: self.vendor_id,
+            product_id: self.product_id,
+            version: 0,
+            descriptor: self.descriptor.clone(),
+        }
+    }
+
+    /// Send the reports through `device` at the pace they were captured.
+    #[cfg(feature = "emulation")]
+    pub async fn replay(&self, device: &mut UhidDevice) -> Result<()> {
+        let Some((first, _)) = self.reports.first() else {
+            return Ok(());
+        };
+        let start = tokio::time::Instant::now();
+        for (time, data) in &self.reports {
+            tokio::time::sleep_until(start + time.saturating_sub(*first)).await;
+            device.send_input(data).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handle::DeviceHandle;
+
+    const CAPTURE: &str = "\
+# hidraw capture of `Xbox Wireless Controller` 045e:0b13 at \"/sys/class/input/event7\"
+1700000000000 DEVICE 0005:045e:0b13 05010905a101
+1700000000004 REPORT 01ff7f
+1700000000008 DECODED South
+1700000000012 ERROR Short report
+1700000000020 REPORT 017f80
+";
+
+    fn recording() -> Recording {
+        let lines: Vec<_> = CAPTURE
+            .lines()
+            .filter_map(|line| CaptureLine::decode(line).unwrap())
+            .collect();
+        Recording::from_lines(&lines).unwrap()
+    }
+
+    #[test]
+    fn device_lines_round_trip() {
+        let line = CaptureLine {
+            time: Duration::from_millis(1700000000000),
+            entry: CaptureEntry::Device {
+                bus: Bus::Bluetooth,
+                vendor_id: 0x045e,
+                product_id: 0x0b13,
+                descriptor: vec![0x05, 0x01, 0x09, 0x05, 0xa1, 0x01],
+            },
+        };
+        assert_eq!(
+            line.encode(),
+            "1700000000000 DEVICE 0005:045e:0b13 05010905a101\n"
+        );
+        assert_eq!(CaptureLine::decode(&line.encode()).unwrap(), Some(line));
+    }
+
+    #[test]
+    fn replays_reports_through_a_mock_device() {
+        let recording = recording();
+        assert_eq!(recording.bus, Bus::Bluetooth);
+        assert_eq!(
+            (recording.vendor_id, recording.product_id),
+            (0x045e, 0x0b13)
+        );
+        assert_eq!(recording.reports[1].0, Duration::from_millis(1700000000020));
+        let mut handle = recording.mock_handle();
+        futures::executor::block_on(async {
+            assert_eq!(
+                handle.read_report().await.unwrap(),
+                Some(vec![0x01, 0xff, 0x7f])
+            );
+            assert_eq!(
+                handle.read_report().await.unwrap(),
+                Some(vec![0x01, 0x7f, 0x80])
+            );
+            assert_eq!(handle.read_report().await.unwrap(), None);
+        });
+    }
+
+    #[cfg(feature = "emulation")]
+    #[tokio::test]
+    async fn replays_reports_through_uhid() {
+        let path = std::env::temp_dir().join(format!("hidraw-replay-{}", std::process::id()));
+        fs::write(&path, []).unwrap();
+        let recording = recording();
+        let mut device = UhidDevice::create_at(&path, &recording.uhid_config())
+            .await
+            .unwrap();
+        recording.replay(&mut device).await.unwrap();
+        drop(device);
+        let written = fs::read(&path).unwrap();
+        // UHID_CREATE2, then a UHID_INPUT2 per report.
+        let events: Vec<_> = written.chunks(written.len() / 3).collect();
+        assert_eq!(events.len(), 3);
+        for (event, (_, report)) in events[1..].iter().zip(&recording.reports) {
+            assert_eq!(event[..4], 12u32.to_ne_bytes());
+            assert_eq!(event[4..6], (report.len() as u16).to_ne_bytes());
+            assert_eq!(event[6..6 + report.len()], report[..]);
+        }
+        let _ = fs::remove_file(path);
+    }
+}
@@ -0,0 +1,1028 @@
+#[cfg(not(feature = "sinks"))]
+use anyhow::bail;
+use anyhow::{Context as ErrorContext, Result};
+use log::{info, warn};
+use std::collections::HashSet;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::Sender;
+
+#[cfg(feature = "sinks")]
+use crate::midi::{MidiProfile, MidiSink};
+#[cfg(feature = "sinks")]
+use crate::osc::{OscProfile, OscSink};
+use crate::report::{Axis, Button};
+use crate::sink::{Control, EmulationTarget, OutputSink, RoutingMatrix};
+use crate::source::{DisconnectPolicy, OutputMode, RoutedSink};
+use crate::storage;
+use crate::transform::AxisTransform;
+
+/// A problem found in a config file. Lines and columns start at 1; a line of 0
+/// means the problem isn't tied to a location, e.g. the file couldn't be read.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}:{}: {}", self.line, self.column, self.message)
+        }
+    }
+}
+
+/// Every problem found in a config file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigError {
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl ConfigError {
+    fn new(message: String) -> ConfigError {
+        ConfigError {
+            diagnostics: vec![Diagnostic {
+                line: 0,
+                column: 0,
+                message,
+            }],
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, diagnostic) in self.diagnostics.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{diagnostic}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SinkKind {
+    Osc(SocketAddr),
+    Midi(PathBuf),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SinkConfig {
+    pub name: String,
+    pub kind: SinkKind,
+}
+
+impl SinkConfig {
+    /// Open the sink. OSC sinks send every control under `/<name>`, and MIDI
+    /// sinks use `MidiProfile::standard`.
+    #[cfg(feature = "sinks")]
+    pub fn open(&self) -> Result<Box<dyn OutputSink + Send>> {
+        Ok(match &self.kind {
+            SinkKind::Osc(target) => Box::new(OscSink::new(
+                *target,
+                OscProfile::with_prefix(&format!("/{}", self.name)),
+            )?),
+            SinkKind::Midi(path) => Box::new(MidiSink::open(path, MidiProfile::standard())?),
+        })
+    }
+
+    #[cfg(not(feature = "sinks"))]
+    pub fn open(&self) -> Result<Box<dyn OutputSink + Send>> {
+        bail!("Sink `{}` needs the `sinks` feature", self.name)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Profile {
+    pub name: String,
+    /// The user whose profile this is, or `None` for one shared by everyone.
+    pub user: Option<String>,
+    pub sinks: Vec<SinkConfig>,
+    pub transforms: Vec<AxisTransform>,
+    pub routing: RoutingMatrix,
+    pub output: OutputMode,
+    pub disconnect: DisconnectPolicy,
+    /// A virtual controller that gets every control, and whose rumble and
+    /// lightbar go back to the device.
+    pub emulate: Option<EmulationTarget>,
+}
+
+/// Which profile to use for a device.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceConfig {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub profile: String,
+    /// The user whose sessions this applies to, or `None` for everyone's.
+    pub user: Option<String>,
+}
+
+/// A parsed and validated config file.
+///
+/// The format is line based, with `#` comments:
+///
+/// ```text
+/// [profile racing]
+/// sink = osc lights 127.0.0.1:9000
+/// sink = midi synth /dev/snd/midiC1D0
+/// transform = merge right_trigger left_trigger left_x
+/// route = synth south east left_x dpad
+/// output = tick 500 interpolate
+/// disconnect = hold 500
+/// emulate = xbox360
+///
+/// [device 045e:028e]
+/// profile = racing
+///
+/// [profile alice-racing]
+/// user = alice
+/// route = synth south east
+///
+/// [device 045e:028e]
+/// user = alice
+/// profile = alice-racing
+/// ```
+///
+/// Sections with a `user` only apply while that user has the active session
+/// on the seat the device is attached to, winning over shared ones. Profile
+/// names are looked up among the user's own profiles first.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Config {
+    pub profiles: Vec<Profile>,
+    pub devices: Vec<DeviceConfig>,
+}
+
+/// The fastest `output = tick` rate, USB's fastest polling rate.
+const MAX_TICK_RATE: u32 = 8000;
+
+// The forms each setting's value can take. Words are placeholders in angle
+// brackets, optional in square brackets, repeated with `...`, or literal, with
+// `a|b` for either.
+const USER_SYNTAX: &[&str] = &["<user>"];
+const SINK_SYNTAX: &[&str] = &["osc|midi <name> <target>"];
+const TRANSFORM_SYNTAX: &[&str] = &["merge|split <axis> <axis> <axis>"];
+const ROUTE_SYNTAX: &[&str] = &["<sink> <control>..."];
+const OUTPUT_SYNTAX: &[&str] = &["input", "tick <hz> [interpolate]"];
+const DISCONNECT_SYNTAX: &[&str] = &["zero", "hold <ms>", "pause"];
+const EMULATE_SYNTAX: &[&str] = &["xbox360|dualshock4"];
+const PROFILE_SYNTAX: &[&str] = &["<profile>"];
+
+/// A setting a section takes.
+struct Setting {
+    key: &'static str,
+    syntax: &'static [&'static str],
+    /// Whether it can be given more than once, adding to the earlier ones
+    /// rather than replacing them.
+    repeats: bool,
+}
+
+const fn setting(key: &'static str, syntax: &'static [&'static str], repeats: bool) -> Setting {
+    Setting {
+        key,
+        syntax,
+        repeats,
+    }
+}
+
+/// Each kind of section, with the syntax of its name and the settings it
+/// takes. Parse errors quote the same syntax, and `syntax_reference` and
+/// `json_schema` are made from it.
+const GRAMMAR: &[(&str, &str, &[Setting])] = &[
+    (
+        "profile",
+        "<name>",
+        &[
+            setting("user", USER_SYNTAX, false),
+            setting("sink", SINK_SYNTAX, true),
+            setting("transform", TRANSFORM_SYNTAX, true),
+            setting("route", ROUTE_SYNTAX, true),
+            setting("output", OUTPUT_SYNTAX, false),
+            setting("disconnect", DISCONNECT_SYNTAX, false),
+            setting("emulate", EMULATE_SYNTAX, false),
+        ],
+    ),
+    (
+        "device",
+        "<vvvv>:<pppp>",
+        &[
+            setting("user", USER_SYNTAX, false),
+            setting("profile", PROFILE_SYNTAX, false),
+        ],
+    ),
+];
+
+/// An error message listing `syntax`'s forms, like "Expected `a`, `b` or `c`".
+fn expected(syntax: &[&str]) -> String {
+    let forms: Vec<_> = syntax.iter().map(|form| format!("`{form}`")).collect();
+    match forms.split_last() {
+        Some((last, [])) => format!("Expected {last}"),
+        Some((last, rest)) => format!("Expected {} or {last}", rest.join(", ")),
+        None => "Expected a value".to_owned(),
+    }
+}
+
+enum Section {
+    None,
+    Profile(usize),
+    Device(usize),
+}
+
+/// A whitespace-separated word and the column it starts at.
+type Token<'a> = (usize, &'a str);
+
+fn tokenize(text: &str, first_column: usize) -> Vec<Token<'_>> {
+    let mut tokens = vec![];
+    let mut start = None;
+    for (i, c) in text.char_indices() {
+        match (c.is_whitespace(), start) {
+            (false, None) => start = Some(i),
+            (true, Some(s)) => {
+                tokens.push((first_column + s, &text[s..i]));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    if let Some(s) = start {
+        tokens.push((first_column + s, &text[s..]));
+    }
+    tokens
+}
+
+struct Parser {
+    config: Config,
+    diagnostics: Vec<Diagnostic>,
+    /// References checked once the whole file has been read.
+    device_profiles: Vec<(usize, usize, usize, String)>,
+    routes: Vec<(usize, usize, usize, String)>,
+    /// Where each profile and device section starts.
+    profile_sections: Vec<(usize, usize)>,
+    device_sections: Vec<(usize, usize)>,
+}
+
+impl Parser {
+    fn error(&mut self, line: usize, column: usize, message: String) {
+        self.diagnostics.push(Diagnostic {
+            line,
+            column,
+            message,
+        });
+    }
+
+    fn axis(&mut self, line: usize, (column, name): Token) -> Option<Axis> {
+        let axis = Axis::from_name(name);
+        if axis.is_none() {
+            self.error(line, column, format!("Unknown axis `{name}`"));
+        }
+        axis
+    }
+
+    fn control(&mut self, line: usize, (column, name): Token) -> Option<Control> {
+        if name == "dpad" {
+            return Some(Control::Dpad);
+        }
+        if let Some(button) = Button::from_name(name) {
+            return Some(Control::Button(button));
+        }
+        if let Some(axis) = Axis::from_name(name) {
+            return Some(Control::Axis(axis));
+        }
+        self.error(line, column, format!("Unknown control `{name}`"));
+        None
+    }
+
+    fn section(&mut self, line: usize, column: usize, header: &str) -> Section {
+        let tokens = tokenize(header, column);
+        match tokens.as_slice() {
+            [(_, "profile"), (column, name)] => {
+                self.profile_sections.push((line, *column));
+                self.config.profiles.push(Profile {
+                    name: name.to_string(),
+                    ..Default::default()
+                });
+                Section::Profile(self.config.profiles.len() - 1)
+            }
+            [(_, "device"), (column, ids)] => {
+                let parsed = ids.split_once(':').and_then(|(v, p)| {
+                    Some((
+                        u16::from_str_radix(v, 16).ok()?,
+                        u16::from_str_radix(p, 16).ok()?,
+                    ))
+                });
+                let Some((vendor_id, product_id)) = parsed else {
+                    self.error(
+                        line,
+                        *column,
+                        format!("Bad device ids `{ids}`, expected vvvv:pppp"),
+                    );
+                    return Section::None;
+                };
+                self.device_sections.push((line, *column));
+                self.config.devices.push(DeviceConfig {
+                    vendor_id,
+                    product_id,
+                    profile: String::new(),
+                    user: None,
+                });
+                Section::Device(self.config.devices.len() - 1)
+            }
+            _ => {
+                self.error(line, column, format!("Unknown section `[{header}]`"));
+                Section::None
+            }
+        }
+    }
+
+    fn transform(&mut self, line: usize, tokens: &[Token]) -> Option<AxisTransform> {
+        let (kind, args) = match tokens {
+            [(_, kind), a, b, c] => (*kind, [*a, *b, *c]),
+            _ => {
+                let column = tokens.first().map_or(0, |t| t.0);
+                self.error(line, column, expected(TRANSFORM_SYNTAX));
+                return None;
+            }
+        };
+        let axes = [
+            self.axis(line, args[0]),
+            self.axis(line, args[1]),
+            self.axis(line, args[2]),
+        ];
+        let [Some(a), Some(b), Some(c)] = axes else {
+            return None;
+        };
+        // Half axes are triggers, full axes sticks.
+        let (halves, full, transform) = match kind {
+            "merge" => (
+                [(a, args[0]), (b, args[1])],
+                (c, args[2]),
+                AxisTransform::Merge {
+                    positive: a as usize,
+                    negative: b as usize,
+                    output: c as usize,
+                },
+            ),
+            "split" => (
+                [(b, args[1]), (c, args[2])],
+                (a, args[0]),
+                AxisTransform::Split {
+                    input: a as usize,
+                    positive: b as usize,
+                    negative: c as usize,
+                },
+            ),
+            _ => {
+                let column = tokens[0].0;
+                self.error(line, column, format!("Unknown transform `{kind}`"));
+                return None;
+            }
+        };
+        let mut ok = true;
+        for (axis, (column, name)) in halves {
+            if !axis.is_trigger() {
+                self.error(line, column, format!("`{name}` is not a half axis"));
+                ok = false;
+            }
+        }
+        if full.0.is_trigger() {
+            let (column, name) = full.1;
+            self.error(line, column, format!("`{name}` is not a full axis"));
+            ok = false;
+        }
+        ok.then_some(transform)
+    }
+
+    fn output(&mut self, line: usize, tokens: &[Token]) -> Option<OutputMode> {
+        let (rate_column, rate, interpolate) = match tokens {
+            [(_, "input")] => return Some(OutputMode::OnInput),
+            [(_, "tick"), (column, rate)] => (*column, *rate, false),
+            [(_, "tick"), (column, rate), (_, "interpolate")] => (*column, *rate, true),
+            _ => {
+                let column = tokens.first().map_or(0, |t| t.0);
+                self.error(line, column, expected(OUTPUT_SYNTAX));
+                return None;
+            }
+        };
+        match rate.parse() {
+            Ok(rate_hz @ 1..=MAX_TICK_RATE) => Some(OutputMode::FixedTick {
+                rate_hz,
+                interpolate,
+            }),
+            _ => {
+                let message = format!("Bad tick rate `{rate}`, expected 1 to {MAX_TICK_RATE} Hz");
+                self.error(line, rate_column, message);
+                None
+            }
+        }
+    }
+
+    fn disconnect(&mut self, line: usize, tokens: &[Token]) -> Option<DisconnectPolicy> {
+        match tokens {
+            [(_, "zero")] => Some(DisconnectPolicy::Zero),
+            [(_, "pause")] => Some(DisconnectPolicy::Pause),
+            [(_, "hold"), (column, ms)] => match ms.parse() {
+                Ok(ms) => Some(DisconnectPolicy::Hold(Duration::from_millis(ms))),
+                Err(_) => {
+                    self.error(line, *column, format!("Bad hold time `{ms}`, expected ms"));
+                    None
+                }
+            },
+            _ => {
+                let column = tokens.first().map_or(0, |t| t.0);
+                self.error(line, column, expected(DISCONNECT_SYNTAX));
+                None
+            }
+        }
+    }
+
+    fn sink(&mut self, line: usize, tokens: &[Token]) -> Option<SinkConfig> {
+        let [(kind_column, kind), (_, name), (target_column, target)] = tokens else {
+            let column = tokens.first().map_or(0, |t| t.0);
+            self.error(line, column, expected(SINK_SYNTAX));
+            return None;
+        };
+        let kind = match *kind {
+            "osc" => match target.parse() {
+                Ok(addr) => SinkKind::Osc(addr),
+                Err(_) => {
+                    self.error(line, *target_column, format!("Bad address `{target}`"));
+                    return None;
+                }
+            },
+            "midi" => SinkKind::Midi(PathBuf::from(target)),
+            _ => {
+                self.error(line, *kind_column, format!("Unknown sink kind `{kind}`"));
+                return None;
+            }
+        };
+        Some(SinkConfig {
+            name: name.to_string(),
+            kind,
+        })
+    }
+
+    fn setting(&mut self, section: &Section, line: usize, text: &str) {
+        let key_column = text.len() - text.trim_start().len() + 1;
+        let Some(eq) = text.find('=') else {
+            self.error(line, key_column, "Expected `key = value`".into());
+            return;
+        };
+        let key = text[..eq].trim();
+        let value = &text[eq + 1..];
+        let value_column = eq + 2;
+        let tokens = tokenize(value, value_column);
+        match (section, key) {
+            (Section::Profile(i), "sink") => {
+                if let Some(sink) = self.sink(line, &tokens) {
+                    let profile = &mut self.config.profiles[*i];
+                    if profile.sinks.iter().any(|s| s.name == sink.name) {
+                        let column = tokens[1].0;
+                        self.error(line, column, format!("Duplicate sink `{}`", sink.name));
+                    } else {
+                        profile.sinks.push(sink);
+                    }
+                }
+            }
+            (Section::Profile(i), "transform") => {
+                if let Some(transform) = self.transform(line, &tokens) {
+                    self.config.profiles[*i].transforms.push(transform);
+                }
+            }
+            (Section::Profile(i), "route") => {
+                let Some(((column, sink), controls)) = tokens.split_first() else {
+                    self.error(line, value_column, expected(ROUTE_SYNTAX));
+                    return;
+                };
+                let controls: Vec<_> = controls.iter().map(|t| self.control(line, *t)).collect();
+                if let Some(controls) = controls.into_iter().collect::<Option<Vec<_>>>() {
+                    let profile = &mut self.config.profiles[*i];
+                    profile.routing = std::mem::take(&mut profile.routing).route(sink, controls);
+                    self.routes.push((line, *column, *i, sink.to_string()));
+                }
+            }
+            (Section::Profile(i), "output") => {
+                if let Some(output) = self.output(line, &tokens) {
+                    self.config.profiles[*i].output = output;
+                }
+            }
+            (Section::Profile(i), "disconnect") => {
+                if let Some(disconnect) = self.disconnect(line, &tokens) {
+                    self.config.profiles[*i].disconnect = disconnect;
+                }
+            }
+            (Section::Profile(i), "emulate") => match tokens.as_slice() {
+                [(column, name)] => match EmulationTarget::from_name(name) {
+                    Some(target) => self.config.profiles[*i].emulate = Some(target),
+                    None => self.error(line, *column, format!("Unknown controller `{name}`")),
+                },
+                _ => self.error(line, value_column, expected(EMULATE_SYNTAX)),
+            },
+            (Section::Device(i), "profile") => match tokens.as_slice() {
+                [(column, name)] => {
+                    self.config.devices[*i].profile = name.to_string();
+                    self.device_profiles
+                        .push((line, *column, *i, name.to_string()));
+                }
+                _ => self.error(line, value_column, expected(PROFILE_SYNTAX)),
+            },
+            (Section::Profile(i), "user") => match tokens.as_slice() {
+                [(_, name)] => self.config.profiles[*i].user = Some(name.to_string()),
+                _ => self.error(line, value_column, expected(USER_SYNTAX)),
+            },
+            (Section::Device(i), "user") => match tokens.as_slice() {
+                [(_, name)] => self.config.devices[*i].user = Some(name.to_string()),
+                _ => self.error(line, value_column, expected(USER_SYNTAX)),
+            },
+            (Section::None, _) => {
+                self.error(line, key_column, "Setting outside of a section".into())
+            }
+            _ => self.error(line, key_column, format!("Unknown setting `{key}`")),
+        }
+    }
+
+    /// Check references between sections.
+    fn validate(&mut self) {
+        // Names only need to be unique within each user's namespace.
+        let mut profiles = HashSet::new();
+        for (profile, &(line, column)) in self.config.profiles.iter().zip(&self.profile_sections) {
+            if !profiles.insert((profile.user.clone(), profile.name.clone())) {
+                self.diagnostics.push(Diagnostic {
+                    line,
+                    column,
+                    message: format!("Duplicate profile `{}`", profile.name),
+                });
+            }
+        }
+        let mut devices = HashSet::new();
+        for (device, &(line, column)) in self.config.devices.iter().zip(&self.device_sections) {
+            let key = (device.user.clone(), device.vendor_id, device.product_id);
+            if !devices.insert(key) {
+                self.diagnostics.push(Diagnostic {
+                    line,
+                    column,
+                    message: format!(
+                        "Duplicate device `{:04x}:{:04x}`",
+                        device.vendor_id, device.product_id
+                    ),
+                });
+            }
+        }
+        for (line, column, device, name) in std::mem::take(&mut self.device_profiles) {
+            let user = self.config.devices[device].user.as_deref();
+            if self.config.profile_as(user, &name).is_none() {
+                self.error(line, column, format!("Unknown profile `{name}`"));
+            }
+        }
+        for (line, column, profile, sink) in std::mem::take(&mut self.routes) {
+            if !self.config.profiles[profile]
+                .sinks
+                .iter()
+                .any(|s| s.name == sink)
+            {
+                self.error(line, column, format!("Unknown sink `{sink}`"));
+            }
+        }
+        let devices = self.config.devices.iter().zip(&self.device_sections);
+        let missing: Vec<_> = devices.filter(|(d, _)| d.profile.is_empty()).collect();
+        for (_, &(line, column)) in missing {
+            self.diagnostics.push(Diagnostic {
+                line,
+                column,
+                message: "Device has no profile".into(),
+            });
+        }
+    }
+}
+
+impl Config {
+    /// Parse and validate a whole config file, reporting every problem found.
+    pub fn parse(text: &str) -> std::result::Result<Config, ConfigError> {
+        let mut parser = Parser {
+            config: Config::default(),
+            diagnostics: vec![],
+            device_profiles: vec![],
+            routes: vec![],
+            profile_sections: vec![],
+            device_sections: vec![],
+        };
+        let mut section = Section::None;
+        for (i, raw) in text.lines().enumerate() {
+            let line = i + 1;
+            let text = raw.split('#').next().unwrap_or("");
+            let trimmed = text.trim();
+            if trimmed.is_empty() {
+                continue;
+            }
+            let indent = text.len() - text.trim_start().len();
+            if let Some(header) = trimmed.strip_prefix('[') {
+                match header.strip_suffix(']') {
+                    Some(header) => section = parser.section(line, indent + 2, header),
+                    None => {
+                        parser.error(line, indent + 1, "Unterminated section header".into());
+                        section = Section::None;
+                    }
+                }
+            } else {
+                parser.setting(&section, line, text);
+            }
+        }
+        parser.validate();
+        parser.diagnostics.sort_by_key(|d| (d.line, d.column));
+        if parser.diagnostics.is_empty() {
+            Ok(parser.config)
+        } else {
+            Err(ConfigError {
+                diagnostics: parser.diagnostics,
+            })
+        }
+    }
+
+    /// The shared profile `name`.
+    pub fn profile(&self, name: &str) -> Option<&Profile> {
+        self.profile_as(None, name)
+    }
+
+    /// The profile `name` as `user` sees it: their own if they have one by
+    /// that name, or else the shared one.
+    pub fn profile_as(&self, user: Option<&str>, name: &str) -> Option<&Profile> {
+        let find = |user: Option<&str>| {
+            self.profiles
+                .iter()
+                .find(|p| p.name == name && p.user.as_deref() == user)
+        };
+        user.and_then(|user| find(Some(user)))
+            .or_else(|| find(None))
+    }
+
+    pub fn profile_for_device(&self, vendor_id: u16, product_id: u16) -> Option<&Profile> {
+        self.profile_for_device_as(None, vendor_id, product_id)
+    }
+
+    /// The profile for a device while `user` has the active session on its
+    /// seat: from their own device section if there's one, or else the
+    /// shared one.
+    pub fn profile_for_device_as(
+        &self,
+        user: Option<&str>,
+        vendor_id: u16,
+        product_id: u16,
+    ) -> Option<&Profile> {
+        let find = |user: Option<&str>| {
+            self.devices.iter().find(|d| {
+                d.vendor_id == vendor_id && d.product_id == product_id && d.user.as_deref() == user
+            })
+        };
+        let device = user
+            .and_then(|user| find(Some(user)))
+            .or_else(|| find(None))?;
+        self.profile_as(user, &device.profile)
+    }
+}
+
+/// Open `sinks`, keyed by their names for a profile's `routing`.
+pub fn open_sinks<'a>(sinks: impl IntoIterator<Item = &'a SinkConfig>) -> Result<Vec<RoutedSink>> {
+    sinks
+        .into_iter()
+        .map(|sink| {
+            let opened = sink
+                .open()
+                .with_context(|| format!("Failed to open sink `{}`", sink.name))?;
+            Ok((sink.name.clone(), opened))
+        })
+        .collect()
+}
+
+/// Read and parse a config file.
+pub fn load(path: &Path) -> std::result::Result<Config, ConfigError> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| ConfigError::new(format!("Failed to read {path:?}: {e}")))?;
+    Config::parse(&text)
+}
+
+/// Where the config comes from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConfigSource {
+    File(PathBuf),
+    /// The `config` entry of the configured storage, for configs shared
+    /// between machines or users.
+    Stored,
+}
+
+impl ConfigSource {
+    pub fn load(&self) -> std::result::Result<Config, ConfigError> {
+        match self {
+            ConfigSource::File(path) => load(path),
+            ConfigSource::Stored => {
+                let storage = storage::configured()
+                    .ok_or_else(|| ConfigError::new("No storage configured".to_owned()))?;
+                let data = storage
+                    .read(STORED_CONFIG_KEY)
+                    .map_err(|e| ConfigError::new(format!("{e:#}")))?
+                    .ok_or_else(|| ConfigError::new("No stored config".to_owned()))?;
+                let text = String::from_utf8(data)
+                    .map_err(|_| ConfigError::new("Stored config isn't UTF-8".to_owned()))?;
+                Config::parse(&text)
+            }
+        }
+    }
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigSource::File(path) => write!(f, "{path:?}"),
+            ConfigSource::Stored => write!(f, "stored `{STORED_CONFIG_KEY}`"),
+        }
+    }
+}
+
+/// The storage key of a stored config.
+pub const STORED_CONFIG_KEY: &str = "config";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConfigEvent {
+    /// A new config was applied.
+    Applied { source: ConfigSource },
+    /// A config reload failed and the previous config is still in use.
+    ConfigError {
+        source: ConfigSource,
+        error: ConfigError,
+    },
+}
+
+/// Owns the live config and replaces it as a whole on reload.
+pub struct ConfigManager {
+    source: ConfigSource,
+    current: Arc<Config>,
+    events: Sender<ConfigEvent>,
+}
+
+impl ConfigManager {
+    /// Load the initial config. Unlike a reload, failing here is fatal.
+    pub fn load(source: ConfigSource, events: Sender<ConfigEvent>) -> Result<ConfigManager> {
+        let config = source
+            .load()
+            .with_context(|| format!("Invalid config {source}"))?;
+        Ok(ConfigManager {
+            source,
+            current: Arc::new(config),
+            events,
+        })
+    }
+
+    pub fn current(&self) -> Arc<Config> {
+        self.current.clone()
+    }
+
+    /// Re-read the config and, if it's entirely valid, hand it to `apply`
+    /// to update live devices. The config is only kept if that works, so
+    /// `apply` must leave the devices as they were when it fails. Either way
+    /// a `ConfigEvent` reports the outcome.
+    pub async fn reload<T>(
+        &mut self,
+        apply: impl AsyncFnOnce(&Config) -> Result<T>,
+    ) -> std::result::Result<T, ConfigError> {
+        let result = match self.source.load() {
+            Ok(config) => match apply(&config).await {
+                Ok(applied) => Ok((config, applied)),
+                Err(e) => Err(ConfigError::new(format!("Failed to apply config: {e:#}"))),
+            },
+            Err(error) => Err(error),
+        };
+        let source = self.source.clone();
+        let (result, event) = match result {
+            Ok((config, applied)) => {
+                info!("Applied config {source}");
+                self.current = Arc::new(config);
+                (Ok(applied), ConfigEvent::Applied { source })
+            }
+            Err(error) => {
+                warn!("Keeping previous config, {source} is invalid:\n{error}");
+                let event = ConfigEvent::ConfigError {
+                    source,
+                    error: error.clone(),
+                };
+                (Err(error), event)
+            }
+        };
+        let _ = self.events.send(event).await;
+        result
+    }
+}
+
+/// A reference to the config syntax, for `--help` style output: every section
+/// with the settings it takes, then the names axes and controls can have.
+pub fn syntax_reference() -> String {
+    let mut out = String::new();
+    for (kind, name, settings) in GRAMMAR {
+        out.push_str(&format!("[{kind} {name}]\n"));
+        for setting in *settings {
+            for form in setting.syntax {
+                out.push_str(&format!("{} = {form}\n", setting.key));
+            }
+        }
+        out.push('\n');
+    }
+    let axes: Vec<_> = Axis::ALL.iter().map(|a| a.as_str()).collect();
+    let buttons: Vec<_> = Button::ALL.iter().map(|b| b.as_str()).collect();
+    out.push_str(&format!("<axis> = {}\n", axes.join("|")));
+    out.push_str(&format!(
+        "<control> = {}|{}|dpad\n",
+        buttons.join("|"),
+        axes.join("|")
+    ));
+    out
+}
+
+/// A regex for a placeholder's values.
+fn placeholder_pattern(name: &str) -> String {
+    let names = |names: Vec<&str>| format!("(?:{})", names.join("|"));
+    let axes = || Axis::ALL.iter().map(|a| a.as_str());
+    match name {
+        "axis" => names(axes().collect()),
+        "control" => names(
+            Button::ALL
+                .iter()
+                .map(|b| b.as_str())
+                .chain(axes())
+                .chain(["dpad"])
+                .collect(),
+        ),
+        "hz" | "ms" => "[0-9]+".to_owned(),
+        "vvvv" | "pppp" => "[0-9a-fA-F]{4}".to_owned(),
+        _ => "\\S+".to_owned(),
+    }
+}
+
+/// A regex for one word of a syntax form.
+fn word_pattern(word: &str) -> String {
+    if let Some(word) = word.strip_prefix('[').and_then(|w| w.strip_suffix(']')) {
+        return format!("(?:\\s+{})?", word_pattern(word));
+    }
+    if let Some(word) = word.strip_suffix("...") {
+        let word = word_pattern(word);
+        return format!("{word}(?:\\s+{word})*");
+    }
+    if word.contains('|') {
+        return format!("(?:{word})");
+    }
+    let mut pattern = String::new();
+    let mut rest = word;
+    while let Some(start) = rest.find('<') {
+        let Some(end) = rest[start..].find('>') else {
+            break;
+        };
+        pattern.push_str(&rest[..start]);
+        pattern.push_str(&placeholder_pattern(&rest[start + 1..start + end]));
+        rest = &rest[start + end + 1..];
+    }
+    pattern.push_str(rest);
+    pattern
+}
+
+/// A regex matching the whole of a value in any of `syntax`'s forms.
+fn syntax_pattern(syntax: &[&str]) -> String {
+    let forms: Vec<_> = syntax
+        .iter()
+        .map(|form| {
+            let mut pattern = String::new();
+            for (i, word) in form.split(' ').enumerate() {
+                // Optional words bring their own leading space.
+                if i > 0 && !word.starts_with('[') {
+                    pattern.push_str("\\s+");
+                }
+                pattern.push_str(&word_pattern(word));
+            }
+            pattern
+        })
+        .collect();
+    format!("^\\s*(?:{})\\s*$", forms.join("|"))
+}
+
+fn json_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// A JSON schema of the config, for editor tooling. It describes the file as
+/// JSON, with each kind of section an array of objects holding the section's
+/// name and its settings as written after `=`, or arrays of them for settings
+/// that repeat. Each setting's pattern follows the syntax the parser takes.
+pub fn json_schema() -> String {
+    let mut sections = vec![];
+    for (kind, name, settings) in GRAMMAR {
+        let mut properties = vec![format!(
+            "\"name\": {{ \"type\": \"string\", \"pattern\": {} }}",
+            json_string(&syntax_pattern(&[name]))
+        )];
+        for setting in *settings {
+            let mut value = format!(
+                "{{ \"type\": \"string\", \"description\": {}, \"pattern\": {} }}",
+                json_string(&setting.syntax.join(" | ")),
+                json_string(&syntax_pattern(setting.syntax))
+            );
+            if setting.repeats {
+                value = format!("{{ \"type\": \"array\", \"items\": {value} }}");
+            }
+            properties.push(format!("\"{}\": {value}", setting.key));
+        }
+        sections.push(format!(
+            r#"    "{kind}": {{
+      "type": "array",
+      "items": {{
+        "type": "object",
+        "required": ["name"],
+        "additionalProperties": false,
+        "properties": {{
+          {}
+        }}
+      }}
+    }}"#,
+            properties.join(",\n          ")
+        ));
+    }
+    format!(
+        r#"{{
+  "$schema": "https://json-schema.org/draft/2020-12/schema",
+  "title": "hidraw config",
+  "type": "object",
+  "additionalProperties": false,
+  "properties": {{
+{}
+  }}
+}}
+"#,
+        sections.join(",\n")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_quote_the_grammar() {
+        for (kind, _, settings) in GRAMMAR {
+            let name = if *kind == "device" { "045e:028e" } else { "p" };
+            for Setting { key, syntax, .. } in *settings {
+                let text = format!("[{kind} {name}]\n{key} =\n");
+                let error = Config::parse(&text).unwrap_err();
+                let message = expected(syntax);
+                assert!(
+                    error
+                        .diagnostics
+                        .iter()
+                        .any(|d| d.line == 2 && d.message == message),
+                    "`{key}` in {kind}: {error}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn reference_lists_every_setting() {
+        let reference = syntax_reference();
+        assert!(reference.contains("[profile <name>]\nuser = <user>\n"));
+        assert!(reference.contains("output = input\noutput = tick <hz> [interpolate]\n"));
+        assert!(reference.contains("profile = <profile>\n"));
+    }
+
+    #[test]
+    fn schema_follows_the_grammar() {
+        let schema = json_schema();
+        let output = r#""pattern": "^\\s*(?:input|tick\\s+[0-9]+(?:\\s+interpolate)?)\\s*$""#;
+        assert!(schema.contains(output), "{schema}");
+        let ids = r#""pattern": "^\\s*(?:[0-9a-fA-F]{4}:[0-9a-fA-F]{4})\\s*$""#;
+        assert!(schema.contains(ids), "{schema}");
+        assert!(schema.contains(r#""sink": { "type": "array", "items": {"#));
+        let opened = schema.matches(['{', '[']).count();
+        assert_eq!(opened, schema.matches(['}', ']']).count());
+    }
+
+    #[test]
+    fn parses_the_emulated_controller() {
+        let config = Config::parse("[profile p]\nemulate = dualshock4\n").unwrap();
+        let emulate = config.profile("p").unwrap().emulate;
+        assert_eq!(emulate, Some(EmulationTarget::DualShock4));
+        let error = Config::parse("[profile p]\nemulate = wiimote\n").unwrap_err();
+        assert_eq!(error.diagnostics[0].message, "Unknown controller `wiimote`");
+    }
+
+    #[cfg(feature = "sinks")]
+    #[test]
+    fn opens_sinks_by_name() {
+        let config = Config::parse("[profile p]\nsink = osc lights 127.0.0.1:9000\n").unwrap();
+        let sinks = open_sinks(&config.profile("p").unwrap().sinks).unwrap();
+        assert_eq!(sinks.len(), 1);
+        assert_eq!((sinks[0].0.as_str(), sinks[0].1.name()), ("lights", "osc"));
+    }
+}
@@ -0,0 +1,207 @@
+use anyhow::{anyhow, bail, Context as ErrorContext, Result};
+use log::{debug, info};
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
+
+use crate::device_monitor::DeviceEvent;
+use crate::ipc::{self, Capability, Command, Hello, Reply, WireEvent};
+
+/// Where the daemon listens for control connections: `$HIDRAW_SOCKET`, or
+/// `hidraw.sock` in `$XDG_RUNTIME_DIR` or `/run`.
+pub fn socket_path() -> PathBuf {
+    if let Some(path) = std::env::var_os("HIDRAW_SOCKET") {
+        return PathBuf::from(path);
+    }
+    let dir = std::env::var_os("XDG_RUNTIME_DIR").unwrap_or_else(|| "/run".into());
+    Path::new(&dir).join("hidraw.sock")
+}
+
+/// A command from a control client, for the daemon's main loop to carry out.
+/// The reply is the `DATA` lines to send back.
+pub struct Request {
+    pub command: Command,
+    pub reply: oneshot::Sender<Result<Vec<String>>>,
+}
+
+/// `Hello::current` with only the capabilities `keep` picks.
+fn hello_with(keep: impl Fn(&Capability) -> bool) -> Hello {
+    let mut hello = Hello::current();
+    hello.capabilities.retain(keep);
+    hello
+}
+
+async fn handle_client(
+    stream: UnixStream,
+    requests: Sender<Request>,
+    events: broadcast::Sender<DeviceEvent>,
+) -> Result<()> {
+    // Before our HELLO, so a client sees every event after it.
+    let mut events = events.subscribe();
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    let hello = lines.next_line().await?.context("Closed before HELLO")?;
+    let theirs = Hello::decode(&hello)?;
+    let ours = Hello::current();
+    write.write_all(ours.encode().as_bytes()).await?;
+    let negotiated = ipc::negotiate(&ours, &theirs)?;
+    let wants_events = negotiated.capabilities.iter().any(Capability::is_event);
+    loop {
+        let line = tokio::select! {
+            line = lines.next_line() => match line? {
+                Some(line) => line,
+                None => break,
+            },
+            event = events.recv(), if wants_events => {
+                match event {
+                    Ok(event) => {
+                        if let Some(event) = WireEvent::from_event(&event, &negotiated) {
+                            write.write_all(event.encode().as_bytes()).await?;
+                        }
+                    }
+                    Err(RecvError::Lagged(missed)) => {
+                        debug!("A control client missed {missed} events");
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+                continue;
+            }
+        };
+        let result = if negotiated.has(Capability::Control) {
+            match Command::decode(&line) {
+                Ok(command) if !negotiated.has(command.capability()) => Err(anyhow!(
+                    "The {} capability wasn't negotiated",
+                    command.capability().as_str()
+                )),
+                Ok(command) => {
+                    let (reply, reply_rx) = oneshot::channel();
+                    requests
+                        .send(Request { command, reply })
+                        .await
+                        .map_err(|_| anyhow!("Shutting down"))?;
+                    reply_rx.await.map_err(|_| anyhow!("Shutting down"))?
+                }
+                Err(e) => Err(e),
+            }
+        } else {
+            Err(anyhow!("The control capability wasn't negotiated"))
+        };
+        let mut out = String::new();
+        match result {
+            Ok(data) => {
+                for text in data {
+                    out += &Reply::Data(text).encode();
+                }
+                out += &Reply::Ok.encode();
+            }
+            Err(e) => out += &Reply::Err(format!("{e:#}")).encode(),
+        }
+        write.write_all(out.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// Accept control connections on `path`, forwarding their commands to
+/// `requests`, and `events` to clients that negotiated them. Runs until
+/// accepting fails.
+pub async fn serve(
+    path: &Path,
+    requests: Sender<Request>,
+    events: broadcast::Sender<DeviceEvent>,
+) -> Result<()> {
+    // A socket left behind by an instance that didn't shut down cleanly.
+    if path.exists() {
+        std::fs::remove_file(path).with_context(|| format!("Failed to remove {path:?}"))?;
+    }
+    let listener = UnixListener::bind(path).with_context(|| format!("Failed to bind {path:?}"))?;
+    info!("Listening for control connections on {path:?}");
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let requests = requests.clone();
+        let events = events.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_client(stream, requests, events).await {
+                debug!("Control connection failed: {e:#}");
+            }
+        });
+    }
+}
+
+/// Send one command to the daemon listening on `path`, returning its `DATA`
+/// lines.
+pub async fn send(path: &Path, command: &Command) -> Result<Vec<String>> {
+    let stream = UnixStream::connect(path)
+        .await
+        .with_context(|| format!("Failed to connect to {path:?}; is the daemon running?"))?;
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    // Events would come between the replies.
+    let ours = hello_with(|c| !c.is_event());
+    write.write_all(ours.encode().as_bytes()).await?;
+    let hello = lines.next_line().await?.context("Closed before HELLO")?;
+    let negotiated = ipc::negotiate(&ours, &Hello::decode(&hello)?)?;
+    if !negotiated.has(Capability::Control) {
+        bail!("The daemon doesn't support control commands");
+    }
+    if !negotiated.has(command.capability()) {
+        bail!(
+            "The daemon doesn't support {}",
+            command.capability().as_str()
+        );
+    }
+    write.write_all(command.encode().as_bytes()).await?;
+    let mut data = vec![];
+    loop {
+        let line = lines.next_line().await?.context("Closed before reply")?;
+        match Reply::decode(&line)? {
+            Reply::Data(text) => data.push(text),
+            Reply::Ok => return Ok(data),
+            Reply::Err(message) => bail!("{message}"),
+        }
+    }
+}
+
+/// A connection to the daemon receiving its device, accessory and fault
+/// events.
+pub struct Monitor {
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    /// The daemon stops sending once it's closed.
+    _write: OwnedWriteHalf,
+}
+
+impl Monitor {
+    /// Connect to the daemon listening on `path`. Events from then on are
+    /// received.
+    pub async fn connect(path: &Path) -> Result<Monitor> {
+        let stream = UnixStream::connect(path)
+            .await
+            .with_context(|| format!("Failed to connect to {path:?}; is the daemon running?"))?;
+        let (read, mut write) = stream.into_split();
+        let mut lines = BufReader::new(read).lines();
+        let ours = hello_with(|c| c.is_event() || *c == Capability::DisconnectReasons);
+        write.write_all(ours.encode().as_bytes()).await?;
+        let hello = lines.next_line().await?.context("Closed before HELLO")?;
+        let negotiated = ipc::negotiate(&ours, &Hello::decode(&hello)?)?;
+        if !negotiated.capabilities.iter().any(Capability::is_event) {
+            bail!("The daemon doesn't send events");
+        }
+        Ok(Monitor {
+            lines,
+            _write: write,
+        })
+    }
+
+    /// The next event, or `None` once the daemon closes the connection.
+    pub async fn next_event(&mut self) -> Result<Option<WireEvent>> {
+        while let Some(line) = self.lines.next_line().await? {
+            if let Some(event) = WireEvent::decode(&line)? {
+                return Ok(Some(event));
+            }
+        }
+        Ok(None)
+    }
+}
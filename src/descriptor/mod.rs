@@ -0,0 +1,732 @@
+use num_enum::TryFromPrimitive;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+pub mod usages;
+
+use crate::error::{Error, IoContext, Result};
+use crate::sandbox;
+use crate::storage::Storage;
+use usages::UsagePage;
+
+const LONG_ITEM: u8 = 0b11111110;
+
+const SIZE_MASK: u8 = 0b00000011;
+const TYPE_MASK: u8 = 0b00001100;
+const TAG_MASK: u8 = 0b11110000;
+
+#[derive(Debug)]
+enum ItemData {
+    None,
+    U8(u8),
+    U16(u16),
+    U32(u32),
+}
+
+impl ItemData {
+    fn unsigned(&self) -> u32 {
+        match *self {
+            ItemData::None => 0,
+            ItemData::U8(v) => v as u32,
+            ItemData::U16(v) => v as u32,
+            ItemData::U32(v) => v,
+        }
+    }
+
+    /// Minimum and maximum values are sign-extended from the item size.
+    fn signed(&self) -> i32 {
+        match *self {
+            ItemData::None => 0,
+            ItemData::U8(v) => v as i8 as i32,
+            ItemData::U16(v) => v as i16 as i32,
+            ItemData::U32(v) => v as i32,
+        }
+    }
+
+    /// Maximums are only sign-extended when the minimum is negative, as Linux
+    /// reads them: devices write 255 as `0x25 0xFF` after a minimum of 0.
+    fn maximum(&self, minimum: i32) -> i32 {
+        if minimum < 0 {
+            self.signed()
+        } else {
+            self.unsigned() as i32
+        }
+    }
+}
+
+#[derive(Debug, Eq, PartialEq, TryFromPrimitive)]
+#[repr(u8)]
+enum ItemType {
+    Main = 0,
+    Global = 1,
+    Local = 2,
+    Reserved = 3,
+}
+
+#[derive(Debug, Eq, PartialEq, TryFromPrimitive)]
+#[repr(u8)]
+enum MainItemTag {
+    Input = 0b1000,
+    Output = 0b1001,
+    Feature = 0b1011,
+    Collection = 0b1010,
+    EndCollection = 0b1100,
+}
+
+#[derive(Debug, Eq, PartialEq, TryFromPrimitive)]
+#[repr(u8)]
+enum GlobalItemTag {
+    UsagePage = 0b0000,
+    LogicalMinimum = 0b0001,
+    LogicalMaximum = 0b0010,
+    PhysicalMinimum = 0b0011,
+    PhysicalMaximum = 0b0100,
+    UnitExponent = 0b0101,
+    Unit = 0b0110,
+    ReportSize = 0b0111,
+    ReportID = 0b1000,
+    ReportCount = 0b1001,
+    Push = 0b1010,
+    Pop = 0b1011,
+    Reserved = 0b1100,
+}
+
+// Local item tags.
+const USAGE: u8 = 0b0000;
+const USAGE_MINIMUM: u8 = 0b0001;
+const USAGE_MAXIMUM: u8 = 0b0010;
+
+#[derive(Debug)]
+enum ItemTag {
+    Main(MainItemTag),
+    Global(GlobalItemTag),
+    Local(u8),
+}
+
+impl TryFrom<(u8, u8)> for ItemTag {
+    type Error = Error;
+    fn try_from(value: (u8, u8)) -> Result<Self> {
+        fn bad_tag(e: impl std::fmt::Display) -> Error {
+            Error::Parse(format!("Bad item tag: {e}"))
+        }
+        let ty = ItemType::try_from(value.0).map_err(bad_tag)?;
+        match ty {
+            ItemType::Global => Ok(ItemTag::Global(
+                GlobalItemTag::try_from(value.1).map_err(bad_tag)?,
+            )),
+            ItemType::Main => Ok(ItemTag::Main(
+                MainItemTag::try_from(value.1).map_err(bad_tag)?,
+            )),
+            ItemType::Local => Ok(ItemTag::Local(value.1)),
+            ItemType::Reserved => Err(Error::Parse("Bad item type".to_owned())),
+        }
+    }
+}
+
+/// A usage, qualified by its usage page.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Usage {
+    pub page: UsagePage,
+    pub id: u16,
+}
+
+impl Usage {
+    /// This usage as one of the typed usages, for matching on.
+    pub fn typed(&self) -> usages::Usage {
+        usages::Usage::new(self.page, self.id)
+    }
+
+    /// Usages with 4 bytes of data carry their own usage page in the high bits.
+    fn from_item(data: &ItemData, page: u16) -> Usage {
+        match *data {
+            ItemData::U32(v) => Usage {
+                page: UsagePage::from((v >> 16) as u16),
+                id: v as u16,
+            },
+            _ => Usage {
+                page: UsagePage::from(page),
+                id: data.unsigned() as u16,
+            },
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FieldKind {
+    Input,
+    Output,
+    Feature,
+}
+
+/// An Input, Output or Feature main item, with the global and local state that
+/// applied to it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Field {
+    pub kind: FieldKind,
+    /// The main item data: bit 0 is constant, bit 1 variable, bit 2 relative.
+    pub flags: u32,
+    pub report_id: Option<u8>,
+    pub report_size: u32,
+    pub report_count: u32,
+    pub usages: Vec<Usage>,
+    pub usage_minimum: Option<Usage>,
+    pub usage_maximum: Option<Usage>,
+    pub logical_minimum: i32,
+    pub logical_maximum: i32,
+    pub physical_minimum: i32,
+    pub physical_maximum: i32,
+}
+
+impl Field {
+    pub fn is_constant(&self) -> bool {
+        self.flags & 0x01 != 0
+    }
+
+    pub fn is_variable(&self) -> bool {
+        self.flags & 0x02 != 0
+    }
+
+    pub fn is_relative(&self) -> bool {
+        self.flags & 0x04 != 0
+    }
+
+    /// The size of this field in bits. Parsing rejects fields whose size
+    /// doesn't fit.
+    pub fn bits(&self) -> u32 {
+        self.report_size * self.report_count
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Collection {
+    /// 0 is physical, 1 application, 2 logical; see the HID spec for the rest.
+    pub kind: u8,
+    pub usage: Option<Usage>,
+    pub children: Vec<Node>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Node {
+    Collection(Collection),
+    Field(Field),
+}
+
+/// A hash of a report descriptor's structure, from
+/// `ReportDescriptor::fingerprint`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Fingerprint(pub u64);
+
+impl std::fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+/// FNV-1a, which unlike std's hashers is the same in every build.
+struct Fnv(u64);
+
+impl Fnv {
+    fn write(&mut self, value: u64) {
+        for byte in value.to_le_bytes() {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn write_usage(&mut self, usage: Option<Usage>) {
+        match usage {
+            Some(usage) => self.write((u16::from(usage.page) as u64) << 16 | usage.id as u64),
+            None => self.write(u64::MAX),
+        }
+    }
+}
+
+/// A parsed report descriptor.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReportDescriptor {
+    pub nodes: Vec<Node>,
+}
+
+impl ReportDescriptor {
+    /// A hash of the descriptor's collections and fields: their usages, report
+    /// IDs, sizes, logical ranges and whether they're constant, variable or
+    /// relative. Unlike a hash of the bytes, it stays the same when firmware
+    /// only changes how items are encoded, physical ranges, units or strings,
+    /// none of which change the reports.
+    pub fn fingerprint(&self) -> Fingerprint {
+        fn walk(nodes: &[Node], hash: &mut Fnv) {
+            hash.write(nodes.len() as u64);
+            for node in nodes {
+                match node {
+                    Node::Collection(c) => {
+                        hash.write(c.kind as u64);
+                        hash.write_usage(c.usage);
+                        walk(&c.children, hash);
+                    }
+                    Node::Field(f) => {
+                        hash.write(f.kind as u64 | 0x100);
+                        hash.write(f.flags as u64 & 0x07);
+                        hash.write(f.report_id.map_or(u64::MAX, u64::from));
+                        hash.write(f.report_size as u64);
+                        hash.write(f.report_count as u64);
+                        hash.write(f.usages.len() as u64);
+                        for usage in &f.usages {
+                            hash.write_usage(Some(*usage));
+                        }
+                        hash.write_usage(f.usage_minimum);
+                        hash.write_usage(f.usage_maximum);
+                        hash.write(f.logical_minimum as u64);
+                        hash.write(f.logical_maximum as u64);
+                    }
+                }
+            }
+        }
+        let mut hash = Fnv(0xcbf29ce484222325);
+        walk(&self.nodes, &mut hash);
+        Fingerprint(hash.0)
+    }
+
+    /// How many usages the fields name on each usage page, counting a usage
+    /// range as the usages in it.
+    pub fn usage_stats(&self) -> BTreeMap<u16, usize> {
+        let mut stats = BTreeMap::new();
+        for field in self.fields() {
+            for usage in &field.usages {
+                *stats.entry(u16::from(usage.page)).or_insert(0) += 1;
+            }
+            if let (Some(min), Some(max)) = (field.usage_minimum, field.usage_maximum) {
+                let count = (max.id as usize + 1).saturating_sub(min.id as usize);
+                *stats.entry(u16::from(min.page)).or_insert(0) += count;
+            }
+        }
+        stats
+    }
+
+    /// Every field in the descriptor, in order, regardless of nesting.
+    pub fn fields(&self) -> Vec<&Field> {
+        fn walk<'a>(nodes: &'a [Node], out: &mut Vec<&'a Field>) {
+            for node in nodes {
+                match node {
+                    Node::Collection(c) => walk(&c.children, out),
+                    Node::Field(f) => out.push(f),
+                }
+            }
+        }
+        let mut fields = vec![];
+        walk(&self.nodes, &mut fields);
+        fields
+    }
+
+    /// The length in bytes of each report of this kind, including the report
+    /// ID byte. Unnumbered reports have ID 0.
+    pub fn report_lengths(&self, kind: FieldKind) -> BTreeMap<u8, usize> {
+        let mut bits = BTreeMap::new();
+        for field in self.fields() {
+            if field.kind == kind {
+                *bits.entry(field.report_id.unwrap_or(0)).or_insert(0) += field.bits() as usize;
+            }
+        }
+        bits.into_iter()
+            .map(|(id, bits)| (id, 1 + bits.div_ceil(8)))
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+struct GlobalState {
+    usage_page: u16,
+    logical_minimum: i32,
+    logical_maximum: i32,
+    physical_minimum: i32,
+    physical_maximum: i32,
+    report_size: u32,
+    report_count: u32,
+    report_id: Option<u8>,
+}
+
+#[derive(Debug, Default)]
+struct LocalState {
+    usages: Vec<Usage>,
+    usage_minimum: Option<Usage>,
+    usage_maximum: Option<Usage>,
+}
+
+pub fn parse_hid_descriptor(data: &[u8]) -> Result<ReportDescriptor> {
+    let mut cur = Cursor::new(data);
+    let mut prefix = [0];
+    let mut global = GlobalState::default();
+    let mut global_stack = vec![];
+    let mut local = LocalState::default();
+    // The root, followed by each open collection.
+    let mut open: Vec<Collection> = vec![Collection {
+        kind: 0,
+        usage: None,
+        children: vec![],
+    }];
+    let truncated = |_| Error::Parse("Truncated item".to_owned());
+    while cur.read_exact(&mut prefix).is_ok() {
+        let first = prefix[0];
+        if first == LONG_ITEM {
+            let mut long_desc = [0, 0];
+            cur.read_exact(&mut long_desc).map_err(truncated)?;
+            // Just skip over the data.
+            let long_size = long_desc[0];
+            cur.seek(SeekFrom::Current(long_size as i64))
+                .map_err(truncated)?;
+            continue;
+        }
+        // A size of 3 means 4 bytes of data.
+        let size = match first & SIZE_MASK {
+            3 => 4,
+            s => s as usize,
+        };
+        let ty = (first & TYPE_MASK) >> 2;
+        let tag = (first & TAG_MASK) >> 4;
+        let tag = ItemTag::try_from((ty, tag))?;
+        let mut data_buf = [0, 0, 0, 0];
+        if size > 0 {
+            cur.read_exact(&mut data_buf[..size]).map_err(truncated)?;
+        }
+        let data = match size {
+            0 => ItemData::None,
+            1 => ItemData::U8(data_buf[0]),
+            2 => ItemData::U16(u16::from_le_bytes([data_buf[0], data_buf[1]])),
+            4 => ItemData::U32(u32::from_le_bytes(data_buf)),
+            _ => unreachable!(),
+        };
+        match tag {
+            ItemTag::Main(main) => {
+                let kind = match main {
+                    MainItemTag::Input => Some(FieldKind::Input),
+                    MainItemTag::Output => Some(FieldKind::Output),
+                    MainItemTag::Feature => Some(FieldKind::Feature),
+                    MainItemTag::Collection => {
+                        open.push(Collection {
+                            kind: data.unsigned() as u8,
+                            usage: local.usages.first().copied(),
+                            children: vec![],
+                        });
+                        None
+                    }
+                    MainItemTag::EndCollection => {
+                        if open.len() < 2 {
+                            return Err(Error::Parse(
+                                "End Collection without a matching Collection".to_owned(),
+                            ));
+                        }
+                        let done = open.pop().unwrap();
+                        open.last_mut()
+                            .unwrap()
+                            .children
+                            .push(Node::Collection(done));
+                        None
+                    }
+                };
+                if let Some(kind) = kind {
+                    if global
+                        .report_size
+                        .checked_mul(global.report_count)
+                        .is_none()
+                    {
+                        return Err(Error::Parse(format!(
+                            "Field of {} x {} bits is too large",
+                            global.report_count, global.report_size
+                        )));
+                    }
+                    let local = std::mem::take(&mut local);
+                    let field = Field {
+                        kind,
+                        flags: data.unsigned(),
+                        report_id: global.report_id,
+                        report_size: global.report_size,
+                        report_count: global.report_count,
+                        usages: local.usages,
+                        usage_minimum: local.usage_minimum,
+                        usage_maximum: local.usage_maximum,
+                        logical_minimum: global.logical_minimum,
+                        logical_maximum: global.logical_maximum,
+                        physical_minimum: global.physical_minimum,
+                        physical_maximum: global.physical_maximum,
+                    };
+                    open.last_mut().unwrap().children.push(Node::Field(field));
+                } else {
+                    local = LocalState::default();
+                }
+            }
+            ItemTag::Global(global_tag) => match global_tag {
+                GlobalItemTag::UsagePage => global.usage_page = data.unsigned() as u16,
+                GlobalItemTag::LogicalMinimum => global.logical_minimum = data.signed(),
+                GlobalItemTag::LogicalMaximum => {
+                    global.logical_maximum = data.maximum(global.logical_minimum)
+                }
+                GlobalItemTag::PhysicalMinimum => global.physical_minimum = data.signed(),
+                GlobalItemTag::PhysicalMaximum => {
+                    global.physical_maximum = data.maximum(global.physical_minimum)
+                }
+                GlobalItemTag::ReportSize => global.report_size = data.unsigned(),
+                GlobalItemTag::ReportID => global.report_id = Some(data.unsigned() as u8),
+                GlobalItemTag::ReportCount => global.report_count = data.unsigned(),
+                GlobalItemTag::Push => global_stack.push(global.clone()),
+                GlobalItemTag::Pop => {
+                    global = global_stack
+                        .pop()
+                        .ok_or_else(|| Error::Parse("Pop without a matching Push".to_owned()))?;
+                }
+                GlobalItemTag::UnitExponent | GlobalItemTag::Unit | GlobalItemTag::Reserved => {}
+            },
+            ItemTag::Local(local_tag) => {
+                let usage = Usage::from_item(&data, global.usage_page);
+                match local_tag {
+                    USAGE => local.usages.push(usage),
+                    USAGE_MINIMUM => local.usage_minimum = Some(usage),
+                    USAGE_MAXIMUM => local.usage_maximum = Some(usage),
+                    // Designators, strings and delimiters.
+                    _ => {}
+                }
+            }
+        }
+    }
+    if open.len() != 1 {
+        return Err(Error::Parse("Unterminated collection".to_owned()));
+    }
+    Ok(ReportDescriptor {
+        nodes: open.pop().unwrap().children,
+    })
+}
+
+/// The name of a collection type, from the HID spec.
+fn collection_kind_name(kind: u8) -> &'static str {
+    match kind {
+        0 => "Physical",
+        1 => "Application",
+        2 => "Logical",
+        3 => "Report",
+        4 => "Named Array",
+        5 => "Usage Switch",
+        6 => "Usage Modifier",
+        0x80..=0xFF => "Vendor Defined",
+        _ => "Reserved",
+    }
+}
+
+fn page_name(page: UsagePage) -> String {
+    match page.name() {
+        Some(name) => name.to_owned(),
+        None => format!("{:#06x}", u16::from(page)),
+    }
+}
+
+fn qualified_usage_name(usage: &Usage) -> String {
+    format!(
+        "{} / {}",
+        page_name(usage.page),
+        usages::usage_name(usage.page, usage.id)
+    )
+}
+
+/// Name a list of usages, only giving the page once if they share one.
+fn usage_list(list: &[Usage]) -> String {
+    let Some(first) = list.first() else {
+        return String::new();
+    };
+    if list.iter().all(|u| u.page == first.page) {
+        let names: Vec<String> = list
+            .iter()
+            .map(|u| usages::usage_name(u.page, u.id))
+            .collect();
+        format!("{} / {}", page_name(first.page), names.join(", "))
+    } else {
+        let names: Vec<String> = list.iter().map(qualified_usage_name).collect();
+        names.join(", ")
+    }
+}
+
+/// Render a descriptor for people: one line per collection and field, with
+/// usages by name, indented by collection depth.
+pub fn dump_descriptor(desc: &ReportDescriptor) -> String {
+    fn walk(nodes: &[Node], depth: usize, out: &mut String) {
+        let indent = "  ".repeat(depth);
+        for node in nodes {
+            match node {
+                Node::Collection(c) => {
+                    let _ = write!(out, "{indent}Collection {}", collection_kind_name(c.kind));
+                    if let Some(usage) = &c.usage {
+                        let _ = write!(out, " ({})", qualified_usage_name(usage));
+                    }
+                    out.push('\n');
+                    walk(&c.children, depth + 1, out);
+                }
+                Node::Field(f) => {
+                    let _ = write!(out, "{indent}{:?}", f.kind);
+                    if let Some(id) = f.report_id {
+                        let _ = write!(out, " report {id:#04x}");
+                    }
+                    let _ = write!(out, " {}x{} bits", f.report_count, f.report_size);
+                    if f.is_constant() {
+                        out.push_str(" constant");
+                    } else {
+                        out.push_str(if f.is_variable() {
+                            " variable"
+                        } else {
+                            " array"
+                        });
+                        if f.is_relative() {
+                            out.push_str(" relative");
+                        }
+                        let _ = write!(out, " {}..={}", f.logical_minimum, f.logical_maximum);
+                    }
+                    if !f.usages.is_empty() {
+                        let _ = write!(out, ": {}", usage_list(&f.usages));
+                    }
+                    if let (Some(min), Some(max)) = (&f.usage_minimum, &f.usage_maximum) {
+                        let _ = write!(
+                            out,
+                            ": {} to {}",
+                            qualified_usage_name(min),
+                            usages::usage_name(max.page, max.id)
+                        );
+                    }
+                    out.push('\n');
+                }
+            }
+        }
+    }
+    let mut out = String::new();
+    walk(&desc.nodes, 0, &mut out);
+    out
+}
+
+/// Where users can put replacement report descriptors for devices whose own
+/// are broken, named `vvvv:pppp.bin` for raw bytes or `vvvv:pppp.hex` for a
+/// hex dump.
+pub const DEFAULT_OVERRIDE_DIR: &str = "/etc/hidraw/descriptors";
+
+/// The descriptor override directory, `$HIDRAW_DESCRIPTORS` if set. Inside
+/// Flatpak, where `/etc` is the runtime's, it's `descriptors` in the user's
+/// config directory instead.
+pub fn override_dir() -> PathBuf {
+    if let Some(dir) = std::env::var_os("HIDRAW_DESCRIPTORS") {
+        return PathBuf::from(dir);
+    }
+    if sandbox::in_flatpak() {
+        if let Some(dir) = sandbox::config_dir() {
+            return dir.join("descriptors");
+        }
+    }
+    PathBuf::from(DEFAULT_OVERRIDE_DIR)
+}
+
+/// Parse a hex dump like `05 01 09 05` or `0x05, 0x01, 0x09, 0x05`. Bytes may
+/// also be run together, and `#` starts a comment.
+pub fn parse_hex(text: &str) -> Result<Vec<u8>> {
+    let mut data = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        for token in line.split(|c: char| c.is_whitespace() || c == ',') {
+            let digits = token.trim_start_matches("0x").trim_start_matches("0X");
+            if digits.is_empty() {
+                continue;
+            }
+            let bad_byte = || Error::Parse(format!("Line {}: bad hex in `{token}`", i + 1));
+            if !digits.len().is_multiple_of(2) {
+                return Err(Error::Parse(format!(
+                    "Line {}: odd number of hex digits in `{token}`",
+                    i + 1
+                )));
+            }
+            for pair in digits.as_bytes().chunks(2) {
+                let pair = std::str::from_utf8(pair).map_err(|_| bad_byte())?;
+                data.push(u8::from_str_radix(pair, 16).map_err(|_| bad_byte())?);
+            }
+        }
+    }
+    Ok(data)
+}
+
+/// Load the override descriptor for a device from `dir`, if there is one.
+pub fn load_override(dir: &Path, vendor_id: u16, product_id: u16) -> Result<Option<Vec<u8>>> {
+    let stem = format!("{vendor_id:04x}:{product_id:04x}");
+    let bin = dir.join(format!("{stem}.bin"));
+    if bin.exists() {
+        return Ok(Some(
+            fs::read(&bin).io_context(|| format!("Failed to read {bin:?}"))?,
+        ));
+    }
+    let hex = dir.join(format!("{stem}.hex"));
+    if hex.exists() {
+        let text = fs::read_to_string(&hex).io_context(|| format!("Failed to read {hex:?}"))?;
+        return Ok(Some(
+            parse_hex(&text).map_err(|e| Error::Parse(format!("Bad hex in {hex:?}: {e}")))?,
+        ));
+    }
+    Ok(None)
+}
+
+/// Load the override descriptor for a device from `storage`, where it's kept
+/// as `descriptors/vvvv:pppp.bin` or `.hex` like the files in an override
+/// directory.
+pub fn load_stored_override(
+    storage: &dyn Storage,
+    vendor_id: u16,
+    product_id: u16,
+) -> Result<Option<Vec<u8>>> {
+    let stem = format!("descriptors/{vendor_id:04x}:{product_id:04x}");
+    let read = |key: &str| {
+        storage
+            .read(key)
+            .map_err(|e| io::Error::other(format!("{e:#}")))
+            .io_context(|| format!("Failed to read stored `{key}`"))
+    };
+    let bin = format!("{stem}.bin");
+    if let Some(data) = read(&bin)? {
+        return Ok(Some(data));
+    }
+    let hex = format!("{stem}.hex");
+    if let Some(data) = read(&hex)? {
+        let text = String::from_utf8_lossy(&data);
+        return Ok(Some(parse_hex(&text).map_err(|e| {
+            Error::Parse(format!("Bad hex in stored `{hex}`: {e}"))
+        })?));
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maximum_is_unsigned_unless_minimum_is_negative() {
+        #[rustfmt::skip]
+        let data = [
+            0x05, 0x01, 0x09, 0x05, 0xA1, 0x01, // Gamepad application
+            0x75, 0x08, 0x95, 0x01,
+            0x15, 0x00, 0x25, 0xFF, 0x09, 0x30, 0x81, 0x02, // X, 0..=255
+            0x15, 0x81, 0x25, 0x7F, 0x09, 0x31, 0x81, 0x02, // Y, -127..=127
+            0x15, 0xFF, 0x25, 0xFF, 0x09, 0x32, 0x81, 0x02, // Z, -1..=-1
+            0xC0,
+        ];
+        let desc = parse_hid_descriptor(&data).unwrap();
+        let ranges: Vec<_> = desc
+            .fields()
+            .iter()
+            .map(|f| (f.logical_minimum, f.logical_maximum))
+            .collect();
+        assert_eq!(ranges, vec![(0, 255), (-127, 127), (-1, -1)]);
+    }
+
+    #[test]
+    fn rejects_fields_too_large_to_measure() {
+        #[rustfmt::skip]
+        let data = [
+            0x05, 0x01, 0x09, 0x05, 0xA1, 0x01, // Gamepad application
+            0x77, 0x00, 0x00, 0x01, 0x00, // Report Size 65536
+            0x97, 0x00, 0x00, 0x01, 0x00, // Report Count 65536
+            0x09, 0x30, 0x81, 0x02,
+            0xC0,
+        ];
+        assert!(parse_hid_descriptor(&data).is_err());
+    }
+}
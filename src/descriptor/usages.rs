@@ -0,0 +1,417 @@
+//! Names from the HID Usage Tables, for printing descriptors.
+
+use num_enum::TryFromPrimitive;
+
+/// A usage page from the HID Usage Tables.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum UsagePage {
+    GenericDesktop,
+    Simulation,
+    Vr,
+    Sport,
+    Game,
+    GenericDevice,
+    Keyboard,
+    Led,
+    Button,
+    Ordinal,
+    Telephony,
+    Consumer,
+    Digitizer,
+    Haptics,
+    Pid,
+    Unicode,
+    EyeHeadTrackers,
+    Sensors,
+    Power,
+    BatterySystem,
+    VendorDefined(u16),
+    Unknown(u16),
+}
+
+impl From<u16> for UsagePage {
+    fn from(page: u16) -> UsagePage {
+        match page {
+            0x01 => UsagePage::GenericDesktop,
+            0x02 => UsagePage::Simulation,
+            0x03 => UsagePage::Vr,
+            0x04 => UsagePage::Sport,
+            0x05 => UsagePage::Game,
+            0x06 => UsagePage::GenericDevice,
+            0x07 => UsagePage::Keyboard,
+            0x08 => UsagePage::Led,
+            0x09 => UsagePage::Button,
+            0x0A => UsagePage::Ordinal,
+            0x0B => UsagePage::Telephony,
+            0x0C => UsagePage::Consumer,
+            0x0D => UsagePage::Digitizer,
+            0x0E => UsagePage::Haptics,
+            0x0F => UsagePage::Pid,
+            0x10 => UsagePage::Unicode,
+            0x12 => UsagePage::EyeHeadTrackers,
+            0x20 => UsagePage::Sensors,
+            0x84 => UsagePage::Power,
+            0x85 => UsagePage::BatterySystem,
+            0xFF00..=0xFFFF => UsagePage::VendorDefined(page),
+            _ => UsagePage::Unknown(page),
+        }
+    }
+}
+
+impl From<UsagePage> for u16 {
+    fn from(page: UsagePage) -> u16 {
+        match page {
+            UsagePage::GenericDesktop => 0x01,
+            UsagePage::Simulation => 0x02,
+            UsagePage::Vr => 0x03,
+            UsagePage::Sport => 0x04,
+            UsagePage::Game => 0x05,
+            UsagePage::GenericDevice => 0x06,
+            UsagePage::Keyboard => 0x07,
+            UsagePage::Led => 0x08,
+            UsagePage::Button => 0x09,
+            UsagePage::Ordinal => 0x0A,
+            UsagePage::Telephony => 0x0B,
+            UsagePage::Consumer => 0x0C,
+            UsagePage::Digitizer => 0x0D,
+            UsagePage::Haptics => 0x0E,
+            UsagePage::Pid => 0x0F,
+            UsagePage::Unicode => 0x10,
+            UsagePage::EyeHeadTrackers => 0x12,
+            UsagePage::Sensors => 0x20,
+            UsagePage::Power => 0x84,
+            UsagePage::BatterySystem => 0x85,
+            UsagePage::VendorDefined(page) | UsagePage::Unknown(page) => page,
+        }
+    }
+}
+
+impl UsagePage {
+    pub fn name(&self) -> Option<&'static str> {
+        Some(match self {
+            UsagePage::GenericDesktop => "Generic Desktop",
+            UsagePage::Simulation => "Simulation Controls",
+            UsagePage::Vr => "VR Controls",
+            UsagePage::Sport => "Sport Controls",
+            UsagePage::Game => "Game Controls",
+            UsagePage::GenericDevice => "Generic Device Controls",
+            UsagePage::Keyboard => "Keyboard/Keypad",
+            UsagePage::Led => "LED",
+            UsagePage::Button => "Button",
+            UsagePage::Ordinal => "Ordinal",
+            UsagePage::Telephony => "Telephony Device",
+            UsagePage::Consumer => "Consumer",
+            UsagePage::Digitizer => "Digitizers",
+            UsagePage::Haptics => "Haptics",
+            UsagePage::Pid => "Physical Input Device",
+            UsagePage::Unicode => "Unicode",
+            UsagePage::EyeHeadTrackers => "Eye and Head Trackers",
+            UsagePage::Sensors => "Sensors",
+            UsagePage::Power => "Power",
+            UsagePage::BatterySystem => "Battery System",
+            UsagePage::VendorDefined(_) => "Vendor Defined",
+            UsagePage::Unknown(_) => return None,
+        })
+    }
+}
+
+/// Usages on the Generic Desktop page, which gamepads put their sticks, hat
+/// switch and d-pad on.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, TryFromPrimitive)]
+#[repr(u16)]
+pub enum GenericDesktop {
+    Pointer = 0x01,
+    Mouse = 0x02,
+    Joystick = 0x04,
+    Gamepad = 0x05,
+    Keyboard = 0x06,
+    Keypad = 0x07,
+    MultiAxisController = 0x08,
+    X = 0x30,
+    Y = 0x31,
+    Z = 0x32,
+    Rx = 0x33,
+    Ry = 0x34,
+    Rz = 0x35,
+    Slider = 0x36,
+    Dial = 0x37,
+    Wheel = 0x38,
+    HatSwitch = 0x39,
+    CountedBuffer = 0x3A,
+    ByteCount = 0x3B,
+    MotionWakeup = 0x3C,
+    Start = 0x3D,
+    Select = 0x3E,
+    Vx = 0x40,
+    Vy = 0x41,
+    Vz = 0x42,
+    Vbrx = 0x43,
+    Vbry = 0x44,
+    Vbrz = 0x45,
+    Vno = 0x46,
+    SystemControl = 0x80,
+    SystemPowerDown = 0x81,
+    SystemSleep = 0x82,
+    SystemWakeUp = 0x83,
+    SystemMainMenu = 0x85,
+    DpadUp = 0x90,
+    DpadDown = 0x91,
+    DpadRight = 0x92,
+    DpadLeft = 0x93,
+}
+
+impl GenericDesktop {
+    /// The six axes gamepads use for sticks and triggers, X to Rz.
+    pub fn is_axis(&self) -> bool {
+        (GenericDesktop::X as u16..=GenericDesktop::Rz as u16).contains(&(*self as u16))
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            GenericDesktop::Pointer => "Pointer",
+            GenericDesktop::Mouse => "Mouse",
+            GenericDesktop::Joystick => "Joystick",
+            GenericDesktop::Gamepad => "Gamepad",
+            GenericDesktop::Keyboard => "Keyboard",
+            GenericDesktop::Keypad => "Keypad",
+            GenericDesktop::MultiAxisController => "Multi-axis Controller",
+            GenericDesktop::X => "X",
+            GenericDesktop::Y => "Y",
+            GenericDesktop::Z => "Z",
+            GenericDesktop::Rx => "Rx",
+            GenericDesktop::Ry => "Ry",
+            GenericDesktop::Rz => "Rz",
+            GenericDesktop::Slider => "Slider",
+            GenericDesktop::Dial => "Dial",
+            GenericDesktop::Wheel => "Wheel",
+            GenericDesktop::HatSwitch => "Hat Switch",
+            GenericDesktop::CountedBuffer => "Counted Buffer",
+            GenericDesktop::ByteCount => "Byte Count",
+            GenericDesktop::MotionWakeup => "Motion Wakeup",
+            GenericDesktop::Start => "Start",
+            GenericDesktop::Select => "Select",
+            GenericDesktop::Vx => "Vx",
+            GenericDesktop::Vy => "Vy",
+            GenericDesktop::Vz => "Vz",
+            GenericDesktop::Vbrx => "Vbrx",
+            GenericDesktop::Vbry => "Vbry",
+            GenericDesktop::Vbrz => "Vbrz",
+            GenericDesktop::Vno => "Vno",
+            GenericDesktop::SystemControl => "System Control",
+            GenericDesktop::SystemPowerDown => "System Power Down",
+            GenericDesktop::SystemSleep => "System Sleep",
+            GenericDesktop::SystemWakeUp => "System Wake Up",
+            GenericDesktop::SystemMainMenu => "System Main Menu",
+            GenericDesktop::DpadUp => "D-pad Up",
+            GenericDesktop::DpadDown => "D-pad Down",
+            GenericDesktop::DpadRight => "D-pad Right",
+            GenericDesktop::DpadLeft => "D-pad Left",
+        }
+    }
+}
+
+/// A usage, typed on the pages we interpret.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Usage {
+    GenericDesktop(GenericDesktop),
+    /// Buttons are numbered from 1; 0 means no button is pressed.
+    Button(u16),
+    Other(UsagePage, u16),
+}
+
+impl Usage {
+    pub fn new(page: UsagePage, id: u16) -> Usage {
+        match page {
+            UsagePage::GenericDesktop => match GenericDesktop::try_from(id) {
+                Ok(usage) => Usage::GenericDesktop(usage),
+                Err(_) => Usage::Other(page, id),
+            },
+            UsagePage::Button => Usage::Button(id),
+            _ => Usage::Other(page, id),
+        }
+    }
+
+    pub fn page(&self) -> UsagePage {
+        match self {
+            Usage::GenericDesktop(_) => UsagePage::GenericDesktop,
+            Usage::Button(_) => UsagePage::Button,
+            Usage::Other(page, _) => *page,
+        }
+    }
+
+    pub fn id(&self) -> u16 {
+        match *self {
+            Usage::GenericDesktop(usage) => usage as u16,
+            Usage::Button(id) | Usage::Other(_, id) => id,
+        }
+    }
+}
+
+fn simulation(id: u16) -> Option<&'static str> {
+    Some(match id {
+        0x01 => "Flight Simulation Device",
+        0x02 => "Automobile Simulation Device",
+        0xB0 => "Aileron",
+        0xB1 => "Aileron Trim",
+        0xB2 => "Anti-Torque Control",
+        0xB3 => "Autopilot Enable",
+        0xB4 => "Chaff Release",
+        0xB5 => "Collective Control",
+        0xB6 => "Dive Brake",
+        0xB7 => "Electronic Countermeasures",
+        0xB8 => "Elevator",
+        0xB9 => "Elevator Trim",
+        0xBA => "Rudder",
+        0xBB => "Throttle",
+        0xBC => "Flight Communications",
+        0xBD => "Flare Release",
+        0xBE => "Landing Gear",
+        0xBF => "Toe Brake",
+        0xC0 => "Trigger",
+        0xC1 => "Weapons Arm",
+        0xC2 => "Weapons Select",
+        0xC3 => "Wing Flaps",
+        0xC4 => "Accelerator",
+        0xC5 => "Brake",
+        0xC6 => "Clutch",
+        0xC7 => "Shifter",
+        0xC8 => "Steering",
+        _ => return None,
+    })
+}
+
+fn game(id: u16) -> Option<&'static str> {
+    Some(match id {
+        0x01 => "3D Game Controller",
+        0x02 => "Pinball Device",
+        0x03 => "Gun Device",
+        0x20 => "Point of View",
+        0x21 => "Turn Right/Left",
+        0x22 => "Pitch Forward/Backward",
+        0x23 => "Roll Right/Left",
+        0x24 => "Move Right/Left",
+        0x25 => "Move Forward/Backward",
+        0x26 => "Move Up/Down",
+        0x27 => "Lean Right/Left",
+        0x28 => "Lean Forward/Backward",
+        0x37 => "Gamepad Fire/Jump",
+        0x39 => "Gamepad Trigger",
+        _ => return None,
+    })
+}
+
+fn generic_device(id: u16) -> Option<&'static str> {
+    Some(match id {
+        0x20 => "Battery Strength",
+        0x21 => "Wireless Channel",
+        0x22 => "Wireless ID",
+        _ => return None,
+    })
+}
+
+fn led(id: u16) -> Option<&'static str> {
+    Some(match id {
+        0x01 => "Num Lock",
+        0x02 => "Caps Lock",
+        0x03 => "Scroll Lock",
+        0x4B => "Generic Indicator",
+        _ => return None,
+    })
+}
+
+fn consumer(id: u16) -> Option<&'static str> {
+    Some(match id {
+        0x01 => "Consumer Control",
+        0x30 => "Power",
+        0x40 => "Menu",
+        0xB0 => "Play",
+        0xB1 => "Pause",
+        0xB5 => "Scan Next Track",
+        0xB6 => "Scan Previous Track",
+        0xB7 => "Stop",
+        0xCD => "Play/Pause",
+        0xE2 => "Mute",
+        0xE9 => "Volume Increment",
+        0xEA => "Volume Decrement",
+        0x223 => "AC Home",
+        0x224 => "AC Back",
+        _ => return None,
+    })
+}
+
+fn digitizer(id: u16) -> Option<&'static str> {
+    Some(match id {
+        0x04 => "Touch Screen",
+        0x05 => "Touch Pad",
+        0x22 => "Finger",
+        0x30 => "Tip Pressure",
+        0x32 => "In Range",
+        0x42 => "Tip Switch",
+        0x47 => "Confidence",
+        0x48 => "Width",
+        0x49 => "Height",
+        0x51 => "Contact Identifier",
+        0x54 => "Contact Count",
+        0x55 => "Contact Count Maximum",
+        0x56 => "Scan Time",
+        _ => return None,
+    })
+}
+
+fn sensors(id: u16) -> Option<&'static str> {
+    Some(match id {
+        0x73 => "Motion: Accelerometer 3D",
+        0x76 => "Motion: Gyrometer 3D",
+        0x453 => "Data Field: Acceleration Axis X",
+        0x454 => "Data Field: Acceleration Axis Y",
+        0x455 => "Data Field: Acceleration Axis Z",
+        0x457 => "Data Field: Angular Velocity X Axis",
+        0x458 => "Data Field: Angular Velocity Y Axis",
+        0x459 => "Data Field: Angular Velocity Z Axis",
+        _ => return None,
+    })
+}
+
+fn battery_system(id: u16) -> Option<&'static str> {
+    Some(match id {
+        0x44 => "Charging",
+        0x45 => "Discharging",
+        0x65 => "Absolute State Of Charge",
+        0x66 => "Remaining Capacity",
+        _ => return None,
+    })
+}
+
+/// The name of a usage, falling back to its hex ID on pages we know but usages
+/// we don't.
+pub fn usage_name(page: UsagePage, id: u16) -> String {
+    let named = match page {
+        UsagePage::GenericDesktop => GenericDesktop::try_from(id).ok().map(|u| u.name()),
+        UsagePage::Simulation => simulation(id),
+        UsagePage::Game => game(id),
+        UsagePage::GenericDevice => generic_device(id),
+        UsagePage::Led => led(id),
+        UsagePage::Consumer => consumer(id),
+        UsagePage::Digitizer => digitizer(id),
+        UsagePage::Sensors => sensors(id),
+        UsagePage::BatterySystem => battery_system(id),
+        // These pages number their usages.
+        UsagePage::Button if id == 0 => Some("No Button Pressed"),
+        UsagePage::Button => return format!("Button {id}"),
+        UsagePage::Ordinal => return format!("Instance {id}"),
+        UsagePage::Keyboard => match id {
+            0x04..=0x1D => return format!("Keyboard {}", (b'a' + (id - 0x04) as u8) as char),
+            0x1E..=0x26 => return format!("Keyboard {}", id - 0x1D),
+            0x27 => Some("Keyboard 0"),
+            0x28 => Some("Keyboard Return"),
+            0x29 => Some("Keyboard Escape"),
+            0x2C => Some("Keyboard Spacebar"),
+            _ => None,
+        },
+        _ => None,
+    };
+    match named {
+        Some(name) => name.to_owned(),
+        None => format!("{id:#06x}"),
+    }
+}
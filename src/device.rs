@@ -1,31 +1,1603 @@
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
+use futures::future::BoxFuture;
+use futures::{Future, FutureExt};
 use libc::input_event;
-use log::info;
-use tokio::fs::OpenOptions;
+use log::{debug, error, info, warn};
+use nix::errno::Errno;
+use num_enum::TryFromPrimitive;
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicI16, Ordering};
+use std::time::{Duration, Instant};
+use tokio::fs::{File, OpenOptions};
 use tokio::io::AsyncReadExt;
-use tokio::sync::mpsc::Receiver;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::oneshot;
 
-use crate::device_monitor::DeviceInfo;
+use crate::calibration::{AxisCalibrator, CalibrationConfig};
+use crate::capture::{self, Capture, CaptureEntry};
+use crate::descriptor::{self, FieldKind};
+use crate::device_monitor::{Bus, DeviceEvent, DeviceInfo, DisconnectReason};
+use crate::drivers::{self, fast_mode, handheld, DriverOptions, HidDriver};
+use crate::error::{self, Error, IoContext};
+#[cfg(feature = "usbfs")]
+use crate::handle::UsbfsHandle;
+use crate::handle::{Demux, DeviceHandle, HidrawHandle};
+use crate::led::{self, EvdevLeds, Led, XpadRing};
+use crate::report::{self, Axis, Button, GamepadInput, HidReportParser, ParsedReport};
+use crate::rumble::{Rumble, RumbleEffect};
+use crate::sdl_mapping::{MappedGamepad, MappingDatabase};
+use crate::trace::{self, Phase};
 
-pub async fn watch_one_device(info: DeviceInfo, mut stop_rx: Receiver<()>) -> Result<()> {
+// From Linux uapi/linux/input-event-codes.h
+pub const EV_SYN: u16 = 0x00;
+pub const EV_KEY: u16 = 0x01;
+pub const EV_REL: u16 = 0x02;
+pub const EV_ABS: u16 = 0x03;
+pub const EV_MSC: u16 = 0x04;
+pub const EV_FF: u16 = 0x15;
+pub const MSC_TIMESTAMP: u16 = 0x05;
+// Multitouch protocol B
+pub const ABS_MT_SLOT: u16 = 0x2F;
+pub const ABS_MT_POSITION_X: u16 = 0x35;
+pub const ABS_MT_POSITION_Y: u16 = 0x36;
+pub const ABS_MT_TRACKING_ID: u16 = 0x39;
+pub const FF_RUMBLE: u16 = 0x50;
+
+/// Gamepad buttons, from Linux uapi/linux/input-event-codes.h. Their names
+/// follow the kernel's gamepad API documentation.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, TryFromPrimitive)]
+#[repr(u16)]
+pub enum GamepadButton {
+    /// `KEY_RECORD`, which hid-microsoft reports the Xbox Series X|S Share
+    /// button as.
+    Record = 0xA7,
+    South = 0x130,
+    East = 0x131,
+    C = 0x132,
+    North = 0x133,
+    West = 0x134,
+    Z = 0x135,
+    TL = 0x136,
+    TR = 0x137,
+    TL2 = 0x138,
+    TR2 = 0x139,
+    Select = 0x13A,
+    Start = 0x13B,
+    Mode = 0x13C,
+    ThumbL = 0x13D,
+    ThumbR = 0x13E,
+    DpadUp = 0x220,
+    DpadDown = 0x221,
+    DpadLeft = 0x222,
+    DpadRight = 0x223,
+}
+
+impl GamepadButton {
+    /// The standard button this is, if it is one. D-pad buttons aren't
+    /// buttons in `GamepadInput`.
+    pub fn button(&self) -> Option<Button> {
+        match self {
+            GamepadButton::South => Some(Button::South),
+            GamepadButton::East => Some(Button::East),
+            GamepadButton::North => Some(Button::North),
+            GamepadButton::West => Some(Button::West),
+            GamepadButton::TL => Some(Button::LeftShoulder),
+            GamepadButton::TR => Some(Button::RightShoulder),
+            GamepadButton::Select => Some(Button::Back),
+            GamepadButton::Start => Some(Button::Start),
+            GamepadButton::Mode => Some(Button::Guide),
+            GamepadButton::ThumbL => Some(Button::LeftStick),
+            GamepadButton::ThumbR => Some(Button::RightStick),
+            GamepadButton::Record => Some(Button::Misc),
+            _ => None,
+        }
+    }
+}
+
+/// Gamepad axes, from Linux uapi/linux/input-event-codes.h.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, TryFromPrimitive)]
+#[repr(u16)]
+pub enum GamepadAxis {
+    X = 0x00,
+    Y = 0x01,
+    Z = 0x02,
+    Rx = 0x03,
+    Ry = 0x04,
+    Rz = 0x05,
+    Hat0X = 0x10,
+    Hat0Y = 0x11,
+}
+
+impl GamepadAxis {
+    /// The standard axis this is, if it is one. Most gamepads report their
+    /// triggers on Z and Rz, and a hat for the d-pad.
+    pub fn axis(&self) -> Option<Axis> {
+        match self {
+            GamepadAxis::X => Some(Axis::LeftX),
+            GamepadAxis::Y => Some(Axis::LeftY),
+            GamepadAxis::Rx => Some(Axis::RightX),
+            GamepadAxis::Ry => Some(Axis::RightY),
+            GamepadAxis::Z => Some(Axis::LeftTrigger),
+            GamepadAxis::Rz => Some(Axis::RightTrigger),
+            GamepadAxis::Hat0X | GamepadAxis::Hat0Y => None,
+        }
+    }
+}
+
+/// An evdev event, decoded.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EvdevEvent {
+    Button {
+        button: GamepadButton,
+        pressed: bool,
+    },
+    Axis {
+        axis: GamepadAxis,
+        value: i32,
+    },
+    /// The end of a batch of events that happened at the same time.
+    Sync,
+    /// Anything else, e.g. keys that aren't gamepad buttons.
+    Other {
+        type_: u16,
+        code: u16,
+        value: i32,
+    },
+}
+
+/// Decode an `input_event`.
+pub fn decode_event(event: &input_event) -> EvdevEvent {
+    let other = EvdevEvent::Other {
+        type_: event.type_,
+        code: event.code,
+        value: event.value,
+    };
+    match event.type_ {
+        EV_SYN => EvdevEvent::Sync,
+        EV_KEY => match GamepadButton::try_from(event.code) {
+            Ok(button) => EvdevEvent::Button {
+                button,
+                pressed: event.value != 0,
+            },
+            Err(_) => other,
+        },
+        EV_ABS => match GamepadAxis::try_from(event.code) {
+            Ok(axis) => EvdevEvent::Axis {
+                axis,
+                value: event.value,
+            },
+            Err(_) => other,
+        },
+        _ => other,
+    }
+}
+
+// From Linux uapi/linux/hid.h
+const HID_MAX_DESCRIPTOR_SIZE: usize = 4096;
+pub const HID_MAX_BUFFER_SIZE: usize = 16384;
+
+#[cfg(feature = "usbfs")]
+const SYS_CLASS_HIDRAW: &str = "/sys/class/hidraw";
+
+/// From Linux uapi/linux/hidraw.h
+#[repr(C)]
+struct HidrawReportDescriptor {
+    size: u32,
+    value: [u8; HID_MAX_DESCRIPTOR_SIZE],
+}
+
+/// An absolute axis's range, from Linux uapi/linux/input.h.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct AbsInfo {
+    pub value: i32,
+    pub minimum: i32,
+    pub maximum: i32,
+    pub fuzz: i32,
+    pub flat: i32,
+    /// Units per millimeter, or per G or degree per second on motion sensors.
+    pub resolution: i32,
+}
+
+/// From Linux uapi/linux/input.h
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct FfRumbleEffect {
+    pub strong_magnitude: u16,
+    pub weak_magnitude: u16,
+}
+
+/// The effect union of `struct ff_effect`. Its largest member,
+/// `struct ff_periodic_effect`, ends in a pointer, which sets its size and
+/// alignment.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub union FfEffectData {
+    pub rumble: FfRumbleEffect,
+    _size: [libc::c_ulong; if cfg!(target_pointer_width = "64") {
+        4
+    } else {
+        7
+    }],
+}
+
+/// From Linux uapi/linux/input.h
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct FfEffect {
+    pub type_: u16,
+    pub id: i16,
+    pub direction: u16,
+    pub trigger_button: u16,
+    pub trigger_interval: u16,
+    /// Milliseconds, or 0 to play until stopped.
+    pub replay_length: u16,
+    pub replay_delay: u16,
+    pub u: FfEffectData,
+}
+
+#[cfg(target_pointer_width = "64")]
+const _: () = assert!(std::mem::size_of::<FfEffect>() == 48);
+#[cfg(target_pointer_width = "32")]
+const _: () = assert!(std::mem::size_of::<FfEffect>() == 44);
+
+mod ioctl {
+    use super::{FfEffect, HidrawReportDescriptor, InputId};
+
+    // From Linux uapi/linux/hidraw.h
+    nix::ioctl_read!(hid_get_rdesc_size, b'H', 0x01, libc::c_int);
+    nix::ioctl_read!(hid_get_rdesc, b'H', 0x02, HidrawReportDescriptor);
+    nix::ioctl_readwrite_buf!(hid_set_feature, b'H', 0x06, u8);
+    nix::ioctl_readwrite_buf!(hid_get_feature, b'H', 0x07, u8);
+    // From Linux uapi/linux/input.h
+    nix::ioctl_read!(eviocgid, b'E', 0x02, InputId);
+    nix::ioctl_write_int!(eviocgrab, b'E', 0x90);
+    nix::ioctl_read_buf!(eviocgname, b'E', 0x06, u8);
+    nix::ioctl_read_buf!(eviocgphys, b'E', 0x07, u8);
+    nix::ioctl_read_buf!(eviocguniq, b'E', 0x08, u8);
+    // EVIOCSFF is declared write-only, but the kernel writes the new effect's
+    // ID back into the struct.
+    nix::ioctl_write_ptr!(eviocsff, b'E', 0x80, FfEffect);
+    nix::ioctl_write_int!(eviocrmff, b'E', 0x81);
+}
+
+/// Send a feature report to a hidraw node. The first byte of `data` is the
+/// report ID, or 0 for devices that don't use numbered reports.
+pub fn send_feature_report(fd: RawFd, data: &[u8]) -> error::Result<()> {
+    let report_id = data.first().copied().unwrap_or(0);
+    let mut buf = data.to_vec();
+    unsafe { ioctl::hid_set_feature(fd, &mut buf) }
+        .io_context(|| format!("Failed to send feature report {report_id:#04x}"))?;
+    Ok(())
+}
+
+/// Read feature report `report_id` from a hidraw node, with room for `len`
+/// bytes including the report ID. The first byte of the result is the ID.
+pub fn get_feature_report(fd: RawFd, report_id: u8, len: usize) -> error::Result<Vec<u8>> {
+    let mut buf = vec![0; len.max(1)];
+    buf[0] = report_id;
+    let read = unsafe { ioctl::hid_get_feature(fd, &mut buf) }
+        .io_context(|| format!("Failed to get feature report {report_id:#04x}"))?;
+    buf.truncate(read.max(0) as usize);
+    Ok(buf)
+}
+
+/// A hidraw node opened for exchanging feature and output reports.
+pub struct Device {
+    file: std::fs::File,
+    /// From the report descriptor, including the report ID byte.
+    feature_lengths: BTreeMap<u8, usize>,
+    output_lengths: BTreeMap<u8, usize>,
+    /// Counts LED reports, for the devices that number their output reports.
+    sequence: u8,
+}
+
+impl Device {
+    pub fn open(hidraw_node: &Path) -> error::Result<Device> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(hidraw_node)
+            .io_context(|| format!("Failed to open {hidraw_node:?}"))?;
+        let desc = descriptor::parse_hid_descriptor(&read_report_descriptor(file.as_raw_fd())?)?;
+        Ok(Device {
+            file,
+            feature_lengths: desc.report_lengths(FieldKind::Feature),
+            output_lengths: desc.report_lengths(FieldKind::Output),
+            sequence: 0,
+        })
+    }
+
+    /// The IDs of the feature reports the device declares, 0 if unnumbered.
+    pub fn feature_report_ids(&self) -> Vec<u8> {
+        self.feature_lengths.keys().copied().collect()
+    }
+
+    /// Read feature report `report_id`. The first byte of the result is the
+    /// report ID. Reports the descriptor doesn't declare are read into a
+    /// maximum size buffer, for devices with incomplete descriptors.
+    pub fn get_feature_report(&self, report_id: u8) -> error::Result<Vec<u8>> {
+        let len = self
+            .feature_lengths
+            .get(&report_id)
+            .copied()
+            .unwrap_or(HID_MAX_BUFFER_SIZE);
+        get_feature_report(self.file.as_raw_fd(), report_id, len)
+    }
+
+    /// Send a feature report. The first byte of `data` is the report ID, or 0
+    /// for devices that don't use numbered reports.
+    pub fn send_feature_report(&self, data: &[u8]) -> error::Result<()> {
+        if data.is_empty() {
+            return Err(Error::Unsupported("Empty feature report".to_owned()));
+        }
+        send_feature_report(self.file.as_raw_fd(), data)
+    }
+
+    /// Read the next input report, waiting at most `timeout`. The first byte
+    /// is the report ID for devices that number their reports.
+    pub fn read_input_report(&self, timeout: Duration) -> error::Result<Option<Vec<u8>>> {
+        let mut pollfd = libc::pollfd {
+            fd: self.file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+        let ready = unsafe { libc::poll(&mut pollfd, 1, timeout) };
+        if ready < 0 {
+            return Err(std::io::Error::last_os_error())
+                .io_context(|| "Failed to poll for reports".to_owned());
+        }
+        if ready == 0 {
+            return Ok(None);
+        }
+        let mut buf = vec![0; HID_MAX_BUFFER_SIZE];
+        let len = (&self.file)
+            .read(&mut buf)
+            .io_context(|| "Failed to read input report".to_owned())?;
+        buf.truncate(len);
+        Ok(Some(buf))
+    }
+
+    /// Write an output report. As with hidraw, the first byte of `data` is the
+    /// report ID, or 0 for devices without numbered reports, which the kernel
+    /// strips before sending. Reports shorter than the descriptor declares
+    /// are zero padded, since some devices ignore short reports.
+    pub fn write_output_report(&self, data: &[u8]) -> error::Result<()> {
+        let Some(&report_id) = data.first() else {
+            return Err(Error::Unsupported("Empty output report".to_owned()));
+        };
+        let mut report = data.to_vec();
+        match self.output_lengths.get(&report_id) {
+            Some(&len) if report.len() < len => report.resize(len, 0),
+            Some(_) => {}
+            // Trust the caller for devices that don't declare output reports.
+            None if self.output_lengths.is_empty() => {}
+            None if report_id == 0 => {
+                let message = "The device uses numbered output reports".to_owned();
+                return Err(Error::Unsupported(message));
+            }
+            None => {
+                let message = format!("The device has no output report {report_id:#04x}");
+                return Err(Error::Unsupported(message));
+            }
+        }
+        let start = Instant::now();
+        let context = || format!("Failed to write output report {report_id:#04x}");
+        let written = (&self.file).write(&report).io_context(context)?;
+        if written != report.len() {
+            let source = std::io::Error::new(
+                std::io::ErrorKind::WriteZero,
+                format!("Short write: {written} of {} bytes", report.len()),
+            );
+            return Err(source).io_context(context);
+        }
+        trace::record("hidraw", Phase::Write, "output_report", start);
+        Ok(())
+    }
+
+    /// Show `led` on the controller `info` describes, with its own output
+    /// reports. Only controllers `led::has_led_reports` accepts are supported.
+    pub fn set_led(&mut self, info: &DeviceInfo, led: Led) -> error::Result<()> {
+        let report = led::output_report(info, self.sequence, led)
+            .map_err(|e| Error::Unsupported(e.to_string()))?;
+        self.sequence = self.sequence.wrapping_add(1);
+        self.write_output_report(&report)
+    }
+}
+
+impl AsRawFd for Device {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+/// Read the report descriptor of the device behind a hidraw node.
+pub fn read_report_descriptor(fd: RawFd) -> error::Result<Vec<u8>> {
+    let context = || "Failed to read the report descriptor".to_owned();
+    let mut size = 0;
+    unsafe { ioctl::hid_get_rdesc_size(fd, &mut size) }.io_context(context)?;
+    let mut desc = Box::new(HidrawReportDescriptor {
+        size: (size.max(0) as usize).min(HID_MAX_DESCRIPTOR_SIZE) as u32,
+        value: [0; HID_MAX_DESCRIPTOR_SIZE],
+    });
+    unsafe { ioctl::hid_get_rdesc(fd, &mut *desc) }.io_context(context)?;
+    Ok(desc.value[..desc.size as usize].to_vec())
+}
+
+/// Read the range of an evdev node's absolute axis `code`.
+pub fn abs_info(fd: RawFd, code: u16) -> error::Result<AbsInfo> {
+    let mut info = AbsInfo::default();
+    // EVIOCGABS encodes the axis in the request, so it can't be declared with
+    // `ioctl_read!`.
+    let request = nix::request_code_read!(b'E', 0x40 + code, std::mem::size_of::<AbsInfo>());
+    Errno::result(unsafe { libc::ioctl(fd, request as _, &mut info) })
+        .io_context(|| format!("Failed to read the range of axis {code:#04x}"))?;
+    Ok(info)
+}
+
+/// What the kernel calls an evdev device. Empty strings mean the driver didn't
+/// set them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EvdevNames {
+    /// From `EVIOCGNAME`.
+    pub name: String,
+    /// From `EVIOCGUNIQ`, usually a serial number, or the MAC address of a
+    /// Bluetooth device.
+    pub uniq: String,
+    /// From `EVIOCGPHYS`, where the device is connected.
+    pub phys: String,
+}
+
+/// Read an evdev node's name, unique ID and physical path.
+pub fn evdev_names(fd: RawFd) -> error::Result<EvdevNames> {
+    type Ioctl = unsafe fn(RawFd, &mut [u8]) -> nix::Result<libc::c_int>;
+    let read = |ioctl: Ioctl, what: &str| {
+        let mut buf = [0u8; 256];
+        match unsafe { ioctl(fd, &mut buf) } {
+            Ok(len) => {
+                // The length includes the nul terminator.
+                let len = (len.max(0) as usize).min(buf.len());
+                let text = buf[..len].split(|&b| b == 0).next().unwrap_or_default();
+                Ok(String::from_utf8_lossy(text).into_owned())
+            }
+            // Drivers that never set one make the ioctl fail with ENOENT.
+            Err(nix::Error::Sys(Errno::ENOENT)) => Ok(String::new()),
+            Err(e) => Err(e).io_context(|| format!("Failed to read the device's {what}")),
+        }
+    };
+    Ok(EvdevNames {
+        name: read(ioctl::eviocgname, "name")?,
+        uniq: read(ioctl::eviocguniq, "unique ID")?,
+        phys: read(ioctl::eviocgphys, "physical path")?,
+    })
+}
+
+/// An evdev device's IDs, `struct input_id` from Linux uapi/linux/input.h. SDL
+/// builds its controller GUIDs from these.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct InputId {
+    pub bustype: u16,
+    pub vendor: u16,
+    pub product: u16,
+    pub version: u16,
+}
+
+/// Read an evdev node's IDs with `EVIOCGID`.
+pub fn read_input_id(fd: RawFd) -> error::Result<InputId> {
+    let mut id = InputId::default();
+    unsafe { ioctl::eviocgid(fd, &mut id) }
+        .io_context(|| "Failed to read the device's IDs".to_owned())?;
+    Ok(id)
+}
+
+/// Grab or release an evdev node. While grabbed, its events are only delivered
+/// to us and not to the rest of the system.
+pub fn grab_device(fd: RawFd, grab: bool) -> error::Result<()> {
+    unsafe { ioctl::eviocgrab(fd, grab as _) }.io_context(|| {
+        let action = if grab { "grab" } else { "release" };
+        format!("Failed to {action} the device")
+    })?;
+    Ok(())
+}
+
+/// Whether another process has grabbed an evdev node. This briefly grabs the
+/// node if it's free, so it should be checked before reading from it.
+pub fn is_grabbed(fd: RawFd) -> error::Result<bool> {
+    match unsafe { ioctl::eviocgrab(fd, 1) } {
+        Ok(_) => {
+            grab_device(fd, false)?;
+            Ok(false)
+        }
+        Err(nix::Error::Sys(Errno::EBUSY)) => Ok(true),
+        Err(e) => Err(e).io_context(|| "Failed to check for a grab".to_owned()),
+    }
+}
+
+/// The layout of the kernel's `struct input_event`, from Linux
+/// uapi/linux/input.h: a timestamp of two `unsigned long`s (seconds and
+/// microseconds) followed by a u16 type, a u16 code and an s32 value, all in
+/// the CPU's byte order. The timestamp stays two `unsigned long`s on 32-bit
+/// architectures with a 64-bit `time_t`, so it can't be read as a `timeval`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct EventLayout {
+    pub long_size: usize,
+    pub big_endian: bool,
+}
+
+impl EventLayout {
+    pub const NATIVE: EventLayout = EventLayout {
+        long_size: std::mem::size_of::<libc::c_ulong>(),
+        big_endian: cfg!(target_endian = "big"),
+    };
+
+    pub const fn size(&self) -> usize {
+        2 * self.long_size + 8
+    }
+
+    /// Decode an event laid out like this.
+    pub fn decode(&self, buf: &[u8]) -> input_event {
+        let int = |bytes: &[u8]| {
+            bytes.iter().enumerate().fold(0u64, |v, (i, b)| {
+                let shift = if self.big_endian {
+                    bytes.len() - 1 - i
+                } else {
+                    i
+                };
+                v | (*b as u64) << (shift * 8)
+            })
+        };
+        let long = self.long_size;
+        let rest = &buf[2 * long..];
+        input_event {
+            time: libc::timeval {
+                tv_sec: int(&buf[..long]) as _,
+                tv_usec: int(&buf[long..2 * long]) as _,
+            },
+            type_: int(&rest[..2]) as u16,
+            code: int(&rest[2..4]) as u16,
+            value: int(&rest[4..8]) as u32 as i32,
+        }
+    }
+
+    /// Encode an event to write to an evdev node, with a zero timestamp.
+    pub fn encode(&self, type_: u16, code: u16, value: i32) -> Vec<u8> {
+        let mut buf = vec![0; 2 * self.long_size];
+        for (int, size) in [(type_ as u32, 2), (code as u32, 2), (value as u32, 4)] {
+            let bytes = int.to_le_bytes();
+            let bytes = &bytes[..size];
+            if self.big_endian {
+                buf.extend(bytes.iter().rev());
+            } else {
+                buf.extend(bytes);
+            }
+        }
+        buf
+    }
+}
+
+/// Rumble through the kernel's force feedback support on an evdev node, which
+/// most gamepad drivers implement with the device's own output reports.
+pub struct EvdevRumble {
+    file: std::fs::File,
+    /// The ID of our uploaded effect, or -1 before the first upload. Uploading
+    /// with an existing ID replaces that effect.
+    effect_id: AtomicI16,
+}
+
+impl EvdevRumble {
+    pub fn open(device_node: &Path) -> error::Result<EvdevRumble> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(device_node)
+            .io_context(|| format!("Failed to open {device_node:?}"))?;
+        Ok(EvdevRumble {
+            file,
+            effect_id: AtomicI16::new(-1),
+        })
+    }
+
+    /// Rumble for `duration_ms`, or until replaced if it's 0. Magnitudes are
+    /// 0 (off) to 0xFFFF (full).
+    pub async fn rumble(&self, strong: u16, weak: u16, duration_ms: u32) -> error::Result<()> {
+        let start = Instant::now();
+        let mut effect = FfEffect {
+            type_: FF_RUMBLE,
+            id: self.effect_id.load(Ordering::Relaxed),
+            direction: 0,
+            trigger_button: 0,
+            trigger_interval: 0,
+            replay_length: duration_ms.min(u16::MAX as u32) as u16,
+            replay_delay: 0,
+            u: FfEffectData {
+                rumble: FfRumbleEffect {
+                    strong_magnitude: strong,
+                    weak_magnitude: weak,
+                },
+            },
+        };
+        let effect_ptr: *mut FfEffect = &mut effect;
+        unsafe { ioctl::eviocsff(self.file.as_raw_fd(), effect_ptr) }
+            .io_context(|| "Failed to upload rumble effect".to_owned())?;
+        self.effect_id.store(effect.id, Ordering::Relaxed);
+        let play = EventLayout::NATIVE.encode(EV_FF, effect.id as u16, 1);
+        (&self.file)
+            .write_all(&play)
+            .io_context(|| "Failed to play rumble effect".to_owned())?;
+        trace::record("rumble", Phase::Write, "ff_effect", start);
+        Ok(())
+    }
+}
+
+impl Rumble for EvdevRumble {
+    fn rumble(&mut self, effect: RumbleEffect) -> BoxFuture<'_, Result<()>> {
+        let effect = effect.without_triggers();
+        async move { Ok(EvdevRumble::rumble(self, effect.strong, effect.weak, 0).await?) }.boxed()
+    }
+}
+
+impl Drop for EvdevRumble {
+    fn drop(&mut self) {
+        let id = self.effect_id.load(Ordering::Relaxed);
+        if id >= 0 {
+            let _ = unsafe { ioctl::eviocrmff(self.file.as_raw_fd(), id as _) };
+        }
+    }
+}
+
+/// Read one `input_event` from an evdev node.
+pub async fn read_input_event(file: &mut File) -> error::Result<input_event> {
+    let mut event_buf = [0; EventLayout::NATIVE.size()];
+    file.read_exact(&mut event_buf)
+        .await
+        .io_context(|| "Failed to read an input event".to_owned())?;
+    Ok(EventLayout::NATIVE.decode(&event_buf))
+}
+
+/// A request for a device's task, sent through its `TaskHandle`.
+pub enum DeviceCommand {
+    Stop,
+    /// Rumble through the driver's output reports if it has them, or the
+    /// device's evdev node like `EvdevRumble::rumble`, which has no trigger
+    /// motors.
+    Rumble {
+        effect: RumbleEffect,
+        duration_ms: u32,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    SetLed {
+        led: Led,
+        reply: oneshot::Sender<Result<()>>,
+    },
+    QueryState {
+        reply: oneshot::Sender<DeviceState>,
+    },
+    /// Turn motion reports on or off, replying whether the device can.
+    SetMotion {
+        enabled: bool,
+        reply: oneshot::Sender<Result<bool>>,
+    },
+    /// Send every input state decoded from now on to `inputs`.
+    Subscribe {
+        inputs: Sender<GamepadInput>,
+    },
+}
+
+/// The latest input a device's task decoded.
+#[derive(Clone, Debug)]
+pub enum DecodedReport {
+    /// From the generic parser, for devices without an SDL mapping.
+    Parsed(ParsedReport),
+    /// From a driver, or the generic parser through the device's SDL mapping.
+    Gamepad(GamepadInput),
+}
+
+/// What a device's task has seen, as of a `TaskHandle::state` call.
+#[derive(Clone, Debug, Default)]
+pub struct DeviceState {
+    /// Reports or evdev events read since the task started.
+    pub reports: u64,
+    pub last_report: Option<Instant>,
+    /// `None` for devices read through evdev, or before the first report.
+    pub latest: Option<DecodedReport>,
+    /// Reports per second, averaged over roughly the last second. With
+    /// `motion`, it's what a device's radio, and so its battery, mostly goes
+    /// on.
+    pub report_rate: f32,
+    /// Whether the device streams motion, if we know.
+    pub motion: Option<bool>,
+}
+
+/// How many input states a subscriber can fall behind by.
+const INPUT_BUFFER: usize = 32;
+
+/// Sends commands to a device's task. Every method fails, rather than
+/// blocking, once the task has stopped, e.g. because the device was
+/// unplugged, so holders don't need to track its lifetime.
+#[derive(Clone, Debug)]
+pub struct TaskHandle {
+    tx: Sender<DeviceCommand>,
+}
+
+impl TaskHandle {
+    /// A handle and the receiver to pass to `watch_one_device`.
+    pub fn channel() -> (TaskHandle, Receiver<DeviceCommand>) {
+        let (tx, rx) = mpsc::channel(4);
+        (TaskHandle { tx }, rx)
+    }
+
+    /// Ask the task to stop, if it's still running.
+    pub async fn stop(&self) {
+        let _ = self.tx.send(DeviceCommand::Stop).await;
+    }
+
+    /// Whether the task has stopped.
+    pub fn is_stopped(&self) -> bool {
+        self.tx.is_closed()
+    }
+
+    /// Wait for the task to stop, e.g. after `stop`, so its fds are closed.
+    pub async fn stopped(&self) {
+        self.tx.closed().await
+    }
+
+    pub async fn rumble(&self, strong: u16, weak: u16, duration_ms: u32) -> Result<()> {
+        self.play(RumbleEffect::new(strong, weak), duration_ms)
+            .await
+    }
+
+    /// Play `effect`, including the trigger motors on devices that have them.
+    pub async fn play(&self, effect: RumbleEffect, duration_ms: u32) -> Result<()> {
+        self.request(|reply| DeviceCommand::Rumble {
+            effect,
+            duration_ms,
+            reply,
+        })
+        .await?
+    }
+
+    pub async fn set_led(&self, led: Led) -> Result<()> {
+        self.request(|reply| DeviceCommand::SetLed { led, reply })
+            .await?
+    }
+
+    pub async fn state(&self) -> Result<DeviceState> {
+        self.request(|reply| DeviceCommand::QueryState { reply })
+            .await
+    }
+
+    /// Turn motion reports on or off, returning whether the device can. Only
+    /// devices whose drivers can stop their motion sensors can.
+    pub async fn set_motion(&self, enabled: bool) -> Result<bool> {
+        self.request(|reply| DeviceCommand::SetMotion { enabled, reply })
+            .await?
+    }
+
+    /// Receive every input state the task decodes from now on, from drivers,
+    /// evdev, or parsed reports with an SDL mapping. States are dropped rather than holding up the device while
+    /// the receiver lags, and it ends once the task stops.
+    pub async fn subscribe(&self) -> Receiver<GamepadInput> {
+        let (inputs, rx) = mpsc::channel(INPUT_BUFFER);
+        let _ = self.tx.send(DeviceCommand::Subscribe { inputs }).await;
+        rx
+    }
+
+    async fn request<T>(
+        &self,
+        command: impl FnOnce(oneshot::Sender<T>) -> DeviceCommand,
+    ) -> Result<T> {
+        let (reply, reply_rx) = oneshot::channel();
+        let stopped = || anyhow!("The device's task has stopped");
+        self.tx.send(command(reply)).await.map_err(|_| stopped())?;
+        reply_rx.await.map_err(|_| stopped())
+    }
+}
+
+/// Rumble through the task, until replaced.
+impl Rumble for TaskHandle {
+    fn rumble(&mut self, effect: RumbleEffect) -> BoxFuture<'_, Result<()>> {
+        async move { self.play(effect, 0).await }.boxed()
+    }
+}
+
+/// How hard to try to save wireless controllers' batteries.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum PowerPolicy {
+    #[default]
+    Normal,
+    /// Stop Bluetooth devices streaming motion while nothing uses it.
+    Save,
+}
+
+impl PowerPolicy {
+    pub fn from_name(name: &str) -> Option<PowerPolicy> {
+        match name {
+            "normal" => Some(PowerPolicy::Normal),
+            "save" => Some(PowerPolicy::Save),
+            _ => None,
+        }
+    }
+
+    /// Whether to turn motion off for `info` while nothing uses it.
+    pub fn saves_motion(&self, info: &DeviceInfo) -> bool {
+        *self == PowerPolicy::Save && info.bus == Bus::Bluetooth
+    }
+}
+
+/// Carries out a task's commands other than `Stop`, and keeps the state that
+/// `QueryState` reports.
+struct Commands<'a> {
+    info: &'a DeviceInfo,
+    rx: Receiver<DeviceCommand>,
+    state: DeviceState,
+    /// Kept open once used, since the kernel drops uploaded effects on close.
+    rumble: Option<EvdevRumble>,
+    leds: Option<LedWriter>,
+    sequence: u8,
+    /// Where decoded input states go, from `TaskHandle::subscribe`.
+    inputs: Vec<Sender<GamepadInput>>,
+}
+
+/// Where a task writes LED changes for devices whose LEDs aren't set through
+/// their own output reports.
+enum LedWriter {
+    XpadRing(XpadRing),
+    Evdev(EvdevLeds),
+}
+
+impl<'a> Commands<'a> {
+    fn new(info: &'a DeviceInfo, rx: Receiver<DeviceCommand>) -> Commands<'a> {
+        Commands {
+            info,
+            rx,
+            state: DeviceState::default(),
+            rumble: None,
+            leds: None,
+            sequence: 0,
+            inputs: vec![],
+        }
+    }
+
+    /// The next command, or `Stop` once every `TaskHandle` is gone.
+    async fn next(&mut self) -> DeviceCommand {
+        self.rx.recv().await.unwrap_or(DeviceCommand::Stop)
+    }
+
+    fn record(&mut self, latest: Option<DecodedReport>) {
+        let now = Instant::now();
+        if let Some(last) = self.state.last_report {
+            // An exponential moving average, with the weight of each report
+            // growing with the time since the last so it covers about a
+            // second whatever the rate.
+            let interval = now.duration_since(last).as_secs_f32();
+            let weight = interval.min(1.0);
+            let rate = if interval > 0.0 { 1.0 / interval } else { 0.0 };
+            self.state.report_rate += (rate - self.state.report_rate) * weight;
+        }
+        self.state.reports += 1;
+        self.state.last_report = Some(now);
+        if let Some(DecodedReport::Gamepad(input)) = &latest {
+            self.publish(input);
+        }
+        if latest.is_some() {
+            self.state.latest = latest;
+        }
+    }
+
+    /// Send `input` to the subscribers, forgetting those that are gone.
+    fn publish(&mut self, input: &GamepadInput) {
+        self.inputs
+            .retain(|tx| !matches!(tx.try_send(input.clone()), Err(TrySendError::Closed(_))));
+    }
+
+    /// Carry out `command`, writing LED reports through `handle` if the
+    /// device takes them. Returns false for `Stop`.
+    async fn run(&mut self, command: DeviceCommand, handle: Option<&mut dyn DeviceHandle>) -> bool {
+        match command {
+            DeviceCommand::Stop => return false,
+            DeviceCommand::Rumble {
+                effect,
+                duration_ms,
+                reply,
+            } => {
+                let _ = reply.send(self.rumble(effect, duration_ms).await);
+            }
+            DeviceCommand::SetLed { led, reply } => {
+                let _ = reply.send(self.set_led(led, handle).await);
+            }
+            DeviceCommand::QueryState { reply } => {
+                let _ = reply.send(self.state.clone());
+            }
+            // Only drivers can, and `watch_reports` handles it for them.
+            DeviceCommand::SetMotion { reply, .. } => {
+                let _ = reply.send(Ok(false));
+            }
+            DeviceCommand::Subscribe { inputs } => self.inputs.push(inputs),
+        }
+        true
+    }
+
+    async fn rumble(&mut self, effect: RumbleEffect, duration_ms: u32) -> Result<()> {
+        if self.rumble.is_none() {
+            self.rumble = Some(EvdevRumble::open(&self.info.device_node)?);
+        }
+        let rumble = self.rumble.as_ref().unwrap();
+        let effect = effect.without_triggers();
+        Ok(rumble
+            .rumble(effect.strong, effect.weak, duration_ms)
+            .await?)
+    }
+
+    async fn set_led(&mut self, led: Led, handle: Option<&mut dyn DeviceHandle>) -> Result<()> {
+        if let Some(handle) = handle.filter(|_| led::has_led_reports(self.info)) {
+            let report = led::output_report(self.info, self.sequence, led)?;
+            self.sequence = self.sequence.wrapping_add(1);
+            return handle.write_report(&report).await;
+        }
+        if self.leds.is_none() {
+            self.leds = Some(match XpadRing::find(&self.info.sys_path) {
+                Some(ring) => LedWriter::XpadRing(ring),
+                None => LedWriter::Evdev(EvdevLeds::open(&self.info.device_node)?),
+            });
+        }
+        match self.leds.as_mut().unwrap() {
+            LedWriter::XpadRing(ring) => ring.set(led),
+            LedWriter::Evdev(leds) => leds.set(led),
+        }
+    }
+}
+
+/// Watch a device, reading raw reports from its hidraw node if we have a driver
+/// or parser for them, or evdev events otherwise, and carrying out `commands`
+/// until told to stop. With the `usbfs` feature, USB devices are read through
+/// libusb instead on kernels without hidraw. Reports that fail to decode are
+/// sent to `events`. `options` say how drivers set the device up.
+pub async fn watch_one_device(
+    info: DeviceInfo,
+    commands: Receiver<DeviceCommand>,
+    events: Sender<DeviceEvent>,
+    options: DriverOptions,
+) -> Result<()> {
+    let commands = Commands::new(&info, commands);
+    let driver = info
+        .hidraw_node
+        .as_ref()
+        .and_then(|_| drivers::probe(&info));
+    match (&info.hidraw_node, &info.parser, driver) {
+        (Some(node), _, Some(mut driver)) => {
+            info!("Using the {} driver for `{}`", driver.name(), info.name);
+            let hidraw = HidrawHandle::open(node).await?;
+            let mut handle = Demux::new(hidraw, driver.reply_ids());
+            apply_handheld_quirk(&mut handle, &info).await;
+            let timeout = driver.init_timeout();
+            let init = tokio::time::timeout(timeout, driver.init(&info, &mut handle));
+            let reason = match init.await {
+                Ok(Ok(())) => {
+                    if let Some(endpoint) = info.usb_endpoint() {
+                        info!(
+                            "`{}` is polled every {:?}, up to {} bytes",
+                            info.name, endpoint.interval, endpoint.max_packet_size
+                        );
+                    }
+                    enable_fast_mode(&mut handle, &info, &options).await;
+                    let handler = ReportHandler::new(&info, Decoder::Driver(driver), events);
+                    return watch_reports("hidraw", handle, handler, commands).await;
+                }
+                Ok(Err(e)) => format!("{e:#}"),
+                Err(_) => format!("Initialization timed out after {timeout:?}"),
+            };
+            // Basic input beats none, if the descriptor is any good.
+            let Some(parser) = &info.parser else {
+                bail!("The {} driver failed: {reason}", driver.name());
+            };
+            let event = DeviceEvent::DriverFallback {
+                sys_path: info.sys_path.clone(),
+                driver: driver.name().to_owned(),
+                reason,
+            };
+            let _ = events.send(event).await;
+            let handler = ReportHandler::new(&info, Decoder::Parser(parser), events);
+            watch_reports("hidraw", handle.into_inner(), handler, commands).await
+        }
+        (Some(node), Some(parser), None) => {
+            let mut handle = HidrawHandle::open(node).await?;
+            apply_handheld_quirk(&mut handle, &info).await;
+            enable_fast_mode(&mut handle, &info, &options).await;
+            let handler = ReportHandler::new(&info, Decoder::Parser(parser), events);
+            watch_reports("hidraw", handle, handler, commands).await
+        }
+        #[cfg(feature = "usbfs")]
+        (None, _, _) if info.bus == Bus::Usb && !Path::new(SYS_CLASS_HIDRAW).exists() => {
+            watch_usbfs(&info, events).await
+        }
+        _ => watch_evdev(&info, commands).await,
+    }
+}
+
+/// Switch the device into its fast report mode if it has one. It works
+/// without, so failing is only logged.
+async fn enable_fast_mode(
+    handle: &mut dyn DeviceHandle,
+    info: &DeviceInfo,
+    options: &DriverOptions,
+) {
+    if let Err(e) = fast_mode::negotiate_fast_mode(handle, info, options).await {
+        warn!(
+            "Failed to enable fast report mode for `{}`: {e:#}",
+            info.name
+        );
+    }
+}
+
+/// Switch a handheld's built-in controller into the mode we expect. It may
+/// work in the mode it's in, so failing is only logged.
+async fn apply_handheld_quirk(handle: &mut dyn DeviceHandle, info: &DeviceInfo) {
+    let quirk = handheld::detect_platform()
+        .and_then(|platform| handheld::find_quirk(platform, info.vendor_id, info.product_id));
+    if let Some(quirk) = quirk {
+        if let Err(e) = handheld::apply_quirk(handle, quirk).await {
+            warn!("Failed to set up `{}`: {e:#}", info.name);
+        }
+    }
+}
+
+async fn watch_evdev(info: &DeviceInfo, mut commands: Commands<'_>) -> Result<()> {
     info!("Starting task for `{:?}`", &info.device_node);
     let mut evdev_file = OpenOptions::new()
         .read(true)
         .write(true)
         .open(&info.device_node)
         .await?;
+    let mut calibrator = AxisCalibrator::new(CalibrationConfig::new());
+    calibrator.set_kernel_info(kernel_abs_info(evdev_file.as_raw_fd()));
+    let mut input = GamepadInput::default();
 
-    let mut event_buf = [0; std::mem::size_of::<input_event>()];
     loop {
         tokio::select! {
-            _ =  stop_rx.recv() => break,
-            Ok(_) = evdev_file.read_exact(&mut event_buf) => {
-                let event: input_event = unsafe { std::mem::transmute(event_buf) };
-                info!("Read event: {:x?}", event);
+            // Commands first, so subscribers see events already queued.
+            biased;
+            command = commands.next() => {
+                if !commands.run(command, None).await {
+                    break;
+                }
+            }
+            // A failed read usually means the device was unplugged; the
+            // error ends the task so the device is removed.
+            event = read_input_event(&mut evdev_file) => {
+                let event = event?;
+                trace::record(&info.name, Phase::Read, "input_event", Instant::now());
+                commands.record(None);
+                match decode_event(&event) {
+                    EvdevEvent::Sync => commands.publish(&input),
+                    event => {
+                        info!("Read event: {:?}", event);
+                        update_input(&mut input, event, &mut calibrator);
+                    }
+                }
             }
-            else => break,
         };
     }
     info!("Stopping task for `{:?}`", &info.device_node);
     Ok(())
 }
+
+/// The ranges the kernel reports for an evdev node's standard axes, by `Axis`.
+pub fn kernel_abs_info(fd: RawFd) -> [Option<AbsInfo>; 6] {
+    let mut kernel_info = [None; 6];
+    for code in 0..6 {
+        let axis = GamepadAxis::try_from(code).ok().and_then(|a| a.axis());
+        if let (Some(axis), Ok(info)) = (axis, abs_info(fd, code)) {
+            kernel_info[axis as usize] = Some(info);
+        }
+    }
+    kernel_info
+}
+
+/// Apply an evdev event to `input`, normalizing axes with `calibrator`.
+pub fn update_input(input: &mut GamepadInput, event: EvdevEvent, calibrator: &mut AxisCalibrator) {
+    match event {
+        EvdevEvent::Button { button, pressed } => match button {
+            GamepadButton::DpadUp => input.dpad.up = pressed,
+            GamepadButton::DpadDown => input.dpad.down = pressed,
+            GamepadButton::DpadLeft => input.dpad.left = pressed,
+            GamepadButton::DpadRight => input.dpad.right = pressed,
+            button => {
+                if let Some(button) = button.button() {
+                    input.set_button(button, pressed);
+                }
+            }
+        },
+        EvdevEvent::Axis {
+            axis: GamepadAxis::Hat0X,
+            value,
+        } => {
+            input.dpad.left = value < 0;
+            input.dpad.right = value > 0;
+        }
+        EvdevEvent::Axis {
+            axis: GamepadAxis::Hat0Y,
+            value,
+        } => {
+            input.dpad.up = value < 0;
+            input.dpad.down = value > 0;
+        }
+        EvdevEvent::Axis { axis, value } => {
+            if let Some(axis) = axis.axis() {
+                input.set_axis(axis, calibrator.normalize(axis, value));
+            }
+        }
+        EvdevEvent::Sync | EvdevEvent::Other { .. } => {}
+    }
+}
+
+/// Report at most one decode error per device this often.
+const DECODE_ERROR_INTERVAL: Duration = Duration::from_secs(1);
+
+/// What decodes a device's reports.
+enum Decoder<'a> {
+    Parser(&'a HidReportParser),
+    Driver(Box<dyn HidDriver>),
+}
+
+/// Decodes and logs a device's raw input reports, reporting reports that fail
+/// to decode as `DeviceEvent::DecodeError`.
+struct ReportHandler<'a> {
+    info: &'a DeviceInfo,
+    decoder: Decoder<'a>,
+    /// Annotated hex dumps of every report, for reverse engineering devices.
+    hex_dump: bool,
+    capture: Option<Capture>,
+    /// Turns parsed reports into the standard layout, for devices SDL has a
+    /// mapping for.
+    mapped: Option<MappedGamepad>,
+    events: Sender<DeviceEvent>,
+    last_error: Option<Instant>,
+    suppressed: u32,
+}
+
+impl<'a> ReportHandler<'a> {
+    fn new(
+        info: &'a DeviceInfo,
+        decoder: Decoder<'a>,
+        events: Sender<DeviceEvent>,
+    ) -> ReportHandler<'a> {
+        let capture = capture::capture_dir().and_then(|dir| {
+            Capture::open(&dir, info, capture::DEFAULT_MAX_BYTES)
+                .map_err(|e| warn!("Not capturing `{}`: {e:#}", info.name))
+                .ok()
+        });
+        let mapped = match &decoder {
+            Decoder::Parser(_) => {
+                let mapping = MappingDatabase::load().find_device(info).cloned();
+                match &mapping {
+                    Some(mapping) => info!("Using the SDL mapping `{}`", mapping.name),
+                    None => info!(
+                        "No SDL mapping for `{}`, its input isn't forwarded",
+                        info.name
+                    ),
+                }
+                mapping.map(MappedGamepad::new)
+            }
+            Decoder::Driver(_) => None,
+        };
+        ReportHandler {
+            info,
+            decoder,
+            hex_dump: std::env::var_os("HIDRAW_HEXDUMP").is_some(),
+            capture,
+            mapped,
+            events,
+            last_error: None,
+            suppressed: 0,
+        }
+    }
+
+    /// Decode and log one report, returning what it decoded to.
+    fn handle(&mut self, data: &[u8]) -> Option<DecodedReport> {
+        let name = &self.info.name;
+        let start = Instant::now();
+        trace::record(name, Phase::Read, "report", start);
+        if self.hex_dump {
+            let dump = match &self.decoder {
+                Decoder::Parser(parser) => parser.annotate(data).ok(),
+                Decoder::Driver(_) => None,
+            };
+            let dump = dump.unwrap_or_else(|| report::hex_dump(data));
+            info!("Report from `{}`:\n{}", name, dump);
+        }
+        self.capture(CaptureEntry::Report(data.to_vec()));
+        let decoded = match &mut self.decoder {
+            Decoder::Parser(parser) => match parser.parse(data) {
+                Ok(report) => {
+                    let text = format!("{report:?}");
+                    let decoded = match &mut self.mapped {
+                        Some(mapped) => {
+                            mapped.update_from_report(&report);
+                            DecodedReport::Gamepad(mapped.state().clone())
+                        }
+                        None => DecodedReport::Parsed(report),
+                    };
+                    Ok((text, Some(decoded)))
+                }
+                Err(e) => Err(e.into()),
+            },
+            Decoder::Driver(driver) => driver.decode(data).map(|input| {
+                let text = format!("{input:?}");
+                (text, input.map(DecodedReport::Gamepad))
+            }),
+        };
+        match decoded {
+            Ok((text, report)) => {
+                trace::record(name, Phase::Decode, "report", start);
+                info!("Read report: {}", text);
+                self.capture(CaptureEntry::Decoded(text));
+                report
+            }
+            Err(e) => {
+                self.decode_error(data, e.to_string());
+                None
+            }
+        }
+    }
+
+    /// Turn motion reports on or off through the driver, returning whether it
+    /// can.
+    async fn set_motion(&mut self, enabled: bool, handle: &mut impl DeviceHandle) -> Result<bool> {
+        let Decoder::Driver(driver) = &mut self.decoder else {
+            return Ok(false);
+        };
+        let Some(report) = driver.motion_report(enabled) else {
+            return Ok(false);
+        };
+        handle.write_report(&report).await?;
+        info!(
+            "Turned motion {} for `{}`",
+            if enabled { "on" } else { "off" },
+            self.info.name
+        );
+        Ok(true)
+    }
+
+    /// Play `effect` through the driver's output report, or return `None` if
+    /// the device isn't rumbled through its reports.
+    async fn rumble(
+        &mut self,
+        effect: &RumbleEffect,
+        handle: &mut impl DeviceHandle,
+    ) -> Option<Result<()>> {
+        let Decoder::Driver(driver) = &mut self.decoder else {
+            return None;
+        };
+        let report = driver.output(effect)?;
+        Some(handle.write_report(&report).await)
+    }
+
+    /// Capturing stops at the first failure rather than logging every report.
+    fn capture(&mut self, entry: CaptureEntry) {
+        if let Some(capture) = &mut self.capture {
+            if let Err(e) = capture.write(entry) {
+                warn!("Stopped capturing `{}`: {e:#}", self.info.name);
+                self.capture = None;
+            }
+        }
+    }
+
+    fn decode_error(&mut self, data: &[u8], reason: String) {
+        self.capture(CaptureEntry::Error(reason.clone()));
+        debug!(
+            "Failed to parse report from `{}`: {}",
+            self.info.name, reason
+        );
+        let now = Instant::now();
+        if self
+            .last_error
+            .is_some_and(|last| now.duration_since(last) < DECODE_ERROR_INTERVAL)
+        {
+            self.suppressed += 1;
+            return;
+        }
+        self.last_error = Some(now);
+        let event = DeviceEvent::DecodeError {
+            sys_path: self.info.sys_path.clone(),
+            reason,
+            data: data.to_vec(),
+            suppressed: std::mem::take(&mut self.suppressed),
+        };
+        // Diagnostics aren't worth blocking input for.
+        let _ = self.events.try_send(event);
+    }
+}
+
+/// Feed reports from `handle` to `handler` until the device goes away or
+/// `commands` says to stop.
+async fn watch_reports(
+    kind: &str,
+    mut handle: impl DeviceHandle,
+    mut handler: ReportHandler<'_>,
+    mut commands: Commands<'_>,
+) -> Result<()> {
+    let name = &handler.info.name;
+    info!("Starting {kind} task for `{name}`");
+    // When to stop rumble played through the driver, which plays until
+    // replaced.
+    let mut rumble_until: Option<Instant> = None;
+    loop {
+        tokio::select! {
+            // Commands first, so subscribers sent before the task started
+            // see the first report.
+            biased;
+            command = commands.next() => match command {
+                DeviceCommand::SetMotion { enabled, reply } => {
+                    let result = handler.set_motion(enabled, &mut handle).await;
+                    if let Ok(true) = result {
+                        commands.state.motion = Some(enabled);
+                    }
+                    let _ = reply.send(result);
+                }
+                DeviceCommand::Rumble { effect, duration_ms, reply } => {
+                    let result = match handler.rumble(&effect, &mut handle).await {
+                        Some(result) => {
+                            let length = Duration::from_millis(duration_ms as u64);
+                            rumble_until = (result.is_ok() && duration_ms > 0 && !effect.is_off())
+                                .then(|| Instant::now() + length);
+                            result
+                        }
+                        None => commands.rumble(effect, duration_ms).await,
+                    };
+                    let _ = reply.send(result);
+                }
+                command => {
+                    if !commands.run(command, Some(&mut handle)).await {
+                        break;
+                    }
+                }
+            },
+            _ = tokio::time::sleep_until(rumble_until.unwrap_or_else(Instant::now).into()),
+                if rumble_until.is_some() =>
+            {
+                rumble_until = None;
+                if let Some(Err(e)) = handler.rumble(&RumbleEffect::default(), &mut handle).await {
+                    warn!("Failed to stop rumble on `{name}`: {e:#}");
+                }
+            }
+            report = handle.read_report() => match report? {
+                Some(report) => commands.record(handler.handle(&report)),
+                None => break,
+            },
+        };
+    }
+    handle.close().await?;
+    info!("Stopping {kind} task for `{name}`");
+    Ok(())
+}
+
+/// Read reports straight from the USB device, for kernels without hidraw.
+/// Claiming the interface unbinds usbhid, which removes the evdev node we were
+/// started for, so this runs until the device is unplugged rather than until
+/// told to stop, and takes no commands.
+#[cfg(feature = "usbfs")]
+async fn watch_usbfs(info: &DeviceInfo, events: Sender<DeviceEvent>) -> Result<()> {
+    let handle = UsbfsHandle::open(info)?;
+    let parser = match &info.parser {
+        Some(parser) => parser.clone(),
+        None => HidReportParser::from_descriptor(&descriptor::parse_hid_descriptor(
+            &handle.device().read_report_descriptor()?,
+        )?)?,
+    };
+    let handler = ReportHandler::new(info, Decoder::Parser(&parser), events);
+    // Holding the sender keeps the commands from ever saying to stop.
+    let (_tx, rx) = mpsc::channel(1);
+    watch_reports("usbfs", handle, handler, Commands::new(info, rx)).await
+}
+
+/// Run a device task, turning a panic into a `DeviceEvent::ParserFault` so that
+/// one device sending malformed data can't take down the whole daemon. A task
+/// that fails is reported as `DeviceEvent::Removed`.
+pub async fn isolate(
+    sys_path: PathBuf,
+    task: impl Future<Output = Result<()>>,
+    events: Sender<DeviceEvent>,
+) -> Result<()> {
+    match AssertUnwindSafe(task).catch_unwind().await {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => {
+            // Reads usually fail because the device was just unplugged, before
+            // the monitor hears about it.
+            let reason = if sys_path.exists() {
+                DisconnectReason::Error(format!("{e:#}"))
+            } else {
+                DisconnectReason::Unplugged
+            };
+            let _ = events.send(DeviceEvent::Removed { sys_path, reason }).await;
+            Err(e)
+        }
+        Err(panic) => {
+            let message = panic
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_owned());
+            error!("Task for {sys_path:?} panicked: {message}");
+            let _ = events
+                .send(DeviceEvent::ParserFault { sys_path, message })
+                .await;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::xbox::{self, XboxBtDriver};
+    use crate::handle::MockHandle;
+    use crate::sdl_mapping::ControllerMapping;
+
+    #[test]
+    fn decodes_events_of_every_layout() {
+        // EV_ABS ABS_RY -300 at 5.000007 seconds.
+        let layouts: [(usize, bool, &[u8]); 4] = [
+            (
+                8,
+                false,
+                &[
+                    5, 0, 0, 0, 0, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0, 3, 0, 4, 0, 0xD4, 0xFE, 0xFF,
+                    0xFF,
+                ],
+            ),
+            (
+                4,
+                false,
+                &[5, 0, 0, 0, 7, 0, 0, 0, 3, 0, 4, 0, 0xD4, 0xFE, 0xFF, 0xFF],
+            ),
+            (
+                8,
+                true,
+                &[
+                    0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0, 7, 0, 3, 0, 4, 0xFF, 0xFF, 0xFE,
+                    0xD4,
+                ],
+            ),
+            (
+                4,
+                true,
+                &[0, 0, 0, 5, 0, 0, 0, 7, 0, 3, 0, 4, 0xFF, 0xFF, 0xFE, 0xD4],
+            ),
+        ];
+        for (long_size, big_endian, bytes) in layouts {
+            let layout = EventLayout {
+                long_size,
+                big_endian,
+            };
+            assert_eq!(layout.size(), bytes.len());
+            let event = layout.decode(bytes);
+            assert_eq!(
+                (event.time.tv_sec, event.time.tv_usec),
+                (5, 7),
+                "{layout:?}"
+            );
+            assert_eq!((event.type_, event.code, event.value), (3, 4, -300));
+            let encoded = layout.encode(3, 4, -300);
+            assert_eq!(encoded[2 * long_size..], bytes[2 * long_size..]);
+        }
+    }
+
+    #[test]
+    fn native_layout_matches_the_kernel_struct() {
+        let event = input_event {
+            time: libc::timeval {
+                tv_sec: 5,
+                tv_usec: 7,
+            },
+            type_: EV_ABS,
+            code: 4,
+            value: -300,
+        };
+        let size = std::mem::size_of::<input_event>();
+        assert_eq!(EventLayout::NATIVE.size(), size);
+        let bytes =
+            unsafe { std::slice::from_raw_parts(&event as *const input_event as *const u8, size) };
+        let decoded = EventLayout::NATIVE.decode(bytes);
+        assert_eq!((decoded.time.tv_sec, decoded.time.tv_usec), (5, 7));
+        assert_eq!(
+            (decoded.type_, decoded.code, decoded.value),
+            (EV_ABS, 4, -300)
+        );
+    }
+
+    #[tokio::test]
+    async fn driver_rumble_goes_through_its_output_report() {
+        let info = DeviceInfo::for_test(xbox::MICROSOFT_VENDOR_ID, 0x0B13, Bus::Bluetooth);
+        let (events, _events_rx) = mpsc::channel(1);
+        let mut handler = ReportHandler::new(&info, Decoder::Driver(XboxBtDriver::boxed()), events);
+        let mut handle = MockHandle::new([]);
+        let effect = RumbleEffect {
+            left_trigger: 0xFFFF,
+            ..RumbleEffect::new(0xFFFF, 0)
+        };
+        handler.rumble(&effect, &mut handle).await.unwrap().unwrap();
+        assert_eq!(
+            handle.written,
+            vec![xbox::bt_rumble_report(&effect).to_vec()]
+        );
+    }
+
+    #[tokio::test]
+    async fn evdev_read_errors_end_the_task() {
+        let path = std::env::temp_dir().join(format!("hidraw-evdev-{}", std::process::id()));
+        std::fs::write(&path, EventLayout::NATIVE.encode(EV_SYN, 0, 0)).unwrap();
+        let info = DeviceInfo {
+            device_node: path.clone(),
+            ..DeviceInfo::for_test(0x046D, 0xC216, Bus::Usb)
+        };
+        let (_task, commands) = TaskHandle::channel();
+        let (events, _events_rx) = mpsc::channel(1);
+        let task = watch_one_device(info, commands, events, DriverOptions::default());
+        let result = tokio::time::timeout(Duration::from_secs(1), task).await;
+        let _ = std::fs::remove_file(&path);
+        // The file ends after one event, like an unplugged device.
+        assert!(result.expect("the task kept running").is_err());
+    }
+
+    #[tokio::test]
+    async fn evdev_input_goes_to_subscribers() {
+        let path = std::env::temp_dir().join(format!("hidraw-evdev-input-{}", std::process::id()));
+        let layout = EventLayout::NATIVE;
+        let events = [
+            layout.encode(EV_KEY, GamepadButton::South as u16, 1),
+            layout.encode(EV_ABS, GamepadAxis::Hat0Y as u16, -1),
+            layout.encode(EV_SYN, 0, 0),
+        ];
+        std::fs::write(&path, events.concat()).unwrap();
+        let info = DeviceInfo {
+            device_node: path.clone(),
+            ..DeviceInfo::for_test(0x046D, 0xC216, Bus::Usb)
+        };
+        let (task, commands) = TaskHandle::channel();
+        let mut inputs = task.subscribe().await;
+        let (events, _events_rx) = mpsc::channel(1);
+        let watch = watch_one_device(info, commands, events, DriverOptions::default());
+        let _ = tokio::time::timeout(Duration::from_secs(1), watch).await;
+        let _ = std::fs::remove_file(&path);
+        let input = inputs.recv().await.unwrap();
+        assert!(input.button(Button::South) && input.dpad.up && !input.dpad.down);
+        // The task ended with the file, and so did the subscription.
+        assert!(inputs.recv().await.is_none());
+    }
+
+    #[test]
+    fn parsed_reports_go_through_the_sdl_mapping() {
+        let info = DeviceInfo::for_test(0x046D, 0xC216, Bus::Usb);
+        let parser = report::find_report_parser_for_device(0x046D, 0xC216).unwrap();
+        let (events, _events_rx) = mpsc::channel(1);
+        let mut handler = ReportHandler::new(&info, Decoder::Parser(&parser), events);
+        let line = format!("{},F310,a:b1,dpup:h0.1,", info.sdl_guid().simple());
+        let mapping = ControllerMapping::parse(&line).unwrap();
+        handler.mapped = Some(MappedGamepad::new(mapping));
+        // Centered sticks, d-pad up and the second button.
+        let report = [0x80, 0x80, 0x80, 0x80, 0x20, 0x00, 0x00, 0x00];
+        let Some(DecodedReport::Gamepad(input)) = handler.handle(&report) else {
+            panic!("expected mapped input");
+        };
+        assert!(input.button(Button::South) && input.dpad.up);
+    }
+
+    #[tokio::test]
+    async fn parsed_devices_rumble_through_evdev() {
+        let info = DeviceInfo::for_test(0x046D, 0xC216, Bus::Usb);
+        let parser = report::find_report_parser_for_device(0x046D, 0xC216).unwrap();
+        let (events, _events_rx) = mpsc::channel(1);
+        let mut handler = ReportHandler::new(&info, Decoder::Parser(&parser), events);
+        let mut handle = MockHandle::new([]);
+        let effect = RumbleEffect::new(0xFFFF, 0);
+        assert!(handler.rumble(&effect, &mut handle).await.is_none());
+        assert!(handle.written.is_empty());
+    }
+}
@@ -1,47 +1,519 @@
-use anyhow::{anyhow, bail, Context as ErrorContext, Result};
-use futures::Future;
-use futures_util::StreamExt;
-use log::{debug, info, warn};
-use std::collections::HashSet;
-use std::convert::TryInto;
+use nix::errno::Errno;
+use nix::unistd::{self, AccessFlags};
+use std::fmt;
+use std::fs;
 use std::path::{Path, PathBuf};
-use tokio::sync::mpsc::Sender;
-use tokio::task::LocalSet;
-use tokio_udev::{AsyncMonitorSocket, Device, Enumerator, EventType, MonitorBuilder};
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
 
-use crate::report::HidReportParser;
+use crate::battery::Battery;
+use crate::device::{GamepadAxis, GamepadButton, InputId};
+use crate::report::{Capabilities, HidReportParser};
+use crate::sdl_mapping;
+use crate::sysfs;
+use crate::wiimote::WiimoteNode;
+
+#[cfg(feature = "udev")]
+use {
+    crate::device::{self, EvdevNames},
+    crate::error::{self, Error},
+    crate::report,
+    crate::wiimote,
+    anyhow::{anyhow, bail, Context as ErrorContext, Result},
+    futures::Future,
+    futures_util::StreamExt,
+    log::{debug, error, info, warn},
+    std::collections::{HashMap, HashSet},
+    std::convert::TryInto,
+    std::ffi::OsStr,
+    std::fs::File,
+    std::os::unix::io::AsRawFd,
+    std::pin::Pin,
+    tokio::sync::mpsc::Sender,
+    tokio::sync::oneshot,
+    tokio_udev::{AsyncMonitorSocket, Device, Enumerator, EventType, MonitorBuilder},
+};
+
+// From Linux uapi/linux/input-event-codes.h
+const INPUT_PROP_BUTTONPAD: usize = 0x02;
+const INPUT_PROP_ACCELEROMETER: usize = 0x06;
+const KEY_MAX: u16 = 0x2FF;
+const ABS_MAX: u16 = 0x3F;
+
+/// A USB device's interrupt IN endpoint, which it sends input reports on.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct UsbEndpoint {
+    /// How often the host polls it.
+    pub interval: Duration,
+    pub max_packet_size: u16,
+}
 
 /// From Linux uapi/linux/input.h
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Bus {
     Usb = 0x03,
     Bluetooth = 0x05,
+    /// Devices created with uinput or uhid, e.g. by Steam or other remappers.
+    Virtual = 0x06,
+    /// Any other bus, like I2C for the built-in controls of some handhelds.
+    /// The kernel's bus number is in `DeviceInfo::input_id`.
+    Unknown = 0x00,
+}
+
+impl Bus {
+    /// The bus with this `BUS_*` number, or `Unknown` if it's not one we tell
+    /// apart.
+    pub fn from_id(id: u16) -> Bus {
+        match id {
+            0x03 => Bus::Usb,
+            0x05 => Bus::Bluetooth,
+            0x06 => Bus::Virtual,
+            _ => Bus::Unknown,
+        }
+    }
+}
+
+/// The `phys` prefix of the virtual devices we create, so we don't pick them up
+/// and end up feeding their input back into themselves.
+pub const EMULATED_PHYS_PREFIX: &str = "hidraw-emulated";
+
+/// Whether a device with this `phys` is one of our own virtual devices.
+pub fn is_emulated(phys: &str) -> bool {
+    phys.starts_with(EMULATED_PHYS_PREFIX)
 }
 
+/// The kinds of input device the monitor can look for.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum InputType {
+    Joystick,
+    Keyboard,
+    Mouse,
+    Touchpad,
+}
+
+impl InputType {
+    pub const ALL: [InputType; 4] = [
+        InputType::Joystick,
+        InputType::Keyboard,
+        InputType::Mouse,
+        InputType::Touchpad,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            InputType::Joystick => "joystick",
+            InputType::Keyboard => "keyboard",
+            InputType::Mouse => "mouse",
+            InputType::Touchpad => "touchpad",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<InputType> {
+        InputType::ALL.into_iter().find(|t| t.as_str() == name)
+    }
+
+    /// The property udev's input_id builtin sets on devices of this type.
+    pub fn udev_property(self) -> &'static str {
+        match self {
+            InputType::Joystick => "ID_INPUT_JOYSTICK",
+            InputType::Keyboard => "ID_INPUT_KEYBOARD",
+            InputType::Mouse => "ID_INPUT_MOUSE",
+            InputType::Touchpad => "ID_INPUT_TOUCHPAD",
+        }
+    }
+}
+
+/// Which devices `monitor_devices` reports. By default that's every joystick.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MonitorConfig {
+    input_types: Vec<InputType>,
+    /// If non-empty, only these vendor/product IDs are reported.
+    allow: Vec<(u16, u16)>,
+    deny: Vec<(u16, u16)>,
+    /// If non-empty, only devices on these buses are reported.
+    buses: Vec<Bus>,
+}
+
+impl MonitorConfig {
+    pub fn new() -> MonitorConfig {
+        MonitorConfig {
+            input_types: vec![InputType::Joystick],
+            allow: vec![],
+            deny: vec![],
+            buses: vec![],
+        }
+    }
+
+    /// Report devices of any of these types instead of just joysticks.
+    pub fn input_types(mut self, input_types: &[InputType]) -> MonitorConfig {
+        self.input_types = input_types.to_vec();
+        self
+    }
+
+    /// Only report allowed devices. Can be called more than once.
+    pub fn allow(mut self, vendor_id: u16, product_id: u16) -> MonitorConfig {
+        self.allow.push((vendor_id, product_id));
+        self
+    }
+
+    /// Never report this device, even if it's allowed.
+    pub fn deny(mut self, vendor_id: u16, product_id: u16) -> MonitorConfig {
+        self.deny.push((vendor_id, product_id));
+        self
+    }
+
+    /// Only report devices on these buses.
+    pub fn buses(mut self, buses: &[Bus]) -> MonitorConfig {
+        self.buses = buses.to_vec();
+        self
+    }
+
+    pub fn wants_type(&self, input_type: InputType) -> bool {
+        self.input_types.contains(&input_type)
+    }
+
+    /// Whether a device with this bus and IDs passes the filters.
+    pub fn matches(&self, bus: Bus, vendor_id: u16, product_id: u16) -> bool {
+        let ids = (vendor_id, product_id);
+        (self.buses.is_empty() || self.buses.contains(&bus))
+            && (self.allow.is_empty() || self.allow.contains(&ids))
+            && !self.deny.contains(&ids)
+    }
+}
+
+impl Default for MonitorConfig {
+    fn default() -> MonitorConfig {
+        MonitorConfig::new()
+    }
+}
+
+#[cfg(feature = "udev")]
 const EVENT_MINOR_BASE: usize = 64;
 
+#[cfg(feature = "udev")]
+const SYS_DEVICES_VIRTUAL: &str = "/sys/devices/virtual";
+
 #[derive(Clone, Debug)]
 pub struct DeviceInfo {
     pub sys_path: PathBuf,
     pub device_node: PathBuf,
+    /// The hidraw node of the HID device behind the evdev node, if any.
+    pub hidraw_node: Option<PathBuf>,
     pub parser: Option<HidReportParser>,
     pub bus: Bus,
+    /// The name the kernel reports, from `EVIOCGNAME` or the input device's
+    /// `name` attribute, which unlike udev's `ID_MODEL` isn't mangled.
     pub name: String,
+    /// A serial number, or the MAC address of a Bluetooth device, which ties
+    /// together the nodes and batteries of one controller.
+    pub uniq: Option<String>,
+    /// Where the device is connected, e.g. `usb-0000:00:14.0-2/input0`.
+    pub phys: Option<String>,
     pub version: u16,
     pub vendor_id: u16,
     pub product_id: u16,
+    /// The IDs the kernel gives the evdev node, from `EVIOCGID`, if it could be
+    /// opened. Unlike `version`, which for USB devices is `bcdDevice`, these
+    /// are what SDL sees.
+    pub input_id: Option<InputId>,
+    /// Where a USB device is plugged in, telling apart devices with the same
+    /// IDs.
+    pub usb_address: Option<UsbAddress>,
+    /// When the device was found.
+    pub connected_at: SystemTime,
 }
 
-#[derive(Debug)]
+/// A USB device's bus number and its address on that bus, as udev's `BUSNUM`
+/// and `DEVNUM` and libusb's `bus_number` and `address`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct UsbAddress {
+    pub bus: u8,
+    pub address: u8,
+}
+
+impl DeviceInfo {
+    /// Whether we lack permission to read the device's evdev node, or to read
+    /// and write its hidraw node, e.g. because no udev rule grants the user
+    /// access to them.
+    pub fn permission_denied(&self) -> bool {
+        self.permission_denied_by(unistd::access)
+    }
+
+    fn permission_denied_by(&self, access: impl Fn(&Path, AccessFlags) -> nix::Result<()>) -> bool {
+        let denied = |path: &Path, mode| {
+            matches!(
+                access(path, mode),
+                Err(nix::Error::Sys(Errno::EACCES | Errno::EPERM))
+            )
+        };
+        denied(&self.device_node, AccessFlags::R_OK)
+            || self
+                .hidraw_node
+                .as_deref()
+                .is_some_and(|node| denied(node, AccessFlags::R_OK | AccessFlags::W_OK))
+    }
+
+    /// The GUID SDL gives the device, for looking up its mapping in a
+    /// `gamecontrollerdb.txt`.
+    pub fn sdl_guid(&self) -> Uuid {
+        let id = self.input_id.unwrap_or(InputId {
+            bustype: self.bus as u16,
+            vendor: self.vendor_id,
+            product: self.product_id,
+            version: self.version,
+        });
+        sdl_mapping::create_sdl_controller_uuid(id.bustype, id.vendor, id.product, id.version)
+    }
+
+    /// The device's battery, if its driver exposes one. Usually only wireless
+    /// controllers have one.
+    pub fn battery(&self) -> Option<Battery> {
+        Battery::find(&self.sys_path).or_else(|| Battery::find_by_uniq(self.uniq.as_deref()?))
+    }
+
+    /// The evdev node for the device's accelerometer and gyro, if its kernel
+    /// driver exposes them, e.g. `Sony Interactive Entertainment Wireless
+    /// Controller Motion Sensors`.
+    pub fn motion_sensors(&self) -> Option<PathBuf> {
+        self.sibling_with_property(INPUT_PROP_ACCELEROMETER)
+    }
+
+    /// The evdev node for the device's touchpad, if it has one and its kernel
+    /// driver exposes it.
+    pub fn touchpad(&self) -> Option<PathBuf> {
+        self.sibling_with_property(INPUT_PROP_BUTTONPAD)
+    }
+
+    /// Another evdev node of the same HID device with the input property
+    /// `prop`.
+    fn sibling_with_property(&self, prop: usize) -> Option<PathBuf> {
+        // The HID device's other input devices are siblings of ours.
+        let inputs = fs::read_dir(self.sys_path.join("device/device/input")).ok()?;
+        inputs.flatten().find_map(|input| {
+            let properties = fs::read_to_string(input.path().join("properties")).ok()?;
+            if !sysfs::has_bit(&properties, prop) {
+                return None;
+            }
+            let event = fs::read_dir(input.path())
+                .ok()?
+                .flatten()
+                .find(|entry| entry.file_name().to_string_lossy().starts_with("event"))?;
+            Some(PathBuf::from("/dev/input").join(event.file_name()))
+        })
+    }
+
+    /// The endpoint a USB device sends input reports on.
+    pub fn usb_endpoint(&self) -> Option<UsbEndpoint> {
+        if self.bus != Bus::Usb {
+            return None;
+        }
+        // The HID device's parent is the USB interface.
+        let interface = fs::read_dir(self.sys_path.join("device/device/..")).ok()?;
+        interface.flatten().find_map(|entry| {
+            let address = entry.file_name().to_str()?.strip_prefix("ep_")?.to_owned();
+            let read = |attr: &str| {
+                let value = fs::read_to_string(entry.path().join(attr)).ok()?;
+                Some(value.trim().to_owned())
+            };
+            // IN endpoints have the top bit of their address set.
+            if u8::from_str_radix(&address, 16).ok()? & 0x80 == 0 || read("type")? != "Interrupt" {
+                return None;
+            }
+            // e.g. `8ms` or `125us`.
+            let interval = read("interval")?;
+            let interval = match interval.strip_suffix("ms") {
+                Some(ms) => Duration::from_millis(ms.parse().ok()?),
+                None => Duration::from_micros(interval.strip_suffix("us")?.parse().ok()?),
+            };
+            Some(UsbEndpoint {
+                interval,
+                max_packet_size: u16::from_str_radix(&read("wMaxPacketSize")?, 16).ok()?,
+            })
+        })
+    }
+
+    /// The controls the device's evdev node declares, and what's known about
+    /// its link.
+    pub fn capabilities(&self) -> Capabilities {
+        let bitmap = |name: &str| {
+            fs::read_to_string(self.sys_path.join("device/capabilities").join(name))
+                .unwrap_or_default()
+        };
+        let (keys, abs) = (bitmap("key"), bitmap("abs"));
+        let mut capabilities = Capabilities::default();
+        for code in 0..=KEY_MAX {
+            let Ok(button) = GamepadButton::try_from(code) else {
+                continue;
+            };
+            if !sysfs::has_bit(&keys, code as usize) {
+                continue;
+            }
+            match button {
+                GamepadButton::DpadUp
+                | GamepadButton::DpadDown
+                | GamepadButton::DpadLeft
+                | GamepadButton::DpadRight => capabilities.dpad = true,
+                _ => match button.button() {
+                    Some(button) if !capabilities.buttons.contains(&button) => {
+                        capabilities.buttons.push(button)
+                    }
+                    _ => {}
+                },
+            }
+        }
+        for code in 0..=ABS_MAX {
+            let Ok(axis) = GamepadAxis::try_from(code) else {
+                continue;
+            };
+            if !sysfs::has_bit(&abs, code as usize) {
+                continue;
+            }
+            match axis {
+                GamepadAxis::Hat0X | GamepadAxis::Hat0Y => capabilities.dpad = true,
+                _ => capabilities.axes.extend(axis.axis()),
+            }
+        }
+        capabilities.motion = self.motion_sensors().is_some();
+        if let Some(endpoint) = self.usb_endpoint() {
+            capabilities.report_interval = Some(endpoint.interval);
+            capabilities.max_packet_size = Some(endpoint.max_packet_size);
+        }
+        capabilities
+    }
+}
+
+#[cfg(test)]
+impl DeviceInfo {
+    /// A device with nothing but its IDs and bus, for tests.
+    pub fn for_test(vendor_id: u16, product_id: u16, bus: Bus) -> DeviceInfo {
+        DeviceInfo {
+            sys_path: PathBuf::from("/sys/devices/test/input/input0/event0"),
+            device_node: PathBuf::from("/dev/input/event0"),
+            hidraw_node: None,
+            parser: None,
+            bus,
+            name: "Test Controller".to_owned(),
+            uniq: None,
+            phys: None,
+            version: 0,
+            vendor_id,
+            product_id,
+            input_id: None,
+            usb_address: None,
+            connected_at: SystemTime::now(),
+        }
+    }
+}
+
+/// The kinds of accessory that can be attached to a controller at runtime.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AccessoryKind {
+    /// A wiimote extension, or one of the wiimote's optional sensor nodes.
+    Wiimote(WiimoteNode),
+}
+
+/// Hardware plugged into a controller, exposed by the kernel as its own node.
+#[derive(Clone, Debug)]
+pub struct Accessory {
+    pub sys_path: PathBuf,
+    pub device_node: PathBuf,
+    pub kind: AccessoryKind,
+    pub capabilities: Capabilities,
+    pub parser: Option<HidReportParser>,
+}
+
+/// Why we stopped handling a device.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The device went away.
+    Unplugged,
+    /// Reading the device failed while it was still present.
+    Error(String),
+    /// Handling the device panicked; see `DeviceEvent::ParserFault`.
+    Quarantined,
+    /// We turned the device off.
+    PowerOff,
+    /// Handed over to another program with `ctl release`.
+    Released,
+}
+
+impl DisconnectReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DisconnectReason::Unplugged => "unplugged",
+            DisconnectReason::Error(_) => "error",
+            DisconnectReason::Quarantined => "quarantined",
+            DisconnectReason::PowerOff => "power-off",
+            DisconnectReason::Released => "released",
+        }
+    }
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DisconnectReason::Error(message) => write!(f, "error: {message}"),
+            reason => write!(f, "{}", reason.as_str()),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
 pub enum DeviceEvent {
     Added(DeviceInfo),
-    Removed(PathBuf),
+    /// A device was found, but we don't have permission to open it. It's sent
+    /// in place of `Added`, which follows once a udev change event shows it
+    /// can be opened, and `Removed` is sent if it's unplugged first. Without
+    /// udev there's no retry.
+    PermissionDenied(DeviceInfo),
+    /// A device we handle changed in place, e.g. its permissions, power state
+    /// or HID driver, or was added again without being removed. Whatever has
+    /// it open should reopen it with this info.
+    Changed(DeviceInfo),
+    /// We stopped handling the device with the given sys path.
+    Removed {
+        sys_path: PathBuf,
+        reason: DisconnectReason,
+    },
+    /// An accessory was attached to the device with the given sys path.
+    AccessoryAttached {
+        parent: PathBuf,
+        accessory: Accessory,
+    },
+    /// The accessory with sys path `sys_path` was removed from `parent`.
+    AccessoryDetached {
+        parent: PathBuf,
+        sys_path: PathBuf,
+    },
+    /// Handling the device with the given sys path panicked, most likely on
+    /// malformed data. It's quarantined until it's removed.
+    ParserFault {
+        sys_path: PathBuf,
+        message: String,
+    },
+    /// A report from the device couldn't be decoded. These are rate limited
+    /// per device; `suppressed` counts the errors dropped since the last one.
+    DecodeError {
+        sys_path: PathBuf,
+        reason: String,
+        data: Vec<u8>,
+        suppressed: u32,
+    },
+    /// The device's driver failed to set it up or timed out, so it's read
+    /// with the generic report parser instead, which may miss some controls.
+    DriverFallback {
+        sys_path: PathBuf,
+        driver: String,
+        reason: String,
+    },
 }
 
+#[cfg(feature = "udev")]
 fn get_integer_prop(device: &Device, prop_name: &'static str) -> Result<u16> {
     Ok(u16::from_str_radix(get_prop(device, prop_name)?, 16)?)
 }
 
+#[cfg(feature = "udev")]
 fn get_prop<'dev>(device: &'dev Device, prop_name: &'static str) -> Result<&'dev str> {
     let raw_prop = device
         .property_value(prop_name)
@@ -51,75 +523,379 @@ fn get_prop<'dev>(device: &'dev Device, prop_name: &'static str) -> Result<&'dev
         .with_context(|| anyhow!("Bad string value"))?)
 }
 
-async fn get_device_info(device: &Device) -> Result<DeviceInfo> {
+#[cfg(feature = "udev")]
+fn get_integer_attr(device: &Device, attr_name: &'static str) -> Result<u16> {
+    let raw_attr = device
+        .attribute_value(attr_name)
+        .with_context(|| anyhow!("Missing attribute: {attr_name}"))?;
+    let raw_attr = raw_attr.to_str().context("Bad string value")?;
+    Ok(u16::from_str_radix(raw_attr.trim(), 16)?)
+}
+
+/// Virtual devices, and those on buses other than USB and Bluetooth, don't get
+/// udev's `ID_*` properties, so read their bus, IDs and name from the parent
+/// input device's sysfs attributes instead.
+#[cfg(feature = "udev")]
+fn get_input_ids(device: &Device) -> Result<(Bus, u16, u16, u16, String)> {
+    let input = device.parent().context("Missing parent input device")?;
+    let bus = Bus::from_id(get_integer_attr(&input, "id/bustype")?);
+    let name = input
+        .attribute_value("name")
+        .context("Missing attribute: name")?
+        .to_string_lossy()
+        .trim()
+        .to_owned();
+    Ok((
+        bus,
+        get_integer_attr(&input, "id/vendor")?,
+        get_integer_attr(&input, "id/product")?,
+        get_integer_attr(&input, "id/version")?,
+        name,
+    ))
+}
+
+/// Find the hidraw node of the HID device an input device belongs to.
+#[cfg(feature = "udev")]
+fn find_hidraw_node(device: &Device) -> Result<Option<PathBuf>> {
+    let Some(hid) = device.parent_with_subsystem("hid")? else {
+        return Ok(None);
+    };
+    let mut enumerator = Enumerator::new()?;
+    enumerator.match_parent(&hid)?;
+    enumerator.match_subsystem("hidraw")?;
+    Ok(enumerator
+        .scan_devices()?
+        .find_map(|d| d.devnode().map(|n| n.to_owned())))
+}
+
+/// The USB device an input device belongs to, if it's on USB.
+#[cfg(feature = "udev")]
+fn get_usb_address(device: &Device) -> Option<UsbAddress> {
+    let usb = device
+        .parent_with_subsystem_devtype("usb", "usb_device")
+        .ok()??;
+    let number = |name| usb.property_value(name)?.to_str()?.parse().ok();
+    Some(UsbAddress {
+        bus: number("BUSNUM")?,
+        address: number("DEVNUM")?,
+    })
+}
+
+#[cfg(feature = "udev")]
+fn get_device_info(device: &Device, config: &MonitorConfig) -> Result<DeviceInfo> {
     let sys_path = device.syspath().to_owned();
     debug!("get_device_info({sys_path:?})");
     let device_node = device.devnode().context("Missing device node")?.to_owned();
-    if device.property_value("ID_INPUT_JOYSTICK").is_none() {
-        bail!("Not a gamepad: {sys_path:?}");
+    let phys = device.parent().and_then(|input| {
+        input
+            .attribute_value("phys")
+            .map(|p| p.to_string_lossy().into_owned())
+    });
+    if phys.as_deref().is_some_and(is_emulated) {
+        bail!("Skipping our own virtual device: {sys_path:?}");
+    }
+    // hid-wiimote nodes aren't tagged as joysticks, so pick out the core remote
+    // node here. Its other nodes are aggregated by `wiimote::WiimoteSource`.
+    match wiimote::classify(device) {
+        Some(WiimoteNode::Core) if config.wants_type(InputType::Joystick) => {}
+        Some(node) => bail!("Skipping wiimote {node:?} node: {sys_path:?}"),
+        None if !InputType::ALL.into_iter().any(|t| {
+            config.wants_type(t) && device.property_value(t.udev_property()).is_some()
+        }) =>
+        {
+            bail!("Not a wanted input device: {sys_path:?}");
+        }
+        None => {}
     }
     // input/jsN have minors 0+, input/eventN have minors 64+
     if get_prop(device, "MINOR")?.parse::<usize>()? < EVENT_MINOR_BASE {
         bail!("Skipping old js device");
     }
-    let vendor_id = get_integer_prop(device, "ID_VENDOR_ID")?;
-    let product_id = get_integer_prop(device, "ID_MODEL_ID")?;
-    let version = get_integer_prop(device, "ID_REVISION")?;
-    let bus = get_prop(device, "ID_BUS")?;
-    let bus = match bus {
-        "usb" => Bus::Usb,
-        "Bluetooth" => Bus::Bluetooth,
-        b @ _ => bail!("Unknown bus: {b}"),
+    let id_bus = match get_prop(device, "ID_BUS").ok() {
+        _ if sys_path.starts_with(SYS_DEVICES_VIRTUAL) => None,
+        Some("usb") => Some(Bus::Usb),
+        Some("Bluetooth") => Some(Bus::Bluetooth),
+        _ => None,
+    };
+    let (bus, vendor_id, product_id, version, name) = match id_bus {
+        Some(bus) => (
+            bus,
+            get_integer_prop(device, "ID_VENDOR_ID")?,
+            get_integer_prop(device, "ID_MODEL_ID")?,
+            get_integer_prop(device, "ID_REVISION")?,
+            get_prop(device, "ID_MODEL")?.to_owned(),
+        ),
+        None => get_input_ids(device)?,
+    };
+    if !config.matches(bus, vendor_id, product_id) {
+        bail!("Filtered out {vendor_id:04x}:{product_id:04x} on {bus:?}: {sys_path:?}");
+    }
+    let attribute = |attr: &str| {
+        let input = device.parent()?;
+        let value = input.attribute_value(attr)?.to_string_lossy().into_owned();
+        Some(value)
     };
-    let name = get_prop(device, "ID_MODEL")?.to_owned();
+    // The ioctls need the node to be readable, but the input device's
+    // attributes say the same.
+    let opened = File::open(&device_node).ok();
+    let input_id = opened
+        .as_ref()
+        .and_then(|node| device::read_input_id(node.as_raw_fd()).ok());
+    let names = opened
+        .and_then(|node| device::evdev_names(node.as_raw_fd()).ok())
+        .unwrap_or_else(|| EvdevNames {
+            name: attribute("name").unwrap_or_default(),
+            uniq: attribute("uniq").unwrap_or_default(),
+            phys: phys.clone().unwrap_or_default(),
+        });
+    let non_empty = |s: String| Some(s).filter(|s| !s.is_empty());
+    let name = non_empty(names.name).unwrap_or(name);
+    let hidraw_node = find_hidraw_node(device).unwrap_or_else(|e| {
+        debug!("Failed to find hidraw node for {sys_path:?}: {e}");
+        None
+    });
+    let hidraw = hidraw_node.as_ref().and_then(|node| {
+        File::open(node)
+            .map_err(|e| debug!("Failed to open {node:?}: {e}"))
+            .ok()
+    });
+    let parser = report::find_report_parser(
+        vendor_id,
+        product_id,
+        hidraw.as_ref().map(|f| f.as_raw_fd()),
+    );
 
     Ok(DeviceInfo {
         sys_path,
         device_node,
-        parser: None,
+        hidraw_node,
+        parser,
         bus,
         name,
+        uniq: non_empty(names.uniq),
+        phys: non_empty(names.phys),
         version,
         vendor_id,
         product_id,
+        input_id,
+        usb_address: get_usb_address(device),
+        connected_at: SystemTime::now(),
     })
 }
 
-async fn monitor_devices_internal(tx: Sender<DeviceEvent>) -> Result<()> {
-    info!("Starting monitor_devices_internal");
-    // We don't care about all devices, so keep track of the ones we do care about.
-    let mut devices = HashSet::new();
-    let mut enumerator = Enumerator::new()?;
-    enumerator.match_subsystem("input")?;
-    enumerator.match_is_initialized()?;
-    enumerator.match_property("ID_INPUT_JOYSTICK", "1")?;
-    for device in enumerator.scan_devices()? {
-        match get_device_info(&device).await {
-            Ok(info) => {
-                devices.insert(info.sys_path.clone());
-                tx.send(DeviceEvent::Added(info)).await?;
+#[cfg(feature = "udev")]
+fn get_accessory(device: &Device) -> Result<Option<(PathBuf, Accessory)>> {
+    let Some((node, parent)) = wiimote::find_core(device)? else {
+        return Ok(None);
+    };
+    let Some(device_node) = device.devnode() else {
+        return Ok(None);
+    };
+    if get_prop(device, "MINOR")?.parse::<usize>()? < EVENT_MINOR_BASE {
+        return Ok(None);
+    }
+    Ok(Some((
+        parent,
+        Accessory {
+            sys_path: device.syspath().to_owned(),
+            device_node: device_node.to_owned(),
+            kind: AccessoryKind::Wiimote(node),
+            capabilities: node.capabilities(),
+            parser: None,
+        },
+    )))
+}
+
+/// What one udev enumeration pass found.
+#[cfg(feature = "udev")]
+struct Scan {
+    devices: Vec<DeviceInfo>,
+    /// Accessories, with their parents' sys paths.
+    accessories: Vec<(PathBuf, Accessory)>,
+}
+
+/// Every initialized input device udev knows of.
+#[cfg(feature = "udev")]
+fn input_devices() -> error::Result<Vec<Device>> {
+    let mut enumerator = Enumerator::new().map_err(Error::Udev)?;
+    enumerator.match_subsystem("input").map_err(Error::Udev)?;
+    enumerator.match_is_initialized().map_err(Error::Udev)?;
+    Ok(enumerator.scan_devices().map_err(Error::Udev)?.collect())
+}
+
+#[cfg(feature = "udev")]
+fn scan(config: &MonitorConfig) -> error::Result<Scan> {
+    let mut devices = vec![];
+    let mut accessories = vec![];
+    for device in input_devices()? {
+        match get_accessory(&device) {
+            Ok(Some(accessory)) => {
+                accessories.push(accessory);
+                continue;
             }
+            Ok(None) => {}
+            Err(e) => debug!("{e}"),
+        }
+        match get_device_info(&device, config) {
+            Ok(info) => devices.push(info),
             //TODO: better error handling
             Err(e) => {
                 debug!("{e}");
             }
         }
     }
+    Ok(Scan {
+        devices,
+        accessories,
+    })
+}
+
+/// Find the gamepads connected right now, without monitoring for changes.
+/// Needs no tokio runtime, for tools that just want a list.
+#[cfg(feature = "udev")]
+pub fn enumerate_gamepads() -> error::Result<Vec<DeviceInfo>> {
+    enumerate_devices(&MonitorConfig::new())
+}
+
+/// Find the devices matching `config` connected right now.
+#[cfg(feature = "udev")]
+pub fn enumerate_devices(config: &MonitorConfig) -> error::Result<Vec<DeviceInfo>> {
+    Ok(iter_devices(config)?.collect())
+}
+
+/// The devices matching `config` connected right now, each read as the
+/// iterator reaches it, so callers can stop at the first one they want.
+#[cfg(feature = "udev")]
+pub fn iter_devices(
+    config: &MonitorConfig,
+) -> error::Result<impl Iterator<Item = DeviceInfo> + '_> {
+    Ok(input_devices()?.into_iter().filter_map(move |device| {
+        if let Ok(Some(_)) = get_accessory(&device) {
+            return None;
+        }
+        get_device_info(&device, config)
+            .map_err(|e| debug!("{e}"))
+            .ok()
+    }))
+}
+
+/// Send `DeviceEvent::Added` for a device, or `DeviceEvent::PermissionDenied`
+/// if we can't open it yet.
+#[cfg(feature = "udev")]
+async fn announce(
+    info: DeviceInfo,
+    devices: &mut HashSet<PathBuf>,
+    denied: &mut HashMap<PathBuf, DeviceInfo>,
+    tx: &Sender<DeviceEvent>,
+) -> Result<()> {
+    if info.permission_denied() {
+        warn!("No permission to open {:?}", info.device_node);
+        denied.insert(info.sys_path.clone(), info.clone());
+        tx.send(DeviceEvent::PermissionDenied(info)).await?;
+    } else {
+        devices.insert(info.sys_path.clone());
+        tx.send(DeviceEvent::Added(info)).await?;
+    }
+    Ok(())
+}
+
+/// Re-read a device we handle after it changed in place, sending `Changed`
+/// if we can still open it, or `Removed` then `PermissionDenied` if not.
+#[cfg(feature = "udev")]
+async fn refresh(
+    device: &Device,
+    config: &MonitorConfig,
+    devices: &mut HashSet<PathBuf>,
+    denied: &mut HashMap<PathBuf, DeviceInfo>,
+    tx: &Sender<DeviceEvent>,
+) -> Result<()> {
+    let info = match get_device_info(device, config) {
+        Ok(info) => info,
+        Err(e) => {
+            debug!("{e}");
+            return Ok(());
+        }
+    };
+    if info.permission_denied() {
+        devices.remove(&info.sys_path);
+        tx.send(DeviceEvent::Removed {
+            sys_path: info.sys_path.clone(),
+            reason: DisconnectReason::Error("Permission revoked".to_owned()),
+        })
+        .await?;
+        announce(info, devices, denied, tx).await
+    } else {
+        debug!("{:?} changed", info.sys_path);
+        tx.send(DeviceEvent::Changed(info)).await?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "udev")]
+async fn monitor_devices_internal(tx: Sender<DeviceEvent>, config: MonitorConfig) -> Result<()> {
+    info!("Starting monitor_devices_internal");
+    // We don't care about all devices, so keep track of the ones we do care about.
+    let mut devices = HashSet::new();
+    // Accessory sys path -> parent device sys path.
+    let mut accessories = HashMap::new();
+    // Devices we can't open yet, until a change event says otherwise.
+    let mut denied = HashMap::new();
+    let found = scan(&config)?;
+    for info in found.devices {
+        announce(info, &mut devices, &mut denied, &tx).await?;
+    }
+    // Send accessories after all devices so their parents are always known.
+    for (parent, accessory) in found.accessories {
+        accessories.insert(accessory.sys_path.clone(), parent.clone());
+        tx.send(DeviceEvent::AccessoryAttached { parent, accessory })
+            .await?;
+    }
 
     let builder = MonitorBuilder::new()?;
-    let mut monitor: AsyncMonitorSocket = builder.match_subsystem("input")?.listen()?.try_into()?;
+    // HID devices for their bind and unbind events, when their driver changes.
+    let mut monitor: AsyncMonitorSocket = builder
+        .match_subsystem("input")?
+        .match_subsystem("hid")?
+        .listen()?
+        .try_into()?;
 
     while let Some(event) = monitor.next().await {
         let event = event?;
         let syspath = event.syspath();
+        if event.subsystem() == Some(OsStr::new("hid")) {
+            if matches!(event.event_type(), EventType::Bind | EventType::Unbind) {
+                let children: Vec<PathBuf> = devices
+                    .iter()
+                    .filter(|path| path.starts_with(syspath))
+                    .cloned()
+                    .collect();
+                for child in children {
+                    // Nodes that went with the old driver are removed too.
+                    if let Ok(device) = Device::from_syspath(&child) {
+                        refresh(&device, &config, &mut devices, &mut denied, &tx).await?;
+                    }
+                }
+            }
+            continue;
+        }
         match event.event_type() {
+            EventType::Add if devices.contains(syspath) => {
+                refresh(&event, &config, &mut devices, &mut denied, &tx).await?;
+            }
             EventType::Add => {
-                // Check device type
-                match get_device_info(&event).await {
-                    Ok(info) => {
-                        devices.insert(info.sys_path.clone());
-                        tx.send(DeviceEvent::Added(info)).await?;
+                match get_accessory(&event) {
+                    Ok(Some((parent, accessory))) => {
+                        accessories.insert(accessory.sys_path.clone(), parent.clone());
+                        tx.send(DeviceEvent::AccessoryAttached { parent, accessory })
+                            .await?;
+                        continue;
                     }
+                    Ok(None) => {}
+                    Err(e) => debug!("{e}"),
+                }
+                // Check device type
+                match get_device_info(&event, &config) {
+                    Ok(info) => announce(info, &mut devices, &mut denied, &tx).await?,
                     //TODO: better error handling
                     Err(e) => {
                         debug!("{e}");
@@ -127,13 +903,59 @@ async fn monitor_devices_internal(tx: Sender<DeviceEvent>) -> Result<()> {
                 }
             }
             EventType::Remove => {
-                if devices.remove(syspath) {
-                    tx.send(DeviceEvent::Removed(syspath.to_owned())).await?;
+                if let Some(parent) = accessories.remove(syspath) {
+                    tx.send(DeviceEvent::AccessoryDetached {
+                        parent,
+                        sys_path: syspath.to_owned(),
+                    })
+                    .await?;
+                } else if devices.remove(syspath) {
+                    let detached: Vec<PathBuf> = accessories
+                        .iter()
+                        .filter(|(_, parent)| parent.as_path() == syspath)
+                        .map(|(accessory, _)| accessory.clone())
+                        .collect();
+                    for accessory in detached {
+                        accessories.remove(&accessory);
+                        tx.send(DeviceEvent::AccessoryDetached {
+                            parent: syspath.to_owned(),
+                            sys_path: accessory,
+                        })
+                        .await?;
+                    }
+                    tx.send(DeviceEvent::Removed {
+                        sys_path: syspath.to_owned(),
+                        reason: DisconnectReason::Unplugged,
+                    })
+                    .await?;
+                } else if denied.remove(syspath).is_some() {
+                    tx.send(DeviceEvent::Removed {
+                        sys_path: syspath.to_owned(),
+                        reason: DisconnectReason::Unplugged,
+                    })
+                    .await?;
                 } else {
                     //TODO: better error handling
                     warn!("Remove event for unknown device: {:?}", syspath);
                 }
             }
+            EventType::Change | EventType::Bind | EventType::Unbind
+                if devices.contains(syspath) =>
+            {
+                refresh(&event, &config, &mut devices, &mut denied, &tx).await?;
+            }
+            // Changing a node's mode or ACL, e.g. with `udevadm trigger` after
+            // adding a rule, comes with a change event.
+            EventType::Change
+                if denied
+                    .get(syspath)
+                    .is_some_and(|info: &DeviceInfo| !info.permission_denied()) =>
+            {
+                let info = denied.remove(syspath).unwrap();
+                info!("Permission granted for {:?}", info.device_node);
+                devices.insert(info.sys_path.clone());
+                tx.send(DeviceEvent::Added(info)).await?;
+            }
             _ => {}
         }
     }
@@ -142,13 +964,68 @@ async fn monitor_devices_internal(tx: Sender<DeviceEvent>) -> Result<()> {
 
 /// Monitor connected hidraw devices via udev.
 ///
-/// Send a DeviceEvent::Added for each gamepad device that is added, and a matching
+/// Send a DeviceEvent::Added for each device matching `config` that is added, and a matching
 /// DeviceEvent::Removed for each gamepad device that was previously added but has now
-/// been removed.
-pub fn monitor_devices(tx: Sender<DeviceEvent>) -> impl Future<Output = ()> {
+/// been removed. Accessories plugged into a gamepad are reported with
+/// DeviceEvent::AccessoryAttached and DeviceEvent::AccessoryDetached, and are always
+/// detached before their parent is removed. Devices we can't open are reported with
+/// DeviceEvent::PermissionDenied, and added once a change event shows we can.
+/// Change, bind and unbind events for devices we handle are reported with
+/// DeviceEvent::Changed.
+///
+/// The tokio-udev types aren't `Send`, so the monitor runs on its own thread with
+/// a single-threaded runtime. The returned future completes when it stops, and can
+/// be spawned or polled from any runtime. Dropping it doesn't stop the monitor,
+/// dropping the receiver does.
+#[cfg(feature = "udev")]
+pub fn monitor_devices(
+    tx: Sender<DeviceEvent>,
+    config: MonitorConfig,
+) -> Pin<Box<dyn Future<Output = ()> + Send>> {
     info!("Starting monitor_devices");
-    // The tokio-udev types are !Send, so we need to run them on a LocalSet.
-    let local = LocalSet::new();
-    local.spawn_local(monitor_devices_internal(tx));
-    local
+    let (done_tx, done_rx) = oneshot::channel::<()>();
+    let spawned = std::thread::Builder::new()
+        .name("udev-monitor".to_owned())
+        .spawn(move || {
+            let _done = done_tx;
+            let runtime = match tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+            {
+                Ok(runtime) => runtime,
+                Err(e) => return error!("Failed to start the udev monitor runtime: {e}"),
+            };
+            if let Err(e) = runtime.block_on(monitor_devices_internal(tx, config)) {
+                error!("Device monitor failed: {e:#}");
+            }
+        });
+    if let Err(e) = spawned {
+        error!("Failed to start the udev monitor thread: {e}");
+    }
+    Box::pin(async move {
+        let _ = done_rx.await;
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hidraw_nodes_need_read_and_write_access() {
+        let mut info = DeviceInfo::for_test(0x045e, 0x028e, Bus::Usb);
+        // The evdev node is readable, the hidraw node only readable.
+        let access = |path: &Path, mode: AccessFlags| {
+            if path == Path::new("/dev/hidraw3") && mode.contains(AccessFlags::W_OK) {
+                Err(nix::Error::Sys(Errno::EACCES))
+            } else {
+                Ok(())
+            }
+        };
+        assert!(!info.permission_denied_by(access));
+        info.hidraw_node = Some(PathBuf::from("/dev/hidraw3"));
+        assert!(info.permission_denied_by(access));
+        let missing = |_: &Path, _| Err(nix::Error::Sys(Errno::ENOENT));
+        assert!(!info.permission_denied_by(missing));
+    }
 }
@@ -0,0 +1,139 @@
+use anyhow::Result;
+use log::{debug, info};
+
+use crate::device_monitor::{Bus, DeviceInfo};
+use crate::drivers::DriverOptions;
+use crate::handle::{DeviceHandle, Transaction};
+use crate::wiimote::NINTENDO_VENDOR_ID;
+
+/// One step of switching a pad into its fast report mode.
+enum FastModeStep {
+    /// Send an output report, starting with the report ID, and wait for a
+    /// report starting with `reply`.
+    Request {
+        report: &'static [u8],
+        reply: &'static [u8],
+    },
+}
+
+/// How to switch one model of pad into a higher report rate.
+struct FastModeQuirk {
+    vendor_id: u16,
+    product_id: u16,
+    bus: Bus,
+    steps: &'static [FastModeStep],
+}
+
+/// Devices with a documented fast report mode. Only add entries whose
+/// sequence is documented by the vendor or verified against the firmware, since
+/// an unknown report can put a pad into an unexpected mode.
+const FAST_MODE_QUIRKS: &[FastModeQuirk] = &[
+    // The Switch Pro Controller's USB bridge talks to the controller at 1
+    // Mbit/s until told to switch to 3 Mbit/s, after which it has to be
+    // handshaken again, as hid-nintendo does.
+    FastModeQuirk {
+        vendor_id: NINTENDO_VENDOR_ID,
+        product_id: 0x2009,
+        bus: Bus::Usb,
+        steps: &[
+            FastModeStep::Request {
+                report: &[0x80, 0x03],
+                reply: &[0x81, 0x03],
+            },
+            FastModeStep::Request {
+                report: &[0x80, 0x02],
+                reply: &[0x81, 0x02],
+            },
+        ],
+    },
+];
+
+fn find_quirk(vendor_id: u16, product_id: u16, bus: Bus) -> Option<&'static FastModeQuirk> {
+    FAST_MODE_QUIRKS
+        .iter()
+        .find(|q| q.vendor_id == vendor_id && q.product_id == product_id && q.bus == bus)
+}
+
+pub fn supports_fast_mode(vendor_id: u16, product_id: u16, bus: Bus) -> bool {
+    find_quirk(vendor_id, product_id, bus).is_some()
+}
+
+/// Switch the device behind `handle` into its fast report mode, if it has one
+/// and `options` allow it. Returns whether fast mode was enabled.
+pub async fn negotiate_fast_mode(
+    handle: &mut dyn DeviceHandle,
+    info: &DeviceInfo,
+    options: &DriverOptions,
+) -> Result<bool> {
+    let (vendor_id, product_id) = (info.vendor_id, info.product_id);
+    let Some(quirk) = find_quirk(vendor_id, product_id, info.bus) else {
+        return Ok(false);
+    };
+    if !options.fast_mode {
+        debug!("Fast report mode disabled for {vendor_id:04x}:{product_id:04x}");
+        return Ok(false);
+    }
+    for step in quirk.steps {
+        match step {
+            FastModeStep::Request { report, reply } => {
+                Transaction::new()
+                    .run(handle, report, |r| r.starts_with(reply).then_some(()))
+                    .await?;
+            }
+        }
+    }
+    info!("Enabled fast report mode for {vendor_id:04x}:{product_id:04x}");
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handle::MockHandle;
+
+    fn switch_pro(bus: Bus) -> DeviceInfo {
+        DeviceInfo::for_test(NINTENDO_VENDOR_ID, 0x2009, bus)
+    }
+
+    #[tokio::test]
+    async fn switch_pro_usb_switches_baud_rate() {
+        // An input report arriving mid-handshake is skipped.
+        let mut handle = MockHandle::new([vec![0x81, 0x03], vec![0x30, 0x00], vec![0x81, 0x02]]);
+        let options = DriverOptions { fast_mode: true };
+        let enabled = negotiate_fast_mode(&mut handle, &switch_pro(Bus::Usb), &options).await;
+        assert!(enabled.unwrap());
+        assert_eq!(handle.written, vec![vec![0x80, 0x03], vec![0x80, 0x02]]);
+    }
+
+    #[tokio::test]
+    async fn opting_out_sends_nothing() {
+        let mut handle = MockHandle::new([]);
+        let options = DriverOptions { fast_mode: false };
+        let enabled = negotiate_fast_mode(&mut handle, &switch_pro(Bus::Usb), &options).await;
+        assert!(!enabled.unwrap());
+        assert!(handle.written.is_empty());
+    }
+
+    #[tokio::test]
+    async fn only_listed_devices_and_buses() {
+        let mut handle = MockHandle::new([]);
+        let options = DriverOptions::default();
+        let info = switch_pro(Bus::Bluetooth);
+        assert!(!negotiate_fast_mode(&mut handle, &info, &options)
+            .await
+            .unwrap());
+        let info = DeviceInfo::for_test(0x054C, 0x09CC, Bus::Usb);
+        assert!(!negotiate_fast_mode(&mut handle, &info, &options)
+            .await
+            .unwrap());
+        assert!(handle.written.is_empty());
+    }
+
+    #[tokio::test]
+    async fn unanswered_request_fails() {
+        let mut handle = MockHandle::new([]);
+        let options = DriverOptions::default();
+        let result = negotiate_fast_mode(&mut handle, &switch_pro(Bus::Usb), &options).await;
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,167 @@
+use anyhow::{bail, Context as ErrorContext, Result};
+use log::info;
+use std::path::Path;
+use std::time::Instant;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::report::{Button, GamepadInput};
+use crate::rumble::RumbleEffect;
+use crate::trace::{self, Phase};
+
+/// The official Nintendo GameCube controller adapter (WUP-028).
+pub const VENDOR_ID: u16 = 0x057E;
+pub const PRODUCT_ID: u16 = 0x0337;
+
+pub const PORTS: usize = 4;
+
+const TRACK: &str = "GameCube adapter";
+
+/// Sent once to make the adapter start streaming input reports.
+const CMD_INIT: u8 = 0x13;
+/// Followed by one byte per port, 1 to enable rumble.
+const CMD_RUMBLE: u8 = 0x11;
+const INPUT_REPORT_ID: u8 = 0x21;
+const INPUT_REPORT_LEN: usize = 1 + PORTS * PORT_LEN;
+const PORT_LEN: usize = 9;
+
+// Port status bits.
+const STATUS_WIRED: u8 = 0x10;
+const STATUS_WIRELESS: u8 = 0x20;
+
+const BUTTONS_1: &[(u8, Button)] = &[
+    (0x01, Button::South),
+    (0x02, Button::West),
+    (0x04, Button::East),
+    (0x08, Button::North),
+];
+const BUTTONS_2: &[(u8, Button)] = &[(0x01, Button::Start), (0x02, Button::RightShoulder)];
+
+/// A change in which ports have a controller plugged in.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PortEvent {
+    Connected(usize),
+    Disconnected(usize),
+}
+
+fn stick(value: u8) -> f32 {
+    ((value as f32 - 128.0) / 127.0).clamp(-1.0, 1.0)
+}
+
+fn decode_port(data: &[u8]) -> Option<GamepadInput> {
+    let status = data[0];
+    if status & (STATUS_WIRED | STATUS_WIRELESS) == 0 {
+        return None;
+    }
+    let mut input = GamepadInput::default();
+    for (mask, button) in BUTTONS_1 {
+        input.set_button(*button, data[1] & mask != 0);
+    }
+    for (mask, button) in BUTTONS_2 {
+        input.set_button(*button, data[2] & mask != 0);
+    }
+    input.dpad.left = data[1] & 0x10 != 0;
+    input.dpad.right = data[1] & 0x20 != 0;
+    input.dpad.down = data[1] & 0x40 != 0;
+    input.dpad.up = data[1] & 0x80 != 0;
+    // GameCube sticks report up as larger values.
+    input.left_stick.x = stick(data[3]);
+    input.left_stick.y = -stick(data[4]);
+    input.right_stick.x = stick(data[5]);
+    input.right_stick.y = -stick(data[6]);
+    input.left_trigger = data[7] as f32 / 255.0;
+    input.right_trigger = data[8] as f32 / 255.0;
+    Some(input)
+}
+
+/// Split an adapter input report into the state of each port. Ports without a
+/// controller are `None`.
+pub fn decode_report(report: &[u8]) -> Result<[Option<GamepadInput>; PORTS]> {
+    if report.len() < INPUT_REPORT_LEN || report[0] != INPUT_REPORT_ID {
+        bail!("Unexpected GameCube adapter report: {report:x?}");
+    }
+    let mut ports: [Option<GamepadInput>; PORTS] = Default::default();
+    for (port, data) in ports.iter_mut().zip(report[1..].chunks_exact(PORT_LEN)) {
+        *port = decode_port(data);
+    }
+    Ok(ports)
+}
+
+/// A GameCube adapter opened via its hidraw node.
+pub struct GcAdapter {
+    file: File,
+    connected: [bool; PORTS],
+    rumble: [bool; PORTS],
+}
+
+impl GcAdapter {
+    pub async fn open(hidraw_node: &Path) -> Result<GcAdapter> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(hidraw_node)
+            .await
+            .with_context(|| format!("Failed to open {hidraw_node:?}"))?;
+        file.write_all(&[CMD_INIT]).await?;
+        Ok(GcAdapter {
+            file,
+            connected: [false; PORTS],
+            rumble: [false; PORTS],
+        })
+    }
+
+    /// Read the next report, returning any port hotplug changes along with the
+    /// state of every port.
+    pub async fn read(&mut self) -> Result<(Vec<PortEvent>, [Option<GamepadInput>; PORTS])> {
+        let mut buf = [0; INPUT_REPORT_LEN];
+        let len = self.file.read(&mut buf).await?;
+        let start = Instant::now();
+        trace::record(TRACK, Phase::Read, "report", start);
+        let ports = decode_report(&buf[..len])?;
+        trace::record(TRACK, Phase::Decode, "report", start);
+        let mut events = vec![];
+        for (i, port) in ports.iter().enumerate() {
+            let connected = port.is_some();
+            if connected != self.connected[i] {
+                self.connected[i] = connected;
+                if connected {
+                    info!("GameCube adapter port {} connected", i + 1);
+                    events.push(PortEvent::Connected(i));
+                } else {
+                    info!("GameCube adapter port {} disconnected", i + 1);
+                    events.push(PortEvent::Disconnected(i));
+                    // The adapter keeps rumbling an unplugged port.
+                    if self.rumble[i] {
+                        self.set_rumble(i, false).await?;
+                    }
+                }
+            }
+        }
+        Ok((events, ports))
+    }
+
+    /// Turn rumble on or off for one port. Rumble only works when the
+    /// adapter's second (grey) USB plug is connected for power.
+    pub async fn set_rumble(&mut self, port: usize, on: bool) -> Result<()> {
+        if port >= PORTS {
+            bail!("Bad GameCube adapter port: {port}");
+        }
+        self.rumble[port] = on;
+        let mut cmd = [0; 1 + PORTS];
+        cmd[0] = CMD_RUMBLE;
+        for (byte, on) in cmd[1..].iter_mut().zip(self.rumble) {
+            *byte = on as u8;
+        }
+        let start = Instant::now();
+        self.file.write_all(&cmd).await?;
+        trace::record(TRACK, Phase::Write, "rumble", start);
+        Ok(())
+    }
+
+    /// Play an effect on one port. GameCube controllers have a single on/off
+    /// motor, so any intensity on any motor turns it on.
+    pub async fn rumble(&mut self, port: usize, effect: RumbleEffect) -> Result<()> {
+        self.set_rumble(port, !effect.without_triggers().is_off())
+            .await
+    }
+}
@@ -0,0 +1,184 @@
+use anyhow::{Context, Result};
+use log::{debug, info};
+use std::fs;
+
+use crate::handle::DeviceHandle;
+use crate::report::{self, HidReportParser};
+
+const DMI_DIR: &str = "/sys/class/dmi/id";
+
+/// Handheld PCs with built-in controls that need special handling.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HandheldPlatform {
+    Ayaneo,
+    Gpd,
+    LegionGo,
+    MsiClaw,
+}
+
+impl HandheldPlatform {
+    /// Identify the platform from its DMI strings.
+    pub fn from_dmi(sys_vendor: &str, product_name: &str, product_version: &str) -> Option<Self> {
+        match sys_vendor {
+            "AYANEO" | "AYADEVICE" => Some(HandheldPlatform::Ayaneo),
+            "GPD" => Some(HandheldPlatform::Gpd),
+            "LENOVO" if product_version.starts_with("Legion Go") => {
+                Some(HandheldPlatform::LegionGo)
+            }
+            "Micro-Star International Co., Ltd." if product_name.starts_with("Claw") => {
+                Some(HandheldPlatform::MsiClaw)
+            }
+            _ => {
+                debug!("Not a known handheld: {sys_vendor} {product_name}");
+                None
+            }
+        }
+    }
+}
+
+/// Detect whether we're running on a known handheld.
+pub fn detect_platform() -> Option<HandheldPlatform> {
+    let read = |name: &str| {
+        fs::read_to_string(format!("{DMI_DIR}/{name}"))
+            .map(|s| s.trim().to_owned())
+            .unwrap_or_default()
+    };
+    HandheldPlatform::from_dmi(
+        &read("sys_vendor"),
+        &read("product_name"),
+        &read("product_version"),
+    )
+}
+
+/// How to handle one of a handheld's built-in HID interfaces.
+pub struct HandheldQuirk {
+    pub platform: HandheldPlatform,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// Output reports that switch the controller into the mode we expect,
+    /// each starting with the report ID, sent in order.
+    pub mode_switch: &'static [&'static [u8]],
+    /// A parser for a report layout the generic descriptor path gets wrong.
+    pub parser: Option<fn() -> HidReportParser>,
+}
+
+/// HID keyboard usages for SysRq and Pause.
+const KEY_SYSRQ: u8 = 0x46;
+const KEY_PAUSE: u8 = 0x48;
+
+/// The MSI Claw's vendor command to switch its controller to XInput mode,
+/// with the mouse as its desktop mode.
+const CLAW_XINPUT_MODE: &[u8] = &[0x0F, 0x00, 0x00, 0x3C, 0x24, 0x01, 0x00, 0x01];
+
+fn gpd_back_buttons() -> HidReportParser {
+    report::keyboard_buttons_parser(&[KEY_SYSRQ, KEY_PAUSE])
+}
+
+/// Known built-in controllers. AYANEO pads and the Legion Go controllers in
+/// XInput mode are handled by the kernel's xpad driver, and AYANEO's extra
+/// buttons arrive on the AT keyboard, so neither needs anything here. GPD's
+/// back buttons are keys on a USB keyboard interface, mapped to SysRq and
+/// Pause in GPD's configuration tool. The MSI Claw starts in a
+/// keyboard-and-mouse mode until told to be a gamepad.
+const QUIRKS: &[HandheldQuirk] = &[
+    HandheldQuirk {
+        platform: HandheldPlatform::Gpd,
+        vendor_id: 0x2F24,
+        product_id: 0x0135,
+        mode_switch: &[],
+        parser: Some(gpd_back_buttons),
+    },
+    HandheldQuirk {
+        platform: HandheldPlatform::MsiClaw,
+        vendor_id: 0x0DB0,
+        product_id: 0x1901,
+        mode_switch: &[CLAW_XINPUT_MODE],
+        parser: None,
+    },
+];
+
+/// Find the quirk for a device on `platform`.
+pub fn find_quirk(
+    platform: HandheldPlatform,
+    vendor_id: u16,
+    product_id: u16,
+) -> Option<&'static HandheldQuirk> {
+    QUIRKS
+        .iter()
+        .find(|q| q.platform == platform && q.vendor_id == vendor_id && q.product_id == product_id)
+}
+
+/// Apply a quirk's mode switch to the device behind `handle`.
+pub async fn apply_quirk(handle: &mut dyn DeviceHandle, quirk: &HandheldQuirk) -> Result<()> {
+    if quirk.mode_switch.is_empty() {
+        return Ok(());
+    }
+    info!(
+        "Switching {:?} controller {:04x}:{:04x} mode",
+        quirk.platform, quirk.vendor_id, quirk.product_id
+    );
+    for report in quirk.mode_switch {
+        handle
+            .write_report(report)
+            .await
+            .with_context(|| format!("Failed to send mode switch report {:#04x}", report[0]))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handle::MockHandle;
+
+    #[test]
+    fn detects_platforms() {
+        let platform = |vendor, name, version| HandheldPlatform::from_dmi(vendor, name, version);
+        assert_eq!(
+            platform("GPD", "G1618-04", "Default string"),
+            Some(HandheldPlatform::Gpd)
+        );
+        assert_eq!(
+            platform("AYANEO", "AIR Plus", ""),
+            Some(HandheldPlatform::Ayaneo)
+        );
+        assert_eq!(
+            platform("LENOVO", "83E1", "Legion Go 8APU1"),
+            Some(HandheldPlatform::LegionGo)
+        );
+        assert_eq!(
+            platform("Micro-Star International Co., Ltd.", "Claw A1M", "1.0"),
+            Some(HandheldPlatform::MsiClaw)
+        );
+        assert_eq!(platform("LENOVO", "ThinkPad X1", "ThinkPad X1"), None);
+    }
+
+    #[test]
+    fn gpd_back_buttons_are_keys() {
+        let quirk = find_quirk(HandheldPlatform::Gpd, 0x2F24, 0x0135).unwrap();
+        let parser = (quirk.parser.unwrap())();
+        let parsed = parser.parse(&[0, 0, 0, KEY_PAUSE, 0, 0, 0, 0]).unwrap();
+        assert_eq!(parsed.buttons, vec![false, true]);
+        let parsed = parser
+            .parse(&[0, 0, KEY_SYSRQ, 0x04, KEY_PAUSE, 0, 0, 0])
+            .unwrap();
+        assert_eq!(parsed.buttons, vec![true, true]);
+        // Other keys aren't buttons.
+        let parsed = parser.parse(&[0, 0, 0x04, 0, 0, 0, 0, 0]).unwrap();
+        assert_eq!(parsed.buttons, vec![false, false]);
+    }
+
+    #[tokio::test]
+    async fn claw_switches_to_xinput() {
+        let quirk = find_quirk(HandheldPlatform::MsiClaw, 0x0DB0, 0x1901).unwrap();
+        let mut handle = MockHandle::new([]);
+        apply_quirk(&mut handle, quirk).await.unwrap();
+        assert_eq!(handle.written, vec![CLAW_XINPUT_MODE.to_vec()]);
+    }
+
+    #[test]
+    fn quirks_are_per_platform() {
+        assert!(find_quirk(HandheldPlatform::Ayaneo, 0x2F24, 0x0135).is_none());
+        assert!(find_quirk(HandheldPlatform::LegionGo, 0x17EF, 0x6182).is_none());
+    }
+}
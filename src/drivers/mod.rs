@@ -0,0 +1,118 @@
+//! Drivers for devices that need more than the generic report parser.
+//!
+//! Drivers that decode a device's reports themselves implement `HidDriver`.
+//! Other crates can add their own with `register`.
+
+use anyhow::Result;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::device_monitor::DeviceInfo;
+use crate::handle::DeviceHandle;
+use crate::report::{Capabilities, GamepadInput};
+use crate::rumble::RumbleEffect;
+
+pub mod fast_mode;
+pub mod gamecube;
+pub mod handheld;
+pub mod player;
+pub mod sony;
+pub mod switch_pro;
+pub mod xbox;
+
+/// How long `HidDriver::init` gets by default before the device falls back to
+/// the generic report parser.
+pub const DEFAULT_INIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Options that change how drivers set up devices.
+#[derive(Clone, Debug)]
+pub struct DriverOptions {
+    /// Negotiate a higher report rate on devices that support one.
+    pub fast_mode: bool,
+}
+
+impl Default for DriverOptions {
+    fn default() -> DriverOptions {
+        DriverOptions { fast_mode: true }
+    }
+}
+
+/// A driver for a HID device whose reports the generic parser can't handle.
+/// Each device gets its own instance, so drivers can keep per-device state.
+pub trait HidDriver: Send {
+    /// A short name for logs.
+    fn name(&self) -> &str;
+
+    /// Whether this driver handles `info`.
+    fn probe(&self, info: &DeviceInfo) -> bool;
+
+    /// Set up a device this driver probed, e.g. switch it into the report
+    /// mode `decode` expects.
+    fn init<'a>(
+        &'a mut self,
+        _info: &'a DeviceInfo,
+        _handle: &'a mut dyn DeviceHandle,
+    ) -> BoxFuture<'a, Result<()>> {
+        async { Ok(()) }.boxed()
+    }
+
+    /// How long `init` may take, e.g. over a noisy Bluetooth link, before
+    /// giving up on the driver.
+    fn init_timeout(&self) -> Duration {
+        DEFAULT_INIT_TIMEOUT
+    }
+
+    /// The IDs of reports that answer the driver's requests rather than carry
+    /// input. These go to `Transaction`s in `init`, and never to `decode`.
+    fn reply_ids(&self) -> &[u8] {
+        &[]
+    }
+
+    /// Decode an input report, including its report ID if the device numbers
+    /// its reports. Reports without input state decode to `None`.
+    fn decode(&mut self, report: &[u8]) -> Result<Option<GamepadInput>>;
+
+    /// The output report that plays `effect`, if the device can rumble.
+    fn output(&mut self, _effect: &RumbleEffect) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// The output report that turns motion reports on or off, if the device
+    /// can stop streaming them to save battery.
+    fn motion_report(&mut self, _enabled: bool) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn capabilities(&self) -> Capabilities;
+}
+
+/// Creates a driver instance for one device.
+pub type DriverFactory = fn() -> Box<dyn HidDriver>;
+
+const BUILTIN_DRIVERS: &[DriverFactory] = &[
+    sony::SonyDriver::boxed,
+    switch_pro::SwitchProDriver::boxed,
+    xbox::XboxBtDriver::boxed,
+];
+
+static REGISTERED: Mutex<Vec<DriverFactory>> = Mutex::new(Vec::new());
+
+/// Add a driver. Drivers registered later are probed first, and all
+/// registered drivers before the built-in ones, so a driver can take over a
+/// device from one we ship.
+pub fn register(factory: DriverFactory) {
+    REGISTERED.lock().unwrap().push(factory);
+}
+
+/// Find a driver for a device.
+pub fn probe(info: &DeviceInfo) -> Option<Box<dyn HidDriver>> {
+    let registered = REGISTERED.lock().unwrap().clone();
+    registered
+        .iter()
+        .rev()
+        .chain(BUILTIN_DRIVERS)
+        .map(|factory| factory())
+        .find(|driver| driver.probe(info))
+}
@@ -0,0 +1,162 @@
+use anyhow::{bail, Context as ErrorContext, Result};
+use log::debug;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::wiimote;
+
+/// What a controller should show to indicate a player number.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PlayerIndicator {
+    /// An Xbox 360 ring LED command, as documented in the kernel's xpad driver:
+    /// 0 is off and 6 to 9 light quadrants 1 to 4.
+    XboxRing(u8),
+    /// A lightbar color.
+    Lightbar { red: u8, green: u8, blue: u8 },
+    /// Individual player LEDs, LED 1 in bit 0.
+    Leds(u8),
+}
+
+/// How a controller family renders player numbers.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum IndicatorStyle {
+    /// Xbox 360 four-quadrant ring.
+    XboxRing,
+    /// DualShock 4 lightbar.
+    Lightbar,
+    /// DualSense row of five LEDs, lit symmetrically.
+    PlayStationLeds,
+    /// Switch controllers: LEDs light cumulatively, 1 to 4.
+    SwitchLeds,
+    /// Wii remotes: one LED per player.
+    SingleLed,
+}
+
+/// Lightbar colors for players 1 to 4, matching the console's.
+const LIGHTBAR_COLORS: [(u8, u8, u8); 4] = [(0, 0, 64), (64, 0, 0), (0, 64, 0), (32, 0, 32)];
+
+// From Linux drivers/hid/hid-playstation.c
+const PLAYSTATION_LEDS: [u8; 5] = [0b00100, 0b01010, 0b10101, 0b11011, 0b11111];
+
+// From Linux drivers/hid/hid-nintendo.c, players 5 to 8 use these patterns.
+const SWITCH_LEDS: [u8; 8] = [
+    0b0001, 0b0011, 0b0111, 0b1111, 0b1001, 0b0101, 0b1101, 0b0110,
+];
+
+impl IndicatorStyle {
+    /// The style for a device, if it's one we know how to indicate on.
+    pub fn for_device(vendor_id: u16, product_id: u16) -> Option<IndicatorStyle> {
+        match (vendor_id, product_id) {
+            (0x045E, 0x028E | 0x028F | 0x0719) => Some(IndicatorStyle::XboxRing),
+            (0x054C, 0x05C4 | 0x09CC | 0x0BA0) => Some(IndicatorStyle::Lightbar),
+            (0x054C, 0x0CE6 | 0x0DF2) => Some(IndicatorStyle::PlayStationLeds),
+            (0x057E, 0x2006..=0x2009) => Some(IndicatorStyle::SwitchLeds),
+            (v, p) if wiimote::is_wiimote(v, p) => Some(IndicatorStyle::SingleLed),
+            _ => None,
+        }
+    }
+
+    /// Render a player number. Player 0 turns the indicator off; numbers past
+    /// what the family can show wrap around.
+    pub fn render(&self, player: u8) -> PlayerIndicator {
+        let wrap = |count: usize| (player as usize - 1) % count;
+        if player == 0 {
+            return match self {
+                IndicatorStyle::XboxRing => PlayerIndicator::XboxRing(0),
+                IndicatorStyle::Lightbar => PlayerIndicator::Lightbar {
+                    red: 0,
+                    green: 0,
+                    blue: 0,
+                },
+                _ => PlayerIndicator::Leds(0),
+            };
+        }
+        match self {
+            IndicatorStyle::XboxRing => PlayerIndicator::XboxRing(6 + wrap(4) as u8),
+            IndicatorStyle::Lightbar => {
+                let (red, green, blue) = LIGHTBAR_COLORS[wrap(LIGHTBAR_COLORS.len())];
+                PlayerIndicator::Lightbar { red, green, blue }
+            }
+            IndicatorStyle::PlayStationLeds => {
+                PlayerIndicator::Leds(PLAYSTATION_LEDS[wrap(PLAYSTATION_LEDS.len())])
+            }
+            IndicatorStyle::SwitchLeds => {
+                PlayerIndicator::Leds(SWITCH_LEDS[wrap(SWITCH_LEDS.len())])
+            }
+            IndicatorStyle::SingleLed => PlayerIndicator::Leds(1 << wrap(4)),
+        }
+    }
+}
+
+/// A controller that can show which player it is.
+pub trait PlayerLeds {
+    fn style(&self) -> IndicatorStyle;
+
+    fn show(&mut self, indicator: PlayerIndicator) -> Result<()>;
+
+    /// Show player `player`, or turn the indicator off for 0.
+    fn set_player(&mut self, player: u8) -> Result<()> {
+        let indicator = self.style().render(player);
+        self.show(indicator)
+    }
+}
+
+/// Player LEDs exposed by a kernel driver through the LED class, e.g.
+/// `<hid device>/leds/<name>:blue:p0` from hid-wiimote or
+/// `<name>:white:player-1` from hid-playstation and hid-nintendo.
+pub struct SysfsPlayerLeds {
+    style: IndicatorStyle,
+    /// `brightness` files, in LED order.
+    leds: Vec<PathBuf>,
+}
+
+/// The LED number from a name like `...:p0` or `...:player-1`.
+fn player_led_index(name: &str) -> Option<usize> {
+    let function = name.rsplit(':').next()?;
+    if let Some(n) = function.strip_prefix("player-") {
+        n.parse::<usize>().ok()?.checked_sub(1)
+    } else {
+        function.strip_prefix('p')?.parse().ok()
+    }
+}
+
+impl SysfsPlayerLeds {
+    /// Find the player LEDs of the HID device at `hid_sys_path`.
+    pub fn find(hid_sys_path: &Path, style: IndicatorStyle) -> Result<SysfsPlayerLeds> {
+        let dir = hid_sys_path.join("leds");
+        let mut leds = vec![];
+        for entry in fs::read_dir(&dir).with_context(|| format!("No LEDs in {dir:?}"))? {
+            let entry = entry?;
+            if let Some(index) = player_led_index(&entry.file_name().to_string_lossy()) {
+                leds.push((index, entry.path().join("brightness")));
+            }
+        }
+        if leds.is_empty() {
+            bail!("No player LEDs in {dir:?}");
+        }
+        leds.sort();
+        debug!("Found {} player LEDs in {dir:?}", leds.len());
+        Ok(SysfsPlayerLeds {
+            style,
+            leds: leds.into_iter().map(|(_, path)| path).collect(),
+        })
+    }
+}
+
+impl PlayerLeds for SysfsPlayerLeds {
+    fn style(&self) -> IndicatorStyle {
+        self.style
+    }
+
+    fn show(&mut self, indicator: PlayerIndicator) -> Result<()> {
+        let PlayerIndicator::Leds(mask) = indicator else {
+            bail!("{indicator:?} can't be shown with individual LEDs");
+        };
+        for (i, path) in self.leds.iter().enumerate() {
+            let on = mask & (1 << i) != 0;
+            fs::write(path, if on { "1" } else { "0" })
+                .with_context(|| format!("Failed to write {path:?}"))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,699 @@
+use anyhow::{bail, Context as ErrorContext, Result};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use log::warn;
+use std::time::Duration;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+use crate::device_monitor::{Bus, DeviceInfo};
+use crate::drivers::HidDriver;
+use crate::handle::DeviceHandle;
+use crate::report::{Axis, Button, Capabilities, GamepadInput, Motion, SensorClock, TouchContact};
+use crate::rumble::{Rumble, RumbleEffect};
+
+pub const SONY_VENDOR_ID: u16 = 0x054C;
+
+// From Linux drivers/hid/hid-ids.h
+const DS4_PRODUCT_IDS: &[u16] = &[0x05C4, 0x09CC, 0x0BA0];
+const DUALSENSE_PRODUCT_IDS: &[u16] = &[0x0CE6, 0x0DF2];
+
+/// The touchpad's resolution, for scaling `Touch` coordinates.
+pub const DS4_TOUCHPAD_SIZE: (u16, u16) = (1920, 942);
+pub const DUALSENSE_TOUCHPAD_SIZE: (u16, u16) = (1920, 1080);
+
+// From Linux drivers/hid/hid-playstation.c
+const DS4_INPUT_REPORT_USB: u8 = 0x01;
+const DS4_INPUT_REPORT_BT: u8 = 0x11;
+const DS4_OUTPUT_REPORT_USB: u8 = 0x05;
+const DS4_OUTPUT_REPORT_USB_SIZE: usize = 32;
+const DS4_OUTPUT_REPORT_BT: u8 = 0x11;
+const DS4_OUTPUT_REPORT_BT_SIZE: usize = 78;
+const DS4_OUTPUT_VALID_MOTOR: u8 = 0x01;
+const DS4_OUTPUT_VALID_LED: u8 = 0x02;
+const DS4_OUTPUT_HWCTL_CRC32: u8 = 0x40;
+const DS4_OUTPUT_HWCTL_HID: u8 = 0x80;
+const DS4_STATUS_CABLE: u8 = 0x10;
+const DS4_FEATURE_REPORT_CALIBRATION: u8 = 0x02;
+const DS4_FEATURE_REPORT_CALIBRATION_BT: u8 = 0x05;
+/// The sensor clock ticks every 16/3µs.
+const DS4_SENSOR_CLOCK_HZ: u64 = 187_500;
+
+const DS_INPUT_REPORT_USB: u8 = 0x01;
+const DS_INPUT_REPORT_BT: u8 = 0x31;
+const DS_OUTPUT_REPORT_USB: u8 = 0x02;
+const DS_OUTPUT_REPORT_USB_SIZE: usize = 63;
+const DS_OUTPUT_REPORT_BT: u8 = 0x31;
+const DS_OUTPUT_REPORT_BT_SIZE: usize = 78;
+const DS_OUTPUT_TAG: u8 = 0x10;
+const DS_OUTPUT_VALID0_COMPATIBLE_VIBRATION: u8 = 0x01;
+const DS_OUTPUT_VALID0_HAPTICS_SELECT: u8 = 0x02;
+const DS_OUTPUT_VALID0_RIGHT_TRIGGER: u8 = 0x04;
+const DS_OUTPUT_VALID0_LEFT_TRIGGER: u8 = 0x08;
+const DS_OUTPUT_VALID1_LIGHTBAR: u8 = 0x04;
+const DS_OUTPUT_VALID1_PLAYER_INDICATOR: u8 = 0x10;
+const DS_OUTPUT_VALID2_COMPATIBLE_VIBRATION2: u8 = 0x04;
+const DS_STATUS_CHARGING_SHIFT: u8 = 4;
+const DS_FEATURE_REPORT_CALIBRATION: u8 = 0x05;
+/// The sensor clock ticks every 1/3µs.
+const DS_SENSOR_CLOCK_HZ: u64 = 3_000_000;
+
+/// Bluetooth output reports end in a CRC32 of this byte followed by the report.
+const OUTPUT_CRC32_SEED: u8 = 0xA2;
+
+/// Face and shoulder buttons, in the order of the bits following the hat
+/// switch. Both families use the same layout.
+const BUTTONS: &[(usize, u8, Button)] = &[
+    (0, 0x10, Button::West),
+    (0, 0x20, Button::South),
+    (0, 0x40, Button::East),
+    (0, 0x80, Button::North),
+    (1, 0x01, Button::LeftShoulder),
+    (1, 0x02, Button::RightShoulder),
+    (1, 0x10, Button::Back),
+    (1, 0x20, Button::Start),
+    (1, 0x40, Button::LeftStick),
+    (1, 0x80, Button::RightStick),
+    (2, 0x01, Button::Guide),
+    // The touchpad click.
+    (2, 0x02, Button::Misc),
+];
+
+/// The Sony controller families we drive.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Model {
+    DualShock4,
+    /// The DualSense and DualSense Edge.
+    DualSense,
+}
+
+impl Model {
+    pub fn for_device(vendor_id: u16, product_id: u16) -> Option<Model> {
+        if vendor_id != SONY_VENDOR_ID {
+            None
+        } else if DS4_PRODUCT_IDS.contains(&product_id) {
+            Some(Model::DualShock4)
+        } else if DUALSENSE_PRODUCT_IDS.contains(&product_id) {
+            Some(Model::DualSense)
+        } else {
+            None
+        }
+    }
+
+    pub fn detect(info: &DeviceInfo) -> Option<Model> {
+        Model::for_device(info.vendor_id, info.product_id)
+    }
+
+    pub fn touchpad_size(&self) -> (u16, u16) {
+        match self {
+            Model::DualShock4 => DS4_TOUCHPAD_SIZE,
+            Model::DualSense => DUALSENSE_TOUCHPAD_SIZE,
+        }
+    }
+
+    /// The clock `SonyInput::sensor_timestamp` counts.
+    pub fn sensor_clock(&self) -> SensorClock {
+        match self {
+            Model::DualShock4 => SensorClock::new(16, DS4_SENSOR_CLOCK_HZ),
+            Model::DualSense => SensorClock::new(32, DS_SENSOR_CLOCK_HZ),
+        }
+    }
+}
+
+/// Converts raw motion readings to G and degrees per second, from the
+/// controller's calibration feature report.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MotionCalibration {
+    pub gyro_bias: [i16; 3],
+    /// Degrees per second per raw unit.
+    pub gyro_scale: [f32; 3],
+    pub accel_bias: [i16; 3],
+    /// G per raw unit.
+    pub accel_scale: [f32; 3],
+}
+
+/// The sensors' nominal ranges, for controllers whose calibration can't be
+/// read: ±2048°/s and ±4 G.
+impl Default for MotionCalibration {
+    fn default() -> MotionCalibration {
+        MotionCalibration {
+            gyro_bias: [0; 3],
+            gyro_scale: [2048.0 / 32767.0; 3],
+            accel_bias: [0; 3],
+            accel_scale: [4.0 / 32767.0; 3],
+        }
+    }
+}
+
+impl MotionCalibration {
+    /// Parse a calibration feature report, including its report ID. A
+    /// DualShock 4 on Bluetooth lists the gyro ranges in a different order.
+    pub fn parse(model: Model, bluetooth: bool, report: &[u8]) -> Result<MotionCalibration> {
+        if report.len() < 35 {
+            bail!("Short calibration report: {} bytes", report.len());
+        }
+        let value = |offset: usize| i16::from_le_bytes([report[offset], report[offset + 1]]) as i32;
+        // Positive and negative gyro readings at a known rotation speed.
+        let gyro_range = |axis: usize| {
+            if model == Model::DualShock4 && bluetooth {
+                (value(7 + axis * 2), value(13 + axis * 2))
+            } else {
+                (value(7 + axis * 4), value(9 + axis * 4))
+            }
+        };
+        let speed = value(19) + value(21);
+        let mut calibration = MotionCalibration::default();
+        for axis in 0..3 {
+            calibration.gyro_bias[axis] = value(1 + axis * 2) as i16;
+            let (plus, minus) = gyro_range(axis);
+            // Accelerometer readings at +1 and -1 G.
+            let (accel_plus, accel_minus) = (value(23 + axis * 4), value(25 + axis * 4));
+            if plus == minus || accel_plus == accel_minus {
+                bail!("Invalid calibration for axis {axis}: {report:x?}");
+            }
+            calibration.gyro_scale[axis] = speed as f32 / (plus - minus) as f32;
+            let range_2g = accel_plus - accel_minus;
+            calibration.accel_bias[axis] = (accel_plus - range_2g / 2) as i16;
+            calibration.accel_scale[axis] = 2.0 / range_2g as f32;
+        }
+        Ok(calibration)
+    }
+
+    pub fn apply(&self, input: &SonyInput, timestamp: Duration) -> Motion {
+        Motion {
+            accel: [0, 1, 2].map(|i| {
+                (input.accel[i] as i32 - self.accel_bias[i] as i32) as f32 * self.accel_scale[i]
+            }),
+            gyro: [0, 1, 2].map(|i| {
+                (input.gyro[i] as i32 - self.gyro_bias[i] as i32) as f32 * self.gyro_scale[i]
+            }),
+            timestamp,
+        }
+    }
+}
+
+/// A touchpad contact slot.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Touch {
+    /// Increments with every new touch, so a finger can be tracked while it's down.
+    pub id: u8,
+    /// False once the finger is lifted, at its last position.
+    pub down: bool,
+    pub x: u16,
+    pub y: u16,
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Battery {
+    pub percent: u8,
+    pub charging: bool,
+}
+
+/// Everything in an extended input report.
+#[derive(Clone, Debug, Default)]
+pub struct SonyInput {
+    pub gamepad: GamepadInput,
+    /// Raw angular velocity around the X, Y and Z axes, uncalibrated.
+    pub gyro: [i16; 3],
+    /// Raw acceleration along the X, Y and Z axes, uncalibrated.
+    pub accel: [i16; 3],
+    /// When the motion sample was taken, in `Model::sensor_clock` ticks.
+    pub sensor_timestamp: u32,
+    pub touches: [Touch; 2],
+    pub battery: Battery,
+}
+
+fn stick(value: u8) -> f32 {
+    ((value as f32 - 128.0) / 127.0).clamp(-1.0, 1.0)
+}
+
+fn read_i16s(data: &[u8]) -> [i16; 3] {
+    [0, 1, 2].map(|i| i16::from_le_bytes([data[i * 2], data[i * 2 + 1]]))
+}
+
+fn decode_touch(data: &[u8]) -> Touch {
+    Touch {
+        id: data[0] & 0x7F,
+        // The top bit is set when the finger is lifted.
+        down: data[0] & 0x80 == 0,
+        x: data[1] as u16 | (data[2] as u16 & 0x0F) << 8,
+        y: (data[2] as u16) >> 4 | (data[3] as u16) << 4,
+    }
+}
+
+/// Decode sticks, triggers, hat and buttons, which both families lay out the
+/// same way apart from where the buttons start.
+fn decode_gamepad(sticks: &[u8], buttons: &[u8], triggers: &[u8]) -> GamepadInput {
+    let mut input = GamepadInput::default();
+    input.left_stick.x = stick(sticks[0]);
+    input.left_stick.y = stick(sticks[1]);
+    input.right_stick.x = stick(sticks[2]);
+    input.right_stick.y = stick(sticks[3]);
+    input.left_trigger = triggers[0] as f32 / 255.0;
+    input.right_trigger = triggers[1] as f32 / 255.0;
+    // Hat switch: 0 is up, counting clockwise in 45° steps, 8 is centered.
+    let hat = buttons[0] & 0x0F;
+    input.dpad.up = matches!(hat, 0 | 1 | 7);
+    input.dpad.right = matches!(hat, 1..=3);
+    input.dpad.down = matches!(hat, 3..=5);
+    input.dpad.left = matches!(hat, 5..=7);
+    for (byte, mask, button) in BUTTONS {
+        input.set_button(*button, buttons[*byte] & mask != 0);
+    }
+    input
+}
+
+fn decode_ds4(data: &[u8]) -> Result<SonyInput> {
+    // The report is common state, then a touch packet count at 32 and the
+    // packets themselves.
+    if data.len() < 42 {
+        bail!("Short DualShock 4 report: {} bytes", data.len());
+    }
+    let status = data[29];
+    let level = status & 0x0F;
+    let charging = status & DS4_STATUS_CABLE != 0 && level < 10;
+    Ok(SonyInput {
+        gamepad: decode_gamepad(&data[0..4], &data[4..7], &data[7..9]),
+        gyro: read_i16s(&data[12..18]),
+        accel: read_i16s(&data[18..24]),
+        sensor_timestamp: u16::from_le_bytes([data[9], data[10]]) as u32,
+        // Skip the touch packet count and the first packet's timestamp.
+        touches: [decode_touch(&data[34..38]), decode_touch(&data[38..42])],
+        battery: Battery {
+            percent: (level * 10 + 5).min(100),
+            charging,
+        },
+    })
+}
+
+fn decode_dualsense(data: &[u8]) -> Result<SonyInput> {
+    if data.len() < 53 {
+        bail!("Short DualSense report: {} bytes", data.len());
+    }
+    let status = data[52];
+    let level = status & 0x0F;
+    let charging_status = status >> DS_STATUS_CHARGING_SHIFT;
+    Ok(SonyInput {
+        gamepad: decode_gamepad(&data[0..4], &data[7..10], &data[4..6]),
+        gyro: read_i16s(&data[15..21]),
+        accel: read_i16s(&data[21..27]),
+        sensor_timestamp: u32::from_le_bytes([data[27], data[28], data[29], data[30]]),
+        touches: [decode_touch(&data[32..36]), decode_touch(&data[36..40])],
+        battery: Battery {
+            // 2 is fully charged.
+            percent: if charging_status == 2 {
+                100
+            } else {
+                (level * 10 + 5).min(100)
+            },
+            charging: charging_status == 1,
+        },
+    })
+}
+
+/// Decode an extended input report, including its report ID.
+///
+/// A DualShock 4 on Bluetooth sends a basic report without motion, touch or
+/// battery until its Bluetooth calibration feature report has been read,
+/// which `SonyDriver` does.
+pub fn decode_report(model: Model, report: &[u8]) -> Result<SonyInput> {
+    match (model, report.first()) {
+        (Model::DualShock4, Some(&DS4_INPUT_REPORT_USB)) if report.len() > 10 => {
+            decode_ds4(&report[1..])
+        }
+        // Reports too short for their header decode as empty, and so fail.
+        (Model::DualShock4, Some(&DS4_INPUT_REPORT_BT)) => {
+            decode_ds4(report.get(3..).unwrap_or_default())
+        }
+        (Model::DualSense, Some(&DS_INPUT_REPORT_USB)) => decode_dualsense(&report[1..]),
+        (Model::DualSense, Some(&DS_INPUT_REPORT_BT)) => {
+            decode_dualsense(report.get(2..).unwrap_or_default())
+        }
+        _ => bail!("Unexpected {model:?} report: {report:x?}"),
+    }
+}
+
+/// A DualSense adaptive trigger effect. There's no public documentation for
+/// these; the modes are the ones the community has worked out.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum TriggerEffect {
+    #[default]
+    Off,
+    /// Resist with `force` from `start` to the end of travel. Positions and
+    /// forces are 0..=255.
+    Resistance { start: u8, force: u8 },
+    /// Resist with `force` between `start` and `end`, like a weapon's trigger.
+    Section { start: u8, end: u8, force: u8 },
+    /// Buzz at `frequency` Hz once pressed past `start`.
+    Vibration {
+        start: u8,
+        amplitude: u8,
+        frequency: u8,
+    },
+}
+
+impl TriggerEffect {
+    fn encode(&self) -> [u8; 11] {
+        let mut data = [0; 11];
+        match *self {
+            TriggerEffect::Off => data[0] = 0x05,
+            TriggerEffect::Resistance { start, force } => {
+                data[..3].copy_from_slice(&[0x01, start, force]);
+            }
+            TriggerEffect::Section { start, end, force } => {
+                data[..4].copy_from_slice(&[0x02, start, end, force]);
+            }
+            TriggerEffect::Vibration {
+                start,
+                amplitude,
+                frequency,
+            } => {
+                data[..4].copy_from_slice(&[0x06, frequency, amplitude, start]);
+            }
+        }
+        data
+    }
+}
+
+/// What to change with an output report. Anything left as `None` keeps its
+/// current state on the controller.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct SonyOutput {
+    pub rumble: Option<RumbleEffect>,
+    /// Red, green and blue.
+    pub lightbar: Option<(u8, u8, u8)>,
+    /// Left and right trigger effects. Ignored on the DualShock 4.
+    pub triggers: Option<(TriggerEffect, TriggerEffect)>,
+    /// The DualSense's five player LEDs, LED 1 in bit 0. Ignored on the
+    /// DualShock 4.
+    pub player_leds: Option<u8>,
+}
+
+/// The CRC32 Bluetooth output reports end with.
+fn output_crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in std::iter::once(&OUTPUT_CRC32_SEED).chain(data) {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn append_crc32(report: &mut [u8]) {
+    let len = report.len() - 4;
+    let crc = output_crc32(&report[..len]);
+    report[len..].copy_from_slice(&crc.to_le_bytes());
+}
+
+fn ds4_output_report(bluetooth: bool, output: &SonyOutput) -> Vec<u8> {
+    let (mut report, common) = if bluetooth {
+        let mut report = vec![0; DS4_OUTPUT_REPORT_BT_SIZE];
+        report[0] = DS4_OUTPUT_REPORT_BT;
+        // Report at the default interval.
+        report[1] = DS4_OUTPUT_HWCTL_HID | DS4_OUTPUT_HWCTL_CRC32 | 4;
+        (report, 3)
+    } else {
+        let mut report = vec![0; DS4_OUTPUT_REPORT_USB_SIZE];
+        report[0] = DS4_OUTPUT_REPORT_USB;
+        (report, 1)
+    };
+    let state = &mut report[common..];
+    if let Some(effect) = output.rumble {
+        let effect = effect.without_triggers();
+        state[0] |= DS4_OUTPUT_VALID_MOTOR;
+        state[3] = (effect.weak >> 8) as u8;
+        state[4] = (effect.strong >> 8) as u8;
+    }
+    if let Some((red, green, blue)) = output.lightbar {
+        state[0] |= DS4_OUTPUT_VALID_LED;
+        state[5..8].copy_from_slice(&[red, green, blue]);
+    }
+    if bluetooth {
+        append_crc32(&mut report);
+    }
+    report
+}
+
+fn dualsense_output_report(bluetooth: bool, sequence: u8, output: &SonyOutput) -> Vec<u8> {
+    let (mut report, common) = if bluetooth {
+        let mut report = vec![0; DS_OUTPUT_REPORT_BT_SIZE];
+        report[0] = DS_OUTPUT_REPORT_BT;
+        report[1] = (sequence & 0x0F) << 4;
+        report[2] = DS_OUTPUT_TAG;
+        (report, 3)
+    } else {
+        let mut report = vec![0; DS_OUTPUT_REPORT_USB_SIZE];
+        report[0] = DS_OUTPUT_REPORT_USB;
+        (report, 1)
+    };
+    let state = &mut report[common..];
+    if let Some(effect) = output.rumble {
+        let effect = effect.without_triggers();
+        // Emulate the DualShock 4's motors rather than driving the voice coils
+        // with audio.
+        state[0] |= DS_OUTPUT_VALID0_COMPATIBLE_VIBRATION | DS_OUTPUT_VALID0_HAPTICS_SELECT;
+        state[38] |= DS_OUTPUT_VALID2_COMPATIBLE_VIBRATION2;
+        state[2] = (effect.weak >> 8) as u8;
+        state[3] = (effect.strong >> 8) as u8;
+    }
+    if let Some((left, right)) = output.triggers {
+        state[0] |= DS_OUTPUT_VALID0_LEFT_TRIGGER | DS_OUTPUT_VALID0_RIGHT_TRIGGER;
+        state[10..21].copy_from_slice(&right.encode());
+        state[21..32].copy_from_slice(&left.encode());
+    }
+    if let Some((red, green, blue)) = output.lightbar {
+        state[1] |= DS_OUTPUT_VALID1_LIGHTBAR;
+        state[44..47].copy_from_slice(&[red, green, blue]);
+    }
+    if let Some(leds) = output.player_leds {
+        state[1] |= DS_OUTPUT_VALID1_PLAYER_INDICATOR;
+        state[43] = leds & 0x1F;
+    }
+    if bluetooth {
+        append_crc32(&mut report);
+    }
+    report
+}
+
+/// Build an output report. `sequence` should increase with every report sent;
+/// only the DualSense on Bluetooth uses it.
+pub fn output_report(model: Model, bluetooth: bool, sequence: u8, output: &SonyOutput) -> Vec<u8> {
+    match model {
+        Model::DualShock4 => ds4_output_report(bluetooth, output),
+        Model::DualSense => dualsense_output_report(bluetooth, sequence, output),
+    }
+}
+
+/// A DualShock 4 or DualSense, for output through its hidraw node.
+pub struct SonyController {
+    file: File,
+    model: Model,
+    bluetooth: bool,
+    sequence: u8,
+}
+
+impl SonyController {
+    pub async fn open(info: &DeviceInfo) -> Result<SonyController> {
+        let model = Model::detect(info).context("Not a Sony controller")?;
+        let hidraw_node = info.hidraw_node.as_ref().context("No hidraw node")?;
+        let file = OpenOptions::new()
+            .write(true)
+            .open(hidraw_node)
+            .await
+            .with_context(|| format!("Failed to open {hidraw_node:?}"))?;
+        Ok(SonyController {
+            file,
+            model,
+            bluetooth: info.bus == Bus::Bluetooth,
+            sequence: 0,
+        })
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    pub async fn send(&mut self, output: &SonyOutput) -> Result<()> {
+        let report = output_report(self.model, self.bluetooth, self.sequence, output);
+        self.sequence = self.sequence.wrapping_add(1);
+        self.file.write_all(&report).await?;
+        Ok(())
+    }
+
+    pub async fn set_lightbar(&mut self, red: u8, green: u8, blue: u8) -> Result<()> {
+        self.send(&SonyOutput {
+            lightbar: Some((red, green, blue)),
+            ..Default::default()
+        })
+        .await
+    }
+
+    /// Set the DualSense's adaptive trigger effects.
+    pub async fn set_trigger_effects(
+        &mut self,
+        left: TriggerEffect,
+        right: TriggerEffect,
+    ) -> Result<()> {
+        if self.model != Model::DualSense {
+            bail!("{:?} has no adaptive triggers", self.model);
+        }
+        self.send(&SonyOutput {
+            triggers: Some((left, right)),
+            ..Default::default()
+        })
+        .await
+    }
+}
+
+impl Rumble for SonyController {
+    fn rumble(&mut self, effect: RumbleEffect) -> BoxFuture<'_, Result<()>> {
+        async move {
+            self.send(&SonyOutput {
+                rumble: Some(effect),
+                ..Default::default()
+            })
+            .await
+        }
+        .boxed()
+    }
+}
+
+/// Decodes DualShock 4 and DualSense reports for the device watcher.
+#[derive(Default)]
+pub struct SonyDriver {
+    model: Option<Model>,
+    bluetooth: bool,
+    sequence: u8,
+    calibration: MotionCalibration,
+    clock: Option<SensorClock>,
+}
+
+impl SonyDriver {
+    pub fn boxed() -> Box<dyn HidDriver> {
+        Box::<SonyDriver>::default()
+    }
+}
+
+impl HidDriver for SonyDriver {
+    fn name(&self) -> &str {
+        "sony"
+    }
+
+    fn probe(&self, info: &DeviceInfo) -> bool {
+        Model::detect(info).is_some()
+    }
+
+    fn init<'a>(
+        &'a mut self,
+        info: &'a DeviceInfo,
+        handle: &'a mut dyn DeviceHandle,
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            let model = Model::detect(info).context("Not a Sony controller")?;
+            self.model = Some(model);
+            self.bluetooth = info.bus == Bus::Bluetooth;
+            self.clock = Some(model.sensor_clock());
+            let report_id = match model {
+                Model::DualShock4 if self.bluetooth => DS4_FEATURE_REPORT_CALIBRATION_BT,
+                Model::DualShock4 => DS4_FEATURE_REPORT_CALIBRATION,
+                Model::DualSense => DS_FEATURE_REPORT_CALIBRATION,
+            };
+            // Reading it is also what switches a DualShock 4 on Bluetooth to
+            // extended reports, so that one has to succeed.
+            let report = match handle.get_feature(report_id).await {
+                Ok(report) => report,
+                Err(e) if model == Model::DualShock4 && self.bluetooth => {
+                    return Err(e.context("Failed to enable extended reports"));
+                }
+                Err(e) => {
+                    warn!(
+                        "Using default motion calibration for `{}`: {e:#}",
+                        info.name
+                    );
+                    return Ok(());
+                }
+            };
+            match MotionCalibration::parse(model, self.bluetooth, &report) {
+                Ok(calibration) => self.calibration = calibration,
+                Err(e) => warn!(
+                    "Using default motion calibration for `{}`: {e:#}",
+                    info.name
+                ),
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn decode(&mut self, report: &[u8]) -> Result<Option<GamepadInput>> {
+        let model = self.model.context("Not initialized")?;
+        let input = decode_report(model, report)?;
+        let clock = self.clock.get_or_insert_with(|| model.sensor_clock());
+        let timestamp = clock.update(input.sensor_timestamp);
+        let motion = self.calibration.apply(&input, timestamp);
+        let (width, height) = model.touchpad_size();
+        let mut gamepad = input.gamepad;
+        gamepad.motion = Some(motion);
+        gamepad.touches = input
+            .touches
+            .iter()
+            .map(|touch| TouchContact {
+                id: touch.id as u32,
+                x: (touch.x as f32 / (width - 1) as f32).min(1.0),
+                y: (touch.y as f32 / (height - 1) as f32).min(1.0),
+                pressed: touch.down,
+            })
+            .collect();
+        Ok(Some(gamepad))
+    }
+
+    fn output(&mut self, effect: &RumbleEffect) -> Option<Vec<u8>> {
+        let output = SonyOutput {
+            rumble: Some(*effect),
+            ..Default::default()
+        };
+        let report = output_report(self.model?, self.bluetooth, self.sequence, &output);
+        self.sequence = self.sequence.wrapping_add(1);
+        Some(report)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            buttons: Button::ALL.to_vec(),
+            axes: Axis::ALL.to_vec(),
+            dpad: true,
+            motion: true,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_truncated_reports() {
+        for (model, id) in [
+            (Model::DualShock4, DS4_INPUT_REPORT_USB),
+            (Model::DualShock4, DS4_INPUT_REPORT_BT),
+            (Model::DualSense, DS_INPUT_REPORT_USB),
+            (Model::DualSense, DS_INPUT_REPORT_BT),
+        ] {
+            for len in 1..4 {
+                let mut report = vec![0; len];
+                report[0] = id;
+                assert!(
+                    decode_report(model, &report).is_err(),
+                    "{model:?} {report:x?}"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,570 @@
+use anyhow::{bail, Context as ErrorContext, Result};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use log::{debug, info, warn};
+use std::time::{Duration, Instant};
+
+use crate::cache;
+use crate::device_monitor::{Bus, DeviceInfo};
+use crate::drivers::HidDriver;
+use crate::handle::{DeviceHandle, Transaction};
+use crate::report::{Axis, Button, Capabilities, GamepadInput, Motion};
+use crate::wiimote::NINTENDO_VENDOR_ID;
+
+pub const PRODUCT_ID: u16 = 0x2009;
+
+// From Linux drivers/hid/hid-nintendo.c
+const OUTPUT_RUMBLE_AND_SUBCMD: u8 = 0x01;
+const OUTPUT_USB_CMD: u8 = 0x80;
+const INPUT_SUBCMD_REPLY: u8 = 0x21;
+const INPUT_IMU_DATA: u8 = 0x30;
+const INPUT_USB_RESPONSE: u8 = 0x81;
+const INPUT_BUTTON_EVENT: u8 = 0x3F;
+
+const USB_CMD_HANDSHAKE: u8 = 0x02;
+const USB_CMD_NO_TIMEOUT: u8 = 0x04;
+
+const SUBCMD_REQ_DEV_INFO: u8 = 0x02;
+const SUBCMD_SET_REPORT_MODE: u8 = 0x03;
+const SUBCMD_SPI_FLASH_READ: u8 = 0x10;
+const SUBCMD_SET_PLAYER_LIGHTS: u8 = 0x30;
+const SUBCMD_ENABLE_IMU: u8 = 0x40;
+const REPORT_MODE_STANDARD_FULL: u8 = 0x30;
+
+const CAL_USR_MAGIC: [u8; 2] = [0xB2, 0xA1];
+const CAL_USR_LEFT_MAGIC_ADDR: u32 = 0x8010;
+const CAL_USR_LEFT_DATA_ADDR: u32 = 0x8012;
+const CAL_USR_RIGHT_MAGIC_ADDR: u32 = 0x801B;
+const CAL_USR_RIGHT_DATA_ADDR: u32 = 0x801D;
+const CAL_FCT_LEFT_DATA_ADDR: u32 = 0x603D;
+const CAL_FCT_RIGHT_DATA_ADDR: u32 = 0x6046;
+const CAL_STICK_DATA_SIZE: u8 = 9;
+const IMU_CAL_FCT_DATA_ADDR: u32 = 0x6020;
+const IMU_CAL_DATA_SIZE: u8 = 24;
+
+/// The raw calibration: left stick, right stick, then IMU.
+const CAL_DATA_SIZE: usize = 2 * CAL_STICK_DATA_SIZE as usize + IMU_CAL_DATA_SIZE as usize;
+
+/// Motors idle, sent with every subcommand.
+const RUMBLE_NEUTRAL: [u8; 8] = [0x00, 0x01, 0x40, 0x40, 0x00, 0x01, 0x40, 0x40];
+
+/// How long to wait for the controller to answer a command, and how many
+/// times to ask, as hid-nintendo does.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+const RETRIES: u32 = 2;
+
+// Button bits, by byte of the input report.
+const BUTTONS: &[(usize, u8, Button)] = &[
+    (3, 0x01, Button::West),
+    (3, 0x02, Button::North),
+    (3, 0x04, Button::South),
+    (3, 0x08, Button::East),
+    (3, 0x40, Button::RightShoulder),
+    (4, 0x01, Button::Back),
+    (4, 0x02, Button::Start),
+    (4, 0x04, Button::RightStick),
+    (4, 0x08, Button::LeftStick),
+    (4, 0x10, Button::Guide),
+    // Capture.
+    (4, 0x20, Button::Misc),
+    (5, 0x40, Button::LeftShoulder),
+];
+const BUTTON_ZR: u8 = 0x80;
+const BUTTON_ZL: u8 = 0x80;
+
+/// One stick's range, in raw 12-bit units.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct StickCalibration {
+    pub center: (u16, u16),
+    pub max_above_center: (u16, u16),
+    pub min_below_center: (u16, u16),
+}
+
+impl Default for StickCalibration {
+    /// A nominal range, for controllers whose calibration can't be read.
+    fn default() -> StickCalibration {
+        StickCalibration {
+            center: (2048, 2048),
+            max_above_center: (1500, 1500),
+            min_below_center: (1500, 1500),
+        }
+    }
+}
+
+/// Unpack the pairs of 12-bit values stick calibration is stored as.
+fn unpack_u12s(data: &[u8]) -> [u16; 6] {
+    let mut values = [0; 6];
+    for (pair, bytes) in values.chunks_exact_mut(2).zip(data.chunks_exact(3)) {
+        pair[0] = bytes[0] as u16 | (bytes[1] as u16 & 0x0F) << 8;
+        pair[1] = (bytes[1] as u16) >> 4 | (bytes[2] as u16) << 4;
+    }
+    values
+}
+
+impl StickCalibration {
+    /// The left stick's calibration, which is stored in a different order to
+    /// the right's.
+    fn left(data: &[u8]) -> StickCalibration {
+        let v = unpack_u12s(data);
+        StickCalibration {
+            max_above_center: (v[0], v[1]),
+            center: (v[2], v[3]),
+            min_below_center: (v[4], v[5]),
+        }
+    }
+
+    fn right(data: &[u8]) -> StickCalibration {
+        let v = unpack_u12s(data);
+        StickCalibration {
+            center: (v[0], v[1]),
+            min_below_center: (v[2], v[3]),
+            max_above_center: (v[4], v[5]),
+        }
+    }
+
+    /// Scale a raw position to -1.0..=1.0, with up as negative like HID sticks.
+    fn apply(&self, x: u16, y: u16) -> (f32, f32) {
+        let axis = |value: u16, center: u16, above: u16, below: u16| {
+            let offset = value as f32 - center as f32;
+            let range = if offset > 0.0 { above } else { below };
+            (offset / range.max(1) as f32).clamp(-1.0, 1.0)
+        };
+        (
+            axis(
+                x,
+                self.center.0,
+                self.max_above_center.0,
+                self.min_below_center.0,
+            ),
+            -axis(
+                y,
+                self.center.1,
+                self.max_above_center.1,
+                self.min_below_center.1,
+            ),
+        )
+    }
+}
+
+/// The factory IMU calibration: offsets and scales for each axis, in raw units.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ImuCalibration {
+    pub accel_offset: [i16; 3],
+    pub accel_scale: [i16; 3],
+    pub gyro_offset: [i16; 3],
+    pub gyro_scale: [i16; 3],
+}
+
+impl Default for ImuCalibration {
+    fn default() -> ImuCalibration {
+        ImuCalibration {
+            accel_offset: [0; 3],
+            accel_scale: [16384; 3],
+            gyro_offset: [0; 3],
+            gyro_scale: [13371; 3],
+        }
+    }
+}
+
+fn read_i16s(data: &[u8]) -> [i16; 3] {
+    [0, 1, 2].map(|i| i16::from_le_bytes([data[i * 2], data[i * 2 + 1]]))
+}
+
+impl ImuCalibration {
+    fn parse(data: &[u8]) -> ImuCalibration {
+        ImuCalibration {
+            accel_offset: read_i16s(&data[0..6]),
+            accel_scale: read_i16s(&data[6..12]),
+            gyro_offset: read_i16s(&data[12..18]),
+            gyro_scale: read_i16s(&data[18..24]),
+        }
+    }
+
+    /// Acceleration in G. The accelerometer's range is ±8 G.
+    pub fn accel(&self, raw: [i16; 3]) -> [f32; 3] {
+        [0, 1, 2].map(|i| {
+            let range = self.accel_scale[i] as f32 - self.accel_offset[i] as f32;
+            raw[i] as f32 * 4.0 / range
+        })
+    }
+
+    /// Angular velocity in degrees per second. The gyro's range is ±2000°/s.
+    pub fn gyro(&self, raw: [i16; 3]) -> [f32; 3] {
+        [0, 1, 2].map(|i| {
+            let range = self.gyro_scale[i] as f32 - self.gyro_offset[i] as f32;
+            (raw[i] as f32 - self.gyro_offset[i] as f32) * 936.0 / range
+        })
+    }
+}
+
+/// The calibration read from the controller's SPI flash.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Calibration {
+    pub left_stick: StickCalibration,
+    pub right_stick: StickCalibration,
+    pub imu: ImuCalibration,
+}
+
+impl Calibration {
+    fn parse(data: &[u8; CAL_DATA_SIZE]) -> Calibration {
+        let (left, rest) = data.split_at(CAL_STICK_DATA_SIZE as usize);
+        let (right, imu) = rest.split_at(CAL_STICK_DATA_SIZE as usize);
+        Calibration {
+            left_stick: StickCalibration::left(left),
+            right_stick: StickCalibration::right(right),
+            imu: ImuCalibration::parse(imu),
+        }
+    }
+}
+
+/// What identifies a controller's cached calibration, from its device info.
+struct CacheKey {
+    firmware: [u8; 2],
+    mac: [u8; 6],
+}
+
+impl CacheKey {
+    fn parse(info: &[u8]) -> Result<CacheKey> {
+        let (Some(firmware), Some(mac)) = (info.get(0..2), info.get(4..10)) else {
+            bail!("Short device info: {info:x?}");
+        };
+        Ok(CacheKey {
+            firmware: firmware.try_into()?,
+            mac: mac.try_into()?,
+        })
+    }
+
+    fn name(&self) -> String {
+        let mac: String = self.mac.iter().map(|b| format!("{b:02x}")).collect();
+        format!("switch-pro/{mac}")
+    }
+
+    /// Cache entries are the firmware version then the raw calibration, so
+    /// a firmware update invalidates them.
+    fn read(&self) -> Option<[u8; CAL_DATA_SIZE]> {
+        let entry = cache::read(&self.name())?;
+        let (firmware, data) = entry.split_at_checked(2)?;
+        if firmware != self.firmware {
+            return None;
+        }
+        data.try_into().ok()
+    }
+
+    fn write(&self, data: &[u8; CAL_DATA_SIZE]) -> Result<()> {
+        cache::write(&self.name(), &[&self.firmware[..], data].concat())
+    }
+}
+
+/// One IMU sample, in raw units.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ImuSample {
+    pub accel: [i16; 3],
+    pub gyro: [i16; 3],
+}
+
+/// Everything in a full input report.
+#[derive(Clone, Debug, Default)]
+pub struct SwitchProInput {
+    pub gamepad: GamepadInput,
+    /// The controller samples its IMU every 5ms and sends the last three
+    /// samples, oldest first, with every report.
+    pub imu: [ImuSample; 3],
+}
+
+/// Decode a full input report (0x30), or the input state at the start of a
+/// subcommand reply (0x21), which has no IMU data.
+pub fn decode_report(calibration: &Calibration, report: &[u8]) -> Result<SwitchProInput> {
+    let imu = match report.first() {
+        Some(&INPUT_IMU_DATA) if report.len() >= 49 => [0, 1, 2].map(|i| {
+            let sample = &report[13 + i * 12..];
+            ImuSample {
+                accel: read_i16s(&sample[0..6]),
+                gyro: read_i16s(&sample[6..12]),
+            }
+        }),
+        Some(&INPUT_SUBCMD_REPLY) if report.len() >= 13 => Default::default(),
+        _ => bail!("Unexpected Switch Pro Controller report: {report:x?}"),
+    };
+    let mut input = GamepadInput::default();
+    for (byte, mask, button) in BUTTONS {
+        input.set_button(*button, report[*byte] & mask != 0);
+    }
+    input.dpad.down = report[5] & 0x01 != 0;
+    input.dpad.up = report[5] & 0x02 != 0;
+    input.dpad.right = report[5] & 0x04 != 0;
+    input.dpad.left = report[5] & 0x08 != 0;
+    // ZL and ZR are digital.
+    input.set_axis(Axis::LeftTrigger, (report[5] & BUTTON_ZL != 0) as u8 as f32);
+    input.set_axis(
+        Axis::RightTrigger,
+        (report[3] & BUTTON_ZR != 0) as u8 as f32,
+    );
+    let sticks = unpack_u12s(&report[6..12]);
+    let (x, y) = calibration.left_stick.apply(sticks[0], sticks[1]);
+    input.left_stick.x = x;
+    input.left_stick.y = y;
+    let (x, y) = calibration.right_stick.apply(sticks[2], sticks[3]);
+    input.right_stick.x = x;
+    input.right_stick.y = y;
+    Ok(SwitchProInput {
+        gamepad: input,
+        imu,
+    })
+}
+
+/// An output report sending subcommand `id`. `packet_number` should
+/// increase with every report sent, modulo 16.
+fn subcommand_report(packet_number: u8, id: u8, args: &[u8]) -> Vec<u8> {
+    let mut report = vec![OUTPUT_RUMBLE_AND_SUBCMD, packet_number & 0x0F];
+    report.extend_from_slice(&RUMBLE_NEUTRAL);
+    report.push(id);
+    report.extend_from_slice(args);
+    report
+}
+
+/// The output report that lights the player LEDs in `leds`, LED 1 in bit 0.
+pub fn player_lights_report(packet_number: u8, leds: u8) -> Vec<u8> {
+    // The high nibble would make them flash instead.
+    subcommand_report(packet_number, SUBCMD_SET_PLAYER_LIGHTS, &[leds & 0x0F])
+}
+
+/// Drives a Switch Pro Controller through the handshake it needs before it
+/// sends full reports: USB setup, full report mode, IMU on, and calibration.
+#[derive(Default)]
+pub struct SwitchProDriver {
+    calibration: Calibration,
+    /// Incremented with every output report, modulo 16.
+    packet_number: u8,
+    /// Motion timestamps count from here, since the controller's timer isn't
+    /// documented.
+    started: Option<Instant>,
+    /// Whether the IMU was turned off after `init`, leaving zeros where its
+    /// samples go.
+    motion_off: bool,
+}
+
+impl SwitchProDriver {
+    pub fn boxed() -> Box<dyn HidDriver> {
+        Box::<SwitchProDriver>::default()
+    }
+
+    pub fn calibration(&self) -> &Calibration {
+        &self.calibration
+    }
+
+    fn transaction(&self) -> Transaction {
+        Transaction::new().timeout(REPLY_TIMEOUT).retries(RETRIES)
+    }
+
+    async fn usb_command(
+        &mut self,
+        handle: &mut dyn DeviceHandle,
+        command: u8,
+        reply: bool,
+    ) -> Result<()> {
+        let request = [OUTPUT_USB_CMD, command];
+        if !reply {
+            return handle.write_report(&request).await;
+        }
+        self.transaction()
+            .run(handle, &request, |r| {
+                (r.len() >= 2 && r[0] == INPUT_USB_RESPONSE && r[1] == command).then_some(())
+            })
+            .await
+            .with_context(|| format!("USB command {command:#04x} failed"))
+    }
+
+    /// Send a subcommand and return the data from its reply.
+    async fn subcommand(
+        &mut self,
+        handle: &mut dyn DeviceHandle,
+        id: u8,
+        args: &[u8],
+    ) -> Result<Vec<u8>> {
+        let report = subcommand_report(self.packet_number, id, args);
+        self.packet_number = (self.packet_number + 1) & 0x0F;
+        let (ack, data) = self
+            .transaction()
+            .run(handle, &report, |r| {
+                (r.len() > 15 && r[0] == INPUT_SUBCMD_REPLY && r[14] == id)
+                    .then(|| (r[13], r[15..].to_vec()))
+            })
+            .await
+            .with_context(|| format!("Subcommand {id:#04x} failed"))?;
+        // The top bit of the ACK byte is set for success.
+        if ack & 0x80 == 0 {
+            bail!("Subcommand {id:#04x} failed");
+        }
+        Ok(data)
+    }
+
+    async fn read_spi(
+        &mut self,
+        handle: &mut dyn DeviceHandle,
+        addr: u32,
+        len: u8,
+    ) -> Result<Vec<u8>> {
+        let mut args = addr.to_le_bytes().to_vec();
+        args.push(len);
+        let data = self
+            .subcommand(handle, SUBCMD_SPI_FLASH_READ, &args)
+            .await?;
+        // The reply echoes the address and length before the data.
+        match data.get(5..5 + len as usize) {
+            Some(data) => Ok(data.to_vec()),
+            None => bail!("Short SPI flash read at {addr:#06x}"),
+        }
+    }
+
+    /// Read user stick calibration if it's been set, or the factory's.
+    async fn read_stick_calibration(
+        &mut self,
+        handle: &mut dyn DeviceHandle,
+        user_magic: u32,
+        user_data: u32,
+        factory_data: u32,
+    ) -> Result<Vec<u8>> {
+        let magic = self
+            .read_spi(handle, user_magic, CAL_USR_MAGIC.len() as u8)
+            .await?;
+        let addr = if magic == CAL_USR_MAGIC {
+            debug!("Using user stick calibration at {user_data:#06x}");
+            user_data
+        } else {
+            factory_data
+        };
+        self.read_spi(handle, addr, CAL_STICK_DATA_SIZE).await
+    }
+
+    async fn read_calibration(
+        &mut self,
+        handle: &mut dyn DeviceHandle,
+    ) -> Result<[u8; CAL_DATA_SIZE]> {
+        let left = self
+            .read_stick_calibration(
+                handle,
+                CAL_USR_LEFT_MAGIC_ADDR,
+                CAL_USR_LEFT_DATA_ADDR,
+                CAL_FCT_LEFT_DATA_ADDR,
+            )
+            .await?;
+        let right = self
+            .read_stick_calibration(
+                handle,
+                CAL_USR_RIGHT_MAGIC_ADDR,
+                CAL_USR_RIGHT_DATA_ADDR,
+                CAL_FCT_RIGHT_DATA_ADDR,
+            )
+            .await?;
+        let imu = self
+            .read_spi(handle, IMU_CAL_FCT_DATA_ADDR, IMU_CAL_DATA_SIZE)
+            .await?;
+        Ok([left, right, imu].concat().try_into().unwrap())
+    }
+
+    /// Read the calibration from the cache, or from the controller if it
+    /// isn't cached, since SPI reads are slow and can fail on Bluetooth.
+    async fn load_calibration(&mut self, handle: &mut dyn DeviceHandle) -> Result<Calibration> {
+        let info = self.subcommand(handle, SUBCMD_REQ_DEV_INFO, &[]).await?;
+        let key = CacheKey::parse(&info)?;
+        if let Some(data) = key.read() {
+            debug!("Using cached calibration {}", key.name());
+            return Ok(Calibration::parse(&data));
+        }
+        let data = self.read_calibration(handle).await?;
+        if let Err(e) = key.write(&data) {
+            warn!("Not caching calibration: {e:#}");
+        }
+        Ok(Calibration::parse(&data))
+    }
+}
+
+impl HidDriver for SwitchProDriver {
+    fn name(&self) -> &str {
+        "switch-pro"
+    }
+
+    fn probe(&self, info: &DeviceInfo) -> bool {
+        (info.vendor_id, info.product_id) == (NINTENDO_VENDOR_ID, PRODUCT_ID)
+    }
+
+    fn init<'a>(
+        &'a mut self,
+        info: &'a DeviceInfo,
+        handle: &'a mut dyn DeviceHandle,
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            if info.bus == Bus::Usb {
+                // Over USB the controller only answers subcommands after this.
+                // Switching to 3 Mbit/s is left to `fast_mode`.
+                self.usb_command(handle, USB_CMD_HANDSHAKE, true).await?;
+                self.usb_command(handle, USB_CMD_NO_TIMEOUT, false).await?;
+            }
+            self.calibration = self
+                .load_calibration(handle)
+                .await
+                .context("Failed to read calibration")?;
+            debug!("Calibration for `{}`: {:?}", info.name, self.calibration);
+            self.subcommand(handle, SUBCMD_SET_REPORT_MODE, &[REPORT_MODE_STANDARD_FULL])
+                .await
+                .context("Failed to set the report mode")?;
+            self.subcommand(handle, SUBCMD_ENABLE_IMU, &[0x01])
+                .await
+                .context("Failed to enable the IMU")?;
+            info!("Switch Pro Controller `{}` is ready", info.name);
+            Ok(())
+        }
+        .boxed()
+    }
+
+    /// Long enough for every subcommand to use its retries.
+    fn init_timeout(&self) -> Duration {
+        Duration::from_secs(15)
+    }
+
+    fn reply_ids(&self) -> &[u8] {
+        &[INPUT_SUBCMD_REPLY, INPUT_USB_RESPONSE]
+    }
+
+    fn decode(&mut self, report: &[u8]) -> Result<Option<GamepadInput>> {
+        match report.first() {
+            Some(&INPUT_IMU_DATA) => {
+                let input = decode_report(&self.calibration, report)?;
+                let started = self.started.get_or_insert_with(Instant::now);
+                // Only the newest of the three samples fits in `GamepadInput`.
+                let sample = input.imu[2];
+                let mut gamepad = input.gamepad;
+                if self.motion_off {
+                    return Ok(Some(gamepad));
+                }
+                gamepad.motion = Some(Motion {
+                    accel: self.calibration.imu.accel(sample.accel),
+                    gyro: self.calibration.imu.gyro(sample.gyro),
+                    timestamp: started.elapsed(),
+                });
+                Ok(Some(gamepad))
+            }
+            // The simple reports the controller sends until `init` sets the
+            // report mode, which `Demux` may have held on to.
+            Some(&INPUT_BUTTON_EVENT) => Ok(None),
+            _ => bail!("Unexpected Switch Pro Controller report: {report:x?}"),
+        }
+    }
+
+    fn motion_report(&mut self, enabled: bool) -> Option<Vec<u8>> {
+        self.motion_off = !enabled;
+        let report = subcommand_report(self.packet_number, SUBCMD_ENABLE_IMU, &[enabled as u8]);
+        self.packet_number = (self.packet_number + 1) & 0x0F;
+        Some(report)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            buttons: Button::ALL.to_vec(),
+            axes: Axis::ALL.to_vec(),
+            dpad: true,
+            motion: true,
+            ..Default::default()
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use anyhow::{bail, Result};
+
+use crate::device_monitor::{Bus, DeviceInfo};
+use crate::drivers::HidDriver;
+use crate::report::{Axis, Button, Capabilities, GamepadInput};
+use crate::rumble::RumbleEffect;
+
+pub const MICROSOFT_VENDOR_ID: u16 = 0x045E;
+
+// From Linux drivers/hid/hid-microsoft.c
+const BT_RUMBLE_REPORT_ID: u8 = 0x03;
+const BT_ENABLE_ALL: u8 = 0x0F;
+
+/// Xbox One S, Series X|S and Elite Series 2 pads with firmware 5 or later,
+/// which all send the same Bluetooth input report.
+const BT_PRODUCT_IDS: &[u16] = &[0x0B13, 0x0B20, 0x0B22];
+const BT_INPUT_REPORT_ID: u8 = 0x01;
+const BT_INPUT_REPORT_LEN: usize = 17;
+
+const BT_BUTTONS: &[(usize, u8, Button)] = &[
+    (14, 0x01, Button::South),
+    (14, 0x02, Button::East),
+    (14, 0x08, Button::West),
+    (14, 0x10, Button::North),
+    (14, 0x40, Button::LeftShoulder),
+    (14, 0x80, Button::RightShoulder),
+    (15, 0x04, Button::Back),
+    (15, 0x08, Button::Start),
+    (15, 0x10, Button::Guide),
+    (15, 0x20, Button::LeftStick),
+    (15, 0x40, Button::RightStick),
+    // Share, on Series X|S pads.
+    (16, 0x01, Button::Misc),
+];
+
+/// Scale a magnitude to the 0..=100 percentage the Bluetooth report uses.
+fn bt_magnitude(value: u16) -> u8 {
+    (value as u32 * 100 / u16::MAX as u32) as u8
+}
+
+/// The rumble output report for Xbox One S and later pads over Bluetooth.
+pub fn bt_rumble_report(effect: &RumbleEffect) -> [u8; 9] {
+    [
+        BT_RUMBLE_REPORT_ID,
+        BT_ENABLE_ALL,
+        bt_magnitude(effect.left_trigger),
+        bt_magnitude(effect.right_trigger),
+        bt_magnitude(effect.strong),
+        bt_magnitude(effect.weak),
+        // Duration and start delay in 10ms units, and loop count.
+        0xFF,
+        0x00,
+        0x00,
+    ]
+}
+
+fn bt_u16(report: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([report[offset], report[offset + 1]])
+}
+
+/// Scale a stick centered on 0x8000 to -1.0..=1.0.
+fn bt_stick(report: &[u8], offset: usize) -> f32 {
+    ((bt_u16(report, offset) as f32 - 32768.0) / 32767.0).clamp(-1.0, 1.0)
+}
+
+/// Decode a Bluetooth input report. Sticks are 16-bit and triggers 10-bit.
+pub fn decode_bt_report(report: &[u8]) -> Result<GamepadInput> {
+    if report.len() < BT_INPUT_REPORT_LEN || report[0] != BT_INPUT_REPORT_ID {
+        bail!("Unexpected Xbox Bluetooth report: {report:x?}");
+    }
+    let mut input = GamepadInput::default();
+    input.left_stick.x = bt_stick(report, 1);
+    input.left_stick.y = bt_stick(report, 3);
+    input.right_stick.x = bt_stick(report, 5);
+    input.right_stick.y = bt_stick(report, 7);
+    input.left_trigger = (bt_u16(report, 9) & 0x3FF) as f32 / 1023.0;
+    input.right_trigger = (bt_u16(report, 11) & 0x3FF) as f32 / 1023.0;
+    // Hat switch: 1 is up, counting clockwise in 45° steps, 0 is centered.
+    let hat = report[13];
+    input.dpad.up = matches!(hat, 1 | 2 | 8);
+    input.dpad.right = matches!(hat, 2..=4);
+    input.dpad.down = matches!(hat, 4..=6);
+    input.dpad.left = matches!(hat, 6..=8);
+    for (byte, mask, button) in BT_BUTTONS {
+        input.set_button(*button, report[*byte] & mask != 0);
+    }
+    Ok(input)
+}
+
+/// Decodes Xbox pads connected over Bluetooth into the standard layout, and
+/// rumbles them including the trigger motors.
+#[derive(Default)]
+pub struct XboxBtDriver;
+
+impl XboxBtDriver {
+    pub fn boxed() -> Box<dyn HidDriver> {
+        Box::new(XboxBtDriver)
+    }
+}
+
+impl HidDriver for XboxBtDriver {
+    fn name(&self) -> &str {
+        "xbox-bt"
+    }
+
+    fn probe(&self, info: &DeviceInfo) -> bool {
+        info.bus == Bus::Bluetooth
+            && info.vendor_id == MICROSOFT_VENDOR_ID
+            && BT_PRODUCT_IDS.contains(&info.product_id)
+    }
+
+    fn decode(&mut self, report: &[u8]) -> Result<Option<GamepadInput>> {
+        match report.first() {
+            Some(&BT_INPUT_REPORT_ID) => Ok(Some(decode_bt_report(report)?)),
+            // Battery and other status reports.
+            _ => Ok(None),
+        }
+    }
+
+    fn output(&mut self, effect: &RumbleEffect) -> Option<Vec<u8>> {
+        Some(bt_rumble_report(effect).to_vec())
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            buttons: Button::ALL.to_vec(),
+            axes: Axis::ALL.to_vec(),
+            dpad: true,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bt_output_includes_trigger_motors() {
+        let effect = RumbleEffect {
+            strong: 0xFFFF,
+            weak: 0x8000,
+            left_trigger: 0,
+            right_trigger: 0xFFFF,
+        };
+        let report = XboxBtDriver.output(&effect).unwrap();
+        assert_eq!(report, vec![0x03, 0x0F, 0, 100, 100, 50, 0xFF, 0x00, 0x00]);
+    }
+}
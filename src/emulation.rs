@@ -0,0 +1,836 @@
+use anyhow::{anyhow, Result};
+use log::{debug, info, warn};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::Arc;
+use tokio::sync::{mpsc, oneshot, watch};
+
+use crate::device::TaskHandle;
+use crate::device_monitor::{Bus, DeviceInfo, EMULATED_PHYS_PREFIX};
+use crate::drivers::player::{IndicatorStyle, PlayerIndicator, PlayerLeds};
+use crate::led::Led;
+use crate::report::{Button, Dpad, GamepadInput};
+use crate::rumble::{Rumble, RumbleEffect};
+use crate::sink::OutputSink;
+use crate::uhid::{ReportType, UhidConfig, UhidDevice, UhidEvent, UHID_PATH};
+
+/// errno returned to the kernel for report requests we can't answer.
+const EIO: u16 = 5;
+
+pub use crate::sink::EmulationTarget;
+
+#[rustfmt::skip]
+const XBOX360_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01,       // Usage Page (Generic Desktop)
+    0x09, 0x05,       // Usage (Gamepad)
+    0xA1, 0x01,       // Collection (Application)
+    0x09, 0x30,       //   Usage (X)
+    0x09, 0x31,       //   Usage (Y)
+    0x09, 0x33,       //   Usage (Rx)
+    0x09, 0x34,       //   Usage (Ry)
+    0x16, 0x00, 0x80, //   Logical Minimum (-32768)
+    0x26, 0xFF, 0x7F, //   Logical Maximum (32767)
+    0x75, 0x10,       //   Report Size (16)
+    0x95, 0x04,       //   Report Count (4)
+    0x81, 0x02,       //   Input (Data, Var, Abs)
+    0x09, 0x32,       //   Usage (Z)
+    0x09, 0x35,       //   Usage (Rz)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x75, 0x08,       //   Report Size (8)
+    0x95, 0x02,       //   Report Count (2)
+    0x81, 0x02,       //   Input (Data, Var, Abs)
+    0x09, 0x39,       //   Usage (Hat switch)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x25, 0x07,       //   Logical Maximum (7)
+    0x35, 0x00,       //   Physical Minimum (0)
+    0x46, 0x3B, 0x01, //   Physical Maximum (315)
+    0x65, 0x14,       //   Unit (Degrees)
+    0x75, 0x04,       //   Report Size (4)
+    0x95, 0x01,       //   Report Count (1)
+    0x81, 0x42,       //   Input (Data, Var, Abs, Null)
+    0x65, 0x00,       //   Unit (None)
+    0x81, 0x03,       //   Input (Const)
+    0x05, 0x09,       //   Usage Page (Button)
+    0x19, 0x01,       //   Usage Minimum (1)
+    0x29, 0x0B,       //   Usage Maximum (11)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x25, 0x01,       //   Logical Maximum (1)
+    0x75, 0x01,       //   Report Size (1)
+    0x95, 0x0B,       //   Report Count (11)
+    0x81, 0x02,       //   Input (Data, Var, Abs)
+    0x75, 0x05,       //   Report Size (5)
+    0x95, 0x01,       //   Report Count (1)
+    0x81, 0x03,       //   Input (Const)
+    0x06, 0x00, 0xFF, //   Usage Page (Vendor Defined)
+    0x09, 0x01,       //   Usage (0x01)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x75, 0x08,       //   Report Size (8)
+    0x95, 0x02,       //   Report Count (2)
+    0x91, 0x02,       //   Output (Data, Var, Abs): strong, weak motor
+    0xC0,             // End Collection
+];
+
+/// A reduced version of the DualShock 4 USB descriptor that keeps the layout of
+/// input report 0x01 and output report 0x05.
+#[rustfmt::skip]
+const DUALSHOCK4_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01,       // Usage Page (Generic Desktop)
+    0x09, 0x05,       // Usage (Gamepad)
+    0xA1, 0x01,       // Collection (Application)
+    0x85, 0x01,       //   Report ID (1)
+    0x09, 0x30,       //   Usage (X)
+    0x09, 0x31,       //   Usage (Y)
+    0x09, 0x32,       //   Usage (Z)
+    0x09, 0x35,       //   Usage (Rz)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x75, 0x08,       //   Report Size (8)
+    0x95, 0x04,       //   Report Count (4)
+    0x81, 0x02,       //   Input (Data, Var, Abs)
+    0x09, 0x39,       //   Usage (Hat switch)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x25, 0x07,       //   Logical Maximum (7)
+    0x35, 0x00,       //   Physical Minimum (0)
+    0x46, 0x3B, 0x01, //   Physical Maximum (315)
+    0x65, 0x14,       //   Unit (Degrees)
+    0x75, 0x04,       //   Report Size (4)
+    0x95, 0x01,       //   Report Count (1)
+    0x81, 0x42,       //   Input (Data, Var, Abs, Null)
+    0x65, 0x00,       //   Unit (None)
+    0x05, 0x09,       //   Usage Page (Button)
+    0x19, 0x01,       //   Usage Minimum (1)
+    0x29, 0x0E,       //   Usage Maximum (14)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x25, 0x01,       //   Logical Maximum (1)
+    0x75, 0x01,       //   Report Size (1)
+    0x95, 0x0E,       //   Report Count (14)
+    0x81, 0x02,       //   Input (Data, Var, Abs)
+    0x06, 0x00, 0xFF, //   Usage Page (Vendor Defined)
+    0x09, 0x20,       //   Usage (0x20): report counter
+    0x25, 0x3F,       //   Logical Maximum (63)
+    0x75, 0x06,       //   Report Size (6)
+    0x95, 0x01,       //   Report Count (1)
+    0x81, 0x02,       //   Input (Data, Var, Abs)
+    0x05, 0x01,       //   Usage Page (Generic Desktop)
+    0x09, 0x33,       //   Usage (Rx)
+    0x09, 0x34,       //   Usage (Ry)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xFF, 0x00, //   Logical Maximum (255)
+    0x75, 0x08,       //   Report Size (8)
+    0x95, 0x02,       //   Report Count (2)
+    0x81, 0x02,       //   Input (Data, Var, Abs)
+    0x06, 0x00, 0xFF, //   Usage Page (Vendor Defined)
+    0x09, 0x21,       //   Usage (0x21): timestamp, sensors, touchpad
+    0x95, 0x36,       //   Report Count (54)
+    0x81, 0x02,       //   Input (Data, Var, Abs)
+    0x85, 0x05,       //   Report ID (5)
+    0x09, 0x22,       //   Usage (0x22): rumble and lightbar
+    0x95, 0x1F,       //   Report Count (31)
+    0x91, 0x02,       //   Output (Data, Var, Abs)
+    0x85, 0x02,       //   Report ID (2)
+    0x09, 0x24,       //   Usage (0x24): IMU calibration
+    0x95, 0x24,       //   Report Count (36)
+    0xB1, 0x02,       //   Feature (Data, Var, Abs)
+    0x85, 0x12,       //   Report ID (18)
+    0x09, 0x25,       //   Usage (0x25): pairing info
+    0x95, 0x0F,       //   Report Count (15)
+    0xB1, 0x02,       //   Feature (Data, Var, Abs)
+    0x85, 0x81,       //   Report ID (129)
+    0x09, 0x26,       //   Usage (0x26): MAC address
+    0x95, 0x06,       //   Report Count (6)
+    0xB1, 0x02,       //   Feature (Data, Var, Abs)
+    0x85, 0xA3,       //   Report ID (163)
+    0x09, 0x27,       //   Usage (0x27): firmware info
+    0x95, 0x30,       //   Report Count (48)
+    0xB1, 0x02,       //   Feature (Data, Var, Abs)
+    0xC0,             // End Collection
+];
+
+const DS4_INPUT_REPORT_LEN: usize = 64;
+const DS4_CALIBRATION_REPORT_LEN: usize = 37;
+const DS4_PAIRING_REPORT_LEN: usize = 16;
+const DS4_ADDRESS_REPORT_LEN: usize = 7;
+const DS4_FIRMWARE_REPORT_LEN: usize = 49;
+
+/// What the emulated DS4 reports as its hardware and firmware versions.
+const DS4_HW_VERSION: u16 = 0x3100;
+const DS4_FW_VERSION: u16 = 0x01A0;
+
+/// Numbers each virtual device's MAC address, since hid-sony and
+/// hid-playstation refuse a second controller with an address they've seen.
+static NEXT_ADDRESS: AtomicU16 = AtomicU16::new(1);
+
+/// A locally administered MAC address for a new virtual device.
+fn next_address() -> [u8; 6] {
+    let [hi, lo] = NEXT_ADDRESS.fetch_add(1, Ordering::Relaxed).to_be_bytes();
+    [0x02, 0x48, 0x49, 0x44, hi, lo]
+}
+
+// DS4 output report 0x05 flags.
+const DS4_FLAG_RUMBLE: u8 = 0x01;
+const DS4_FLAG_LIGHTBAR: u8 = 0x02;
+
+/// Something another process asked the emulated device to do.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum EmulatedOutput {
+    Rumble(RumbleEffect),
+    Lightbar { red: u8, green: u8, blue: u8 },
+}
+
+/// Convert a -1.0..=1.0 axis to 0..=255.
+fn axis_to_u8(value: f32) -> u8 {
+    ((value.clamp(-1.0, 1.0) + 1.0) * 127.5).round() as u8
+}
+
+/// Convert a -1.0..=1.0 axis to a signed 16-bit value.
+fn axis_to_i16(value: f32) -> i16 {
+    (value.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16
+}
+
+/// Convert a 0.0..=1.0 trigger to 0..=255.
+fn trigger_to_u8(value: f32) -> u8 {
+    (value.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+/// Encode a dpad as a hat switch value: 0 is up, increasing clockwise in 45°
+/// steps, and 8 is centered (out of range, so reported as null).
+fn dpad_to_hat(dpad: &Dpad) -> u8 {
+    match (dpad.up, dpad.right, dpad.down, dpad.left) {
+        (true, false, _, true) => 7,
+        (true, true, _, _) => 1,
+        (true, _, _, _) => 0,
+        (_, true, true, _) => 3,
+        (_, true, _, _) => 2,
+        (_, _, true, true) => 5,
+        (_, _, true, _) => 4,
+        (_, _, _, true) => 6,
+        _ => 8,
+    }
+}
+
+fn pack_buttons(input: &GamepadInput, buttons: &[Button]) -> u16 {
+    buttons
+        .iter()
+        .enumerate()
+        .filter(|(_, b)| input.button(**b))
+        .fold(0, |bits, (i, _)| bits | (1 << i))
+}
+
+impl EmulationTarget {
+    /// The identity and descriptor to create the virtual device with.
+    pub fn uhid_config(&self) -> UhidConfig {
+        match self {
+            EmulationTarget::Xbox360 => UhidConfig {
+                name: "Microsoft X-Box 360 pad".to_owned(),
+                phys: format!("{EMULATED_PHYS_PREFIX}/xbox360"),
+                uniq: String::new(),
+                bus: Bus::Usb,
+                vendor_id: 0x045E,
+                product_id: 0x028E,
+                version: 0x0114,
+                descriptor: XBOX360_DESCRIPTOR.to_vec(),
+            },
+            EmulationTarget::DualShock4 => UhidConfig {
+                name: "Sony Computer Entertainment Wireless Controller".to_owned(),
+                phys: format!("{EMULATED_PHYS_PREFIX}/dualshock4"),
+                uniq: String::new(),
+                bus: Bus::Usb,
+                vendor_id: 0x054C,
+                product_id: 0x05C4,
+                version: 0x0100,
+                descriptor: DUALSHOCK4_DESCRIPTOR.to_vec(),
+            },
+        }
+    }
+
+    /// Encode `input` as an input report for this target. `counter` is a
+    /// rolling report counter, for targets that have one.
+    pub fn encode_input(&self, input: &GamepadInput, counter: u8) -> Vec<u8> {
+        match self {
+            EmulationTarget::Xbox360 => {
+                let mut report = Vec::with_capacity(13);
+                for axis in [
+                    input.left_stick.x,
+                    input.left_stick.y,
+                    input.right_stick.x,
+                    input.right_stick.y,
+                ] {
+                    report.extend_from_slice(&axis_to_i16(axis).to_le_bytes());
+                }
+                report.push(trigger_to_u8(input.left_trigger));
+                report.push(trigger_to_u8(input.right_trigger));
+                report.push(dpad_to_hat(&input.dpad));
+                let buttons = pack_buttons(
+                    input,
+                    &[
+                        Button::South,
+                        Button::East,
+                        Button::West,
+                        Button::North,
+                        Button::LeftShoulder,
+                        Button::RightShoulder,
+                        Button::Back,
+                        Button::Start,
+                        Button::LeftStick,
+                        Button::RightStick,
+                        Button::Guide,
+                    ],
+                );
+                report.extend_from_slice(&buttons.to_le_bytes());
+                report
+            }
+            EmulationTarget::DualShock4 => {
+                let mut report = vec![0; DS4_INPUT_REPORT_LEN];
+                report[0] = 0x01;
+                report[1] = axis_to_u8(input.left_stick.x);
+                report[2] = axis_to_u8(input.left_stick.y);
+                report[3] = axis_to_u8(input.right_stick.x);
+                report[4] = axis_to_u8(input.right_stick.y);
+                let buttons = pack_buttons(
+                    input,
+                    &[
+                        Button::West,
+                        Button::South,
+                        Button::East,
+                        Button::North,
+                        Button::LeftShoulder,
+                        Button::RightShoulder,
+                    ],
+                ) as u8;
+                report[5] = dpad_to_hat(&input.dpad) | (buttons << 4);
+                report[6] = (buttons >> 4)
+                    | ((input.left_trigger > 0.5) as u8) << 2
+                    | ((input.right_trigger > 0.5) as u8) << 3
+                    | (pack_buttons(
+                        input,
+                        &[
+                            Button::Back,
+                            Button::Start,
+                            Button::LeftStick,
+                            Button::RightStick,
+                        ],
+                    ) as u8)
+                        << 4;
+                report[7] = pack_buttons(input, &[Button::Guide, Button::Misc]) as u8
+                    | (counter & 0x3F) << 2;
+                report[8] = trigger_to_u8(input.left_trigger);
+                report[9] = trigger_to_u8(input.right_trigger);
+                report
+            }
+        }
+    }
+
+    /// The contents of a feature report the host may request, if we know it.
+    /// `address` is the device's MAC address, most significant byte first.
+    pub fn feature_report(&self, report_number: u8, address: [u8; 6]) -> Option<Vec<u8>> {
+        let len = match (self, report_number) {
+            // hid-sony refuses to bind without IMU calibration data. All zeroes
+            // makes it fall back to uncalibrated values.
+            (EmulationTarget::DualShock4, 0x02) => DS4_CALIBRATION_REPORT_LEN,
+            // The address is read from 0x81 by hid-sony and from the pairing
+            // info by hid-playstation, both least significant byte first.
+            (EmulationTarget::DualShock4, 0x12) => DS4_PAIRING_REPORT_LEN,
+            (EmulationTarget::DualShock4, 0x81) => DS4_ADDRESS_REPORT_LEN,
+            (EmulationTarget::DualShock4, 0xA3) => DS4_FIRMWARE_REPORT_LEN,
+            _ => return None,
+        };
+        let mut report = vec![0; len];
+        report[0] = report_number;
+        match report_number {
+            0x12 | 0x81 => {
+                for (i, byte) in address.iter().rev().enumerate() {
+                    report[1 + i] = *byte;
+                }
+            }
+            0xA3 => {
+                // Build date and time as NUL-padded strings, then the versions.
+                copy_padded(&mut report[1..17], "Sep 21 2018");
+                copy_padded(&mut report[17..33], "04:50:51");
+                report[35..37].copy_from_slice(&DS4_HW_VERSION.to_le_bytes());
+                report[41..43].copy_from_slice(&DS4_FW_VERSION.to_le_bytes());
+            }
+            _ => {}
+        }
+        Some(report)
+    }
+
+    /// Decode an output report written to the virtual device. A single report
+    /// can carry several outputs.
+    pub fn decode_output(&self, data: &[u8]) -> Vec<EmulatedOutput> {
+        let byte = |b: u8| (b as u16) << 8;
+        match self {
+            // The descriptor has no report IDs, so hidraw writes start with
+            // report number 0.
+            EmulationTarget::Xbox360 => match data {
+                [0x00, strong, weak, ..] => vec![EmulatedOutput::Rumble(RumbleEffect::new(
+                    byte(*strong),
+                    byte(*weak),
+                ))],
+                _ => vec![],
+            },
+            EmulationTarget::DualShock4 => match data {
+                [0x05, flags, _, _, weak, strong, red, green, blue, ..] => {
+                    let mut outputs = vec![];
+                    if flags & DS4_FLAG_RUMBLE != 0 {
+                        let effect = RumbleEffect::new(byte(*strong), byte(*weak));
+                        outputs.push(EmulatedOutput::Rumble(effect));
+                    }
+                    if flags & DS4_FLAG_LIGHTBAR != 0 {
+                        outputs.push(EmulatedOutput::Lightbar {
+                            red: *red,
+                            green: *green,
+                            blue: *blue,
+                        });
+                    }
+                    outputs
+                }
+                _ => vec![],
+            },
+        }
+    }
+}
+
+fn copy_padded(buf: &mut [u8], s: &str) {
+    let len = s.len().min(buf.len());
+    buf[..len].copy_from_slice(&s.as_bytes()[..len]);
+}
+
+/// `target`'s config for a device with the MAC address `address`.
+fn device_config(target: EmulationTarget, address: [u8; 6]) -> UhidConfig {
+    let uniq: Vec<String> = address.iter().map(|b| format!("{b:02x}")).collect();
+    UhidConfig {
+        uniq: uniq.join(":"),
+        ..target.uhid_config()
+    }
+}
+
+/// A virtual controller presenting as one of the `EmulationTarget`s.
+#[derive(Debug)]
+pub struct EmulatedDevice {
+    target: EmulationTarget,
+    device: UhidDevice,
+    /// The uhid node, to create devices for new targets on.
+    path: PathBuf,
+    address: [u8; 6],
+    counter: u8,
+    /// Outputs decoded but not yet returned by `next_output`.
+    pending: VecDeque<EmulatedOutput>,
+}
+
+impl EmulatedDevice {
+    pub async fn create(target: EmulationTarget) -> Result<EmulatedDevice> {
+        EmulatedDevice::create_at(Path::new(UHID_PATH), target).await
+    }
+
+    pub async fn create_at(path: &Path, target: EmulationTarget) -> Result<EmulatedDevice> {
+        let address = next_address();
+        let device = UhidDevice::create_at(path, &device_config(target, address)).await?;
+        Ok(EmulatedDevice {
+            target,
+            device,
+            path: path.to_owned(),
+            address,
+            counter: 0,
+            pending: VecDeque::new(),
+        })
+    }
+
+    pub fn target(&self) -> EmulationTarget {
+        self.target
+    }
+
+    /// Switch to presenting as `target`.
+    ///
+    /// If the new target has the same identity and descriptor, the existing
+    /// virtual device is kept so applications never see a disconnect. Otherwise
+    /// the new device is created first, and only once that worked is the old
+    /// one released to a neutral state and destroyed. If creating it fails, we
+    /// keep presenting as the old target.
+    pub async fn retarget(&mut self, target: EmulationTarget) -> Result<()> {
+        if target == self.target {
+            return Ok(());
+        }
+        if target.uhid_config() == self.target.uhid_config() {
+            self.target = target;
+            return Ok(());
+        }
+        info!("Switching emulation from {:?} to {:?}", self.target, target);
+        // Both devices exist for a moment, so the new one needs its own
+        // address.
+        let address = next_address();
+        let device = UhidDevice::create_at(&self.path, &device_config(target, address)).await?;
+        // Don't leave anything held down in the application.
+        if let Err(e) = self.send_state(&GamepadInput::default()).await {
+            debug!("Failed to release the old virtual device: {e:#}");
+        }
+        let mut old = std::mem::replace(&mut self.device, device);
+        if let Err(e) = old.destroy().await {
+            debug!("Failed to destroy the old virtual device: {e:#}");
+        }
+        self.target = target;
+        self.address = address;
+        self.counter = 0;
+        Ok(())
+    }
+
+    /// Send the current controller state to the virtual device.
+    pub async fn send_state(&mut self, input: &GamepadInput) -> Result<()> {
+        let report = self.target.encode_input(input, self.counter);
+        self.counter = self.counter.wrapping_add(1);
+        self.device.send_input(&report).await
+    }
+
+    /// Wait for the next output from the host, answering report requests along
+    /// the way.
+    pub async fn next_output(&mut self) -> Result<EmulatedOutput> {
+        loop {
+            if let Some(output) = self.pending.pop_front() {
+                return Ok(output);
+            }
+            match self.device.next_event().await? {
+                UhidEvent::Output { data, .. } => {
+                    let outputs = self.target.decode_output(&data);
+                    if outputs.is_empty() {
+                        debug!("Unhandled output report: {data:x?}");
+                    }
+                    self.pending.extend(outputs);
+                }
+                UhidEvent::GetReport {
+                    id,
+                    report_number,
+                    report_type: ReportType::Feature,
+                } => match self.target.feature_report(report_number, self.address) {
+                    Some(report) => self.device.reply_get_report(id, 0, &report).await?,
+                    None => self.device.reply_get_report(id, EIO, &[]).await?,
+                },
+                UhidEvent::GetReport { id, .. } => {
+                    self.device.reply_get_report(id, EIO, &[]).await?
+                }
+                UhidEvent::SetReport { id, data, .. } => {
+                    self.device.reply_set_report(id, 0).await?;
+                    self.pending.extend(self.target.decode_output(&data));
+                }
+                event => debug!("uhid event: {event:?}"),
+            }
+        }
+    }
+}
+
+/// Forwards what applications write to an emulated device to the physical
+/// device behind it, so emulation isn't input-only.
+#[derive(Default)]
+pub struct OutputLoopback {
+    pub rumble: Option<Box<dyn Rumble>>,
+    pub leds: Option<Box<dyn PlayerLeds + Send>>,
+}
+
+impl OutputLoopback {
+    /// Forward to the device behind `task`, which is `info`.
+    pub fn for_task(task: &TaskHandle, info: &DeviceInfo) -> OutputLoopback {
+        let leds = IndicatorStyle::for_device(info.vendor_id, info.product_id).map(|style| {
+            Box::new(TaskLeds {
+                task: task.clone(),
+                style,
+            }) as Box<dyn PlayerLeds + Send>
+        });
+        OutputLoopback {
+            rumble: Some(Box::new(task.clone())),
+            leds,
+        }
+    }
+
+    pub async fn forward(&mut self, output: EmulatedOutput) -> Result<()> {
+        match output {
+            EmulatedOutput::Rumble(effect) => {
+                if let Some(rumble) = self.rumble.as_mut() {
+                    rumble.rumble(effect).await?;
+                }
+            }
+            EmulatedOutput::Lightbar { red, green, blue } => match self.leds.as_mut() {
+                Some(leds) if leds.style() == IndicatorStyle::Lightbar => {
+                    leds.show(PlayerIndicator::Lightbar { red, green, blue })?;
+                }
+                _ => debug!("Dropping lightbar color, the device has no lightbar"),
+            },
+        }
+        Ok(())
+    }
+}
+
+/// Sets a device's LEDs through its task. `show` can't wait for the task, so
+/// failures are only logged.
+struct TaskLeds {
+    task: TaskHandle,
+    style: IndicatorStyle,
+}
+
+impl PlayerLeds for TaskLeds {
+    fn style(&self) -> IndicatorStyle {
+        self.style
+    }
+
+    fn show(&mut self, indicator: PlayerIndicator) -> Result<()> {
+        let led = match indicator {
+            PlayerIndicator::Lightbar { red, green, blue } => Led::Lightbar { red, green, blue },
+            PlayerIndicator::Leds(leds) => Led::Player(leds),
+            // Commands 6 to 9 light one quadrant, which `XpadRing` sets from
+            // the player LED of the same number.
+            PlayerIndicator::XboxRing(command @ 6..=9) => Led::Player(1 << (command - 6)),
+            PlayerIndicator::XboxRing(_) => Led::Player(0),
+        };
+        let task = self.task.clone();
+        tokio::spawn(async move {
+            if let Err(e) = task.set_led(led).await {
+                debug!("Failed to set LEDs: {e:#}");
+            }
+        });
+        Ok(())
+    }
+}
+
+/// A request to switch an emulated device's target, and where to send the
+/// outcome.
+type Retarget = (EmulationTarget, oneshot::Sender<Result<()>>);
+
+/// An emulated device run by a task of its own, which sends it the states its
+/// sinks are given and forwards what applications write to it through an
+/// `OutputLoopback`. Sinks come and go with profiles while this stays, so the
+/// device goes away only once it and all its sinks are dropped.
+pub struct Emulation {
+    target: EmulationTarget,
+    state: Arc<watch::Sender<GamepadInput>>,
+    retargets: mpsc::Sender<Retarget>,
+}
+
+impl Emulation {
+    pub fn start(device: EmulatedDevice, loopback: OutputLoopback) -> Emulation {
+        let target = device.target();
+        let (state, states) = watch::channel(GamepadInput::default());
+        let (retargets, retarget_rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            if let Err(e) = run_emulation(device, states, retarget_rx, loopback).await {
+                warn!("Emulated {target:?} failed: {e:#}");
+            }
+        });
+        Emulation {
+            target,
+            state: Arc::new(state),
+            retargets,
+        }
+    }
+
+    pub fn target(&self) -> EmulationTarget {
+        self.target
+    }
+
+    /// Switch the device to `target` as `EmulatedDevice::retarget` does,
+    /// keeping the current one if that fails.
+    pub async fn retarget(&mut self, target: EmulationTarget) -> Result<()> {
+        if target == self.target {
+            return Ok(());
+        }
+        let gone = || anyhow!("Emulated {:?} is gone", self.target);
+        let (reply, reply_rx) = oneshot::channel();
+        self.retargets
+            .send((target, reply))
+            .await
+            .map_err(|_| gone())?;
+        reply_rx.await.map_err(|_| gone())??;
+        self.target = target;
+        Ok(())
+    }
+
+    /// A sink sending states to the device.
+    pub fn sink(&self) -> Box<dyn OutputSink + Send> {
+        Box::new(EmulationSink {
+            state: Arc::clone(&self.state),
+        })
+    }
+}
+
+struct EmulationSink {
+    state: Arc<watch::Sender<GamepadInput>>,
+}
+
+impl OutputSink for EmulationSink {
+    fn name(&self) -> &str {
+        "emulation"
+    }
+
+    /// Only the latest state is sent if the device falls behind.
+    fn send(&mut self, input: &GamepadInput) -> Result<()> {
+        self.state.send_replace(input.clone());
+        Ok(())
+    }
+}
+
+async fn run_emulation(
+    mut device: EmulatedDevice,
+    mut states: watch::Receiver<GamepadInput>,
+    mut retargets: mpsc::Receiver<Retarget>,
+    mut loopback: OutputLoopback,
+) -> Result<()> {
+    loop {
+        tokio::select! {
+            Some((target, reply)) = retargets.recv() => {
+                let _ = reply.send(device.retarget(target).await);
+            }
+            changed = states.changed() => {
+                if changed.is_err() {
+                    return Ok(());
+                }
+                let input = states.borrow_and_update().clone();
+                device.send_state(&input).await?;
+            }
+            output = device.next_output() => {
+                if let Err(e) = loopback.forward(output?).await {
+                    debug!("Failed to forward output to the device: {e:#}");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::DeviceCommand;
+
+    const ADDRESS: [u8; 6] = [0x02, 0x48, 0x49, 0x44, 0x00, 0x07];
+
+    #[test]
+    fn virtual_devices_are_marked_as_ours() {
+        for target in [EmulationTarget::Xbox360, EmulationTarget::DualShock4] {
+            assert!(crate::device_monitor::is_emulated(
+                &target.uhid_config().phys
+            ));
+        }
+    }
+
+    #[test]
+    fn ds4_answers_address_reports() {
+        let target = EmulationTarget::DualShock4;
+        let report = target.feature_report(0x81, ADDRESS).unwrap();
+        assert_eq!(report, [0x81, 0x07, 0x00, 0x44, 0x49, 0x48, 0x02]);
+        let report = target.feature_report(0x12, ADDRESS).unwrap();
+        assert_eq!(report.len(), DS4_PAIRING_REPORT_LEN);
+        assert_eq!(report[..7], [0x12, 0x07, 0x00, 0x44, 0x49, 0x48, 0x02]);
+    }
+
+    #[test]
+    fn ds4_answers_firmware_report() {
+        let report = EmulationTarget::DualShock4
+            .feature_report(0xA3, ADDRESS)
+            .unwrap();
+        assert_eq!(report.len(), DS4_FIRMWARE_REPORT_LEN);
+        assert_eq!(report[0], 0xA3);
+        assert_eq!(u16::from_le_bytes([report[35], report[36]]), DS4_HW_VERSION);
+        assert_eq!(u16::from_le_bytes([report[41], report[42]]), DS4_FW_VERSION);
+        assert!(report[1..17].starts_with(b"Sep 21 2018\0"));
+    }
+
+    #[test]
+    fn unknown_feature_reports_are_refused() {
+        assert_eq!(
+            EmulationTarget::DualShock4.feature_report(0x99, ADDRESS),
+            None
+        );
+        assert_eq!(EmulationTarget::Xbox360.feature_report(0x81, ADDRESS), None);
+    }
+
+    #[test]
+    fn xbox360_rumble_skips_the_report_number() {
+        // As written to /dev/hidrawN by e.g. SDL: report number, then motors.
+        let outputs = EmulationTarget::Xbox360.decode_output(&[0x00, 0xFF, 0x40]);
+        let effect = RumbleEffect::new(0xFF00, 0x4000);
+        assert_eq!(outputs, [EmulatedOutput::Rumble(effect)]);
+        assert!(EmulationTarget::Xbox360
+            .decode_output(&[0xFF, 0x40])
+            .is_empty());
+    }
+
+    fn scratch_node(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("hidraw-{name}-{}", std::process::id()));
+        std::fs::write(&path, []).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn retarget_keeps_old_device_on_failure() {
+        let path = scratch_node("retarget-fail");
+        let mut device = EmulatedDevice::create_at(&path, EmulationTarget::Xbox360)
+            .await
+            .unwrap();
+        let address = device.address;
+        std::fs::remove_file(&path).unwrap();
+        assert!(device.retarget(EmulationTarget::DualShock4).await.is_err());
+        assert_eq!(device.target(), EmulationTarget::Xbox360);
+        assert_eq!(device.address, address);
+        // The old device is still there to take input.
+        device.send_state(&GamepadInput::default()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn retarget_swaps_to_new_device() {
+        let path = scratch_node("retarget");
+        let mut device = EmulatedDevice::create_at(&path, EmulationTarget::Xbox360)
+            .await
+            .unwrap();
+        let address = device.address;
+        device.send_state(&GamepadInput::default()).await.unwrap();
+        device.retarget(EmulationTarget::DualShock4).await.unwrap();
+        assert_eq!(device.target(), EmulationTarget::DualShock4);
+        assert_ne!(device.address, address);
+        assert_eq!(device.counter, 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn loopback_goes_through_the_device_task() {
+        let (task, mut commands) = TaskHandle::channel();
+        let info = DeviceInfo::for_test(0x054C, 0x05C4, Bus::Usb);
+        let mut loopback = OutputLoopback::for_task(&task, &info);
+        let effect = RumbleEffect::new(0x8000, 0x4000);
+        let forward = tokio::spawn(async move {
+            loopback.forward(EmulatedOutput::Rumble(effect)).await?;
+            let lightbar = EmulatedOutput::Lightbar {
+                red: 1,
+                green: 2,
+                blue: 3,
+            };
+            loopback.forward(lightbar).await
+        });
+        match commands.recv().await.unwrap() {
+            DeviceCommand::Rumble {
+                effect: played,
+                duration_ms: 0,
+                reply,
+            } => {
+                assert_eq!(played, effect);
+                reply.send(Ok(())).unwrap();
+            }
+            _ => panic!("expected rumble"),
+        }
+        forward.await.unwrap().unwrap();
+        match commands.recv().await.unwrap() {
+            DeviceCommand::SetLed { led, .. } => assert_eq!(
+                led,
+                Led::Lightbar {
+                    red: 1,
+                    green: 2,
+                    blue: 3
+                }
+            ),
+            _ => panic!("expected an LED change"),
+        }
+    }
+
+    #[test]
+    fn uniq_is_the_address() {
+        let config = device_config(EmulationTarget::DualShock4, ADDRESS);
+        assert_eq!(config.uniq, "02:48:49:44:00:07");
+    }
+}
@@ -0,0 +1,51 @@
+use std::io;
+
+/// Why one of the library's device, descriptor or report functions failed,
+/// for callers that need to tell failures apart. Drivers and the daemon's own
+/// tasks use `anyhow`, which these convert into.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Enumerating or monitoring devices through udev failed.
+    #[error("udev failed")]
+    Udev(#[source] io::Error),
+    /// The device isn't one we can handle, or lacks what was asked of it.
+    #[error("{0}")]
+    Unsupported(String),
+    /// Reading or writing a device or file failed.
+    #[error("{context}")]
+    Io {
+        context: String,
+        #[source]
+        source: io::Error,
+    },
+    /// Data from a device or file, like a report descriptor, is malformed.
+    #[error("{0}")]
+    Parse(String),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Describes I/O failures as `Error::Io`, like `anyhow::Context` does.
+pub trait IoContext<T> {
+    fn io_context(self, context: impl FnOnce() -> String) -> Result<T>;
+}
+
+impl<T> IoContext<T> for std::result::Result<T, io::Error> {
+    fn io_context(self, context: impl FnOnce() -> String) -> Result<T> {
+        self.map_err(|source| Error::Io {
+            context: context(),
+            source,
+        })
+    }
+}
+
+/// For ioctls and other calls through nix.
+impl<T> IoContext<T> for nix::Result<T> {
+    fn io_context(self, context: impl FnOnce() -> String) -> Result<T> {
+        self.map_err(|e| match e.as_errno() {
+            Some(errno) => io::Error::from_raw_os_error(errno as i32),
+            None => io::Error::other(e),
+        })
+        .io_context(context)
+    }
+}
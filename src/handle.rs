@@ -0,0 +1,499 @@
+use anyhow::{anyhow, bail, Context as ErrorContext, Result};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use log::debug;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::unix::AsyncFd;
+#[cfg(feature = "usbfs")]
+use {
+    crate::device_monitor::DeviceInfo, crate::usbfs::UsbHidDevice, std::sync::Arc,
+    tokio::sync::mpsc::Receiver,
+};
+
+use crate::descriptor::{self, FieldKind};
+use crate::device::{self, HID_MAX_BUFFER_SIZE};
+
+/// An open HID device, however it's reached, so drivers and the device watcher
+/// don't depend on where reports come from.
+///
+/// Reports are laid out as with hidraw: input and feature reports start with
+/// their report ID if the device numbers its reports, and reports written
+/// always start with the report ID, or 0 for unnumbered reports.
+pub trait DeviceHandle: Send {
+    /// Wait for the next input report. `None` means the device went away.
+    fn read_report(&mut self) -> BoxFuture<'_, Result<Option<Vec<u8>>>>;
+
+    /// Wait for the next report that could be a reply to a request. Handles
+    /// that can't tell replies from input return every report.
+    fn read_reply(&mut self) -> BoxFuture<'_, Result<Option<Vec<u8>>>> {
+        self.read_report()
+    }
+
+    fn write_report<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, Result<()>>;
+
+    fn get_feature(&mut self, report_id: u8) -> BoxFuture<'_, Result<Vec<u8>>>;
+
+    fn set_feature<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, Result<()>>;
+
+    /// Finish any pending writes. The device is released when the handle is
+    /// dropped.
+    fn close(&mut self) -> BoxFuture<'_, Result<()>> {
+        async { Ok(()) }.boxed()
+    }
+}
+
+/// A request and the wait for its reply, for driver handshakes over the report
+/// stream, e.g. Switch subcommands.
+///
+/// Reports that aren't the reply are skipped. Through a `Demux`, input reports
+/// the device sends meanwhile are kept for the input decoder. Dropping the
+/// future part way through leaves the handle usable: a late reply is just
+/// another report the next transaction skips.
+#[derive(Clone, Debug)]
+pub struct Transaction {
+    timeout: Duration,
+    retries: u32,
+}
+
+impl Transaction {
+    pub fn new() -> Transaction {
+        Transaction {
+            timeout: Duration::from_secs(1),
+            retries: 0,
+        }
+    }
+
+    /// How long to wait for each attempt's reply.
+    pub fn timeout(mut self, timeout: Duration) -> Transaction {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How many times to resend the request if no reply arrives.
+    pub fn retries(mut self, retries: u32) -> Transaction {
+        self.retries = retries;
+        self
+    }
+
+    /// Send `request` as an output report and wait for a report that
+    /// `accept` returns something for.
+    pub async fn run<T>(
+        &self,
+        handle: &mut dyn DeviceHandle,
+        request: &[u8],
+        mut accept: impl FnMut(&[u8]) -> Option<T>,
+    ) -> Result<T> {
+        for attempt in 0..=self.retries {
+            if attempt > 0 {
+                debug!("Retrying request {request:02x?}, attempt {}", attempt + 1);
+            }
+            handle.write_report(request).await?;
+            let reply = async {
+                loop {
+                    let Some(report) = handle.read_reply().await? else {
+                        bail!("The device went away");
+                    };
+                    if let Some(value) = accept(&report) {
+                        return Ok(value);
+                    }
+                }
+            };
+            if let Ok(result) = tokio::time::timeout(self.timeout, reply).await {
+                return result;
+            }
+        }
+        Err(anyhow!(
+            "No reply to {request:02x?} after {} attempts",
+            self.retries + 1
+        ))
+    }
+}
+
+impl Default for Transaction {
+    fn default() -> Transaction {
+        Transaction::new()
+    }
+}
+
+/// How many input reports a `Demux` keeps while a transaction waits for its
+/// reply. Older ones are dropped.
+const DEMUX_MAX_QUEUED: usize = 64;
+
+/// Splits a device's reports by report ID into replies to a driver's requests
+/// and input, so a handshake doesn't lose input and the input decoder never
+/// sees protocol traffic.
+///
+/// `read_report` returns only input, dropping replies no one waited for, and
+/// `read_reply` returns only replies, queueing input for `read_report`.
+pub struct Demux<H> {
+    handle: H,
+    reply_ids: Vec<u8>,
+    input: VecDeque<Vec<u8>>,
+}
+
+impl<H: DeviceHandle> Demux<H> {
+    pub fn new(handle: H, reply_ids: &[u8]) -> Demux<H> {
+        Demux {
+            handle,
+            reply_ids: reply_ids.to_vec(),
+            input: VecDeque::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> H {
+        self.handle
+    }
+
+    fn is_reply(&self, report: &[u8]) -> bool {
+        report.first().is_some_and(|id| self.reply_ids.contains(id))
+    }
+}
+
+impl<H: DeviceHandle> DeviceHandle for Demux<H> {
+    fn read_report(&mut self) -> BoxFuture<'_, Result<Option<Vec<u8>>>> {
+        async move {
+            if let Some(report) = self.input.pop_front() {
+                return Ok(Some(report));
+            }
+            loop {
+                let Some(report) = self.handle.read_report().await? else {
+                    return Ok(None);
+                };
+                if !self.is_reply(&report) {
+                    return Ok(Some(report));
+                }
+                debug!("Dropping unexpected reply {report:02x?}");
+            }
+        }
+        .boxed()
+    }
+
+    fn read_reply(&mut self) -> BoxFuture<'_, Result<Option<Vec<u8>>>> {
+        async move {
+            loop {
+                let Some(report) = self.handle.read_report().await? else {
+                    return Ok(None);
+                };
+                if self.is_reply(&report) {
+                    return Ok(Some(report));
+                }
+                if self.input.len() == DEMUX_MAX_QUEUED {
+                    self.input.pop_front();
+                }
+                self.input.push_back(report);
+            }
+        }
+        .boxed()
+    }
+
+    fn write_report<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        self.handle.write_report(data)
+    }
+
+    fn get_feature(&mut self, report_id: u8) -> BoxFuture<'_, Result<Vec<u8>>> {
+        self.handle.get_feature(report_id)
+    }
+
+    fn set_feature<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        self.handle.set_feature(data)
+    }
+
+    fn close(&mut self) -> BoxFuture<'_, Result<()>> {
+        self.handle.close()
+    }
+}
+
+/// A device reached through its hidraw node. The node is non-blocking, so a
+/// read dropped part way, e.g. by a `select!`, doesn't hold up writes.
+pub struct HidrawHandle {
+    fd: AsyncFd<File>,
+    /// From the report descriptor, including the report ID byte.
+    feature_lengths: BTreeMap<u8, usize>,
+}
+
+impl HidrawHandle {
+    /// Open a hidraw node. Must be called within a tokio runtime.
+    pub async fn open(hidraw_node: &Path) -> Result<HidrawHandle> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(hidraw_node)
+            .with_context(|| format!("Failed to open {hidraw_node:?}"))?;
+        // Devices with broken descriptors can still be read, so feature
+        // report lengths are best effort.
+        let feature_lengths = device::read_report_descriptor(file.as_raw_fd())
+            .and_then(|desc| descriptor::parse_hid_descriptor(&desc))
+            .map(|desc| desc.report_lengths(FieldKind::Feature))
+            .unwrap_or_default();
+        HidrawHandle::from_file(file, feature_lengths)
+    }
+
+    fn from_file(file: File, feature_lengths: BTreeMap<u8, usize>) -> Result<HidrawHandle> {
+        Ok(HidrawHandle {
+            fd: AsyncFd::new(file)?,
+            feature_lengths,
+        })
+    }
+}
+
+impl DeviceHandle for HidrawHandle {
+    fn read_report(&mut self) -> BoxFuture<'_, Result<Option<Vec<u8>>>> {
+        async move {
+            // hidraw returns one whole report per read.
+            let mut buf = vec![0; HID_MAX_BUFFER_SIZE];
+            let len = loop {
+                let mut guard = self.fd.readable().await?;
+                match guard.try_io(|fd| fd.get_ref().read(&mut buf)) {
+                    Ok(result) => break result?,
+                    Err(_would_block) => continue,
+                }
+            };
+            if len == 0 {
+                return Ok(None);
+            }
+            buf.truncate(len);
+            Ok(Some(buf))
+        }
+        .boxed()
+    }
+
+    fn write_report<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        async move {
+            // hidraw takes one whole report per write.
+            loop {
+                let mut guard = self.fd.writable().await?;
+                match guard.try_io(|fd| fd.get_ref().write(data)) {
+                    Ok(result) => {
+                        result?;
+                        return Ok(());
+                    }
+                    Err(_would_block) => continue,
+                }
+            }
+        }
+        .boxed()
+    }
+
+    fn get_feature(&mut self, report_id: u8) -> BoxFuture<'_, Result<Vec<u8>>> {
+        async move {
+            let len = self
+                .feature_lengths
+                .get(&report_id)
+                .copied()
+                .unwrap_or(HID_MAX_BUFFER_SIZE);
+            Ok(device::get_feature_report(
+                self.fd.as_raw_fd(),
+                report_id,
+                len,
+            )?)
+        }
+        .boxed()
+    }
+
+    fn set_feature<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        async move { Ok(device::send_feature_report(self.fd.as_raw_fd(), data)?) }.boxed()
+    }
+}
+
+/// A USB device read directly through libusb, for kernels without hidraw.
+/// Transfers are synchronous, with libusb's timeout.
+#[cfg(feature = "usbfs")]
+pub struct UsbfsHandle {
+    device: Arc<UsbHidDevice>,
+    reports: Receiver<Vec<u8>>,
+}
+
+#[cfg(feature = "usbfs")]
+impl UsbfsHandle {
+    pub fn open(info: &DeviceInfo) -> Result<UsbfsHandle> {
+        let device = UsbHidDevice::open(info.vendor_id, info.product_id, info.usb_address)?;
+        let device = Arc::new(device);
+        let reports = device.spawn_reader();
+        Ok(UsbfsHandle { device, reports })
+    }
+
+    pub fn device(&self) -> &UsbHidDevice {
+        &self.device
+    }
+}
+
+#[cfg(feature = "usbfs")]
+impl DeviceHandle for UsbfsHandle {
+    fn read_report(&mut self) -> BoxFuture<'_, Result<Option<Vec<u8>>>> {
+        async move { Ok(self.reports.recv().await) }.boxed()
+    }
+
+    fn write_report<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        async move { self.device.write_report(data) }.boxed()
+    }
+
+    fn get_feature(&mut self, report_id: u8) -> BoxFuture<'_, Result<Vec<u8>>> {
+        async move {
+            self.device
+                .get_feature_report(report_id, HID_MAX_BUFFER_SIZE)
+                .with_context(|| format!("Failed to get feature report {report_id:#04x}"))
+        }
+        .boxed()
+    }
+
+    fn set_feature<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        async move { self.device.send_feature_report(data) }.boxed()
+    }
+}
+
+/// A scripted device, for exercising drivers without hardware.
+#[derive(Debug, Default)]
+pub struct MockHandle {
+    /// Input reports to return, in order. Once they run out the device reads
+    /// as unplugged.
+    pub input: VecDeque<Vec<u8>>,
+    /// Every report written, in order.
+    pub written: Vec<Vec<u8>>,
+    /// Feature reports by ID, updated by `set_feature`.
+    pub features: BTreeMap<u8, Vec<u8>>,
+    pub closed: bool,
+}
+
+impl MockHandle {
+    pub fn new(input: impl IntoIterator<Item = Vec<u8>>) -> MockHandle {
+        MockHandle {
+            input: input.into_iter().collect(),
+            ..Default::default()
+        }
+    }
+}
+
+impl DeviceHandle for MockHandle {
+    fn read_report(&mut self) -> BoxFuture<'_, Result<Option<Vec<u8>>>> {
+        async move { Ok(self.input.pop_front()) }.boxed()
+    }
+
+    fn write_report<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        async move {
+            if self.closed {
+                bail!("Write after close");
+            }
+            self.written.push(data.to_vec());
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn get_feature(&mut self, report_id: u8) -> BoxFuture<'_, Result<Vec<u8>>> {
+        async move {
+            match self.features.get(&report_id) {
+                Some(report) => Ok(report.clone()),
+                None => bail!("No feature report {report_id:#04x}"),
+            }
+        }
+        .boxed()
+    }
+
+    fn set_feature<'a>(&'a mut self, data: &'a [u8]) -> BoxFuture<'a, Result<()>> {
+        async move {
+            let Some(&report_id) = data.first() else {
+                bail!("Empty feature report");
+            };
+            self.features.insert(report_id, data.to_vec());
+            Ok(())
+        }
+        .boxed()
+    }
+
+    fn close(&mut self) -> BoxFuture<'_, Result<()>> {
+        async move {
+            self.closed = true;
+            Ok(())
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixDatagram;
+
+    /// A hidraw handle over one end of a datagram socket, which like hidraw
+    /// passes whole reports, and the other end.
+    fn socket_handle() -> (HidrawHandle, UnixDatagram) {
+        let (ours, theirs) = UnixDatagram::pair().unwrap();
+        ours.set_nonblocking(true).unwrap();
+        let file = File::from(std::os::fd::OwnedFd::from(ours));
+        (
+            HidrawHandle::from_file(file, BTreeMap::new()).unwrap(),
+            theirs,
+        )
+    }
+
+    #[tokio::test]
+    async fn cancelled_read_doesnt_block_writes() {
+        let (mut handle, device) = socket_handle();
+        let read = tokio::time::timeout(Duration::from_millis(10), handle.read_report());
+        assert!(read.await.is_err());
+        let write =
+            tokio::time::timeout(Duration::from_secs(1), handle.write_report(&[0x01, 0x02]));
+        write.await.unwrap().unwrap();
+        let mut buf = [0; 8];
+        assert_eq!(device.recv(&mut buf).unwrap(), 2);
+        assert_eq!(buf[..2], [0x01, 0x02]);
+        device.send(&[0x30, 0x00, 0x7F]).unwrap();
+        assert_eq!(
+            handle.read_report().await.unwrap(),
+            Some(vec![0x30, 0x00, 0x7F])
+        );
+    }
+
+    #[tokio::test]
+    async fn demux_keeps_input_during_a_transaction() {
+        let reports = [vec![0x30, 0x01], vec![0x21, 0xAA], vec![0x30, 0x02]];
+        let mut handle = Demux::new(MockHandle::new(reports), &[0x21]);
+        let reply = Transaction::new()
+            .run(&mut handle, &[0x01, 0x02], |report| Some(report[1]))
+            .await
+            .unwrap();
+        assert_eq!(reply, 0xAA);
+        assert_eq!(handle.read_report().await.unwrap(), Some(vec![0x30, 0x01]));
+        assert_eq!(handle.read_report().await.unwrap(), Some(vec![0x30, 0x02]));
+        assert_eq!(handle.read_report().await.unwrap(), None);
+        assert_eq!(handle.into_inner().written, vec![vec![0x01, 0x02]]);
+    }
+
+    #[tokio::test]
+    async fn demux_drops_replies_no_one_waited_for() {
+        let reports = [vec![0x21, 0xAA], vec![0x30, 0x01]];
+        let mut handle = Demux::new(MockHandle::new(reports), &[0x21]);
+        assert_eq!(handle.read_report().await.unwrap(), Some(vec![0x30, 0x01]));
+    }
+
+    #[tokio::test]
+    async fn transaction_fails_once_the_device_goes_away() {
+        let mut handle = MockHandle::new([vec![0x30, 0x01]]);
+        let result = Transaction::new()
+            .retries(2)
+            .run(&mut handle, &[0x01], |report| {
+                (report[0] == 0x21).then_some(())
+            })
+            .await;
+        assert!(result.is_err());
+        assert_eq!(handle.written.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn mock_features_round_trip() {
+        let mut handle = MockHandle::new([]);
+        assert!(handle.get_feature(0x05).await.is_err());
+        handle.set_feature(&[0x05, 0x01, 0x02]).await.unwrap();
+        assert_eq!(
+            handle.get_feature(0x05).await.unwrap(),
+            vec![0x05, 0x01, 0x02]
+        );
+    }
+}
@@ -0,0 +1,728 @@
+use anyhow::{anyhow, bail, Context as ErrorContext, Result};
+use std::path::PathBuf;
+
+use crate::device_monitor::{AccessoryKind, DeviceEvent};
+
+/// The protocol version spoken by this build.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// The oldest protocol version this build can still talk to.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// Optional protocol features, negotiated per connection.
+///
+/// New event kinds or commands must be gated behind a new capability so that
+/// existing consumers never see messages they don't understand.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Capability {
+    /// Device added/removed notifications.
+    DeviceEvents,
+    /// Accessory attached/detached notifications.
+    AccessoryEvents,
+    /// Notifications that a device was quarantined after a fault.
+    FaultEvents,
+    /// `Command`s to control a running daemon.
+    Control,
+    /// Why a device was removed, appended to `REMOVED` events.
+    DisconnectReasons,
+    /// The `RELEASE` and `REACQUIRE` commands.
+    DeviceRelease,
+}
+
+impl Capability {
+    pub const ALL: &'static [Capability] = &[
+        Capability::DeviceEvents,
+        Capability::AccessoryEvents,
+        Capability::FaultEvents,
+        Capability::Control,
+        Capability::DisconnectReasons,
+        Capability::DeviceRelease,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Capability::DeviceEvents => "device-events",
+            Capability::AccessoryEvents => "accessory-events",
+            Capability::FaultEvents => "fault-events",
+            Capability::Control => "control",
+            Capability::DisconnectReasons => "disconnect-reasons",
+            Capability::DeviceRelease => "device-release",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Capability> {
+        Capability::ALL.iter().copied().find(|c| c.as_str() == name)
+    }
+
+    /// Whether it has the daemon send events, which can come between the
+    /// replies to commands.
+    pub fn is_event(&self) -> bool {
+        matches!(
+            self,
+            Capability::DeviceEvents | Capability::AccessoryEvents | Capability::FaultEvents
+        )
+    }
+}
+
+/// The first message sent by each side of a connection.
+///
+/// Wire format: `HELLO <version> <capability>[,<capability>...]\n`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u32,
+    pub capabilities: Vec<Capability>,
+}
+
+impl Hello {
+    /// A `Hello` advertising everything this build supports.
+    pub fn current() -> Hello {
+        Hello {
+            version: PROTOCOL_VERSION,
+            capabilities: Capability::ALL.to_vec(),
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        let caps: Vec<&str> = self.capabilities.iter().map(|c| c.as_str()).collect();
+        format!("HELLO {} {}\n", self.version, caps.join(","))
+    }
+
+    /// Parse a `Hello` line. Capabilities we don't know about are ignored so that
+    /// newer peers can still talk to us.
+    pub fn decode(line: &str) -> Result<Hello> {
+        let mut parts = line.trim_end().splitn(3, ' ');
+        if parts.next() != Some("HELLO") {
+            bail!("Expected HELLO: {line:?}");
+        }
+        let version = parts
+            .next()
+            .context("Missing protocol version")?
+            .parse()
+            .with_context(|| anyhow!("Bad protocol version: {line:?}"))?;
+        let capabilities = parts
+            .next()
+            .unwrap_or("")
+            .split(',')
+            .filter_map(Capability::from_name)
+            .collect();
+        Ok(Hello {
+            version,
+            capabilities,
+        })
+    }
+}
+
+/// The result of a successful handshake.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Negotiated {
+    pub version: u32,
+    pub capabilities: Vec<Capability>,
+}
+
+impl Negotiated {
+    pub fn has(&self, capability: Capability) -> bool {
+        self.capabilities.contains(&capability)
+    }
+}
+
+/// Pick the highest version both sides speak and the capabilities both support.
+pub fn negotiate(ours: &Hello, theirs: &Hello) -> Result<Negotiated> {
+    let version = ours.version.min(theirs.version);
+    if version < MIN_PROTOCOL_VERSION {
+        bail!(
+            "Unsupported protocol version {} (need at least {MIN_PROTOCOL_VERSION})",
+            theirs.version
+        );
+    }
+    let capabilities = ours
+        .capabilities
+        .iter()
+        .copied()
+        .filter(|c| theirs.capabilities.contains(c))
+        .collect();
+    Ok(Negotiated {
+        version,
+        capabilities,
+    })
+}
+
+/// A device event as sent over the wire.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WireEvent {
+    /// `ADDED <vendor>:<product> <sys_path> <name>`
+    Added {
+        vendor_id: u16,
+        product_id: u16,
+        sys_path: PathBuf,
+        name: String,
+    },
+    /// `CHANGED <vendor>:<product> <sys_path> <name>`
+    Changed {
+        vendor_id: u16,
+        product_id: u16,
+        sys_path: PathBuf,
+        name: String,
+    },
+    /// `DENIED <vendor>:<product> <sys_path> <name>`
+    PermissionDenied {
+        vendor_id: u16,
+        product_id: u16,
+        sys_path: PathBuf,
+        name: String,
+    },
+    /// `REMOVED <sys_path> [<reason>]`
+    Removed {
+        sys_path: PathBuf,
+        reason: Option<String>,
+    },
+    /// `ATTACHED <parent> <sys_path> <kind>`
+    AccessoryAttached {
+        parent: PathBuf,
+        sys_path: PathBuf,
+        kind: String,
+    },
+    /// `DETACHED <parent> <sys_path>`
+    AccessoryDetached { parent: PathBuf, sys_path: PathBuf },
+    /// `FAULT <sys_path> <message>`
+    ParserFault { sys_path: PathBuf, message: String },
+    /// `DECODE <sys_path> <hex data> <reason>`
+    DecodeError {
+        sys_path: PathBuf,
+        data: Vec<u8>,
+        reason: String,
+    },
+    /// `FALLBACK <sys_path> <driver> <reason>`
+    DriverFallback {
+        sys_path: PathBuf,
+        driver: String,
+        reason: String,
+    },
+}
+
+fn accessory_kind_name(kind: &AccessoryKind) -> String {
+    match kind {
+        AccessoryKind::Wiimote(node) => format!("wiimote-{node:?}").to_lowercase(),
+    }
+}
+
+impl WireEvent {
+    /// Convert a `DeviceEvent` for a peer, returning `None` if the peer didn't
+    /// negotiate the capability needed to receive it.
+    pub fn from_event(event: &DeviceEvent, negotiated: &Negotiated) -> Option<WireEvent> {
+        let required = match event {
+            DeviceEvent::Added(_)
+            | DeviceEvent::Changed(_)
+            | DeviceEvent::PermissionDenied(_)
+            | DeviceEvent::Removed { .. } => Capability::DeviceEvents,
+            DeviceEvent::AccessoryAttached { .. } | DeviceEvent::AccessoryDetached { .. } => {
+                Capability::AccessoryEvents
+            }
+            DeviceEvent::ParserFault { .. }
+            | DeviceEvent::DecodeError { .. }
+            | DeviceEvent::DriverFallback { .. } => Capability::FaultEvents,
+        };
+        if !negotiated.has(required) {
+            return None;
+        }
+        match event {
+            DeviceEvent::Added(info) => Some(WireEvent::Added {
+                vendor_id: info.vendor_id,
+                product_id: info.product_id,
+                sys_path: info.sys_path.clone(),
+                name: info.name.clone(),
+            }),
+            DeviceEvent::Changed(info) => Some(WireEvent::Changed {
+                vendor_id: info.vendor_id,
+                product_id: info.product_id,
+                sys_path: info.sys_path.clone(),
+                name: info.name.clone(),
+            }),
+            DeviceEvent::PermissionDenied(info) => Some(WireEvent::PermissionDenied {
+                vendor_id: info.vendor_id,
+                product_id: info.product_id,
+                sys_path: info.sys_path.clone(),
+                name: info.name.clone(),
+            }),
+            DeviceEvent::Removed { sys_path, reason } => Some(WireEvent::Removed {
+                sys_path: sys_path.clone(),
+                reason: negotiated
+                    .has(Capability::DisconnectReasons)
+                    .then(|| reason.to_string().replace('\n', " ")),
+            }),
+            DeviceEvent::AccessoryAttached { parent, accessory } => {
+                Some(WireEvent::AccessoryAttached {
+                    parent: parent.clone(),
+                    sys_path: accessory.sys_path.clone(),
+                    kind: accessory_kind_name(&accessory.kind),
+                })
+            }
+            DeviceEvent::AccessoryDetached { parent, sys_path } => {
+                Some(WireEvent::AccessoryDetached {
+                    parent: parent.clone(),
+                    sys_path: sys_path.clone(),
+                })
+            }
+            DeviceEvent::ParserFault { sys_path, message } => Some(WireEvent::ParserFault {
+                sys_path: sys_path.clone(),
+                // Keep the event on one line.
+                message: message.replace('\n', " "),
+            }),
+            DeviceEvent::DecodeError {
+                sys_path,
+                reason,
+                data,
+                ..
+            } => Some(WireEvent::DecodeError {
+                sys_path: sys_path.clone(),
+                data: data.clone(),
+                reason: reason.replace('\n', " "),
+            }),
+            DeviceEvent::DriverFallback {
+                sys_path,
+                driver,
+                reason,
+            } => Some(WireEvent::DriverFallback {
+                sys_path: sys_path.clone(),
+                driver: driver.clone(),
+                reason: reason.replace('\n', " "),
+            }),
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        match self {
+            WireEvent::Added {
+                vendor_id,
+                product_id,
+                sys_path,
+                name,
+            } => format!(
+                "ADDED {vendor_id:04x}:{product_id:04x} {} {name}\n",
+                sys_path.display()
+            ),
+            WireEvent::Changed {
+                vendor_id,
+                product_id,
+                sys_path,
+                name,
+            } => format!(
+                "CHANGED {vendor_id:04x}:{product_id:04x} {} {name}\n",
+                sys_path.display()
+            ),
+            WireEvent::PermissionDenied {
+                vendor_id,
+                product_id,
+                sys_path,
+                name,
+            } => format!(
+                "DENIED {vendor_id:04x}:{product_id:04x} {} {name}\n",
+                sys_path.display()
+            ),
+            WireEvent::Removed {
+                sys_path,
+                reason: None,
+            } => format!("REMOVED {}\n", sys_path.display()),
+            WireEvent::Removed {
+                sys_path,
+                reason: Some(reason),
+            } => format!("REMOVED {} {reason}\n", sys_path.display()),
+            WireEvent::AccessoryAttached {
+                parent,
+                sys_path,
+                kind,
+            } => format!(
+                "ATTACHED {} {} {kind}\n",
+                parent.display(),
+                sys_path.display()
+            ),
+            WireEvent::AccessoryDetached { parent, sys_path } => {
+                format!("DETACHED {} {}\n", parent.display(), sys_path.display())
+            }
+            WireEvent::ParserFault { sys_path, message } => {
+                format!("FAULT {} {message}\n", sys_path.display())
+            }
+            WireEvent::DecodeError {
+                sys_path,
+                data,
+                reason,
+            } => {
+                let hex: String = data.iter().map(|b| format!("{b:02x}")).collect();
+                format!("DECODE {} {hex} {reason}\n", sys_path.display())
+            }
+            WireEvent::DriverFallback {
+                sys_path,
+                driver,
+                reason,
+            } => format!("FALLBACK {} {driver} {reason}\n", sys_path.display()),
+        }
+    }
+
+    /// Parse an event line. Returns `Ok(None)` for event kinds added in later
+    /// protocol versions, which consumers should skip.
+    pub fn decode(line: &str) -> Result<Option<WireEvent>> {
+        let line = line.trim_end();
+        let (kind, rest) = line.split_once(' ').unwrap_or((line, ""));
+        match kind {
+            "ADDED" | "CHANGED" | "DENIED" => {
+                let mut parts = rest.splitn(3, ' ');
+                let ids = parts.next().context("Missing device ids")?;
+                let (vendor, product) = ids
+                    .split_once(':')
+                    .with_context(|| anyhow!("Bad device ids: {ids:?}"))?;
+                let sys_path = parts.next().context("Missing sys path")?;
+                let (vendor_id, product_id) = (
+                    u16::from_str_radix(vendor, 16)?,
+                    u16::from_str_radix(product, 16)?,
+                );
+                let sys_path = PathBuf::from(sys_path);
+                let name = parts.next().unwrap_or("").to_owned();
+                Ok(Some(match kind {
+                    "ADDED" => WireEvent::Added {
+                        vendor_id,
+                        product_id,
+                        sys_path,
+                        name,
+                    },
+                    "CHANGED" => WireEvent::Changed {
+                        vendor_id,
+                        product_id,
+                        sys_path,
+                        name,
+                    },
+                    _ => WireEvent::PermissionDenied {
+                        vendor_id,
+                        product_id,
+                        sys_path,
+                        name,
+                    },
+                }))
+            }
+            "REMOVED" => {
+                let (sys_path, reason) = match rest.split_once(' ') {
+                    Some((sys_path, reason)) => (sys_path, Some(reason.to_owned())),
+                    None => (rest, None),
+                };
+                if sys_path.is_empty() {
+                    bail!("Missing sys path");
+                }
+                Ok(Some(WireEvent::Removed {
+                    sys_path: PathBuf::from(sys_path),
+                    reason,
+                }))
+            }
+            "ATTACHED" => {
+                let mut parts = rest.splitn(3, ' ');
+                let parent = parts.next().filter(|p| !p.is_empty());
+                let parent = parent.context("Missing parent sys path")?;
+                let sys_path = parts.next().context("Missing sys path")?;
+                let kind = parts.next().context("Missing accessory kind")?;
+                Ok(Some(WireEvent::AccessoryAttached {
+                    parent: PathBuf::from(parent),
+                    sys_path: PathBuf::from(sys_path),
+                    kind: kind.to_owned(),
+                }))
+            }
+            "DETACHED" => {
+                let (parent, sys_path) = rest
+                    .split_once(' ')
+                    .with_context(|| anyhow!("Bad DETACHED event: {line:?}"))?;
+                Ok(Some(WireEvent::AccessoryDetached {
+                    parent: PathBuf::from(parent),
+                    sys_path: PathBuf::from(sys_path),
+                }))
+            }
+            "FAULT" => {
+                let (sys_path, message) = rest.split_once(' ').unwrap_or((rest, ""));
+                if sys_path.is_empty() {
+                    bail!("Missing sys path");
+                }
+                Ok(Some(WireEvent::ParserFault {
+                    sys_path: PathBuf::from(sys_path),
+                    message: message.to_owned(),
+                }))
+            }
+            "DECODE" => {
+                let mut parts = rest.splitn(3, ' ');
+                let sys_path = parts.next().filter(|p| !p.is_empty());
+                let sys_path = sys_path.context("Missing sys path")?;
+                let hex = parts.next().context("Missing report data")?;
+                if !hex.is_ascii() || !hex.len().is_multiple_of(2) {
+                    bail!("Bad report data: {hex:?}");
+                }
+                let data = (0..hex.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+                    .collect::<Result<Vec<u8>, _>>()
+                    .with_context(|| anyhow!("Bad report data: {hex:?}"))?;
+                Ok(Some(WireEvent::DecodeError {
+                    sys_path: PathBuf::from(sys_path),
+                    data,
+                    reason: parts.next().unwrap_or("").to_owned(),
+                }))
+            }
+            "FALLBACK" => {
+                let mut parts = rest.splitn(3, ' ');
+                let sys_path = parts.next().filter(|p| !p.is_empty());
+                let sys_path = sys_path.context("Missing sys path")?;
+                let driver = parts.next().context("Missing driver name")?;
+                Ok(Some(WireEvent::DriverFallback {
+                    sys_path: PathBuf::from(sys_path),
+                    driver: driver.to_owned(),
+                    reason: parts.next().unwrap_or("").to_owned(),
+                }))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+/// A request to a running daemon. Devices are named by sys path or device node.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// `LIST`: one `DATA <sys_path> <vendor>:<product> <profile> <connected>s <name>`
+    /// per device. `LIST VERBOSE` adds `<fingerprint> <usage stats>` before
+    /// the name, each `-` if the device has no report descriptor to read.
+    List { verbose: bool },
+    /// `PROFILE <device> <name>`
+    Profile { device: PathBuf, name: String },
+    /// `RUMBLE <device> <strong> <weak> <duration_ms>`
+    Rumble {
+        device: PathBuf,
+        strong: u16,
+        weak: u16,
+        duration_ms: u32,
+    },
+    /// `RELOAD`: re-read the config file.
+    Reload,
+    /// `METRICS`: one `DATA <name> <value>` per counter.
+    Metrics,
+    /// `SHUTDOWN`: stop the daemon once the reply is sent.
+    Shutdown,
+    /// `RELEASE <device>`: stop handling a device and close everything we
+    /// hold on it, so another program like a firmware updater can have it to
+    /// itself. It stays released, even if it reconnects, until `REACQUIRE`.
+    Release { device: PathBuf },
+    /// `REACQUIRE <device>`: handle a released device again.
+    Reacquire { device: PathBuf },
+}
+
+impl Command {
+    pub fn encode(&self) -> String {
+        match self {
+            Command::List { verbose: false } => "LIST\n".to_owned(),
+            Command::List { verbose: true } => "LIST VERBOSE\n".to_owned(),
+            Command::Profile { device, name } => {
+                format!("PROFILE {} {name}\n", device.display())
+            }
+            Command::Rumble {
+                device,
+                strong,
+                weak,
+                duration_ms,
+            } => format!(
+                "RUMBLE {} {strong} {weak} {duration_ms}\n",
+                device.display()
+            ),
+            Command::Reload => "RELOAD\n".to_owned(),
+            Command::Metrics => "METRICS\n".to_owned(),
+            Command::Shutdown => "SHUTDOWN\n".to_owned(),
+            Command::Release { device } => format!("RELEASE {}\n", device.display()),
+            Command::Reacquire { device } => format!("REACQUIRE {}\n", device.display()),
+        }
+    }
+
+    /// The capability a peer must have negotiated to send the command.
+    pub fn capability(&self) -> Capability {
+        match self {
+            Command::Release { .. } | Command::Reacquire { .. } => Capability::DeviceRelease,
+            _ => Capability::Control,
+        }
+    }
+
+    pub fn decode(line: &str) -> Result<Command> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["LIST"] => Ok(Command::List { verbose: false }),
+            ["LIST", "VERBOSE"] => Ok(Command::List { verbose: true }),
+            ["PROFILE", device, name] => Ok(Command::Profile {
+                device: PathBuf::from(device),
+                name: name.to_string(),
+            }),
+            ["RUMBLE", device, strong, weak, duration_ms] => Ok(Command::Rumble {
+                device: PathBuf::from(device),
+                strong: strong.parse().context("Bad strong magnitude")?,
+                weak: weak.parse().context("Bad weak magnitude")?,
+                duration_ms: duration_ms.parse().context("Bad duration")?,
+            }),
+            ["RELOAD"] => Ok(Command::Reload),
+            ["METRICS"] => Ok(Command::Metrics),
+            ["SHUTDOWN"] => Ok(Command::Shutdown),
+            ["RELEASE", device] => Ok(Command::Release {
+                device: PathBuf::from(device),
+            }),
+            ["REACQUIRE", device] => Ok(Command::Reacquire {
+                device: PathBuf::from(device),
+            }),
+            _ => bail!("Unknown command: {:?}", line.trim_end()),
+        }
+    }
+}
+
+/// A line of the daemon's answer to a `Command`: any number of `DATA` lines,
+/// then `OK` or `ERR`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Reply {
+    /// `DATA <text>`
+    Data(String),
+    /// `OK`
+    Ok,
+    /// `ERR <message>`
+    Err(String),
+}
+
+impl Reply {
+    pub fn encode(&self) -> String {
+        match self {
+            Reply::Data(text) => format!("DATA {}\n", text.replace('\n', " ")),
+            Reply::Ok => "OK\n".to_owned(),
+            Reply::Err(message) => format!("ERR {}\n", message.replace('\n', " ")),
+        }
+    }
+
+    pub fn decode(line: &str) -> Result<Reply> {
+        let line = line.trim_end();
+        let (kind, rest) = line.split_once(' ').unwrap_or((line, ""));
+        match kind {
+            "DATA" => Ok(Reply::Data(rest.to_owned())),
+            "OK" => Ok(Reply::Ok),
+            "ERR" => Ok(Reply::Err(rest.to_owned())),
+            _ => bail!("Bad reply: {line:?}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::{self, Monitor, Request};
+    use crate::device_monitor::{Bus, DeviceInfo, DisconnectReason};
+    use tokio::sync::{broadcast, mpsc};
+
+    #[test]
+    fn hello_round_trips() {
+        let hello = Hello::current();
+        assert_eq!(Hello::decode(&hello.encode()).unwrap(), hello);
+        let theirs = Hello::decode("HELLO 1 control,some-future-thing\n").unwrap();
+        assert_eq!(theirs.capabilities, vec![Capability::Control]);
+        let negotiated = negotiate(&hello, &theirs).unwrap();
+        assert!(negotiated.has(Capability::Control));
+        assert!(!negotiated.has(Capability::DeviceEvents));
+    }
+
+    #[test]
+    fn commands_and_replies_round_trip() {
+        let commands = [
+            Command::List { verbose: true },
+            Command::Profile {
+                device: PathBuf::from("/dev/hidraw3"),
+                name: "racing".to_owned(),
+            },
+            Command::Rumble {
+                device: PathBuf::from("/dev/hidraw3"),
+                strong: 40000,
+                weak: 100,
+                duration_ms: 250,
+            },
+            Command::Release {
+                device: PathBuf::from("/dev/hidraw3"),
+            },
+        ];
+        for command in commands {
+            assert_eq!(Command::decode(&command.encode()).unwrap(), command);
+        }
+        for reply in [
+            Reply::Data("a b c".to_owned()),
+            Reply::Ok,
+            Reply::Err("no such device".to_owned()),
+        ] {
+            assert_eq!(Reply::decode(&reply.encode()).unwrap(), reply);
+        }
+        let event = WireEvent::DecodeError {
+            sys_path: PathBuf::from("/sys/devices/x"),
+            data: vec![0x01, 0xff],
+            reason: "short report".to_owned(),
+        };
+        assert_eq!(WireEvent::decode(&event.encode()).unwrap(), Some(event));
+        assert_eq!(WireEvent::decode("SOMETHING new\n").unwrap(), None);
+        assert!(WireEvent::decode("DECODE /sys/devices/x aéb short\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn request_and_reply_over_socket() {
+        let path = std::env::temp_dir().join(format!("hidraw-ipc-{}.sock", std::process::id()));
+        let (requests, mut requests_rx) = mpsc::channel::<Request>(1);
+        let (events, _) = broadcast::channel(4);
+        let server = {
+            let path = path.clone();
+            tokio::spawn(async move { control::serve(&path, requests, events).await })
+        };
+        // Answer like the daemon's main loop.
+        tokio::spawn(async move {
+            while let Some(Request { command, reply }) = requests_rx.recv().await {
+                let result = match command {
+                    Command::List { verbose: false } => {
+                        Ok(vec!["/sys/a 054c:09cc default 5s Pad".to_owned()])
+                    }
+                    other => Err(anyhow!("Unexpected {other:?}")),
+                };
+                let _ = reply.send(result);
+            }
+        });
+        while !path.exists() {
+            tokio::task::yield_now().await;
+        }
+        let data = control::send(&path, &Command::List { verbose: false })
+            .await
+            .unwrap();
+        assert_eq!(data, vec!["/sys/a 054c:09cc default 5s Pad"]);
+        let e = control::send(&path, &Command::Metrics).await.unwrap_err();
+        assert!(e.to_string().starts_with("Unexpected Metrics"), "{e}");
+        server.abort();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn events_go_to_clients_that_negotiated_them() {
+        let path = std::env::temp_dir().join(format!("hidraw-events-{}.sock", std::process::id()));
+        let (requests, _requests_rx) = mpsc::channel::<Request>(1);
+        let (events, _) = broadcast::channel(4);
+        let server = {
+            let (path, events) = (path.clone(), events.clone());
+            tokio::spawn(async move { control::serve(&path, requests, events).await })
+        };
+        while !path.exists() {
+            tokio::task::yield_now().await;
+        }
+        let mut monitor = Monitor::connect(&path).await.unwrap();
+        let info = DeviceInfo::for_test(0x054c, 0x09cc, Bus::Usb);
+        let sys_path = info.sys_path.clone();
+        events.send(DeviceEvent::Added(info)).unwrap();
+        let removed = DeviceEvent::Removed {
+            sys_path: sys_path.clone(),
+            reason: DisconnectReason::Unplugged,
+        };
+        events.send(removed).unwrap();
+        match monitor.next_event().await.unwrap() {
+            Some(WireEvent::Added {
+                vendor_id: 0x054c,
+                product_id: 0x09cc,
+                ..
+            }) => {}
+            event => panic!("Unexpected {event:?}"),
+        }
+        let event = monitor.next_event().await.unwrap();
+        let reason = Some(DisconnectReason::Unplugged.to_string());
+        assert_eq!(event, Some(WireEvent::Removed { sys_path, reason }));
+        server.abort();
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,273 @@
+use anyhow::{Context as ErrorContext, Result};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use libc::input_event;
+use log::{debug, warn};
+use std::collections::HashSet;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use tokio::fs::OpenOptions;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::task::JoinHandle;
+
+use crate::device::{grab_device, read_input_event, EV_KEY, EV_REL, EV_SYN};
+use crate::mouse::{MouseStick, MouseStickConfig, DECAY_INTERVAL};
+use crate::report::{Axis, Button, GamepadInput};
+use crate::source::InputSource;
+#[cfg(feature = "emulation")]
+use crate::uinput::UinputKeyboard;
+
+/// What a key does on the virtual gamepad.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum KeyTarget {
+    Button(Button),
+    DpadUp,
+    DpadDown,
+    DpadLeft,
+    DpadRight,
+    /// Push an axis towards a value, e.g. `(Axis::LeftX, -1.0)` for "A" in WASD.
+    /// Keys held together on the same axis add up.
+    Axis(Axis, f32),
+}
+
+/// A key code from Linux uapi/linux/input-event-codes.h bound to a control.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct KeyBinding {
+    pub key: u16,
+    pub target: KeyTarget,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KeyboardProfile {
+    pub bindings: Vec<KeyBinding>,
+    /// Grab the keyboard so bound keys don't also reach other applications.
+    /// evdev grabs are all-or-nothing, so while grabbed the unbound keys are
+    /// passed through a virtual keyboard, or swallowed without the `emulation`
+    /// feature.
+    pub grab: bool,
+}
+
+impl KeyboardProfile {
+    /// Build controller state from the set of held keys.
+    pub fn map_keys(&self, held: &HashSet<u16>) -> GamepadInput {
+        let mut input = GamepadInput::default();
+        let mut axes = [0.0; Axis::ALL.len()];
+        for binding in self.bindings.iter().filter(|b| held.contains(&b.key)) {
+            match binding.target {
+                KeyTarget::Button(button) => input.set_button(button, true),
+                KeyTarget::DpadUp => input.dpad.up = true,
+                KeyTarget::DpadDown => input.dpad.down = true,
+                KeyTarget::DpadLeft => input.dpad.left = true,
+                KeyTarget::DpadRight => input.dpad.right = true,
+                KeyTarget::Axis(axis, value) => axes[axis as usize] += value,
+            }
+        }
+        for axis in Axis::ALL {
+            let range = if axis.is_trigger() { 0.0 } else { -1.0 };
+            input.set_axis(axis, axes[axis as usize].clamp(range, 1.0));
+        }
+        input
+    }
+
+    fn is_bound(&self, key: u16) -> bool {
+        self.bindings.iter().any(|b| b.key == key)
+    }
+}
+
+/// An `InputSource` that turns a keyboard, and optionally a mouse, into a
+/// virtual gamepad.
+pub struct KeyboardSource {
+    name: String,
+    profile: KeyboardProfile,
+    held: HashSet<u16>,
+    mouse: Option<MouseStick>,
+    /// Where unbound keys go while the keyboard is grabbed.
+    #[cfg(feature = "emulation")]
+    passthrough: Option<UinputKeyboard>,
+    tx: Sender<input_event>,
+    rx: Receiver<input_event>,
+    readers: Vec<JoinHandle<()>>,
+}
+
+impl KeyboardSource {
+    pub async fn open(device_node: &Path, profile: KeyboardProfile) -> Result<KeyboardSource> {
+        let (tx, rx) = mpsc::channel(64);
+        let mut source = KeyboardSource {
+            name: device_node.display().to_string(),
+            profile,
+            held: HashSet::new(),
+            mouse: None,
+            #[cfg(feature = "emulation")]
+            passthrough: None,
+            tx,
+            rx,
+            readers: vec![],
+        };
+        let grab = source.profile.grab;
+        source.add_device(device_node, grab).await?;
+        #[cfg(feature = "emulation")]
+        if grab {
+            let name = format!("{} passthrough", source.name);
+            match UinputKeyboard::create(&name) {
+                Ok(keyboard) => source.passthrough = Some(keyboard),
+                Err(e) => warn!("Unbound keys of {device_node:?} won't reach applications: {e:#}"),
+            }
+        }
+        Ok(source)
+    }
+
+    /// Drive the right stick from a mouse. The mouse is grabbed along with the
+    /// keyboard if the profile asks for it.
+    pub async fn add_mouse(&mut self, device_node: &Path, config: MouseStickConfig) -> Result<()> {
+        self.add_device(device_node, self.profile.grab).await?;
+        self.mouse = Some(MouseStick::new(config));
+        Ok(())
+    }
+
+    fn state(&self) -> GamepadInput {
+        let mut input = self.profile.map_keys(&self.held);
+        if let Some(mouse) = &self.mouse {
+            mouse.apply(&mut input);
+        }
+        input
+    }
+
+    /// Start reading events from another evdev node into this source.
+    async fn add_device(&mut self, device_node: &Path, grab: bool) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .open(device_node)
+            .await
+            .with_context(|| format!("Failed to open {device_node:?}"))?;
+        if grab {
+            if let Err(e) = grab_device(file.as_raw_fd(), true) {
+                warn!("Failed to grab {device_node:?}: {e}");
+            }
+        }
+        debug!("Reading keyboard events from {device_node:?}");
+        let tx = self.tx.clone();
+        self.readers.push(tokio::spawn(async move {
+            while let Ok(event) = read_input_event(&mut file).await {
+                if tx.send(event).await.is_err() {
+                    break;
+                }
+            }
+        }));
+        Ok(())
+    }
+}
+
+impl Drop for KeyboardSource {
+    fn drop(&mut self) {
+        for reader in &self.readers {
+            reader.abort();
+        }
+    }
+}
+
+impl InputSource for KeyboardSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn next_input(&mut self) -> BoxFuture<'_, Result<Option<GamepadInput>>> {
+        async move {
+            loop {
+                // While the mouse has the stick deflected, keep sending states
+                // so it decays back to center after the mouse stops.
+                let decaying = self.mouse.as_ref().is_some_and(|m| !m.is_centered());
+                let event = tokio::select! {
+                    event = self.rx.recv() => match event {
+                        Some(event) => event,
+                        None => return Ok(None),
+                    },
+                    _ = tokio::time::sleep(DECAY_INTERVAL), if decaying => {
+                        if let Some(mouse) = self.mouse.as_mut() {
+                            mouse.tick();
+                        }
+                        return Ok(Some(self.state()));
+                    }
+                };
+                match event.type_ {
+                    EV_SYN => return Ok(Some(self.state())),
+                    EV_KEY if self.profile.is_bound(event.code) => {
+                        // 0 is release, 1 press and 2 autorepeat.
+                        if event.value == 0 {
+                            self.held.remove(&event.code);
+                        } else {
+                            self.held.insert(event.code);
+                        }
+                    }
+                    #[cfg(feature = "emulation")]
+                    EV_KEY => {
+                        if let Some(passthrough) = self.passthrough.as_mut() {
+                            passthrough.send_key(event.code, event.value)?;
+                        }
+                    }
+                    EV_REL => {
+                        if let Some(mouse) = self.mouse.as_mut() {
+                            mouse.add_motion(event.code, event.value);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        .boxed()
+    }
+}
+
+#[cfg(all(test, feature = "emulation"))]
+mod tests {
+    use super::*;
+    use crate::device::EventLayout;
+    use std::fs::{self, File};
+
+    const KEY_W: u16 = 17;
+    const KEY_T: u16 = 20;
+
+    #[tokio::test]
+    async fn unbound_keys_pass_through() {
+        let dir = std::env::temp_dir();
+        let keyboard = dir.join(format!("hidraw-keyboard-{}", std::process::id()));
+        let passthrough = dir.join(format!("hidraw-passthrough-{}", std::process::id()));
+        let layout = EventLayout::NATIVE;
+        let events = [
+            (EV_KEY, KEY_T, 1),
+            (EV_SYN, 0, 0),
+            (EV_KEY, KEY_W, 1),
+            (EV_SYN, 0, 0),
+        ];
+        let stream: Vec<u8> = events
+            .iter()
+            .flat_map(|&(type_, code, value)| layout.encode(type_, code, value))
+            .collect();
+        fs::write(&keyboard, stream).unwrap();
+
+        let profile = KeyboardProfile {
+            bindings: vec![KeyBinding {
+                key: KEY_W,
+                target: KeyTarget::DpadUp,
+            }],
+            grab: false,
+        };
+        let mut source = KeyboardSource::open(&keyboard, profile).await.unwrap();
+        let file = File::create(&passthrough).unwrap();
+        source.passthrough = Some(UinputKeyboard::for_test(file));
+
+        let input = source.next_input().await.unwrap().unwrap();
+        assert!(!input.dpad.up);
+        let input = source.next_input().await.unwrap().unwrap();
+        assert!(input.dpad.up);
+
+        let written = fs::read(&passthrough).unwrap();
+        let _ = fs::remove_file(&keyboard);
+        let _ = fs::remove_file(&passthrough);
+        let size = layout.size();
+        assert_eq!(written.len(), 2 * size);
+        let event = layout.decode(&written[..size]);
+        assert_eq!((event.type_, event.code, event.value), (EV_KEY, KEY_T, 1));
+        let event = layout.decode(&written[size..]);
+        assert_eq!(event.type_, EV_SYN);
+    }
+}
@@ -0,0 +1,103 @@
+use std::sync::{Arc, Mutex};
+
+use crate::device_monitor::{DeviceInfo, DisconnectReason};
+use crate::report::{Axis, Button};
+
+/// Translates a label from its key and named arguments, Fluent style, or
+/// returns `None` to fall back to English. Keys are stable: `button-south`,
+/// `axis-left-trigger`, `dpad`, `disconnect-unplugged` (with `message` for
+/// `disconnect-error`) and `device-045e-028e` (with `name`, the name the
+/// device gives itself).
+pub type Localizer = Arc<dyn Fn(&str, &[(&str, &str)]) -> Option<String> + Send + Sync>;
+
+static LOCALIZER: Mutex<Option<Localizer>> = Mutex::new(None);
+
+/// Translate labels with `localizer` from now on, or only use English with
+/// `None`.
+pub fn set_localizer(localizer: Option<Localizer>) {
+    *LOCALIZER.lock().unwrap() = localizer;
+}
+
+/// The label for `key`, from the localizer if there is one and it knows the
+/// key, or `english` otherwise.
+pub fn translate(key: &str, args: &[(&str, &str)], english: impl FnOnce() -> String) -> String {
+    let localizer = LOCALIZER.lock().unwrap().clone();
+    localizer
+        .and_then(|localizer| localizer(key, args))
+        .unwrap_or_else(english)
+}
+
+pub fn button_key(button: Button) -> String {
+    format!("button-{}", button.as_str().replace('_', "-"))
+}
+
+pub fn axis_key(axis: Axis) -> String {
+    format!("axis-{}", axis.as_str().replace('_', "-"))
+}
+
+pub fn device_key(info: &DeviceInfo) -> String {
+    format!("device-{:04x}-{:04x}", info.vendor_id, info.product_id)
+}
+
+pub fn disconnect_key(reason: &DisconnectReason) -> String {
+    format!("disconnect-{}", reason.as_str())
+}
+
+pub fn button_label(button: Button) -> String {
+    let english = match button {
+        Button::South => "South",
+        Button::East => "East",
+        Button::West => "West",
+        Button::North => "North",
+        Button::LeftShoulder => "Left shoulder",
+        Button::RightShoulder => "Right shoulder",
+        Button::Back => "Back",
+        Button::Start => "Start",
+        Button::LeftStick => "Left stick press",
+        Button::RightStick => "Right stick press",
+        Button::Guide => "Guide",
+        Button::Misc => "Misc",
+    };
+    translate(&button_key(button), &[], || english.to_owned())
+}
+
+pub fn axis_label(axis: Axis) -> String {
+    let english = match axis {
+        Axis::LeftX => "Left stick X",
+        Axis::LeftY => "Left stick Y",
+        Axis::RightX => "Right stick X",
+        Axis::RightY => "Right stick Y",
+        Axis::LeftTrigger => "Left trigger",
+        Axis::RightTrigger => "Right trigger",
+    };
+    translate(&axis_key(axis), &[], || english.to_owned())
+}
+
+pub fn dpad_label() -> String {
+    translate("dpad", &[], || "D-pad".to_owned())
+}
+
+/// The device's name, which in English is the one it gives itself.
+pub fn device_label(info: &DeviceInfo) -> String {
+    translate(&device_key(info), &[("name", &info.name)], || {
+        info.name.clone()
+    })
+}
+
+pub fn disconnect_label(reason: &DisconnectReason) -> String {
+    let message = match reason {
+        DisconnectReason::Error(message) => message.as_str(),
+        _ => "",
+    };
+    translate(
+        &disconnect_key(reason),
+        &[("message", message)],
+        || match reason {
+            DisconnectReason::Unplugged => "Unplugged".to_owned(),
+            DisconnectReason::Error(message) => format!("Error: {message}"),
+            DisconnectReason::Quarantined => "Stopped after a fault".to_owned(),
+            DisconnectReason::PowerOff => "Turned off".to_owned(),
+            DisconnectReason::Released => "Released to another program".to_owned(),
+        },
+    )
+}
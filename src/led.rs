@@ -0,0 +1,122 @@
+use anyhow::{bail, Context as ErrorContext, Result};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use crate::device::{EventLayout, EV_SYN};
+use crate::device_monitor::{Bus, DeviceInfo};
+use crate::drivers::sony::{self, SonyOutput};
+use crate::drivers::switch_pro;
+use crate::wiimote::NINTENDO_VENDOR_ID;
+
+// From Linux uapi/linux/input-event-codes.h
+const EV_LED: u16 = 0x11;
+
+/// What to show on a controller's LEDs.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Led {
+    /// The lightbar color of a DualShock 4 or DualSense.
+    Lightbar { red: u8, green: u8, blue: u8 },
+    /// Player LEDs, LED 1 in bit 0.
+    Player(u8),
+}
+
+/// Whether `info` is a controller whose LEDs we set with its own output
+/// reports, through `Device::set_led`.
+pub fn has_led_reports(info: &DeviceInfo) -> bool {
+    sony::Model::detect(info).is_some()
+        || (info.vendor_id, info.product_id) == (NINTENDO_VENDOR_ID, switch_pro::PRODUCT_ID)
+}
+
+/// The output report that shows `led` on a controller `has_led_reports`
+/// accepts. `sequence` should increase with every report sent.
+pub fn output_report(info: &DeviceInfo, sequence: u8, led: Led) -> Result<Vec<u8>> {
+    if let Some(model) = sony::Model::detect(info) {
+        let output = match led {
+            Led::Lightbar { red, green, blue } => SonyOutput {
+                lightbar: Some((red, green, blue)),
+                ..Default::default()
+            },
+            Led::Player(_) if model == sony::Model::DualShock4 => {
+                bail!("The DualShock 4 has no player LEDs")
+            }
+            Led::Player(leds) => SonyOutput {
+                player_leds: Some(leds),
+                ..Default::default()
+            },
+        };
+        let bluetooth = info.bus == Bus::Bluetooth;
+        return Ok(sony::output_report(model, bluetooth, sequence, &output));
+    }
+    match led {
+        Led::Player(leds) if has_led_reports(info) => {
+            Ok(switch_pro::player_lights_report(sequence, leds))
+        }
+        _ => bail!("Can't show {led:?} on `{}`", info.name),
+    }
+}
+
+/// The ring of an Xbox 360 controller, which xpad exposes as an LED class
+/// device that takes ring commands as its brightness.
+pub struct XpadRing {
+    brightness: PathBuf,
+}
+
+impl XpadRing {
+    /// Find the ring of the controller behind the evdev device at `sys_path`.
+    pub fn find(sys_path: &Path) -> Option<XpadRing> {
+        let leds = fs::read_dir(sys_path.join("device/device/leds")).ok()?;
+        let led = leds
+            .flatten()
+            .find(|entry| entry.file_name().to_string_lossy().starts_with("xpad"))?;
+        Some(XpadRing {
+            brightness: led.path().join("brightness"),
+        })
+    }
+
+    /// Light the quadrant of the lowest player LED in `led`, or turn the
+    /// ring off.
+    pub fn set(&self, led: Led) -> Result<()> {
+        let Led::Player(leds) = led else {
+            bail!("An Xbox 360 ring can't show {led:?}");
+        };
+        // As documented in xpad: 0 is off and 6 to 9 light quadrants 1 to 4.
+        let command = match leds.trailing_zeros() {
+            quadrant @ 0..=3 => 6 + quadrant,
+            _ => 0,
+        };
+        fs::write(&self.brightness, command.to_string())
+            .with_context(|| format!("Failed to write {:?}", self.brightness))
+    }
+}
+
+/// LEDs set by writing `EV_LED` events to an evdev node, for devices whose
+/// kernel driver maps its LEDs to them. Player LED 1 is `LED_NUML`, the
+/// first LED code, and so on.
+pub struct EvdevLeds {
+    file: File,
+}
+
+impl EvdevLeds {
+    pub fn open(device_node: &Path) -> Result<EvdevLeds> {
+        let file = OpenOptions::new()
+            .write(true)
+            .open(device_node)
+            .with_context(|| format!("Failed to open {device_node:?}"))?;
+        Ok(EvdevLeds { file })
+    }
+
+    pub fn set(&mut self, led: Led) -> Result<()> {
+        let Led::Player(leds) = led else {
+            bail!("evdev LEDs can't show {led:?}");
+        };
+        let mut events = vec![];
+        for code in 0..4 {
+            let on = leds & (1 << code) != 0;
+            events.extend(EventLayout::NATIVE.encode(EV_LED, code, on as i32));
+        }
+        events.extend(EventLayout::NATIVE.encode(EV_SYN, 0, 0));
+        self.file.write_all(&events).context("Failed to set LEDs")?;
+        Ok(())
+    }
+}
@@ -1,5 +1,58 @@
+pub mod battery;
+pub mod cache;
+pub mod calibration;
+pub mod capture;
+pub mod config;
+pub mod control;
 pub mod descriptor;
 pub mod device;
 pub mod device_monitor;
+pub mod drivers;
+pub mod handle;
+#[cfg(feature = "emulation")]
+pub mod emulation;
+pub mod error;
+pub mod ipc;
+pub mod keyboard;
+pub mod labels;
+pub mod led;
+pub mod lock;
+pub mod manager;
+#[cfg(feature = "sinks")]
+pub mod midi;
+pub mod mouse;
+#[cfg(feature = "portal")]
+pub mod portal;
+#[cfg(feature = "sinks")]
+pub mod osc;
+pub mod prediction;
 pub mod report;
+pub mod rumble;
+pub mod sandbox;
 pub mod sdl_mapping;
+pub mod selftest;
+pub mod session;
+pub mod sink;
+pub mod source;
+pub mod steam;
+pub mod storage;
+pub mod sysfs;
+pub mod touch_regions;
+pub mod trace;
+pub mod transform;
+#[cfg(feature = "emulation")]
+pub mod uhid;
+#[cfg(feature = "emulation")]
+pub mod uinput;
+#[cfg(feature = "usbfs")]
+pub mod usbfs;
+pub mod wiimote;
+
+#[cfg(feature = "udev")]
+pub use device_monitor::{enumerate_gamepads as enumerate, iter_devices};
+pub use error::{Error, Result};
+pub use manager::{
+    DeviceId, DeviceManager, GamepadEvent, GamepadHandle, GamepadState, IdReuse, Overflow,
+};
+#[cfg(not(feature = "udev"))]
+pub use sysfs::{enumerate_gamepads as enumerate, iter_devices};
@@ -0,0 +1,118 @@
+use anyhow::{Context as ErrorContext, Result};
+use log::{info, warn};
+use nix::errno::Errno;
+use nix::fcntl::{flock, FlockArg};
+use std::fs::{File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+use crate::device::is_grabbed;
+
+/// What to do when another process seems to be handling a device already.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ContentionPolicy {
+    /// Leave the device alone.
+    Skip,
+    /// Handle the device alongside the other process.
+    Share,
+    /// Handle the device and lock it so cooperating processes back off. A
+    /// device another process has grabbed can't be taken over.
+    TakeOver,
+}
+
+impl ContentionPolicy {
+    pub fn from_name(name: &str) -> Option<ContentionPolicy> {
+        match name {
+            "skip" => Some(ContentionPolicy::Skip),
+            "share" => Some(ContentionPolicy::Share),
+            "takeover" => Some(ContentionPolicy::TakeOver),
+            _ => None,
+        }
+    }
+}
+
+/// Signs that another process is handling a device.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Contention {
+    Free,
+    /// Another process holds an advisory `flock` on the device node.
+    Locked,
+    /// Another process has an exclusive evdev grab, so we'd see no events.
+    Grabbed,
+}
+
+/// An advisory lock on a device node, released when dropped.
+#[derive(Debug)]
+pub struct DeviceLock {
+    _file: File,
+}
+
+fn try_lock(file: &File) -> Result<bool> {
+    match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+        Ok(()) => Ok(true),
+        Err(nix::Error::Sys(Errno::EAGAIN)) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Whether to handle a device.
+#[derive(Debug)]
+pub enum Decision {
+    Skip,
+    /// Handle the device, holding the lock (if any) until done.
+    Handle(Option<DeviceLock>),
+}
+
+/// Check whether another process is handling the device at `device_node` and
+/// decide whether to handle it per `policy`.
+pub fn acquire(device_node: &Path, policy: ContentionPolicy) -> Result<Decision> {
+    let file = OpenOptions::new()
+        .read(true)
+        .open(device_node)
+        .with_context(|| format!("Failed to open {device_node:?}"))?;
+    let locked = try_lock(&file)?;
+    let is_evdev = device_node.starts_with("/dev/input/event");
+    let contention = if is_evdev && is_grabbed(file.as_raw_fd())? {
+        Contention::Grabbed
+    } else if !locked {
+        Contention::Locked
+    } else {
+        Contention::Free
+    };
+    Ok(match (contention, policy) {
+        (Contention::Free, ContentionPolicy::TakeOver) => {
+            Decision::Handle(Some(DeviceLock { _file: file }))
+        }
+        (Contention::Free, _) => Decision::Handle(None),
+        // There's no way to get events from a device someone else grabbed.
+        (Contention::Grabbed, _) | (_, ContentionPolicy::Skip) => {
+            warn!("Skipping {device_node:?}, another process is handling it ({contention:?})");
+            Decision::Skip
+        }
+        (Contention::Locked, ContentionPolicy::Share) => {
+            info!("Sharing {device_node:?} with the process that has it locked");
+            Decision::Handle(None)
+        }
+        (Contention::Locked, ContentionPolicy::TakeOver) => {
+            warn!("Taking over {device_node:?} from the process that has it locked");
+            Decision::Handle(None)
+        }
+    })
+}
+
+/// Held by the running daemon so a second instance doesn't fight it over devices.
+#[derive(Debug)]
+pub struct InstanceLock {
+    _file: File,
+}
+
+/// Take the instance lock at `path`, or return `None` if another instance has it.
+pub fn lock_instance(path: &Path) -> Result<Option<InstanceLock>> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .with_context(|| format!("Failed to open {path:?}"))?;
+    Ok(try_lock(&file)?.then_some(InstanceLock { _file: file }))
+}
@@ -1,21 +1,854 @@
-use anyhow::Result;
+use anyhow::{bail, Context as ErrorContext, Result};
 use env_logger::Builder;
-use log::{info, LevelFilter};
-use std::collections::HashMap;
-use tokio::sync::mpsc;
+use log::{debug, info, warn, LevelFilter};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
 
-use hidraw::device;
-use hidraw::device_monitor::{self, DeviceEvent, DeviceInfo};
+#[cfg(feature = "emulation")]
+use hidraw::capture::Recording;
+use hidraw::config::{Config, ConfigManager, ConfigSource, Profile};
+use hidraw::control::{self, Request};
+use hidraw::device::{PowerPolicy, TaskHandle};
+#[cfg(feature = "udev")]
+use hidraw::device_monitor::monitor_devices;
+use hidraw::device_monitor::{DeviceEvent, DeviceInfo, DisconnectReason, MonitorConfig};
+use hidraw::drivers::DriverOptions;
+#[cfg(feature = "emulation")]
+use hidraw::emulation::{EmulatedDevice, Emulation, OutputLoopback};
+use hidraw::ipc::Command;
+use hidraw::lock::{self, ContentionPolicy, Decision, InstanceLock};
+#[cfg(feature = "portal")]
+use hidraw::portal::PortalService;
+use hidraw::selftest::{self, Outcome};
+use hidraw::sink::EmulationTarget;
+#[cfg(not(feature = "emulation"))]
+use hidraw::sink::OutputSink;
+use hidraw::source::{self, ChannelSource, RoutedSink};
+use hidraw::steam::{self, SteamPolicy};
+#[cfg(not(feature = "udev"))]
+use hidraw::sysfs::monitor_devices;
+#[cfg(feature = "emulation")]
+use hidraw::uhid::UhidDevice;
+use hidraw::{config, descriptor, device, report, sandbox, session, storage, trace};
 
 fn log_info(info: &DeviceInfo) {
     info!(
         "New device `{}` {:04x}:{:04x} on {:?} ({:?})",
         info.name, info.vendor_id, info.product_id, info.bus, info.device_node
     );
+    debug!("SDL GUID {}", info.sdl_guid().simple());
+}
+
+/// Validate a config file without starting the daemon, printing one
+/// `path:line:column: message` line per problem.
+fn check_config(path: Option<&String>) -> Result<()> {
+    let Some(path) = path else {
+        bail!("Usage: hidraw check-config <path>");
+    };
+    match config::load(Path::new(path)) {
+        Ok(config) => {
+            println!(
+                "{path}: OK, {} profiles, {} devices",
+                config.profiles.len(),
+                config.devices.len()
+            );
+            Ok(())
+        }
+        Err(error) => {
+            for diagnostic in &error.diagnostics {
+                println!("{path}:{diagnostic}");
+            }
+            std::process::exit(1);
+        }
+    }
+}
+
+const CTL_USAGE: &str = "Usage: hidraw ctl list [--verbose] | profile <device> <name> | \
+rumble <device> [<strong> <weak> <duration_ms>] | reload | metrics | shutdown | \
+release <device> | reacquire <device> | monitor";
+
+/// Send a command to the running daemon and print its reply.
+async fn run_ctl(args: &[String]) -> Result<()> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let command = match args.as_slice() {
+        ["list"] => Command::List { verbose: false },
+        ["list", "--verbose" | "-v"] => Command::List { verbose: true },
+        ["profile", device, name] => Command::Profile {
+            device: PathBuf::from(device),
+            name: name.to_string(),
+        },
+        ["rumble", device] => Command::Rumble {
+            device: PathBuf::from(device),
+            strong: 0xFFFF,
+            weak: 0xFFFF,
+            duration_ms: 500,
+        },
+        ["rumble", device, strong, weak, duration_ms] => Command::Rumble {
+            device: PathBuf::from(device),
+            strong: strong.parse().context("Bad strong magnitude")?,
+            weak: weak.parse().context("Bad weak magnitude")?,
+            duration_ms: duration_ms.parse().context("Bad duration")?,
+        },
+        ["reload"] => Command::Reload,
+        ["metrics"] => Command::Metrics,
+        ["shutdown"] => Command::Shutdown,
+        ["release", device] => Command::Release {
+            device: PathBuf::from(device),
+        },
+        ["reacquire", device] => Command::Reacquire {
+            device: PathBuf::from(device),
+        },
+        ["monitor"] => return monitor_events().await,
+        _ => bail!(CTL_USAGE),
+    };
+    for line in control::send(&control::socket_path(), &command).await? {
+        println!("{line}");
+    }
+    Ok(())
+}
+
+/// Print the running daemon's device events until it stops.
+async fn monitor_events() -> Result<()> {
+    let mut monitor = control::Monitor::connect(&control::socket_path()).await?;
+    while let Some(event) = monitor.next_event().await? {
+        print!("{}", event.encode());
+    }
+    Ok(())
+}
+
+/// A device the daemon is handling.
+struct Handled {
+    task: TaskHandle,
+    info: DeviceInfo,
+    /// A profile chosen with `ctl profile`, overriding the config, and the
+    /// user whose session it was chosen in. It only applies in theirs.
+    profile: Option<(Option<String>, String)>,
+    /// The profile last applied, even if its sinks failed to open.
+    applied: Option<Profile>,
+    /// Where the device's input is going, if its profile's sinks opened.
+    source: Option<Source>,
+    /// The virtual controller its profile emulates. It outlives sources, so
+    /// applications don't see it unplugged when only the profile changes.
+    emulation: Option<Emulation>,
+}
+
+impl Handled {
+    /// The device's profile under `config`, in the session of whoever is
+    /// active on its seat.
+    fn profile_in(&self, config: &Config) -> Option<Profile> {
+        let user = session::device_user(&self.info).map(|user| user.name);
+        let chosen = match &self.profile {
+            Some((chosen_by, name)) if *chosen_by == user => {
+                config.profile_as(user.as_deref(), name)
+            }
+            _ => None,
+        };
+        let profile = chosen.or_else(|| {
+            let (vendor_id, product_id) = (self.info.vendor_id, self.info.product_id);
+            config.profile_for_device_as(user.as_deref(), vendor_id, product_id)
+        });
+        profile.cloned()
+    }
+
+    /// Get everything `profile` needs ready without touching the device's
+    /// source: open the sinks the source doesn't have, and create or retarget
+    /// the virtual controller. Sinks the source has are handed over from it
+    /// later instead, since e.g. raw MIDI devices take one writer.
+    async fn stage(&mut self, profile: Option<Profile>) -> Result<Staged> {
+        let current = match (&self.source, &self.applied) {
+            (Some(_), Some(applied)) => &applied.sinks[..],
+            _ => &[],
+        };
+        let added = profile
+            .iter()
+            .flat_map(|p| &p.sinks)
+            .filter(|sink| !current.contains(sink));
+        let mut staged = Staged {
+            sys_path: self.info.sys_path.clone(),
+            sinks: config::open_sinks(added)?,
+            profile,
+            emulation: None,
+            retargeted_from: None,
+        };
+        // Last, as it's the one step that changes what applications see.
+        match (staged.emulate(), &mut self.emulation) {
+            (Some(target), Some(emulation)) if emulation.target() != target => {
+                let from = emulation.target();
+                emulation.retarget(target).await?;
+                staged.retargeted_from = Some(from);
+            }
+            (Some(target), None) => {
+                let emulation = create_emulation(target, &self.task, &self.info).await?;
+                staged.emulation = Some(emulation);
+            }
+            _ => {}
+        }
+        Ok(staged)
+    }
+
+    /// Undo `stage`, for when another device couldn't be staged.
+    async fn unstage(&mut self, staged: Staged) {
+        let (Some(from), Some(emulation)) = (staged.retargeted_from, &mut self.emulation) else {
+            return;
+        };
+        if let Err(e) = emulation.retarget(from).await {
+            warn!(
+                "Failed to restore the virtual controller of {:?}: {e:#}",
+                self.info.sys_path
+            );
+        }
+    }
+
+    /// Replace the device's source with one for a staged profile, which
+    /// can't fail: the sinks the old source has that the profile keeps are
+    /// handed over, and the rest were opened by `stage`.
+    async fn switch_profile(&mut self, staged: Staged) {
+        let target = staged.emulate();
+        let Staged {
+            profile,
+            mut sinks,
+            emulation,
+            ..
+        } = staged;
+        let mut old = match self.source.take() {
+            Some(source) => source.stop().await,
+            None => vec![],
+        };
+        let previous = self.applied.take().map_or(vec![], |p| p.sinks);
+        let kept = profile
+            .iter()
+            .flat_map(|p| &p.sinks)
+            .filter(|sink| previous.contains(sink));
+        for sink in kept {
+            if let Some(i) = old.iter().position(|(name, _)| *name == sink.name) {
+                sinks.push(old.swap_remove(i));
+            }
+        }
+        if emulation.is_some() {
+            self.emulation = emulation;
+        }
+        if target.is_none() {
+            self.emulation = None;
+        }
+        self.applied = profile.clone();
+        let Some(profile) = profile else {
+            return;
+        };
+        info!(
+            "Applying profile {} to {:?}",
+            profile.name, self.info.sys_path
+        );
+        // No route can name an empty id, so it gets every control.
+        sinks.extend(self.emulation.as_ref().map(|e| (String::new(), e.sink())));
+        let source = Source::start(&self.info.name, &self.task, &profile, sinks).await;
+        self.source = Some(source);
+    }
+
+    /// Stop sending the device's input anywhere, for a profile that
+    /// couldn't be staged.
+    async fn fail_profile(&mut self, profile: Option<Profile>) {
+        if let Some(source) = self.source.take() {
+            source.stop().await;
+        }
+        self.emulation = None;
+        self.applied = profile;
+    }
+}
+
+/// A task sending a device's input to the sinks of a profile.
+struct Source {
+    /// Stops the task when sent to or dropped.
+    stop: mpsc::Sender<()>,
+    /// Hands back the sinks once it ends.
+    task: JoinHandle<Vec<RoutedSink>>,
+}
+
+impl Source {
+    /// Start sending `task`'s input to `sinks`, opened from `profile`.
+    async fn start(
+        name: &str,
+        task: &TaskHandle,
+        profile: &Profile,
+        mut sinks: Vec<RoutedSink>,
+    ) -> Source {
+        let input = ChannelSource::new(name, task.subscribe().await);
+        let (stop, stop_rx) = mpsc::channel(1);
+        let Profile {
+            transforms,
+            routing,
+            output,
+            disconnect,
+            ..
+        } = profile.clone();
+        let task = tokio::spawn(async move {
+            let source = Box::new(input);
+            let result = source::run_source(
+                source, stop_rx, transforms, routing, &mut sinks, output, disconnect,
+            );
+            if let Err(e) = result.await {
+                warn!("Source failed: {e:#}");
+            }
+            sinks
+        });
+        Source { stop, task }
+    }
+
+    /// Stop the task and take back its sinks.
+    async fn stop(self) -> Vec<RoutedSink> {
+        let _ = self.stop.send(()).await;
+        self.task.await.unwrap_or_default()
+    }
+
+    /// Let the task run until the device's input ends, and then play out the
+    /// profile's disconnect policy.
+    fn finish(self) {
+        tokio::spawn(async move { self.stop.closed().await });
+    }
+}
+
+/// A device's new profile, with everything it needs that can fail ready.
+struct Staged {
+    sys_path: PathBuf,
+    profile: Option<Profile>,
+    /// The profile's sinks that the device's source doesn't have, open.
+    sinks: Vec<RoutedSink>,
+    /// A virtual controller for a device that had none.
+    emulation: Option<Emulation>,
+    /// What the device's virtual controller presented as before it was
+    /// retargeted, to undo that.
+    retargeted_from: Option<EmulationTarget>,
+}
+
+impl Staged {
+    fn emulate(&self) -> Option<EmulationTarget> {
+        self.profile.as_ref().and_then(|p| p.emulate)
+    }
+}
+
+/// Stage every device whose profile `config` changes, or none of them: if
+/// one can't be staged, the ones before it are unstaged, so that a reload
+/// which can't be applied leaves every device as it was.
+async fn stage_profiles(
+    devices: &mut HashMap<PathBuf, Handled>,
+    config: &Config,
+) -> Result<Vec<Staged>> {
+    let mut staged = vec![];
+    let mut failed = None;
+    for handled in devices.values_mut() {
+        let profile = handled.profile_in(config);
+        if profile == handled.applied {
+            continue;
+        }
+        match handled.stage(profile).await {
+            Ok(device) => staged.push(device),
+            Err(e) => {
+                let sys_path = &handled.info.sys_path;
+                failed = Some(e.context(format!("Can't apply the config to {sys_path:?}")));
+                break;
+            }
+        }
+    }
+    let Some(e) = failed else {
+        return Ok(staged);
+    };
+    for device in staged {
+        if let Some(handled) = devices.get_mut(&device.sys_path) {
+            handled.unstage(device).await;
+        }
+    }
+    Err(e)
+}
+
+/// A virtual controller presenting as `target`, whose rumble and lightbar go
+/// back to the device behind `task`.
+#[cfg(feature = "emulation")]
+async fn create_emulation(
+    target: EmulationTarget,
+    task: &TaskHandle,
+    info: &DeviceInfo,
+) -> Result<Emulation> {
+    let device = EmulatedDevice::create(target).await?;
+    Ok(Emulation::start(
+        device,
+        OutputLoopback::for_task(task, info),
+    ))
+}
+
+#[cfg(not(feature = "emulation"))]
+async fn create_emulation(
+    _target: EmulationTarget,
+    _task: &TaskHandle,
+    _info: &DeviceInfo,
+) -> Result<Emulation> {
+    bail!("Emulating a controller needs the `emulation` feature")
+}
+
+/// Stands in for `hidraw::emulation::Emulation`, which no device can have
+/// without the `emulation` feature.
+#[cfg(not(feature = "emulation"))]
+enum Emulation {}
+
+#[cfg(not(feature = "emulation"))]
+impl Emulation {
+    fn target(&self) -> EmulationTarget {
+        match *self {}
+    }
+
+    async fn retarget(&mut self, _target: EmulationTarget) -> Result<()> {
+        match *self {}
+    }
+
+    fn sink(&self) -> Box<dyn OutputSink + Send> {
+        match *self {}
+    }
+}
+
+/// A device handed over with `ctl release`, e.g. for a firmware update.
+struct Released {
+    /// Where it was when it was released.
+    sys_path: PathBuf,
+    /// Where it's connected, to recognize it if it comes back at a new sys
+    /// path, as devices do when they restart after an update.
+    phys: Option<String>,
+    /// The device as last seen, or `None` while it's unplugged.
+    info: Option<DeviceInfo>,
+}
+
+impl Released {
+    fn matches(&self, info: &DeviceInfo) -> bool {
+        info.sys_path == self.sys_path || (self.phys.is_some() && info.phys == self.phys)
+    }
+}
+
+/// How often to check whose session is active on each device's seat.
+const SESSION_POLL: Duration = Duration::from_secs(2);
+
+/// How many device events a control client can fall behind by before it
+/// misses some.
+const WIRE_EVENT_BUFFER: usize = 64;
+
+/// How long `ctl release` waits for a device's task to close it.
+const RELEASE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The storage key the counters are saved under on shutdown.
+const STATS_KEY: &str = "stats";
+
+/// Counters reported by `ctl metrics`.
+struct Metrics {
+    started: Instant,
+    added: u64,
+    /// By `DisconnectReason::as_str`.
+    disconnects: BTreeMap<&'static str, u64>,
+    decode_errors: u64,
+    faults: u64,
+}
+
+/// The daemon's state that control commands can see and change.
+struct Daemon {
+    devices: HashMap<PathBuf, Handled>,
+    /// Devices whose task panicked, ignored until they're unplugged.
+    quarantined: HashSet<PathBuf>,
+    released: Vec<Released>,
+    /// Where device events go, to handle reacquired devices like new ones.
+    events: mpsc::Sender<DeviceEvent>,
+    config: Option<ConfigManager>,
+    metrics: Metrics,
+    #[cfg(feature = "portal")]
+    portal: Option<PortalService>,
+}
+
+impl Daemon {
+    fn find(&mut self, device: &Path) -> Result<&mut Handled> {
+        self.devices
+            .values_mut()
+            .find(|h| h.info.sys_path == device || h.info.device_node == device)
+            .with_context(|| format!("No such device: {device:?}"))
+    }
+
+    /// Stop handling a device, if we are.
+    async fn disconnect(&mut self, sys_path: &Path, reason: DisconnectReason) {
+        let Some(handled) = self.devices.remove(sys_path) else {
+            return;
+        };
+        let duration = handled.info.connected_at.elapsed().unwrap_or_default();
+        info!(
+            "{:?} disconnected after {}s ({})",
+            sys_path,
+            duration.as_secs(),
+            reason
+        );
+        *self.metrics.disconnects.entry(reason.as_str()).or_insert(0) += 1;
+        #[cfg(feature = "portal")]
+        if let Some(portal) = &self.portal {
+            if let Err(e) = portal.removed(sys_path, &reason).await {
+                warn!("Failed to remove {sys_path:?} from the session bus: {e:#}");
+            }
+        }
+        // The task has already stopped if it failed.
+        handled.task.stop().await;
+        if let Some(source) = handled.source {
+            source.finish();
+        }
+    }
+
+    /// Send a device's input to the sinks of its profile, restarting its
+    /// source if the profile changed.
+    async fn apply_profile(&mut self, sys_path: &Path) {
+        let Some(config) = self.config.as_ref().map(ConfigManager::current) else {
+            return;
+        };
+        let Some(handled) = self.devices.get_mut(sys_path) else {
+            return;
+        };
+        let profile = handled.profile_in(&config);
+        if profile == handled.applied {
+            return;
+        }
+        match handled.stage(profile.clone()).await {
+            Ok(staged) => handled.switch_profile(staged).await,
+            Err(e) => {
+                // Unlike a reload, this doesn't keep the old profile, which
+                // may be for whoever's session was active before.
+                warn!("Not sending input from {sys_path:?}: {e:#}");
+                handled.fail_profile(profile).await;
+            }
+        }
+    }
+
+    /// Re-pick every device's profile, e.g. once another user's session is
+    /// active on its seat.
+    async fn apply_profiles(&mut self) {
+        let sys_paths: Vec<PathBuf> = self.devices.keys().cloned().collect();
+        for sys_path in sys_paths {
+            self.apply_profile(&sys_path).await;
+        }
+    }
+
+    async fn handle_command(&mut self, command: Command) -> Result<Vec<String>> {
+        match command {
+            Command::List { verbose } => Ok(self
+                .devices
+                .values()
+                .map(|h| {
+                    let details = if verbose {
+                        describe_descriptor(&h.info) + " "
+                    } else {
+                        String::new()
+                    };
+                    format!(
+                        "{} {:04x}:{:04x} {} {}s {details}{}",
+                        h.info.sys_path.display(),
+                        h.info.vendor_id,
+                        h.info.product_id,
+                        h.applied.as_ref().map_or("-", |p| p.name.as_str()),
+                        h.info.connected_at.elapsed().unwrap_or_default().as_secs(),
+                        h.info.name
+                    )
+                })
+                .collect()),
+            Command::Profile { device, name } => {
+                let current = self
+                    .config
+                    .as_ref()
+                    .context("No config file loaded")?
+                    .current();
+                let handled = self.find(&device)?;
+                let user = session::device_user(&handled.info).map(|user| user.name);
+                if current.profile_as(user.as_deref(), &name).is_none() {
+                    bail!("No such profile: {name}");
+                }
+                info!("Switching {:?} to profile {}", handled.info.sys_path, name);
+                handled.profile = Some((user, name));
+                // Choosing it again retries a profile that failed to apply.
+                if handled.source.is_none() {
+                    handled.applied = None;
+                }
+                let sys_path = handled.info.sys_path.clone();
+                self.apply_profile(&sys_path).await;
+                Ok(vec![])
+            }
+            Command::Rumble {
+                device,
+                strong,
+                weak,
+                duration_ms,
+            } => {
+                let handled = self.find(&device)?;
+                handled.task.rumble(strong, weak, duration_ms).await?;
+                Ok(vec![])
+            }
+            Command::Release { device } => {
+                let handled = self.find(&device)?;
+                let info = handled.info.clone();
+                let task = handled.task.clone();
+                self.disconnect(&info.sys_path, DisconnectReason::Released)
+                    .await;
+                if tokio::time::timeout(RELEASE_TIMEOUT, task.stopped())
+                    .await
+                    .is_err()
+                {
+                    bail!("{:?} didn't let go of the device in time", info.sys_path);
+                }
+                info!("Released {:?}", info.sys_path);
+                self.released.push(Released {
+                    sys_path: info.sys_path.clone(),
+                    phys: info.phys.clone(),
+                    info: Some(info),
+                });
+                Ok(vec![])
+            }
+            Command::Reacquire { device } => {
+                let index = self
+                    .released
+                    .iter()
+                    .position(|r| {
+                        r.sys_path == device
+                            || r.info.as_ref().is_some_and(|info| {
+                                info.sys_path == device || info.device_node == device
+                            })
+                    })
+                    .with_context(|| format!("{device:?} isn't released"))?;
+                let released = self.released.remove(index);
+                info!("Reacquiring {:?}", released.sys_path);
+                // Otherwise it's handled when it's plugged back in.
+                if let Some(mut info) = released.info {
+                    info.connected_at = SystemTime::now();
+                    let events = self.events.clone();
+                    // We're the receiver, so don't wait for room here.
+                    tokio::spawn(async move { events.send(DeviceEvent::Added(info)).await });
+                }
+                Ok(vec![])
+            }
+            Command::Reload => {
+                let manager = self
+                    .config
+                    .as_mut()
+                    .context("No config file loaded; set HIDRAW_CONFIG")?;
+                let devices = &mut self.devices;
+                let staged = manager
+                    .reload(async |config| stage_profiles(devices, config).await)
+                    .await
+                    .context("Keeping the previous config")?;
+                // Drop overrides naming profiles that no longer exist.
+                let current = manager.current();
+                for handled in self.devices.values_mut() {
+                    if let Some((user, name)) = &handled.profile {
+                        if current.profile_as(user.as_deref(), name).is_none() {
+                            warn!(
+                                "Profile {} is gone, reverting {:?}",
+                                name, handled.info.sys_path
+                            );
+                            handled.profile = None;
+                        }
+                    }
+                }
+                for staged in staged {
+                    if let Some(handled) = self.devices.get_mut(&staged.sys_path) {
+                        handled.switch_profile(staged).await;
+                    }
+                }
+                Ok(vec![])
+            }
+            Command::Metrics => Ok(self.metrics_lines()),
+            // The main loop stops once this is answered.
+            Command::Shutdown => Ok(vec![]),
+        }
+    }
+
+    fn metrics_lines(&self) -> Vec<String> {
+        let metrics = &self.metrics;
+        let mut lines = vec![
+            format!("uptime_secs {}", metrics.started.elapsed().as_secs()),
+            format!("devices {}", self.devices.len()),
+            format!("quarantined {}", self.quarantined.len()),
+            format!("devices_added {}", metrics.added),
+            format!("decode_errors {}", metrics.decode_errors),
+            format!("faults {}", metrics.faults),
+        ];
+        for (reason, count) in &metrics.disconnects {
+            lines.push(format!("disconnects_{} {count}", reason.replace('-', "_")));
+        }
+        lines
+    }
+}
+
+/// A device's descriptor fingerprint and how many usages it names on each
+/// usage page, like `1c0ffee0ddba11ed 01:6,09:14`, for support requests.
+fn describe_descriptor(info: &DeviceInfo) -> String {
+    let desc = info.hidraw_node.as_ref().and_then(|node| {
+        let file = File::open(node).ok()?;
+        let data = device::read_report_descriptor(file.as_raw_fd()).ok()?;
+        descriptor::parse_hid_descriptor(&data).ok()
+    });
+    let Some(desc) = desc else {
+        return "- -".to_owned();
+    };
+    let stats: Vec<String> = desc
+        .usage_stats()
+        .iter()
+        .map(|(page, count)| format!("{page:02x}:{count}"))
+        .collect();
+    format!("{} {}", desc.fingerprint(), stats.join(","))
+}
+
+/// Print the gamepads connected right now, without starting the daemon.
+fn list_devices() -> Result<()> {
+    for info in hidraw::enumerate()? {
+        println!(
+            "{} {:04x}:{:04x} {:?} {}",
+            info.device_node.display(),
+            info.vendor_id,
+            info.product_id,
+            info.bus,
+            info.name
+        );
+    }
+    Ok(())
+}
+
+/// Print a report descriptor with usage names, read from a hidraw node or a
+/// `.bin` or `.hex` file like the descriptor overrides.
+fn dump_descriptor(path: Option<&String>) -> Result<()> {
+    let Some(path) = path.map(Path::new) else {
+        bail!("Usage: hidraw dump-descriptor <hidraw node or file>");
+    };
+    let data = if path.starts_with("/dev") {
+        let file = File::open(path).with_context(|| format!("Failed to open {path:?}"))?;
+        device::read_report_descriptor(file.as_raw_fd())?
+    } else if path.extension().is_some_and(|e| e == "hex") {
+        descriptor::parse_hex(&std::fs::read_to_string(path)?)?
+    } else {
+        std::fs::read(path).with_context(|| format!("Failed to read {path:?}"))?
+    };
+    let desc = descriptor::parse_hid_descriptor(&data)?;
+    print!("{}", descriptor::dump_descriptor(&desc));
+    println!("Fingerprint: {}", desc.fingerprint());
+    Ok(())
+}
+
+/// Serve devices on the session bus if there is one, and report which of the
+/// desktop portal's input interfaces are available.
+#[cfg(feature = "portal")]
+async fn start_portal() -> Option<PortalService> {
+    let portal = match PortalService::start().await {
+        Ok(portal) => portal,
+        Err(e) => {
+            warn!("Not serving devices on the session bus: {e:#}");
+            return None;
+        }
+    };
+    match portal.portal_input_interfaces().await {
+        Ok(found) if !found.is_empty() => info!("The desktop portal offers {}", found.join(", ")),
+        Ok(_) => info!("The desktop portal has no input interfaces"),
+        Err(e) => debug!("{e:#}"),
+    }
+    Some(portal)
+}
+
+/// The config file to load: `$HIDRAW_CONFIG`, or inside Flatpak `config` in
+/// the user's config directory if it exists.
+fn config_source() -> Option<ConfigSource> {
+    if let Some(path) = std::env::var_os("HIDRAW_CONFIG") {
+        return Some(ConfigSource::File(PathBuf::from(path)));
+    }
+    if storage::configured().is_some() {
+        return Some(ConfigSource::Stored);
+    }
+    let path = sandbox::config_dir()?.join("config");
+    (sandbox::in_flatpak() && path.exists()).then_some(ConfigSource::File(path))
+}
+
+/// How long `--takeover` waits for the running instance to exit.
+const TAKEOVER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Become the only running instance. With `takeover`, ask a running instance
+/// to shut down and wait for it, otherwise refuse to start.
+async fn lock_instance(takeover: bool) -> Result<InstanceLock> {
+    let path = control::socket_path().with_extension("lock");
+    if let Some(lock) = lock::lock_instance(&path)? {
+        return Ok(lock);
+    }
+    if !takeover {
+        bail!("Another instance is running; use --takeover to replace it");
+    }
+    info!("Asking the running instance to shut down");
+    // It may exit before its reply makes it out.
+    if let Err(e) = control::send(&control::socket_path(), &Command::Shutdown).await {
+        debug!("Shutdown request: {e:#}");
+    }
+    let start = Instant::now();
+    while start.elapsed() < TAKEOVER_TIMEOUT {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        if let Some(lock) = lock::lock_instance(&path)? {
+            return Ok(lock);
+        }
+    }
+    bail!("The running instance didn't shut down");
+}
+
+/// Exercise a device's output paths, printing one `capability: result` line
+/// per check.
+async fn run_selftest(device: Option<&String>) -> Result<()> {
+    let Some(device) = device else {
+        bail!("Usage: hidraw selftest <device>");
+    };
+    let results = selftest::run(Path::new(device)).await?;
+    for result in &results {
+        println!("{}: {}", result.capability, result.outcome);
+    }
+    if results
+        .iter()
+        .any(|r| matches!(r.outcome, Outcome::Fail(_)))
+    {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Recreate a captured device with uhid and send its reports again, for the
+/// running daemon to pick up.
+#[cfg(feature = "emulation")]
+async fn replay(capture: Option<&String>) -> Result<()> {
+    let Some(capture) = capture else {
+        bail!("Usage: hidraw replay <capture>");
+    };
+    let recording = Recording::read(Path::new(capture))?;
+    let mut device = UhidDevice::create(&recording.uhid_config()).await?;
+    println!(
+        "Replaying {} reports of {:04x}:{:04x}",
+        recording.reports.len(),
+        recording.vendor_id,
+        recording.product_id
+    );
+    recording.replay(&mut device).await
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().collect();
+    match args.get(1).map(String::as_str) {
+        Some("check-config") => return check_config(args.get(2)),
+        Some("devices") => return list_devices(),
+        Some("dump-descriptor") => return dump_descriptor(args.get(2)),
+        Some("selftest") => return run_selftest(args.get(2)).await,
+        Some("ctl") => return run_ctl(&args[2..]).await,
+        #[cfg(feature = "emulation")]
+        Some("replay") => return replay(args.get(2)).await,
+        Some("config-schema") => {
+            print!("{}", config::json_schema());
+            return Ok(());
+        }
+        Some("config-syntax") => {
+            print!("{}", config::syntax_reference());
+            return Ok(());
+        }
+        _ => {}
+    }
     Builder::new()
         .filter_level(LevelFilter::Debug)
         .format_module_path(false)
@@ -23,32 +856,334 @@ async fn main() -> Result<()> {
         .parse_default_env()
         .init();
     info!("Starting");
-    let mut devices = HashMap::new();
-    // Spawn a task to monitor devices via udev.
+    sandbox::log_access();
+    let _instance = lock_instance(args.iter().any(|a| a == "--takeover")).await?;
+    // Record a timeline of device activity for Perfetto/chrome://tracing.
+    let trace_path = std::env::var_os("HIDRAW_TRACE");
+    if trace_path.is_some() {
+        trace::enable();
+    }
+    // What to do with devices another process is already handling.
+    let policy = std::env::var("HIDRAW_CONTENTION")
+        .ok()
+        .and_then(|p| ContentionPolicy::from_name(&p))
+        .unwrap_or(ContentionPolicy::Skip);
+    // Whether to handle Steam Input's virtual controllers.
+    let steam_policy = std::env::var("HIDRAW_STEAM")
+        .ok()
+        .and_then(|p| SteamPolicy::from_name(&p))
+        .unwrap_or(SteamPolicy::Ignore);
+    // Whether to stop wireless pads streaming motion, which nothing here uses.
+    let power_policy = std::env::var("HIDRAW_POWER")
+        .ok()
+        .and_then(|p| PowerPolicy::from_name(&p))
+        .unwrap_or_default();
+    // Whether to switch pads that can into a higher report rate.
+    let driver_options = DriverOptions {
+        fast_mode: std::env::var("HIDRAW_FAST_MODE").map_or(true, |v| v != "off"),
+    };
+    match steam::find_steam_devices() {
+        Ok(found) if !found.is_empty() => {
+            info!(
+                "Steam Input is running with {} virtual controllers",
+                found.len()
+            );
+        }
+        Ok(_) => {}
+        Err(e) => warn!("Failed to look for Steam virtual controllers: {e}"),
+    }
+    let (config_tx, mut config_rx) = mpsc::channel(4);
     let (tx, mut rx) = mpsc::channel(4);
-    let mut local_set = device_monitor::monitor_devices(tx);
+    let config = match config_source() {
+        Some(source) => Some(ConfigManager::load(source, config_tx)?),
+        None => None,
+    };
+    let mut daemon = Daemon {
+        devices: HashMap::new(),
+        quarantined: HashSet::new(),
+        released: vec![],
+        events: tx.clone(),
+        config,
+        metrics: Metrics {
+            started: Instant::now(),
+            added: 0,
+            disconnects: BTreeMap::new(),
+            decode_errors: 0,
+            faults: 0,
+        },
+        #[cfg(feature = "portal")]
+        portal: start_portal().await,
+    };
+    // Accept `hidraw ctl` commands, and send device events to clients that
+    // want them.
+    let (request_tx, mut request_rx) = mpsc::channel::<Request>(4);
+    let (wire_tx, _) = broadcast::channel(WIRE_EVENT_BUFFER);
+    let wire_events = wire_tx.clone();
+    tokio::spawn(async move {
+        if let Err(e) = control::serve(&control::socket_path(), request_tx, wire_events).await {
+            warn!("Control socket failed: {e:#}");
+        }
+    });
+    // Spawn a task to monitor devices via udev, or sysfs without udev.
+    let mut monitor = monitor_devices(tx.clone(), MonitorConfig::new());
+    let mut monitor_done = false;
+    // logind only says who's active through its state files here.
+    let mut sessions = tokio::time::interval(SESSION_POLL);
     loop {
         tokio::select! {
             Some(event) =  rx.recv() => {
+                // Fails only while no client is listening.
+                let _ = wire_tx.send(event.clone());
                 match event {
                     DeviceEvent::Added(info) => {
+                        if let Some(released) =
+                            daemon.released.iter_mut().find(|r| r.matches(&info))
+                        {
+                            info!("Leaving released device {:?} alone", info.sys_path);
+                            released.info = Some(info);
+                            continue;
+                        }
+                        if daemon.quarantined.contains(&info.sys_path) {
+                            warn!("Ignoring quarantined device {:?}", info.sys_path);
+                            continue;
+                        }
+                        if steam_policy == SteamPolicy::Ignore && steam::is_steam_virtual(&info) {
+                            info!("Ignoring Steam virtual controller {:?}", info.sys_path);
+                            continue;
+                        }
                         log_info(&info);
-                        let (tx, rx) = mpsc::channel(4);
-                        devices.insert(info.sys_path.clone(), tx);
-                        tokio::task::spawn(device::watch_one_device(info, rx));
+                        let lock = match lock::acquire(&info.device_node, policy) {
+                            Ok(Decision::Handle(lock)) => lock,
+                            Ok(Decision::Skip) => continue,
+                            Err(e) => {
+                                warn!("Failed to check {:?}: {}", info.device_node, e);
+                                None
+                            }
+                        };
+                        let (task, commands) = TaskHandle::channel();
+                        if power_policy.saves_motion(&info) {
+                            let task = task.clone();
+                            // Sent once the driver has set the device up.
+                            tokio::spawn(async move {
+                                if let Err(e) = task.set_motion(false).await {
+                                    debug!("Failed to turn motion off: {e:#}");
+                                }
+                            });
+                        }
+                        let sys_path = info.sys_path.clone();
+                        daemon.metrics.added += 1;
+                        daemon.devices.insert(
+                            sys_path.clone(),
+                            Handled {
+                                task,
+                                info: info.clone(),
+                                profile: None,
+                                applied: None,
+                                source: None,
+                                emulation: None,
+                            },
+                        );
+                        #[cfg(feature = "portal")]
+                        if let Some(portal) = &daemon.portal {
+                            if let Err(e) = portal.added(&info).await {
+                                warn!("Failed to add {sys_path:?} to the session bus: {e:#}");
+                            }
+                        }
+                        let events = tx.clone();
+                        let options = driver_options.clone();
+                        let task = async move {
+                            let _lock = lock;
+                            device::watch_one_device(info, commands, events, options).await
+                        };
+                        tokio::task::spawn(device::isolate(sys_path.clone(), task, tx.clone()));
+                        daemon.apply_profile(&sys_path).await;
                     }
-                    DeviceEvent::Removed(sys_path) => {
-                        if let Some(tx) = devices.remove(&sys_path) {
-                            tx.send(()).await?;
+                    DeviceEvent::Changed(info) => {
+                        // Reopen it as if it had just been plugged in.
+                        if let Some(handled) = daemon.devices.remove(&info.sys_path) {
+                            info!("{:?} changed, reopening it", info.sys_path);
+                            // Let it close the device before it's opened again.
+                            handled.task.stop().await;
+                            handled.task.stopped().await;
                         }
+                        let events = tx.clone();
+                        tokio::spawn(async move { events.send(DeviceEvent::Added(info)).await });
+                    }
+                    DeviceEvent::PermissionDenied(info) => {
+                        warn!(
+                            "No permission to open {:?} (`{}`), waiting for a udev rule to grant it",
+                            info.device_node, info.name
+                        );
+                    }
+                    DeviceEvent::Removed { sys_path, reason } => {
+                        // Only unplugging lifts a quarantine.
+                        if reason == DisconnectReason::Unplugged {
+                            daemon.quarantined.remove(&sys_path);
+                        }
+                        for released in &mut daemon.released {
+                            if released.info.as_ref().is_some_and(|i| i.sys_path == sys_path) {
+                                released.info = None;
+                            }
+                        }
+                        daemon.disconnect(&sys_path, reason).await;
+                    }
+                    DeviceEvent::AccessoryAttached { parent, accessory } => {
+                        info!("Accessory {:?} attached to {:?}", accessory.kind, parent);
                     }
+                    DeviceEvent::AccessoryDetached { parent, sys_path } => {
+                        info!("Accessory {:?} detached from {:?}", sys_path, parent);
+                    }
+                    DeviceEvent::DecodeError { sys_path, reason, data, suppressed } => {
+                        daemon.metrics.decode_errors += 1 + suppressed as u64;
+                        warn!(
+                            "Bad report from {:?} ({} more suppressed): {}\n{}",
+                            sys_path, suppressed, reason, report::hex_dump(&data)
+                        );
+                    }
+                    DeviceEvent::DriverFallback { sys_path, driver, reason } => {
+                        warn!(
+                            "The {} driver failed for {:?}, using the generic parser: {}",
+                            driver, sys_path, reason
+                        );
+                    }
+                    DeviceEvent::ParserFault { sys_path, message } => {
+                        warn!("Quarantining {:?} after a fault: {}", sys_path, message);
+                        daemon.metrics.faults += 1;
+                        daemon.disconnect(&sys_path, DisconnectReason::Quarantined).await;
+                        daemon.quarantined.insert(sys_path);
+                    }
+                }
+            }
+            Some(request) = request_rx.recv() => {
+                let shutdown = request.command == Command::Shutdown;
+                let result = daemon.handle_command(request.command).await;
+                let _ = request.reply.send(result);
+                if shutdown {
+                    info!("Shutdown requested over the control socket");
+                    break;
                 }
             }
-            _ = &mut local_set => {}
+            Some(event) = config_rx.recv() => debug!("{:?}", event),
+            _ = sessions.tick() => daemon.apply_profiles().await,
+            _ = &mut monitor, if !monitor_done => {
+                // Devices already open keep working, and `ctl` still answers.
+                warn!("Device monitor stopped, no new devices will be found");
+                monitor_done = true;
+            }
             else => break,
         };
     }
     info!("Shutting down");
+    if let Some(storage) = storage::configured() {
+        let stats = daemon.metrics_lines().join("\n") + "\n";
+        if let Err(e) = storage.write(STATS_KEY, stats.as_bytes()) {
+            warn!("Failed to save stats: {e:#}");
+        }
+    }
+    if let Some(path) = trace_path {
+        trace::write(Path::new(&path))?;
+    }
 
     Ok(())
 }
+
+#[cfg(all(test, feature = "emulation"))]
+mod tests {
+    use super::*;
+    use hidraw::device_monitor::Bus;
+
+    fn handled(task: TaskHandle) -> Handled {
+        Handled {
+            task,
+            info: DeviceInfo {
+                sys_path: PathBuf::from("/sys/devices/test/input/input0/event0"),
+                device_node: PathBuf::from("/dev/input/event0"),
+                hidraw_node: None,
+                parser: None,
+                bus: Bus::Usb,
+                name: "Test Controller".to_owned(),
+                uniq: None,
+                phys: None,
+                version: 0,
+                vendor_id: 0x045E,
+                product_id: 0x028E,
+                input_id: None,
+                usb_address: None,
+                connected_at: SystemTime::now(),
+            },
+            profile: None,
+            applied: None,
+            source: None,
+            emulation: None,
+        }
+    }
+
+    /// A device with a virtual controller on a scratch uhid node, which is
+    /// returned too.
+    async fn emulating(name: &str, target: EmulationTarget) -> (Handled, PathBuf) {
+        let (task, _commands) = TaskHandle::channel();
+        let mut handled = handled(task);
+        let node = std::env::temp_dir().join(format!("hidraw-{name}-{}", std::process::id()));
+        std::fs::write(&node, []).unwrap();
+        let device = EmulatedDevice::create_at(&node, target).await.unwrap();
+        handled.emulation = Some(Emulation::start(device, OutputLoopback::default()));
+        (handled, node)
+    }
+
+    #[tokio::test]
+    async fn switching_profiles_keeps_the_virtual_controller() {
+        let config = Config::parse(
+            "[profile a]\nemulate = xbox360\n\
+             [profile b]\noutput = tick 100\nemulate = xbox360\n\
+             [profile c]\n",
+        )
+        .unwrap();
+        let profile = |name| config.profile_as(None, name).cloned();
+        let (mut handled, node) = emulating("switch", EmulationTarget::Xbox360).await;
+        let created = std::fs::metadata(&node).unwrap().len();
+
+        for name in ["a", "b"] {
+            let staged = handled.stage(profile(name)).await.unwrap();
+            handled.switch_profile(staged).await;
+            assert_eq!(handled.applied, profile(name));
+            assert!(handled.source.is_some());
+            let target = handled.emulation.as_ref().map(Emulation::target);
+            assert_eq!(target, Some(EmulationTarget::Xbox360));
+        }
+        // Nothing was created on or written to the uhid node since.
+        assert_eq!(std::fs::metadata(&node).unwrap().len(), created);
+
+        let staged = handled.stage(profile("c")).await.unwrap();
+        handled.switch_profile(staged).await;
+        assert!(handled.source.is_some());
+        assert!(handled.emulation.is_none());
+        std::fs::remove_file(&node).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reloads_that_cant_be_applied_change_nothing() {
+        let (mut handled, node) = emulating("reload", EmulationTarget::Xbox360).await;
+        let config = Config::parse("[profile a]\nemulate = xbox360\n").unwrap();
+        let applied = config.profile_as(None, "a").cloned();
+        let staged = handled.stage(applied.clone()).await.unwrap();
+        handled.switch_profile(staged).await;
+        std::fs::remove_file(&node).unwrap();
+        let sys_path = handled.info.sys_path.clone();
+        let mut devices = HashMap::from([(sys_path.clone(), handled)]);
+
+        // One can't open its sink, the other can't create its controller.
+        for profile in [
+            "sink = midi synth /nonexistent/midi\nemulate = xbox360",
+            "emulate = dualshock4",
+        ] {
+            let text = format!("[profile b]\n{profile}\n[device 045e:028e]\nprofile = b\n");
+            let config = Config::parse(&text).unwrap();
+            assert!(stage_profiles(&mut devices, &config).await.is_err());
+            let handled = &devices[&sys_path];
+            assert_eq!(handled.applied, applied);
+            assert!(handled.source.is_some());
+            let target = handled.emulation.as_ref().map(Emulation::target);
+            assert_eq!(target, Some(EmulationTarget::Xbox360));
+        }
+    }
+}
@@ -0,0 +1,1412 @@
+use anyhow::{Context as ErrorContext, Result};
+use futures::{future, stream, Future, FutureExt, Stream};
+use log::{debug, info, warn};
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fmt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::fs::OpenOptions;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+use crate::battery::{self, Battery, BatteryLevel};
+use crate::calibration::{AxisCalibrator, CalibrationConfig};
+use crate::device::{self, Device, DeviceCommand, EvdevEvent, EvdevRumble, TaskHandle};
+use crate::device::{GamepadAxis, GamepadButton};
+use crate::device::{ABS_MT_POSITION_X, ABS_MT_POSITION_Y, ABS_MT_SLOT, ABS_MT_TRACKING_ID};
+use crate::device::{EV_ABS, EV_MSC};
+use crate::device::{EV_SYN, MSC_TIMESTAMP};
+#[cfg(feature = "udev")]
+use crate::device_monitor::monitor_devices;
+use crate::device_monitor::{Bus, DeviceEvent, DeviceInfo, DisconnectReason, MonitorConfig};
+use crate::drivers::{self, DriverOptions};
+use crate::led::{self, EvdevLeds, Led, XpadRing};
+#[cfg(feature = "portal")]
+use crate::portal::DaemonDevices;
+use crate::prediction::{AxisPredictor, PredictionConfig};
+use crate::report::TouchContact;
+use crate::report::{Axis, Button, Capabilities, Dpad, GamepadInput, ReportTimer, SensorClock};
+#[cfg(feature = "portal")]
+use crate::sandbox;
+#[cfg(not(feature = "udev"))]
+use crate::sysfs::monitor_devices;
+
+/// A small number naming a device in `GamepadEvent`s, from when it's found
+/// until it's disconnected. `DeviceManager::info` looks one up.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct DeviceId(pub u32);
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Whether the `DeviceId` of a device that's gone can name another.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum IdReuse {
+    /// Every device gets a new ID, like SDL's instance IDs, so a stale ID
+    /// never names the wrong device.
+    #[default]
+    Never,
+    /// Each device gets the lowest ID not in use, like player numbers. Events
+    /// of a device that just went can still be queued when its ID is reused.
+    Lowest,
+}
+
+/// Hands out `DeviceId`s.
+#[derive(Default)]
+struct IdAllocator {
+    reuse: IdReuse,
+    next: u32,
+    in_use: BTreeSet<u32>,
+}
+
+impl IdAllocator {
+    fn allocate(&mut self) -> DeviceId {
+        let id = match self.reuse {
+            IdReuse::Never => {
+                self.next += 1;
+                self.next - 1
+            }
+            IdReuse::Lowest => (0..).find(|id| !self.in_use.contains(id)).unwrap(),
+        };
+        self.in_use.insert(id);
+        DeviceId(id)
+    }
+
+    fn release(&mut self, id: DeviceId) {
+        self.in_use.remove(&id.0);
+    }
+}
+
+/// What happened to a gamepad, as seen by applications embedding the crate.
+#[derive(Debug)]
+pub enum GamepadEvent {
+    Connected {
+        device: DeviceId,
+        info: DeviceInfo,
+    },
+    /// A gamepad was found but can't be opened, usually for lack of a udev
+    /// rule granting access. It's `Connected`, with the same ID, once its
+    /// permissions change.
+    PermissionDenied {
+        device: DeviceId,
+        info: DeviceInfo,
+    },
+    Disconnected {
+        device: DeviceId,
+        reason: DisconnectReason,
+        /// How long the device was connected.
+        duration: Duration,
+    },
+    /// A Bluetooth controller that disconnected came back as `new_id`, in
+    /// place of `Connected`. Its calibration and prediction settings carry
+    /// over from `old_id`.
+    Reconnected {
+        old_id: DeviceId,
+        new_id: DeviceId,
+        info: DeviceInfo,
+    },
+    Button {
+        device: DeviceId,
+        button: Button,
+        pressed: bool,
+    },
+    /// Sticks are in the range -1.0..=1.0 and triggers 0.0..=1.0. With
+    /// prediction enabled for the device, `value` is extrapolated ahead of the
+    /// report and `predicted` is set.
+    Axis {
+        device: DeviceId,
+        axis: Axis,
+        value: f32,
+        predicted: bool,
+    },
+    Dpad {
+        device: DeviceId,
+        dpad: Dpad,
+    },
+    /// Sent when a device with a battery connects, then whenever the level
+    /// or charging state changes.
+    Battery {
+        device: DeviceId,
+        level: BatteryLevel,
+    },
+    /// A motion sensor sample, with acceleration in G and angular velocity in
+    /// degrees per second. `timestamp` is from the controller's clock, with an
+    /// arbitrary start.
+    Motion {
+        device: DeviceId,
+        accel: [f32; 3],
+        gyro: [f32; 3],
+        timestamp: Duration,
+    },
+    /// A touchpad contact moved, or a finger touched or left the touchpad.
+    /// `x` and `y` are 0.0..=1.0 from the top left, and `id` stays the same
+    /// while a finger is down.
+    Touch {
+        device: DeviceId,
+        id: u32,
+        x: f32,
+        y: f32,
+        pressed: bool,
+    },
+    /// A report from the device couldn't be decoded. These are rate limited;
+    /// `suppressed` counts the errors dropped since the last one.
+    DecodeError {
+        device: DeviceId,
+        reason: String,
+        data: Vec<u8>,
+        suppressed: u32,
+    },
+}
+
+/// What a device's event queue does when it's full, because the application
+/// isn't taking events as fast as the device sends them.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Overflow {
+    /// Wait for room, holding up the device's reads until the application
+    /// catches up. No event is lost.
+    #[default]
+    Block,
+    /// Drop the device's oldest queued event to make room.
+    DropOldest,
+    /// Drop the queued event an axis or motion event supersedes: the one for
+    /// the same axis, or the last motion sample. Anything else waits for room
+    /// like `Block`.
+    CoalesceAxes,
+}
+
+/// How many events each device can have queued, by default.
+const EVENT_CAPACITY: usize = 64;
+
+/// A device's events on their way to the application, so that a device
+/// sending faster than the application takes them only holds up itself.
+struct EventQueue {
+    events: Mutex<VecDeque<GamepadEvent>>,
+    capacity: usize,
+    overflow: Overflow,
+    /// Woken when events are taken, for senders waiting for room.
+    space: Notify,
+    /// Shared by every device's queue, woken when one gets an event.
+    ready: Arc<Notify>,
+    /// Events dropped or coalesced away for lack of room.
+    dropped: AtomicU64,
+}
+
+impl EventQueue {
+    fn new(capacity: usize, overflow: Overflow, ready: Arc<Notify>) -> EventQueue {
+        EventQueue {
+            events: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity: capacity.max(1),
+            overflow,
+            space: Notify::new(),
+            ready,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    async fn send(&self, event: GamepadEvent) {
+        loop {
+            // Made before looking, so room made in between isn't missed.
+            let space = self.space.notified();
+            {
+                let mut events = self.events.lock().unwrap();
+                let superseded = match self.overflow {
+                    _ if events.len() < self.capacity => None,
+                    Overflow::Block => None,
+                    Overflow::DropOldest => Some(0),
+                    Overflow::CoalesceAxes => events.iter().rposition(|q| supersedes(&event, q)),
+                };
+                if events.len() < self.capacity || superseded.is_some() {
+                    if let Some(index) = superseded {
+                        events.remove(index);
+                        self.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                    events.push_back(event);
+                    self.ready.notify_one();
+                    return;
+                }
+            }
+            space.await;
+        }
+    }
+
+    fn pop(&self) -> Option<GamepadEvent> {
+        let event = self.events.lock().unwrap().pop_front();
+        if event.is_some() {
+            self.space.notify_waiters();
+        }
+        event
+    }
+
+    fn drain(&self) -> Vec<GamepadEvent> {
+        let events: Vec<_> = self.events.lock().unwrap().drain(..).collect();
+        self.space.notify_waiters();
+        events
+    }
+}
+
+/// Whether `event` makes `queued` stale, for `Overflow::CoalesceAxes`.
+fn supersedes(event: &GamepadEvent, queued: &GamepadEvent) -> bool {
+    match (event, queued) {
+        (GamepadEvent::Axis { axis, .. }, GamepadEvent::Axis { axis: queued, .. }) => {
+            axis == queued
+        }
+        (GamepadEvent::Motion { .. }, GamepadEvent::Motion { .. }) => true,
+        _ => false,
+    }
+}
+
+/// A device's controls as of its latest events, for applications that poll
+/// once a frame instead of handling every event.
+#[derive(Clone, Debug, Default)]
+pub struct GamepadState {
+    /// Indexed by `Axis as usize`, with the values sent in `GamepadEvent::Axis`.
+    pub axes: [f32; 6],
+    /// Bit `Button as usize` is set while that button is held.
+    pub buttons: u32,
+    pub dpad: Dpad,
+}
+
+impl GamepadState {
+    pub fn button(&self, button: Button) -> bool {
+        self.buttons & (1 << button as usize) != 0
+    }
+
+    pub fn axis(&self, axis: Axis) -> f32 {
+        self.axes[axis as usize]
+    }
+
+    fn apply(&mut self, event: &GamepadEvent) {
+        match event {
+            GamepadEvent::Button {
+                button, pressed, ..
+            } => {
+                let bit = 1 << *button as usize;
+                if *pressed {
+                    self.buttons |= bit;
+                } else {
+                    self.buttons &= !bit;
+                }
+            }
+            GamepadEvent::Axis { axis, value, .. } => self.axes[*axis as usize] = *value,
+            GamepadEvent::Dpad { dpad, .. } => self.dpad = dpad.clone(),
+            _ => {}
+        }
+    }
+}
+
+/// What a device's task shares with its `GamepadHandle`.
+#[derive(Default)]
+struct DeviceShared {
+    report_timer: Mutex<ReportTimer>,
+    predictor: Mutex<Option<AxisPredictor>>,
+    calibrator: Mutex<AxisCalibrator>,
+    state: Mutex<GamepadState>,
+}
+
+/// Read a device's evdev node, translating its events into `GamepadEvent`s,
+/// timing its reports, normalizing and predicting its axes, and keeping its
+/// state in `shared`. Button, axis and d-pad events are only sent while
+/// `input_events` is set.
+async fn read_device(
+    id: DeviceId,
+    device_node: &Path,
+    shared: Arc<DeviceShared>,
+    input_events: Arc<AtomicBool>,
+    queue: &EventQueue,
+) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .open(device_node)
+        .await
+        .with_context(|| format!("Failed to open {device_node:?}"))?;
+    // Axes are normalized from the ranges the kernel reports, rather than
+    // assuming 0..=255.
+    let kernel_info = device::kernel_abs_info(file.as_raw_fd());
+    debug!("{device_node:?} reports its axes as {kernel_info:?}");
+    shared
+        .calibrator
+        .lock()
+        .unwrap()
+        .set_kernel_info(kernel_info);
+    let mut dpad = Dpad::default();
+    loop {
+        let event = device::read_input_event(&mut file).await?;
+        let device = id;
+        let gamepad_event = match device::decode_event(&event) {
+            EvdevEvent::Button {
+                button:
+                    button @ (GamepadButton::DpadUp
+                    | GamepadButton::DpadDown
+                    | GamepadButton::DpadLeft
+                    | GamepadButton::DpadRight),
+                pressed,
+            } => {
+                match button {
+                    GamepadButton::DpadUp => dpad.up = pressed,
+                    GamepadButton::DpadDown => dpad.down = pressed,
+                    GamepadButton::DpadLeft => dpad.left = pressed,
+                    _ => dpad.right = pressed,
+                }
+                Some(GamepadEvent::Dpad {
+                    device,
+                    dpad: dpad.clone(),
+                })
+            }
+            EvdevEvent::Button { button, pressed } => {
+                button.button().map(|button| GamepadEvent::Button {
+                    device,
+                    button,
+                    pressed,
+                })
+            }
+            EvdevEvent::Axis {
+                axis: GamepadAxis::Hat0X,
+                value,
+            } => {
+                dpad.left = value < 0;
+                dpad.right = value > 0;
+                Some(GamepadEvent::Dpad {
+                    device,
+                    dpad: dpad.clone(),
+                })
+            }
+            EvdevEvent::Axis {
+                axis: GamepadAxis::Hat0Y,
+                value,
+            } => {
+                dpad.up = value < 0;
+                dpad.down = value > 0;
+                Some(GamepadEvent::Dpad {
+                    device,
+                    dpad: dpad.clone(),
+                })
+            }
+            EvdevEvent::Axis { axis, value } => axis.axis().map(|axis| {
+                let value = shared.calibrator.lock().unwrap().normalize(axis, value);
+                let predicted = shared
+                    .predictor
+                    .lock()
+                    .unwrap()
+                    .as_mut()
+                    .map(|predictor| predictor.predict(axis, value, Instant::now()));
+                GamepadEvent::Axis {
+                    device,
+                    axis,
+                    value: predicted.unwrap_or(value),
+                    predicted: predicted.is_some(),
+                }
+            }),
+            EvdevEvent::Sync => {
+                // Each report from the device ends with a sync.
+                shared.report_timer.lock().unwrap().record(Instant::now());
+                None
+            }
+            EvdevEvent::Other { .. } => None,
+        };
+        if let Some(gamepad_event) = gamepad_event {
+            shared.state.lock().unwrap().apply(&gamepad_event);
+            if !input_events.load(Ordering::Relaxed) {
+                continue;
+            }
+            queue.send(gamepad_event).await;
+        }
+    }
+}
+
+/// Read a device through its driver, in a `device::watch_one_device` task
+/// driven by `commands`, translating each input state it decodes into events
+/// for what changed. Like `read_device`, it keeps the device's state in
+/// `shared` and only sends button, axis and d-pad events while `input_events`
+/// is set. Reports that fail to decode go to `events`.
+async fn read_driver(
+    id: DeviceId,
+    info: DeviceInfo,
+    (task, commands): (TaskHandle, Receiver<DeviceCommand>),
+    events: Sender<DeviceEvent>,
+    shared: Arc<DeviceShared>,
+    input_events: Arc<AtomicBool>,
+    queue: &EventQueue,
+) -> Result<()> {
+    // Subscribed before the task starts, so it doesn't miss the first report.
+    let mut inputs = task.subscribe().await;
+    let watch = device::watch_one_device(info, commands, events, DriverOptions::default());
+    let read = async {
+        let mut last = GamepadInput::default();
+        // Ends once the task stops and what it decoded has been sent.
+        while let Some(input) = inputs.recv().await {
+            shared.report_timer.lock().unwrap().record(Instant::now());
+            for event in input_changes(id, &last, &input, &shared) {
+                shared.state.lock().unwrap().apply(&event);
+                let motion = matches!(
+                    event,
+                    GamepadEvent::Motion { .. } | GamepadEvent::Touch { .. }
+                );
+                if motion || input_events.load(Ordering::Relaxed) {
+                    queue.send(event).await;
+                }
+            }
+            last = input;
+        }
+    };
+    let (result, ()) = tokio::join!(watch, read);
+    result
+}
+
+/// The events for what changed from `last` to `input`, normalizing and
+/// predicting axes like `read_device`. Every motion sample is sent.
+fn input_changes(
+    device: DeviceId,
+    last: &GamepadInput,
+    input: &GamepadInput,
+    shared: &DeviceShared,
+) -> Vec<GamepadEvent> {
+    let mut events = Vec::new();
+    for button in Button::ALL {
+        let pressed = input.button(button);
+        if pressed != last.button(button) {
+            events.push(GamepadEvent::Button {
+                device,
+                button,
+                pressed,
+            });
+        }
+    }
+    for axis in Axis::ALL {
+        if input.axis(axis) == last.axis(axis) {
+            continue;
+        }
+        let value = shared
+            .calibrator
+            .lock()
+            .unwrap()
+            .adjust(axis, input.axis(axis));
+        let predicted = shared
+            .predictor
+            .lock()
+            .unwrap()
+            .as_mut()
+            .map(|predictor| predictor.predict(axis, value, Instant::now()));
+        events.push(GamepadEvent::Axis {
+            device,
+            axis,
+            value: predicted.unwrap_or(value),
+            predicted: predicted.is_some(),
+        });
+    }
+    if input.dpad != last.dpad {
+        events.push(GamepadEvent::Dpad {
+            device,
+            dpad: input.dpad.clone(),
+        });
+    }
+    if let Some(motion) = &input.motion {
+        events.push(GamepadEvent::Motion {
+            device,
+            accel: motion.accel,
+            gyro: motion.gyro,
+            timestamp: motion.timestamp,
+        });
+    }
+    for (slot, contact) in input.touches.iter().enumerate() {
+        if last.touches.get(slot).copied().unwrap_or_default() != *contact {
+            events.push(GamepadEvent::Touch {
+                device,
+                id: contact.id,
+                x: contact.x,
+                y: contact.y,
+                pressed: contact.pressed,
+            });
+        }
+    }
+    events
+}
+
+/// Read a controller's motion sensor node, sending `GamepadEvent::Motion` for
+/// every sample. The kernel drivers apply the controller's calibration.
+async fn read_motion(id: DeviceId, motion_node: &Path, queue: &EventQueue) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .open(motion_node)
+        .await
+        .with_context(|| format!("Failed to open {motion_node:?}"))?;
+    // Accelerometer X, Y, Z then gyro X, Y, Z, in units per G or degree per
+    // second.
+    let mut resolution = [1.0; 6];
+    for (code, resolution) in resolution.iter_mut().enumerate() {
+        let info = device::abs_info(file.as_raw_fd(), code as u16)?;
+        *resolution = info.resolution.max(1) as f32;
+    }
+    // MSC_TIMESTAMP is in microseconds.
+    let mut clock = SensorClock::new(32, 1_000_000);
+    let mut values = [0.0; 6];
+    let mut timestamp = Duration::ZERO;
+    loop {
+        let event = device::read_input_event(&mut file).await?;
+        match (event.type_, event.code) {
+            (EV_ABS, code @ 0..=5) => {
+                values[code as usize] = event.value as f32 / resolution[code as usize];
+            }
+            (EV_MSC, MSC_TIMESTAMP) => timestamp = clock.update(event.value as u32),
+            (EV_SYN, _) => {
+                let motion = GamepadEvent::Motion {
+                    device: id,
+                    accel: [values[0], values[1], values[2]],
+                    gyro: [values[3], values[4], values[5]],
+                    timestamp,
+                };
+                queue.send(motion).await;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The most contacts a touchpad can report. Sony's report two.
+const MAX_TOUCH_SLOTS: usize = 10;
+
+/// Read a controller's touchpad node, sending `GamepadEvent::Touch` for each
+/// contact that changes.
+async fn read_touchpad(id: DeviceId, touchpad_node: &Path, queue: &EventQueue) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .open(touchpad_node)
+        .await
+        .with_context(|| format!("Failed to open {touchpad_node:?}"))?;
+    let fd = file.as_raw_fd();
+    let (x_info, y_info) = (
+        device::abs_info(fd, ABS_MT_POSITION_X)?,
+        device::abs_info(fd, ABS_MT_POSITION_Y)?,
+    );
+    let normalize = |value: i32, info: &device::AbsInfo| {
+        let range = (info.maximum - info.minimum).max(1) as f32;
+        ((value - info.minimum) as f32 / range).clamp(0.0, 1.0)
+    };
+    // Each slot's contact, and whether it changed since the last sync.
+    let mut slots = [(TouchContact::default(), false); MAX_TOUCH_SLOTS];
+    let mut slot = 0;
+    loop {
+        let event = device::read_input_event(&mut file).await?;
+        let (contact, changed) = &mut slots[slot];
+        match (event.type_, event.code) {
+            (EV_ABS, ABS_MT_SLOT) => {
+                slot = (event.value.max(0) as usize).min(MAX_TOUCH_SLOTS - 1);
+                continue;
+            }
+            (EV_ABS, ABS_MT_TRACKING_ID) => {
+                // -1 when the finger is lifted.
+                contact.pressed = event.value >= 0;
+                if contact.pressed {
+                    contact.id = event.value as u32;
+                }
+            }
+            (EV_ABS, ABS_MT_POSITION_X) => contact.x = normalize(event.value, &x_info),
+            (EV_ABS, ABS_MT_POSITION_Y) => contact.y = normalize(event.value, &y_info),
+            (EV_SYN, _) => {
+                for (contact, changed) in slots.iter_mut().filter(|(_, changed)| *changed) {
+                    *changed = false;
+                    let touch = GamepadEvent::Touch {
+                        device: id,
+                        id: contact.id,
+                        x: contact.x,
+                        y: contact.y,
+                        pressed: contact.pressed,
+                    };
+                    queue.send(touch).await;
+                }
+                continue;
+            }
+            _ => continue,
+        }
+        *changed = true;
+    }
+}
+
+/// Poll a battery, sending `GamepadEvent::Battery` when it changes.
+async fn watch_battery(id: DeviceId, sys_path: PathBuf, battery: Battery, queue: &EventQueue) {
+    let mut last = None;
+    let mut interval = tokio::time::interval(battery::POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        let level = match battery.read() {
+            Ok(level) => level,
+            Err(e) => {
+                debug!("Stopped watching the battery of {sys_path:?}: {e:#}");
+                return;
+            }
+        };
+        if last == Some(level) {
+            continue;
+        }
+        last = Some(level);
+        queue
+            .send(GamepadEvent::Battery { device: id, level })
+            .await;
+    }
+}
+
+/// How long a Bluetooth controller's settings are kept after it disconnects,
+/// for when it reconnects.
+const RECONNECT_WINDOW: Duration = Duration::from_secs(300);
+
+/// The settings of a Bluetooth controller that disconnected.
+struct Departed {
+    id: DeviceId,
+    sys_path: PathBuf,
+    settings: DeviceSettings,
+    at: Instant,
+}
+
+/// What the application set for a device, kept when it's reopened or
+/// reconnects.
+#[derive(Clone)]
+struct DeviceSettings {
+    calibration: CalibrationConfig,
+    prediction: Option<PredictionConfig>,
+}
+
+/// What identifies a controller across reconnects: the MAC address of a
+/// Bluetooth one. Their sys paths change every time.
+fn reconnect_key(info: &DeviceInfo) -> Option<&str> {
+    let uniq = info.uniq.as_deref().filter(|uniq| !uniq.is_empty())?;
+    (info.bus == Bus::Bluetooth).then_some(uniq)
+}
+
+/// A device the manager is reading.
+struct ManagedDevice {
+    id: DeviceId,
+    task: JoinHandle<()>,
+    info: DeviceInfo,
+    /// Opened on first use, and kept open since the kernel drops uploaded
+    /// effects when the fd is closed.
+    rumble: Option<EvdevRumble>,
+    /// Where LEDs are set, opened on first use.
+    leds: Option<LedOutput>,
+    /// The task reading the device through its driver, if it has one, which
+    /// rumble goes through.
+    driver: Option<TaskHandle>,
+    shared: Arc<DeviceShared>,
+    queue: Arc<EventQueue>,
+}
+
+impl ManagedDevice {
+    fn settings(&self) -> DeviceSettings {
+        DeviceSettings {
+            calibration: self.shared.calibrator.lock().unwrap().config().clone(),
+            prediction: self
+                .shared
+                .predictor
+                .lock()
+                .unwrap()
+                .as_ref()
+                .map(|p| p.config),
+        }
+    }
+}
+
+enum LedOutput {
+    Hidraw(Device),
+    XpadRing(XpadRing),
+    Evdev(EvdevLeds),
+}
+
+impl LedOutput {
+    fn open(info: &DeviceInfo) -> Result<LedOutput> {
+        if let Some(hidraw_node) = info
+            .hidraw_node
+            .as_ref()
+            .filter(|_| led::has_led_reports(info))
+        {
+            return Ok(LedOutput::Hidraw(Device::open(hidraw_node)?));
+        }
+        if let Some(ring) = XpadRing::find(&info.sys_path) {
+            return Ok(LedOutput::XpadRing(ring));
+        }
+        Ok(LedOutput::Evdev(EvdevLeds::open(&info.device_node)?))
+    }
+
+    fn set(&mut self, info: &DeviceInfo, led: Led) -> Result<()> {
+        match self {
+            LedOutput::Hidraw(device) => Ok(device.set_led(info, led)?),
+            LedOutput::XpadRing(ring) => ring.set(led),
+            LedOutput::Evdev(leds) => leds.set(led),
+        }
+    }
+}
+
+/// One of a `DeviceManager`'s devices, for output.
+pub struct GamepadHandle<'a> {
+    device: &'a mut ManagedDevice,
+}
+
+impl GamepadHandle<'_> {
+    pub fn info(&self) -> &DeviceInfo {
+        &self.device.info
+    }
+
+    /// The device's controls and link, with the report interval measured from
+    /// its reports once enough have arrived, since devices often report
+    /// slower than they're polled.
+    pub fn capabilities(&self) -> Capabilities {
+        let mut capabilities = self.device.info.capabilities();
+        if let Some(interval) = self.device.shared.report_timer.lock().unwrap().interval() {
+            capabilities.report_interval = Some(interval);
+        }
+        capabilities
+    }
+
+    /// The device's controls as of its latest events, whether or not they've
+    /// been taken from the manager yet.
+    pub fn state(&self) -> GamepadState {
+        self.device.shared.state.lock().unwrap().clone()
+    }
+
+    /// How many of the device's events have been dropped or coalesced away
+    /// because the application didn't take them in time.
+    pub fn dropped_events(&self) -> u64 {
+        self.device.queue.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Rumble for `duration_ms`, or until replaced if it's 0. Magnitudes are
+    /// 0 (off) to 0xFFFF (full).
+    pub async fn rumble(&mut self, strong: u16, weak: u16, duration_ms: u32) -> Result<()> {
+        if let Some(task) = &self.device.driver {
+            return task.rumble(strong, weak, duration_ms).await;
+        }
+        if self.device.rumble.is_none() {
+            self.device.rumble = Some(EvdevRumble::open(&self.device.info.device_node)?);
+        }
+        let rumble = self.device.rumble.as_ref().unwrap();
+        Ok(rumble.rumble(strong, weak, duration_ms).await?)
+    }
+
+    /// Extrapolate the device's axes ahead of its reports, or stop with
+    /// `None`. Meant for Bluetooth pads, whose latency is often 10ms or more.
+    pub fn set_prediction(&mut self, config: Option<PredictionConfig>) {
+        *self.device.shared.predictor.lock().unwrap() = config.map(AxisPredictor::new);
+    }
+
+    /// Override the manager's calibration for this device.
+    pub fn set_calibration(&mut self, config: CalibrationConfig) {
+        self.device
+            .shared
+            .calibrator
+            .lock()
+            .unwrap()
+            .set_config(config);
+    }
+
+    pub fn calibration(&self) -> CalibrationConfig {
+        self.device
+            .shared
+            .calibrator
+            .lock()
+            .unwrap()
+            .config()
+            .clone()
+    }
+
+    /// Set the lightbar or player LEDs: with the controller's own output
+    /// reports on Sony and Switch Pro controllers, the ring on Xbox 360
+    /// controllers, and evdev LEDs otherwise.
+    pub fn set_led(&mut self, led: Led) -> Result<()> {
+        let info = &self.device.info;
+        if self.device.leds.is_none() {
+            self.device.leds = Some(LedOutput::open(info)?);
+        }
+        self.device.leds.as_mut().unwrap().set(info, led)
+    }
+}
+
+impl Drop for ManagedDevice {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Watches for gamepads and reads them all, producing a single stream of
+/// `GamepadEvent`s. This is what `main` does, packaged for embedding.
+pub struct DeviceManager {
+    monitor: Pin<Box<dyn Future<Output = ()> + Send>>,
+    monitor_done: bool,
+    device_rx: Receiver<DeviceEvent>,
+    /// For device tasks to report decode errors through, like the monitor.
+    device_tx: Sender<DeviceEvent>,
+    /// Woken when any device's queue gets an event.
+    ready: Arc<Notify>,
+    /// Events taken from the queues or made by the manager, in the order
+    /// they're to be returned.
+    pending: VecDeque<GamepadEvent>,
+    event_capacity: usize,
+    overflow: Overflow,
+    devices: HashMap<PathBuf, ManagedDevice>,
+    input_events: Arc<AtomicBool>,
+    calibration: CalibrationConfig,
+    /// By vendor and product ID, replacing `calibration`.
+    device_calibration: HashMap<(u16, u16), CalibrationConfig>,
+    /// By `reconnect_key`.
+    departed: HashMap<String, Departed>,
+    /// Of every device found and not yet gone, including ones we can't open.
+    ids: HashMap<PathBuf, DeviceId>,
+    id_allocator: IdAllocator,
+}
+
+/// Monitor devices directly, or inside Flatpak through the daemon on the
+/// session bus if it's running there.
+fn start_monitor(
+    tx: Sender<DeviceEvent>,
+    config: MonitorConfig,
+) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+    #[cfg(feature = "portal")]
+    if sandbox::in_flatpak() {
+        return Box::pin(async move {
+            match DaemonDevices::connect().await {
+                Ok(devices) => {
+                    if let Err(e) = devices.monitor(tx).await {
+                        warn!("Stopped reading devices through the daemon: {e:#}");
+                    }
+                }
+                Err(e) => {
+                    warn!("Reading devices directly: {e:#}");
+                    sandbox::log_access();
+                    monitor_devices(tx, config).await
+                }
+            }
+        });
+    }
+    monitor_devices(tx, config)
+}
+
+impl DeviceManager {
+    /// Start monitoring gamepads. Must be called within a tokio runtime.
+    pub fn new() -> DeviceManager {
+        DeviceManager::with_config(MonitorConfig::new())
+    }
+
+    /// Start monitoring the devices `config` selects.
+    pub fn with_config(config: MonitorConfig) -> DeviceManager {
+        let (device_tx, device_rx) = mpsc::channel(4);
+        DeviceManager {
+            monitor: start_monitor(device_tx.clone(), config),
+            monitor_done: false,
+            device_rx,
+            device_tx,
+            ready: Arc::new(Notify::new()),
+            pending: VecDeque::new(),
+            event_capacity: EVENT_CAPACITY,
+            overflow: Overflow::default(),
+            devices: HashMap::new(),
+            input_events: Arc::new(AtomicBool::new(true)),
+            calibration: CalibrationConfig::default(),
+            device_calibration: HashMap::new(),
+            departed: HashMap::new(),
+            ids: HashMap::new(),
+            id_allocator: IdAllocator::default(),
+        }
+    }
+
+    /// Whether IDs of devices that are gone can be given to new ones.
+    pub fn id_reuse(mut self, reuse: IdReuse) -> DeviceManager {
+        self.id_allocator.reuse = reuse;
+        self
+    }
+
+    /// How many events each device found from now on can have queued for the
+    /// application, 64 by default. Each device has its own queue, so one that
+    /// sends faster than the application keeps up, like a 1000 Hz pad or a
+    /// motion sensor, can't hold up the others or the device monitor.
+    pub fn event_capacity(mut self, capacity: usize) -> DeviceManager {
+        self.event_capacity = capacity;
+        self
+    }
+
+    /// What the queues of devices found from now on do when they're full.
+    pub fn overflow(mut self, overflow: Overflow) -> DeviceManager {
+        self.overflow = overflow;
+        self
+    }
+
+    /// How to normalize the axes of devices found from now on.
+    pub fn calibration(mut self, config: CalibrationConfig) -> DeviceManager {
+        self.calibration = config;
+        self
+    }
+
+    /// How to normalize the axes of one model of device, instead of the
+    /// manager's calibration. Can be called more than once.
+    pub fn device_calibration(
+        mut self,
+        vendor_id: u16,
+        product_id: u16,
+        config: CalibrationConfig,
+    ) -> DeviceManager {
+        self.device_calibration
+            .insert((vendor_id, product_id), config);
+        self
+    }
+
+    /// Whether to send button, axis and d-pad events. Applications that poll
+    /// `GamepadHandle::state` each frame can turn them off so they don't
+    /// pile up; connection and other events are still sent.
+    pub fn set_input_events(&mut self, enabled: bool) {
+        self.input_events.store(enabled, Ordering::Relaxed);
+    }
+
+    /// The ID of the device at `sys_path`, given it now if it has none.
+    fn id(&mut self, sys_path: &Path) -> DeviceId {
+        if let Some(id) = self.ids.get(sys_path) {
+            return *id;
+        }
+        let id = self.id_allocator.allocate();
+        self.ids.insert(sys_path.to_owned(), id);
+        id
+    }
+
+    /// Stop reading a device, keeping the events it had queued.
+    fn remove_device(&mut self, sys_path: &Path) -> Option<ManagedDevice> {
+        let device = self.devices.remove(sys_path)?;
+        self.pending.extend(device.queue.drain());
+        Some(device)
+    }
+
+    fn disconnect(&mut self, sys_path: PathBuf, reason: DisconnectReason) -> Option<GamepadEvent> {
+        if let Some(id) = self.ids.remove(&sys_path) {
+            self.id_allocator.release(id);
+        }
+        let device = self.remove_device(&sys_path)?;
+        if let Some(key) = reconnect_key(&device.info) {
+            self.departed
+                .retain(|_, d| d.at.elapsed() < RECONNECT_WINDOW);
+            let departed = Departed {
+                id: device.id,
+                sys_path: sys_path.clone(),
+                settings: device.settings(),
+                at: Instant::now(),
+            };
+            self.departed.insert(key.to_owned(), departed);
+        }
+        Some(GamepadEvent::Disconnected {
+            device: device.id,
+            reason,
+            duration: device.info.connected_at.elapsed().unwrap_or_default(),
+        })
+    }
+
+    /// Start reading a device, returning its `Connected` or `Reconnected`.
+    /// `settings` are those of the device if it's being reopened.
+    fn add(&mut self, info: DeviceInfo, settings: Option<DeviceSettings>) -> GamepadEvent {
+        let id = self.id(&info.sys_path);
+        let sys_path = info.sys_path.clone();
+        let device_node = info.device_node.clone();
+        let queue = Arc::new(EventQueue::new(
+            self.event_capacity,
+            self.overflow,
+            self.ready.clone(),
+        ));
+        let device_queue = queue.clone();
+        let battery = info.battery();
+        // Drivers decode motion and touches along with the rest, so the
+        // kernel's sensor nodes would only repeat them.
+        let has_driver = info.hidraw_node.is_some() && drivers::probe(&info).is_some();
+        let (motion_node, touchpad_node) = if has_driver {
+            (None, None)
+        } else {
+            (info.motion_sensors(), info.touchpad())
+        };
+        let driver = has_driver.then(TaskHandle::channel);
+        let driver_task = driver.as_ref().map(|(task, _)| task.clone());
+        let driver_info = info.clone();
+        let events = self.device_tx.clone();
+        let departed = reconnect_key(&info)
+            .and_then(|key| self.departed.remove(key))
+            .filter(|d| d.at.elapsed() < RECONNECT_WINDOW);
+        let settings = settings
+            .or_else(|| departed.as_ref().map(|d| d.settings.clone()))
+            .unwrap_or_else(|| DeviceSettings {
+                calibration: self
+                    .device_calibration
+                    .get(&(info.vendor_id, info.product_id))
+                    .unwrap_or(&self.calibration)
+                    .clone(),
+                prediction: None,
+            });
+        let DeviceSettings {
+            calibration,
+            prediction,
+        } = settings;
+        let shared = Arc::new(DeviceShared {
+            calibrator: Mutex::new(AxisCalibrator::new(calibration)),
+            predictor: Mutex::new(prediction.map(AxisPredictor::new)),
+            ..Default::default()
+        });
+        let device_shared = shared.clone();
+        let input_events = self.input_events.clone();
+        let task = tokio::spawn(async move {
+            let queue = &*device_queue;
+            let battery = async {
+                if let Some(battery) = battery {
+                    watch_battery(id, sys_path.clone(), battery, queue).await;
+                }
+                // Keep reading the device after the battery goes away.
+                future::pending::<()>().await
+            };
+            let motion = async {
+                if let Some(motion_node) = motion_node {
+                    if let Err(e) = read_motion(id, &motion_node, queue).await {
+                        debug!("Stopped reading {motion_node:?}: {e:#}");
+                    }
+                }
+                future::pending::<()>().await
+            };
+            let touchpad = async {
+                if let Some(touchpad_node) = touchpad_node {
+                    if let Err(e) = read_touchpad(id, &touchpad_node, queue).await {
+                        debug!("Stopped reading {touchpad_node:?}: {e:#}");
+                    }
+                }
+                future::pending::<()>().await
+            };
+            let input = match driver {
+                Some(driver) => {
+                    let shared = device_shared;
+                    read_driver(id, driver_info, driver, events, shared, input_events, queue)
+                        .boxed()
+                }
+                None => read_device(id, &device_node, device_shared, input_events, queue).boxed(),
+            };
+            tokio::select! {
+                result = input => {
+                    if let Err(e) = result {
+                        debug!("Stopped reading {device_node:?}: {e}");
+                    }
+                }
+                _ = battery => {}
+                _ = motion => {}
+                _ = touchpad => {}
+            }
+        });
+        let device = ManagedDevice {
+            id,
+            task,
+            info: info.clone(),
+            rumble: None,
+            leds: None,
+            driver: driver_task,
+            shared,
+            queue,
+        };
+        self.devices.insert(info.sys_path.clone(), device);
+        match departed {
+            Some(departed) => {
+                info!("{:?} reconnected as {:?}", departed.sys_path, info.sys_path);
+                GamepadEvent::Reconnected {
+                    old_id: departed.id,
+                    new_id: id,
+                    info,
+                }
+            }
+            None => GamepadEvent::Connected { device: id, info },
+        }
+    }
+
+    /// Act on a monitor event, queueing what the application should hear of.
+    fn handle(&mut self, event: DeviceEvent) {
+        let event = match event {
+            DeviceEvent::Added(info) => Some(self.add(info, None)),
+            DeviceEvent::PermissionDenied(info) => Some(GamepadEvent::PermissionDenied {
+                device: self.id(&info.sys_path),
+                info,
+            }),
+            DeviceEvent::Changed(info) => {
+                // Reopen it, only telling the application if it's new to it.
+                let settings = self
+                    .remove_device(&info.sys_path)
+                    .map(|device| device.settings());
+                let known = settings.is_some();
+                let event = self.add(info, settings);
+                Some(event).filter(|_| !known)
+            }
+            DeviceEvent::Removed { sys_path, reason } => self.disconnect(sys_path, reason),
+            DeviceEvent::ParserFault { sys_path, message } => {
+                warn!("Dropping {sys_path:?} after a fault: {message}");
+                self.disconnect(sys_path, DisconnectReason::Quarantined)
+            }
+            DeviceEvent::DecodeError {
+                sys_path,
+                reason,
+                data,
+                suppressed,
+            } => Some(GamepadEvent::DecodeError {
+                device: self.id(&sys_path),
+                reason,
+                data,
+                suppressed,
+            }),
+            DeviceEvent::AccessoryAttached { .. }
+            | DeviceEvent::AccessoryDetached { .. }
+            | DeviceEvent::DriverFallback { .. } => None,
+        };
+        self.pending.extend(event);
+    }
+
+    /// Take the next event of each device that has one, so a busy device
+    /// can't starve the others. Returns whether there were any.
+    fn take_queued(&mut self) -> bool {
+        let before = self.pending.len();
+        for device in self.devices.values() {
+            self.pending.extend(device.queue.pop());
+        }
+        self.pending.len() > before
+    }
+
+    /// Wait for the next event. Returns `None` once the monitor has stopped and
+    /// every device it found is gone. Each device's events come in order, but
+    /// different devices' events can be interleaved differently than they
+    /// happened.
+    pub async fn next_event(&mut self) -> Option<GamepadEvent> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(event);
+            }
+            if self.take_queued() {
+                continue;
+            }
+            if self.monitor_done && self.devices.is_empty() {
+                return None;
+            }
+            tokio::select! {
+                _ = self.ready.notified() => {}
+                Some(event) = self.device_rx.recv() => self.handle(event),
+                _ = &mut self.monitor, if !self.monitor_done => {
+                    info!("Device monitor stopped");
+                    self.monitor_done = true;
+                }
+            }
+        }
+    }
+
+    /// Take every event that has arrived since the last call, without
+    /// waiting, for applications that run once a frame rather than awaiting
+    /// `next_event`. Events are buffered between calls. Must be called from a
+    /// thread in a multi-threaded tokio runtime's context, e.g. one that has
+    /// called `Runtime::enter`, so that device tasks keep running between
+    /// calls.
+    pub fn pump(&mut self) -> Vec<GamepadEvent> {
+        // Input first, so a device's last events come before its
+        // `Disconnected`.
+        let mut events: Vec<_> = self.pending.drain(..).collect();
+        for device in self.devices.values() {
+            events.extend(device.queue.drain());
+        }
+        if !self.monitor_done && (&mut self.monitor).now_or_never().is_some() {
+            info!("Device monitor stopped");
+            self.monitor_done = true;
+        }
+        while let Ok(event) = self.device_rx.try_recv() {
+            self.handle(event);
+        }
+        events.extend(self.pending.drain(..));
+        events
+    }
+
+    /// A connected device, by the ID in its events.
+    pub fn device(&mut self, id: DeviceId) -> Result<GamepadHandle<'_>> {
+        let device = self
+            .devices
+            .values_mut()
+            .find(|device| device.id == id)
+            .with_context(|| format!("Unknown device {id}"))?;
+        Ok(GamepadHandle { device })
+    }
+
+    /// What's known about a connected device, by the ID in its events.
+    pub fn info(&self, id: DeviceId) -> Option<&DeviceInfo> {
+        self.devices
+            .values()
+            .find(|device| device.id == id)
+            .map(|device| &device.info)
+    }
+
+    /// The ID of the device at `sys_path`, if it's been found and not gone.
+    pub fn device_id(&self, sys_path: &Path) -> Option<DeviceId> {
+        self.ids.get(sys_path).copied()
+    }
+
+    /// Rumble a device for `duration_ms`, or until replaced if it's 0.
+    /// Magnitudes are 0 (off) to 0xFFFF (full).
+    pub async fn rumble(
+        &mut self,
+        device: DeviceId,
+        strong: u16,
+        weak: u16,
+        duration_ms: u32,
+    ) -> Result<()> {
+        self.device(device)?.rumble(strong, weak, duration_ms).await
+    }
+
+    /// All events as a `Stream`.
+    pub fn into_stream(self) -> impl Stream<Item = GamepadEvent> {
+        stream::unfold(self, |mut manager| async move {
+            let event = manager.next_event().await?;
+            Some((event, manager))
+        })
+    }
+}
+
+impl Default for DeviceManager {
+    fn default() -> DeviceManager {
+        DeviceManager::new()
+    }
+}
+
+#[cfg(test)]
+impl DeviceManager {
+    /// A manager fed by the returned sender instead of a device monitor.
+    fn for_test() -> (DeviceManager, Sender<DeviceEvent>) {
+        let (tx, rx) = mpsc::channel(4);
+        let mut manager = DeviceManager::with_config(MonitorConfig::new());
+        manager.monitor = Box::pin(future::pending());
+        manager.device_rx = rx;
+        manager.device_tx = tx.clone();
+        (manager, tx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivers::HidDriver;
+    use std::ffi::CString;
+    use std::io::Write;
+    use std::os::unix::ffi::OsStrExt;
+
+    /// A driver from outside the crate, for 2-byte reports of the South
+    /// button then the left stick's X.
+    struct TestDriver;
+
+    impl HidDriver for TestDriver {
+        fn name(&self) -> &str {
+            "test"
+        }
+
+        fn probe(&self, info: &DeviceInfo) -> bool {
+            (info.vendor_id, info.product_id) == (0x1234, 0x5678)
+        }
+
+        fn decode(&mut self, report: &[u8]) -> Result<Option<GamepadInput>> {
+            let mut input = GamepadInput::default();
+            input.set_button(Button::South, report[0] != 0);
+            input.set_axis(Axis::LeftX, (report[1] as f32 - 128.0) / 127.0);
+            Ok(Some(input))
+        }
+
+        fn capabilities(&self) -> Capabilities {
+            Capabilities::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn forwards_decode_errors() {
+        let (mut manager, tx) = DeviceManager::for_test();
+        let sys_path = PathBuf::from("/sys/devices/virtual/misc/uhid/0003:045E:0B13.0001");
+        let id = manager.id(&sys_path);
+        let error = DeviceEvent::DecodeError {
+            sys_path,
+            reason: "Short report".to_owned(),
+            data: vec![0x01, 0x02],
+            suppressed: 3,
+        };
+        tx.send(error).await.unwrap();
+        match manager.next_event().await {
+            Some(GamepadEvent::DecodeError {
+                device,
+                reason,
+                data,
+                suppressed,
+            }) => {
+                assert_eq!(device, id);
+                assert_eq!(reason, "Short report");
+                assert_eq!(data, [0x01, 0x02]);
+                assert_eq!(suppressed, 3);
+            }
+            event => panic!("Unexpected {event:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_input_decoded_by_registered_drivers() {
+        drivers::register(|| Box::new(TestDriver));
+        // A FIFO stands in for the hidraw node.
+        let node = std::env::temp_dir().join(format!("hidraw-driver-{}", std::process::id()));
+        let _ = std::fs::remove_file(&node);
+        let path = CString::new(node.as_os_str().as_bytes()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o600) }, 0);
+        // Opened for reading too, so it doesn't wait for the task to open it.
+        let mut reports = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&node)
+            .unwrap();
+        let mut info = DeviceInfo::for_test(0x1234, 0x5678, Bus::Usb);
+        info.hidraw_node = Some(node.clone());
+        let (mut manager, tx) = DeviceManager::for_test();
+        tx.send(DeviceEvent::Added(info)).await.unwrap();
+        let Some(GamepadEvent::Connected { device: id, .. }) = manager.next_event().await else {
+            panic!("Not connected");
+        };
+        reports.write_all(&[0x01, 0xFF]).unwrap();
+        match manager.next_event().await {
+            Some(GamepadEvent::Button {
+                device,
+                button: Button::South,
+                pressed: true,
+            }) => assert_eq!(device, id),
+            event => panic!("Unexpected {event:?}"),
+        }
+        match manager.next_event().await {
+            Some(GamepadEvent::Axis {
+                device,
+                axis: Axis::LeftX,
+                value,
+                predicted: false,
+            }) => {
+                assert_eq!(device, id);
+                assert_eq!(value, 1.0);
+            }
+            event => panic!("Unexpected {event:?}"),
+        }
+        assert!(manager.device(id).unwrap().state().button(Button::South));
+        std::fs::remove_file(&node).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reopened_devices_keep_their_settings() {
+        let (mut manager, tx) = DeviceManager::for_test();
+        let info = DeviceInfo::for_test(0x054c, 0x09cc, Bus::Usb);
+        tx.send(DeviceEvent::Added(info.clone())).await.unwrap();
+        let Some(GamepadEvent::Connected { device: id, .. }) = manager.next_event().await else {
+            panic!("Not connected");
+        };
+        let calibration = CalibrationConfig::new().deadzone(Axis::LeftX, 0.2);
+        let prediction = PredictionConfig::default();
+        let mut handle = manager.device(id).unwrap();
+        handle.set_calibration(calibration.clone());
+        handle.set_prediction(Some(prediction));
+        tx.send(DeviceEvent::Changed(info)).await.unwrap();
+        // `next_event` would wait for input, so handle the change directly.
+        while let Ok(event) = manager.device_rx.try_recv() {
+            manager.handle(event);
+        }
+        assert!(manager.pending.is_empty());
+        let settings = manager.devices.values().next().unwrap().settings();
+        assert_eq!(settings.calibration, calibration);
+        assert_eq!(settings.prediction, Some(prediction));
+    }
+}
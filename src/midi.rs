@@ -0,0 +1,115 @@
+use anyhow::{Context as ErrorContext, Result};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use crate::report::{Axis, Button, GamepadInput};
+use crate::sink::OutputSink;
+
+const NOTE_OFF: u8 = 0x80;
+const NOTE_ON: u8 = 0x90;
+const CONTROL_CHANGE: u8 = 0xB0;
+
+/// How controller inputs map to MIDI messages.
+#[derive(Clone, Debug, Default)]
+pub struct MidiProfile {
+    /// MIDI channel, 0-15.
+    pub channel: u8,
+    /// Note velocity used for button presses.
+    pub velocity: u8,
+    /// Buttons that send Note On/Off with the given note number.
+    pub notes: Vec<(Button, u8)>,
+    /// Axes that send Control Change with the given controller number.
+    pub controllers: Vec<(Axis, u8)>,
+}
+
+impl MidiProfile {
+    /// Every button as a note from C2 up, like a row of drum pads, and every
+    /// axis as one of the general purpose controllers from 16 up, on the first
+    /// channel.
+    pub fn standard() -> MidiProfile {
+        MidiProfile {
+            channel: 0,
+            velocity: 100,
+            notes: Button::ALL.iter().zip(36..).map(|(b, n)| (*b, n)).collect(),
+            controllers: Axis::ALL.iter().zip(16..).map(|(a, c)| (*a, c)).collect(),
+        }
+    }
+}
+
+/// Convert an axis value to a 7-bit MIDI value.
+fn axis_to_midi(axis: Axis, value: f32) -> u8 {
+    let unit = if axis.is_trigger() {
+        value.clamp(0.0, 1.0)
+    } else {
+        (value.clamp(-1.0, 1.0) + 1.0) / 2.0
+    };
+    (unit * 127.0).round() as u8
+}
+
+/// Write MIDI messages to a raw MIDI device such as `/dev/snd/midiC1D0`. Using
+/// the `snd-virmidi` module exposes these as ALSA sequencer ports.
+pub struct MidiSink {
+    file: File,
+    profile: MidiProfile,
+    buttons: Vec<bool>,
+    values: Vec<Option<u8>>,
+}
+
+impl MidiSink {
+    pub fn open(path: &Path, profile: MidiProfile) -> Result<MidiSink> {
+        let file = OpenOptions::new()
+            .write(true)
+            .open(path)
+            .with_context(|| format!("Failed to open MIDI device {path:?}"))?;
+        Ok(MidiSink {
+            file,
+            buttons: vec![false; profile.notes.len()],
+            values: vec![None; profile.controllers.len()],
+            profile,
+        })
+    }
+
+    /// Build the messages needed to go from the previous state to `input`.
+    fn messages(&mut self, input: &GamepadInput) -> Vec<u8> {
+        let channel = self.profile.channel & 0x0F;
+        let mut out = vec![];
+        for (i, &(button, note)) in self.profile.notes.iter().enumerate() {
+            let pressed = input.button(button);
+            if pressed != self.buttons[i] {
+                self.buttons[i] = pressed;
+                if pressed {
+                    out.extend_from_slice(&[
+                        NOTE_ON | channel,
+                        note & 0x7F,
+                        self.profile.velocity & 0x7F,
+                    ]);
+                } else {
+                    out.extend_from_slice(&[NOTE_OFF | channel, note & 0x7F, 0]);
+                }
+            }
+        }
+        for (i, &(axis, controller)) in self.profile.controllers.iter().enumerate() {
+            let value = axis_to_midi(axis, input.axis(axis));
+            if self.values[i] != Some(value) {
+                self.values[i] = Some(value);
+                out.extend_from_slice(&[CONTROL_CHANGE | channel, controller & 0x7F, value]);
+            }
+        }
+        out
+    }
+}
+
+impl OutputSink for MidiSink {
+    fn name(&self) -> &str {
+        "midi"
+    }
+
+    fn send(&mut self, input: &GamepadInput) -> Result<()> {
+        let messages = self.messages(input);
+        if !messages.is_empty() {
+            self.file.write_all(&messages)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+use crate::report::GamepadInput;
+
+// From Linux uapi/linux/input-event-codes.h
+pub const REL_X: u16 = 0x00;
+pub const REL_Y: u16 = 0x01;
+
+/// How often a deflected stick decays back towards center.
+pub const DECAY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Deflections smaller than this snap to center.
+const EPSILON: f32 = 0.01;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MouseStickConfig {
+    /// Stick deflection per count of mouse motion.
+    pub sensitivity: f32,
+    /// Fraction of the deflection kept every `DECAY_INTERVAL`, 0.0..1.0. Lower
+    /// values return the stick to center faster once the mouse stops.
+    pub decay: f32,
+}
+
+impl Default for MouseStickConfig {
+    fn default() -> Self {
+        MouseStickConfig {
+            sensitivity: 0.05,
+            decay: 0.8,
+        }
+    }
+}
+
+/// Turns relative mouse motion into right stick deflection.
+#[derive(Clone, Debug, Default)]
+pub struct MouseStick {
+    pub config: MouseStickConfig,
+    x: f32,
+    y: f32,
+}
+
+impl MouseStick {
+    pub fn new(config: MouseStickConfig) -> MouseStick {
+        MouseStick {
+            config,
+            x: 0.0,
+            y: 0.0,
+        }
+    }
+
+    /// Handle a relative motion event. Wheel and other axes are ignored.
+    pub fn add_motion(&mut self, code: u16, delta: i32) {
+        let delta = delta as f32 * self.config.sensitivity;
+        match code {
+            REL_X => self.x = (self.x + delta).clamp(-1.0, 1.0),
+            REL_Y => self.y = (self.y + delta).clamp(-1.0, 1.0),
+            _ => {}
+        }
+    }
+
+    /// Decay the stick towards center by one `DECAY_INTERVAL`.
+    pub fn tick(&mut self) {
+        let decay = self.config.decay.clamp(0.0, 1.0);
+        for value in [&mut self.x, &mut self.y] {
+            *value *= decay;
+            if value.abs() < EPSILON {
+                *value = 0.0;
+            }
+        }
+    }
+
+    pub fn is_centered(&self) -> bool {
+        self.x == 0.0 && self.y == 0.0
+    }
+
+    /// Write the stick position into `input`, taking over the right stick if
+    /// the mouse is deflecting it.
+    pub fn apply(&self, input: &mut GamepadInput) {
+        if !self.is_centered() {
+            input.right_stick.x = self.x;
+            input.right_stick.y = self.y;
+        }
+    }
+}
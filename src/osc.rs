@@ -0,0 +1,130 @@
+use anyhow::{Context as ErrorContext, Result};
+use std::net::{SocketAddr, UdpSocket};
+
+use crate::report::{Axis, Button, GamepadInput};
+use crate::sink::OutputSink;
+
+const BUTTONS: &[(Button, &str)] = &[
+    (Button::South, "south"),
+    (Button::East, "east"),
+    (Button::West, "west"),
+    (Button::North, "north"),
+    (Button::LeftShoulder, "leftshoulder"),
+    (Button::RightShoulder, "rightshoulder"),
+    (Button::Back, "back"),
+    (Button::Start, "start"),
+    (Button::LeftStick, "leftstick"),
+    (Button::RightStick, "rightstick"),
+    (Button::Guide, "guide"),
+    (Button::Misc, "misc"),
+];
+
+const AXES: &[(Axis, &str)] = &[
+    (Axis::LeftX, "leftx"),
+    (Axis::LeftY, "lefty"),
+    (Axis::RightX, "rightx"),
+    (Axis::RightY, "righty"),
+    (Axis::LeftTrigger, "lefttrigger"),
+    (Axis::RightTrigger, "righttrigger"),
+];
+
+/// Which OSC address each control is sent on.
+#[derive(Clone, Debug, Default)]
+pub struct OscProfile {
+    /// Buttons are sent as an int32 argument, 1 for pressed and 0 for released.
+    pub buttons: Vec<(Button, String)>,
+    /// Axes are sent as a float32 argument.
+    pub axes: Vec<(Axis, String)>,
+}
+
+impl OscProfile {
+    /// Send every control on `<prefix>/button/<name>` and `<prefix>/axis/<name>`.
+    pub fn with_prefix(prefix: &str) -> OscProfile {
+        OscProfile {
+            buttons: BUTTONS
+                .iter()
+                .map(|(b, name)| (*b, format!("{prefix}/button/{name}")))
+                .collect(),
+            axes: AXES
+                .iter()
+                .map(|(a, name)| (*a, format!("{prefix}/axis/{name}")))
+                .collect(),
+        }
+    }
+}
+
+/// Append an OSC string: NUL terminated and padded to a multiple of 4 bytes.
+fn push_osc_string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(s.as_bytes());
+    let padding = 4 - (s.len() % 4);
+    buf.resize(buf.len() + padding, 0);
+}
+
+fn encode_int(address: &str, value: i32) -> Vec<u8> {
+    let mut buf = vec![];
+    push_osc_string(&mut buf, address);
+    push_osc_string(&mut buf, ",i");
+    buf.extend_from_slice(&value.to_be_bytes());
+    buf
+}
+
+fn encode_float(address: &str, value: f32) -> Vec<u8> {
+    let mut buf = vec![];
+    push_osc_string(&mut buf, address);
+    push_osc_string(&mut buf, ",f");
+    buf.extend_from_slice(&value.to_be_bytes());
+    buf
+}
+
+/// Send controller state as OSC messages over UDP.
+pub struct OscSink {
+    socket: UdpSocket,
+    target: SocketAddr,
+    profile: OscProfile,
+    buttons: Vec<Option<bool>>,
+    axes: Vec<Option<f32>>,
+}
+
+impl OscSink {
+    pub fn new(target: SocketAddr, profile: OscProfile) -> Result<OscSink> {
+        let bind: SocketAddr = if target.is_ipv4() {
+            ([0, 0, 0, 0], 0).into()
+        } else {
+            ([0u16; 8], 0).into()
+        };
+        let socket = UdpSocket::bind(bind).context("Failed to bind OSC socket")?;
+        Ok(OscSink {
+            socket,
+            target,
+            buttons: vec![None; profile.buttons.len()],
+            axes: vec![None; profile.axes.len()],
+            profile,
+        })
+    }
+}
+
+impl OutputSink for OscSink {
+    fn name(&self) -> &str {
+        "osc"
+    }
+
+    fn send(&mut self, input: &GamepadInput) -> Result<()> {
+        for (i, (button, address)) in self.profile.buttons.iter().enumerate() {
+            let pressed = input.button(*button);
+            if self.buttons[i] != Some(pressed) {
+                self.buttons[i] = Some(pressed);
+                self.socket
+                    .send_to(&encode_int(address, pressed as i32), self.target)?;
+            }
+        }
+        for (i, (axis, address)) in self.profile.axes.iter().enumerate() {
+            let value = input.axis(*axis);
+            if self.axes[i] != Some(value) {
+                self.axes[i] = Some(value);
+                self.socket
+                    .send_to(&encode_float(address, value), self.target)?;
+            }
+        }
+        Ok(())
+    }
+}
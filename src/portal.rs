@@ -0,0 +1,306 @@
+use anyhow::{bail, Context as ErrorContext, Result};
+use futures::StreamExt;
+use log::{info, warn};
+use std::collections::HashMap;
+use std::fs::File;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tokio::sync::mpsc::Sender;
+use zbus::fdo::IntrospectableProxy;
+use zbus::object_server::SignalContext;
+use zbus::{interface, proxy, zvariant, Connection};
+
+use crate::device;
+use crate::device_monitor::{Bus, DeviceEvent, DeviceInfo, DisconnectReason};
+
+/// The name the daemon takes on the session bus. Flatpak apps can reach it
+/// with `--talk-name=org.hidraw.Daemon`, without access to `/dev/input`.
+pub const BUS_NAME: &str = "org.hidraw.Daemon";
+const DEVICES_PATH: &str = "/org/hidraw/Devices";
+
+const PORTAL_NAME: &str = "org.freedesktop.portal.Desktop";
+const PORTAL_PATH: &str = "/org/freedesktop/portal/desktop";
+
+/// xdg-desktop-portal interfaces that give sandboxed apps input devices. None
+/// are specific to game controllers yet, so for now these are only reported.
+pub const PORTAL_INPUT_INTERFACES: &[&str] = &[
+    "org.freedesktop.portal.InputCapture",
+    "org.freedesktop.portal.RemoteDesktop",
+];
+
+/// The devices we handle, served as `org.hidraw.Devices1`.
+#[derive(Default)]
+struct Devices {
+    devices: Vec<DeviceInfo>,
+}
+
+#[interface(name = "org.hidraw.Devices1")]
+impl Devices {
+    /// Every device, as sys path, bus type, vendor ID, product ID and name.
+    fn list(&self) -> Vec<(String, u16, u16, u16, String)> {
+        self.devices
+            .iter()
+            .map(|info| {
+                let sys_path = info.sys_path.to_string_lossy().into_owned();
+                let (bus, name) = (info.bus as u16, info.name.clone());
+                (sys_path, bus, info.vendor_id, info.product_id, name)
+            })
+            .collect()
+    }
+
+    /// A read-only fd for a device's evdev node, opened by us, so the caller
+    /// needs no access to the node itself.
+    fn open(&self, sys_path: &str) -> zbus::fdo::Result<zvariant::OwnedFd> {
+        let info = self
+            .devices
+            .iter()
+            .find(|info| info.sys_path == Path::new(sys_path))
+            .ok_or_else(|| zbus::fdo::Error::InvalidArgs(format!("No such device: {sys_path}")))?;
+        let file = File::open(&info.device_node)
+            .map_err(|e| zbus::fdo::Error::AccessDenied(format!("{e}")))?;
+        Ok(OwnedFd::from(file).into())
+    }
+
+    #[zbus(signal)]
+    async fn added(
+        ctxt: &SignalContext<'_>,
+        sys_path: &str,
+        bus: u16,
+        vendor_id: u16,
+        product_id: u16,
+        name: &str,
+    ) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    async fn removed(ctxt: &SignalContext<'_>, sys_path: &str, reason: &str) -> zbus::Result<()>;
+}
+
+/// The daemon's devices on the session bus, for sandboxed apps.
+pub struct PortalService {
+    connection: Connection,
+}
+
+impl PortalService {
+    /// Take `BUS_NAME` on the session bus and start serving devices.
+    pub async fn start() -> Result<PortalService> {
+        let connection = Connection::session()
+            .await
+            .context("Failed to connect to the session bus")?;
+        connection
+            .object_server()
+            .at(DEVICES_PATH, Devices::default())
+            .await?;
+        connection
+            .request_name(BUS_NAME)
+            .await
+            .with_context(|| format!("Failed to take {BUS_NAME}"))?;
+        info!("Serving devices on the session bus as {BUS_NAME}");
+        Ok(PortalService { connection })
+    }
+
+    /// Which of `PORTAL_INPUT_INTERFACES` the desktop portal offers.
+    pub async fn portal_input_interfaces(&self) -> Result<Vec<&'static str>> {
+        let proxy = IntrospectableProxy::builder(&self.connection)
+            .destination(PORTAL_NAME)?
+            .path(PORTAL_PATH)?
+            .build()
+            .await?;
+        let xml = proxy
+            .introspect()
+            .await
+            .context("Failed to introspect the desktop portal")?;
+        Ok(PORTAL_INPUT_INTERFACES
+            .iter()
+            .copied()
+            .filter(|name| xml.contains(&format!("<interface name=\"{name}\"")))
+            .collect())
+    }
+
+    pub async fn added(&self, info: &DeviceInfo) -> Result<()> {
+        let devices = self.devices().await?;
+        let mut served = devices.get_mut().await;
+        // A device reopened after it changed replaces itself.
+        served
+            .devices
+            .retain(|served| served.sys_path != info.sys_path);
+        served.devices.push(info.clone());
+        drop(served);
+        let sys_path = info.sys_path.to_string_lossy();
+        let (vendor_id, product_id) = (info.vendor_id, info.product_id);
+        Devices::added(
+            devices.signal_context(),
+            &sys_path,
+            info.bus as u16,
+            vendor_id,
+            product_id,
+            &info.name,
+        )
+        .await?;
+        Ok(())
+    }
+
+    pub async fn removed(&self, sys_path: &Path, reason: &DisconnectReason) -> Result<()> {
+        let devices = self.devices().await?;
+        let mut served = devices.get_mut().await;
+        let count = served.devices.len();
+        served.devices.retain(|info| info.sys_path != sys_path);
+        if served.devices.len() == count {
+            return Ok(());
+        }
+        drop(served);
+        let sys_path = sys_path.to_string_lossy();
+        Devices::removed(devices.signal_context(), &sys_path, reason.as_str()).await?;
+        Ok(())
+    }
+
+    async fn devices(&self) -> Result<zbus::object_server::InterfaceRef<Devices>> {
+        Ok(self
+            .connection
+            .object_server()
+            .interface::<_, Devices>(DEVICES_PATH)
+            .await?)
+    }
+}
+
+#[proxy(
+    interface = "org.hidraw.Devices1",
+    default_service = "org.hidraw.Daemon",
+    default_path = "/org/hidraw/Devices",
+    gen_blocking = false
+)]
+trait Devices1 {
+    fn list(&self) -> zbus::Result<Vec<(String, u16, u16, u16, String)>>;
+
+    fn open(&self, sys_path: &str) -> zbus::Result<zvariant::OwnedFd>;
+
+    #[zbus(signal)]
+    fn added(
+        &self,
+        sys_path: &str,
+        bus: u16,
+        vendor_id: u16,
+        product_id: u16,
+        name: &str,
+    ) -> zbus::Result<()>;
+
+    #[zbus(signal)]
+    fn removed(&self, sys_path: &str, reason: &str) -> zbus::Result<()>;
+}
+
+/// The devices of a daemon outside the sandbox we're in, read through fds it
+/// opens for us.
+pub struct DaemonDevices {
+    proxy: Devices1Proxy<'static>,
+    added: AddedStream<'static>,
+    removed: RemovedStream<'static>,
+    initial: Vec<(String, u16, u16, u16, String)>,
+    /// By sys path. Devices are read by reopening these through
+    /// `/proc/self/fd`.
+    fds: HashMap<PathBuf, OwnedFd>,
+}
+
+impl DaemonDevices {
+    /// Connect to the daemon on the session bus, failing if it isn't running.
+    pub async fn connect() -> Result<DaemonDevices> {
+        let connection = Connection::session()
+            .await
+            .context("Failed to connect to the session bus")?;
+        let proxy = Devices1Proxy::new(&connection).await?;
+        // Subscribe first so no device falls between the list and the signals.
+        let added = proxy.receive_added().await?;
+        let removed = proxy.receive_removed().await?;
+        let initial = proxy
+            .list()
+            .await
+            .with_context(|| format!("{BUS_NAME} isn't running"))?;
+        Ok(DaemonDevices {
+            proxy,
+            added,
+            removed,
+            initial,
+            fds: HashMap::new(),
+        })
+    }
+
+    /// Send `DeviceEvent::Added` for each of the daemon's devices, then for
+    /// the ones it finds later, and `Removed` as they go.
+    pub async fn monitor(mut self, tx: Sender<DeviceEvent>) -> Result<()> {
+        info!("Reading devices through {BUS_NAME}");
+        for (sys_path, bus, vendor_id, product_id, name) in std::mem::take(&mut self.initial) {
+            self.add(&tx, sys_path, bus, vendor_id, product_id, name)
+                .await?;
+        }
+        loop {
+            tokio::select! {
+                Some(signal) = self.added.next() => {
+                    let args = signal.args()?;
+                    let (sys_path, name) = (args.sys_path.to_owned(), args.name.to_owned());
+                    let (bus, vendor_id, product_id) = (args.bus, args.vendor_id, args.product_id);
+                    self.add(&tx, sys_path, bus, vendor_id, product_id, name).await?;
+                }
+                Some(signal) = self.removed.next() => {
+                    let args = signal.args()?;
+                    let sys_path = PathBuf::from(args.sys_path);
+                    if self.fds.remove(&sys_path).is_some() {
+                        let reason = disconnect_reason(args.reason);
+                        tx.send(DeviceEvent::Removed { sys_path, reason }).await?;
+                    }
+                }
+                else => bail!("Lost {BUS_NAME}"),
+            }
+        }
+    }
+
+    async fn add(
+        &mut self,
+        tx: &Sender<DeviceEvent>,
+        sys_path: String,
+        bus: u16,
+        vendor_id: u16,
+        product_id: u16,
+        name: String,
+    ) -> Result<()> {
+        let bus = Bus::from_id(bus);
+        let fd = match self.proxy.open(&sys_path).await {
+            Ok(fd) => OwnedFd::from(fd),
+            Err(e) => {
+                warn!("{BUS_NAME} wouldn't open {sys_path}: {e}");
+                return Ok(());
+            }
+        };
+        let sys_path = PathBuf::from(sys_path);
+        let info = DeviceInfo {
+            sys_path: sys_path.clone(),
+            device_node: PathBuf::from(format!("/proc/self/fd/{}", fd.as_raw_fd())),
+            hidraw_node: None,
+            parser: None,
+            bus,
+            name,
+            uniq: None,
+            phys: None,
+            version: 0,
+            vendor_id,
+            product_id,
+            input_id: device::read_input_id(fd.as_raw_fd()).ok(),
+            usb_address: None,
+            connected_at: SystemTime::now(),
+        };
+        let event = match self.fds.insert(sys_path, fd) {
+            Some(_) => DeviceEvent::Changed(info),
+            None => DeviceEvent::Added(info),
+        };
+        tx.send(event).await?;
+        Ok(())
+    }
+}
+
+fn disconnect_reason(name: &str) -> DisconnectReason {
+    match name {
+        "unplugged" => DisconnectReason::Unplugged,
+        "quarantined" => DisconnectReason::Quarantined,
+        "power-off" => DisconnectReason::PowerOff,
+        "released" => DisconnectReason::Released,
+        _ => DisconnectReason::Error(format!("{BUS_NAME} dropped the device")),
+    }
+}
@@ -0,0 +1,64 @@
+use std::time::{Duration, Instant};
+
+use crate::report::Axis;
+
+/// Reports further apart than this are treated as the axis having been at
+/// rest, since most devices only report on change.
+const MAX_REPORT_GAP: Duration = Duration::from_millis(50);
+
+/// How far to extrapolate analog axes, for hiding some of the latency of slow
+/// links like Bluetooth.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct PredictionConfig {
+    /// How far ahead of the last report to predict, roughly the link's
+    /// latency.
+    pub lead: Duration,
+    /// The most a prediction may move an axis from its last real value.
+    pub max_delta: f32,
+}
+
+impl Default for PredictionConfig {
+    fn default() -> Self {
+        PredictionConfig {
+            lead: Duration::from_millis(10),
+            max_delta: 0.1,
+        }
+    }
+}
+
+/// Extrapolates each axis from its velocity over its last two reports.
+#[derive(Clone, Debug)]
+pub struct AxisPredictor {
+    pub config: PredictionConfig,
+    last: [Option<(f32, Instant)>; Axis::ALL.len()],
+}
+
+impl AxisPredictor {
+    pub fn new(config: PredictionConfig) -> AxisPredictor {
+        AxisPredictor {
+            config,
+            last: [None; Axis::ALL.len()],
+        }
+    }
+
+    /// Record `value`, read from `axis` at `at`, and predict where the axis
+    /// will be `lead` later.
+    pub fn predict(&mut self, axis: Axis, value: f32, at: Instant) -> f32 {
+        let last = self.last[axis as usize].replace((value, at));
+        let velocity = match last {
+            Some((last, last_at)) => {
+                let elapsed = at.saturating_duration_since(last_at);
+                if elapsed.is_zero() || elapsed > MAX_REPORT_GAP {
+                    0.0
+                } else {
+                    (value - last) / elapsed.as_secs_f32()
+                }
+            }
+            None => 0.0,
+        };
+        let max_delta = self.config.max_delta;
+        let delta = (velocity * self.config.lead.as_secs_f32()).clamp(-max_delta, max_delta);
+        let min = if axis.is_trigger() { 0.0 } else { -1.0 };
+        (value + delta).clamp(min, 1.0)
+    }
+}
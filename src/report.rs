@@ -1,15 +1,51 @@
 #![allow(unused)]
 
+use log::{debug, info, warn};
+use std::collections::{BTreeMap, VecDeque};
+use std::os::unix::io::RawFd;
+use std::time::{Duration, Instant};
+
+use crate::descriptor::usages::{GenericDesktop, Usage, UsagePage};
+use crate::descriptor::{self, Field, FieldKind, ReportDescriptor};
+use crate::device;
+use crate::drivers::handheld;
+use crate::error::{Error, Result};
+use crate::storage;
+
 #[derive(Debug)]
-pub struct HidReportParserBuilder {}
+pub struct HidReportParserBuilder {
+    normalize: bool,
+    trigger_usages: Vec<GenericDesktop>,
+}
 
 impl HidReportParserBuilder {
     pub fn new() -> HidReportParserBuilder {
-        HidReportParserBuilder {}
+        HidReportParserBuilder {
+            normalize: false,
+            trigger_usages: vec![],
+        }
+    }
+
+    /// Scale axis values from their logical range to -1.0..=1.0, or 0.0..=1.0
+    /// for triggers.
+    pub fn normalize(mut self, normalize: bool) -> HidReportParserBuilder {
+        self.normalize = normalize;
+        self
     }
 
-    pub fn build(self) -> HidReportParser {
-        unimplemented!()
+    /// Treat axes with these Generic Desktop usages as triggers. Descriptors
+    /// don't say which axes are triggers, and devices disagree: some report
+    /// them on Z and Rz, others use those for the right stick.
+    pub fn trigger_usages(mut self, usages: &[GenericDesktop]) -> HidReportParserBuilder {
+        self.trigger_usages = usages.to_vec();
+        self
+    }
+
+    pub fn build(self, desc: &ReportDescriptor) -> Result<HidReportParser> {
+        let mut parser = HidReportParser::from_descriptor(desc)?;
+        parser.normalize = self.normalize;
+        parser.trigger_usages = self.trigger_usages;
+        Ok(parser)
     }
 }
 
@@ -19,10 +55,6 @@ enum Size {
     Bytes(u8),
 }
 
-const AXIS_X: u8 = 0x30;
-const AXIS_Y: u8 = 0x31;
-const AXIS_Z: u8 = 0x32;
-const AXIS_RZ: u8 = 0x35;
 const MAX_BUTTONS: usize = 16;
 
 #[derive(Debug, Clone)]
@@ -32,13 +64,18 @@ enum What {
         to: u8,
     },
     Dpad {
-        min: u8,
-        max: u8,
+        min: i32,
+        max: i32,
     },
     Axis {
-        usage: u8,
-        min: u8,
-        max: u8,
+        usage: GenericDesktop,
+        min: i32,
+        max: i32,
+    },
+    /// An array of pressed keyboard usages, one per byte. Button `i` is
+    /// pressed when `keys[i]` is among them.
+    Keys {
+        keys: &'static [u8],
     },
     /// Constant items are used for padding out bytes.
     Const,
@@ -53,13 +90,13 @@ struct HidReportItem {
     what: What,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Default)]
 pub struct AnalogStick {
     pub x: f32,
     pub y: f32,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct Dpad {
     pub left: bool,
     pub up: bool,
@@ -67,91 +104,853 @@ pub struct Dpad {
     pub down: bool,
 }
 
-#[derive(Debug)]
+/// A motion sensor sample, along the device's own X, Y and Z axes.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Motion {
+    /// Acceleration in G, including gravity.
+    pub accel: [f32; 3],
+    /// Angular velocity in degrees per second.
+    pub gyro: [f32; 3],
+    /// When the sample was taken, from an arbitrary start. Devices with a
+    /// sensor clock use it, so the time between samples is accurate even when
+    /// reports arrive in bursts.
+    pub timestamp: Duration,
+}
+
+/// A finger on a touch surface, like the Sony controllers' touchpads.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TouchContact {
+    /// Stays the same while the finger is down, so it can be tracked.
+    pub id: u32,
+    /// From the left edge, 0.0..=1.0.
+    pub x: f32,
+    /// From the top edge, 0.0..=1.0.
+    pub y: f32,
+    /// False once the finger is lifted, at its last position.
+    pub pressed: bool,
+}
+
+/// Turns a device's wrapping sensor clock into a timestamp that doesn't wrap.
+#[derive(Debug, Clone)]
+pub struct SensorClock {
+    mask: u32,
+    ticks_per_second: u64,
+    last: Option<u32>,
+    ticks: u64,
+}
+
+impl SensorClock {
+    /// A clock of `bits` bits counting `ticks_per_second`.
+    pub fn new(bits: u32, ticks_per_second: u64) -> SensorClock {
+        SensorClock {
+            mask: u32::MAX >> (32 - bits),
+            ticks_per_second,
+            last: None,
+            ticks: 0,
+        }
+    }
+
+    /// The time since the first reading, given the clock's current value.
+    pub fn update(&mut self, raw: u32) -> Duration {
+        if let Some(last) = self.last {
+            self.ticks += (raw.wrapping_sub(last) & self.mask) as u64;
+        }
+        self.last = Some(raw);
+        let nanos = self.ticks as u128 * 1_000_000_000 / self.ticks_per_second as u128;
+        Duration::from_nanos(nanos as u64)
+    }
+}
+
+/// Standard gamepad buttons, named by position so they mean the same thing
+/// across controller families. Used to index `GamepadInput::buttons`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Button {
+    South,
+    East,
+    West,
+    North,
+    LeftShoulder,
+    RightShoulder,
+    Back,
+    Start,
+    LeftStick,
+    RightStick,
+    Guide,
+    Misc,
+}
+
+impl Button {
+    pub const ALL: [Button; 12] = [
+        Button::South,
+        Button::East,
+        Button::West,
+        Button::North,
+        Button::LeftShoulder,
+        Button::RightShoulder,
+        Button::Back,
+        Button::Start,
+        Button::LeftStick,
+        Button::RightStick,
+        Button::Guide,
+        Button::Misc,
+    ];
+
+    /// The name used in config files.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Button::South => "south",
+            Button::East => "east",
+            Button::West => "west",
+            Button::North => "north",
+            Button::LeftShoulder => "left_shoulder",
+            Button::RightShoulder => "right_shoulder",
+            Button::Back => "back",
+            Button::Start => "start",
+            Button::LeftStick => "left_stick",
+            Button::RightStick => "right_stick",
+            Button::Guide => "guide",
+            Button::Misc => "misc",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Button> {
+        Button::ALL.iter().copied().find(|b| b.as_str() == name)
+    }
+}
+
+/// Standard gamepad axes. Sticks are in the range -1.0..=1.0 and triggers in
+/// the range 0.0..=1.0.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Axis {
+    LeftX,
+    LeftY,
+    RightX,
+    RightY,
+    LeftTrigger,
+    RightTrigger,
+}
+
+impl Axis {
+    /// All axes, in the order used by `GamepadInput::axes`.
+    pub const ALL: [Axis; 6] = [
+        Axis::LeftX,
+        Axis::LeftY,
+        Axis::RightX,
+        Axis::RightY,
+        Axis::LeftTrigger,
+        Axis::RightTrigger,
+    ];
+
+    pub fn is_trigger(&self) -> bool {
+        matches!(self, Axis::LeftTrigger | Axis::RightTrigger)
+    }
+
+    /// The name used in config files.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Axis::LeftX => "left_x",
+            Axis::LeftY => "left_y",
+            Axis::RightX => "right_x",
+            Axis::RightY => "right_y",
+            Axis::LeftTrigger => "left_trigger",
+            Axis::RightTrigger => "right_trigger",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Axis> {
+        Axis::ALL.iter().copied().find(|a| a.as_str() == name)
+    }
+}
+
+/// The controls a device or accessory provides.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    pub buttons: Vec<Button>,
+    pub axes: Vec<Axis>,
+    pub dpad: bool,
+    pub motion: bool,
+    /// How often the device sends input reports, once known: measured from
+    /// when reports arrive, or the USB endpoint's polling interval.
+    pub report_interval: Option<Duration>,
+    /// The largest packet the device's link carries, from its USB endpoint.
+    /// Linux doesn't expose the L2CAP MTU of Bluetooth devices.
+    pub max_packet_size: Option<u16>,
+}
+
+/// How many report intervals `ReportTimer` keeps.
+const REPORT_TIMER_SAMPLES: usize = 32;
+
+/// Estimates how often a device sends reports from when they arrive.
+#[derive(Debug, Default)]
+pub struct ReportTimer {
+    last: Option<Instant>,
+    intervals: VecDeque<Duration>,
+}
+
+impl ReportTimer {
+    pub fn record(&mut self, at: Instant) {
+        if let Some(last) = self.last.replace(at) {
+            if self.intervals.len() == REPORT_TIMER_SAMPLES {
+                self.intervals.pop_front();
+            }
+            self.intervals.push_back(at.saturating_duration_since(last));
+        }
+    }
+
+    /// The median of recent intervals, once there are enough. Many devices
+    /// only report on change, and the median ignores the gaps while idle.
+    pub fn interval(&self) -> Option<Duration> {
+        if self.intervals.len() < REPORT_TIMER_SAMPLES / 2 {
+            return None;
+        }
+        let mut intervals: Vec<_> = self.intervals.iter().copied().collect();
+        intervals.sort();
+        Some(intervals[intervals.len() / 2])
+    }
+}
+
+#[derive(Debug, Clone, Default)]
 pub struct GamepadInput {
     pub left_stick: AnalogStick,
     pub right_stick: AnalogStick,
+    /// Triggers are in the range 0.0..=1.0.
+    pub left_trigger: f32,
+    pub right_trigger: f32,
     pub dpad: Dpad,
     pub buttons: [bool; MAX_BUTTONS],
+    /// The latest motion sample, from drivers for devices with motion sensors.
+    pub motion: Option<Motion>,
+    /// Every contact slot of the device's touch surface, if it has one.
+    pub touches: Vec<TouchContact>,
+}
+
+impl GamepadInput {
+    pub fn button(&self, button: Button) -> bool {
+        self.buttons[button as usize]
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        self.buttons[button as usize] = pressed;
+    }
+
+    pub fn axis(&self, axis: Axis) -> f32 {
+        match axis {
+            Axis::LeftX => self.left_stick.x,
+            Axis::LeftY => self.left_stick.y,
+            Axis::RightX => self.right_stick.x,
+            Axis::RightY => self.right_stick.y,
+            Axis::LeftTrigger => self.left_trigger,
+            Axis::RightTrigger => self.right_trigger,
+        }
+    }
+
+    /// All axis values, indexed by `Axis as usize`.
+    pub fn axes(&self) -> [f32; 6] {
+        Axis::ALL.map(|a| self.axis(a))
+    }
+
+    pub fn set_axes(&mut self, values: &[f32; 6]) {
+        for (axis, value) in Axis::ALL.iter().zip(values) {
+            self.set_axis(*axis, *value);
+        }
+    }
+
+    pub fn set_axis(&mut self, axis: Axis, value: f32) {
+        let slot = match axis {
+            Axis::LeftX => &mut self.left_stick.x,
+            Axis::LeftY => &mut self.left_stick.y,
+            Axis::RightX => &mut self.right_stick.x,
+            Axis::RightY => &mut self.right_stick.y,
+            Axis::LeftTrigger => &mut self.left_trigger,
+            Axis::RightTrigger => &mut self.right_trigger,
+        };
+        *slot = value;
+    }
+}
+
+/// The result of parsing one input report.
+#[derive(Debug, Clone, Default)]
+pub struct ParsedReport {
+    /// The report ID, for devices that number their reports.
+    pub report_id: Option<u8>,
+    /// Axis values, in report order.
+    pub axes: Vec<AxisValue>,
+    /// Button states, starting from button usage 1.
+    pub buttons: Vec<bool>,
+    pub dpad: Option<Dpad>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AxisValue {
+    pub usage: GenericDesktop,
+    /// The logical value, sign-extended if the logical minimum is negative.
+    pub raw: i32,
+    /// The value scaled to -1.0..=1.0, or 0.0..=1.0 for triggers, if the
+    /// parser normalizes.
+    pub value: Option<f32>,
+    /// Whether the parser treats the axis as a trigger.
+    pub trigger: bool,
+}
+
+/// Sign-extend a `bits` wide two's complement value.
+fn sign_extend(value: u32, bits: usize) -> i32 {
+    if bits == 0 || bits >= 32 {
+        return value as i32;
+    }
+    let shift = 32 - bits;
+    ((value << shift) as i32) >> shift
+}
+
+/// Scale `value` from `min..=max` to `low..=1.0`.
+fn scale(value: i32, min: i32, max: i32, low: f32) -> f32 {
+    if max <= min {
+        return 0.0;
+    }
+    // In i64, since a signed minimum with an unsigned maximum can span more
+    // than i32.
+    let span = max as i64 - min as i64;
+    let t = (value.clamp(min, max) as i64 - min as i64) as f64 / span as f64;
+    low + t as f32 * (1.0 - low)
+}
+
+/// Extract `bits` bits (at most 32) starting at bit `offset` of `data`. HID
+/// fields are packed least significant bit first and can span bytes.
+fn extract_bits(data: &[u8], offset: usize, bits: usize) -> u32 {
+    let mut value = 0u32;
+    let mut done = 0;
+    while done < bits {
+        let bit = offset + done;
+        let shift = bit % 8;
+        let take = (8 - shift).min(bits - done);
+        let byte = (data[bit / 8] >> shift) as u32 & ((1 << take) - 1);
+        value |= byte << done;
+        done += take;
+    }
+    value
+}
+
+/// Decode a hat switch value, where `min` is up and each step turns clockwise
+/// by 360° / (max - min + 1). Out of range values mean centered.
+fn hat_to_dpad(value: i32, min: i32, max: i32) -> Dpad {
+    let positions = max - min + 1;
+    if value < min || value > max || !(positions == 4 || positions == 8) {
+        return Dpad::default();
+    }
+    // Convert to eighths of a turn.
+    let eighth = (value - min) * 8 / positions;
+    Dpad {
+        up: matches!(eighth, 7 | 0 | 1),
+        right: matches!(eighth, 1..=3),
+        down: matches!(eighth, 3..=5),
+        left: matches!(eighth, 5..=7),
+    }
+}
+
+/// Format `data` as a hex dump, 16 bytes per line, prefixed with offsets.
+pub fn hex_dump(data: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, line) in data.chunks(16).enumerate() {
+        let bytes: Vec<String> = line.iter().map(|b| format!("{b:02x}")).collect();
+        out.push_str(&format!("{:04x}  {}\n", i * 16, bytes.join(" ")));
+    }
+    out
+}
+
+fn items_bits(items: &[HidReportItem]) -> usize {
+    items.iter().fold(0, |sum, i| {
+        sum + match i.size {
+            Size::Bits(s) => s as usize,
+            Size::Bytes(s) => (s as usize) * 8,
+        }
+    })
 }
 
 #[derive(Debug, Clone)]
 pub struct HidReportParser {
-    inputs: Vec<HidReportItem>,
+    /// Input report layouts by report ID, or a single layout under ID 0 for
+    /// devices that don't number their reports.
+    reports: BTreeMap<u8, Vec<HidReportItem>>,
+    uses_report_ids: bool,
+    normalize: bool,
+    trigger_usages: Vec<GenericDesktop>,
 }
 
 impl HidReportParser {
+    /// A parser for a device with a single, unnumbered input report.
+    fn unnumbered(inputs: Vec<HidReportItem>) -> HidReportParser {
+        HidReportParser {
+            reports: BTreeMap::from([(0, inputs)]),
+            uses_report_ids: false,
+            normalize: false,
+            trigger_usages: vec![],
+        }
+    }
+
+    /// The length in bytes of the longest input report, including the report
+    /// ID if there is one.
     pub fn len(&self) -> usize {
-        let bits = self.inputs.iter().fold(0, |sum, i| {
-            sum + match i.size {
+        let bits = self.reports.values().map(|items| items_bits(items)).max();
+        bits.unwrap_or(0) / 8 + self.uses_report_ids as usize
+    }
+
+    pub fn uses_report_ids(&self) -> bool {
+        self.uses_report_ids
+    }
+
+    /// The IDs of the input reports this parser understands.
+    pub fn report_ids(&self) -> Vec<u8> {
+        if self.uses_report_ids {
+            self.reports.keys().copied().collect()
+        } else {
+            vec![]
+        }
+    }
+
+    /// Find the layout for a report, returning it along with the report ID
+    /// and the report data following the ID.
+    fn layout<'a>(&self, report: &'a [u8]) -> Result<(Option<u8>, &[HidReportItem], &'a [u8])> {
+        let (report_id, data) = if self.uses_report_ids {
+            let (id, data) = report
+                .split_first()
+                .ok_or_else(|| Error::Parse("Empty report".to_owned()))?;
+            (Some(*id), data)
+        } else {
+            (None, report)
+        };
+        let Some(items) = self.reports.get(&report_id.unwrap_or(0)) else {
+            return Err(Error::Parse(format!("Unknown report ID {report_id:?}")));
+        };
+        if data.len() * 8 < items_bits(items) {
+            return Err(Error::Parse(format!(
+                "Short report: {} bytes",
+                report.len()
+            )));
+        }
+        Ok((report_id, items, data))
+    }
+
+    pub fn parse(&self, report: &[u8]) -> Result<ParsedReport> {
+        let (report_id, items, data) = self.layout(report)?;
+        let mut parsed = ParsedReport {
+            report_id,
+            ..Default::default()
+        };
+        let mut offset = 0;
+        for item in items {
+            let bits = match item.size {
                 Size::Bits(s) => s as usize,
                 Size::Bytes(s) => (s as usize) * 8,
+            };
+            match item.what {
+                What::Buttons { from, to } => {
+                    let count = (to as usize + 1).saturating_sub(from as usize).min(bits);
+                    let first = (from as usize).saturating_sub(1);
+                    if parsed.buttons.len() < first + count {
+                        parsed.buttons.resize(first + count, false);
+                    }
+                    for i in 0..count {
+                        parsed.buttons[first + i] = extract_bits(data, offset + i, 1) != 0;
+                    }
+                }
+                What::Keys { keys } => {
+                    let pressed = &data[offset / 8..(offset + bits) / 8];
+                    if parsed.buttons.len() < keys.len() {
+                        parsed.buttons.resize(keys.len(), false);
+                    }
+                    for (i, key) in keys.iter().enumerate() {
+                        parsed.buttons[i] |= pressed.contains(key);
+                    }
+                }
+                What::Dpad { min, max } if bits <= 32 => {
+                    let value = extract_bits(data, offset, bits) as i32;
+                    parsed.dpad = Some(hat_to_dpad(value, min, max));
+                }
+                What::Axis { usage, min, max } if bits <= 32 => {
+                    let bits_value = extract_bits(data, offset, bits);
+                    let raw = if min < 0 {
+                        sign_extend(bits_value, bits)
+                    } else {
+                        bits_value as i32
+                    };
+                    let trigger = self.trigger_usages.contains(&usage);
+                    let value = self.normalize.then(|| {
+                        let low = if trigger { 0.0 } else { -1.0 };
+                        scale(raw, min, max, low)
+                    });
+                    parsed.axes.push(AxisValue {
+                        usage,
+                        raw,
+                        value,
+                        trigger,
+                    });
+                }
+                _ => {}
+            }
+            offset += bits;
+        }
+        Ok(parsed)
+    }
+
+    /// Describe a report as a hex dump followed by one line per field, with
+    /// its bit range, the bytes it covers and its decoded value, e.g.
+    ///
+    /// ```text
+    /// 0000  01 80 7f 00 08
+    ///   [  8..  16) 80          axis 0x30 = 128
+    /// ```
+    pub fn annotate(&self, report: &[u8]) -> Result<String> {
+        let (report_id, items, data) = self.layout(report)?;
+        let mut out = hex_dump(report);
+        // Bit offsets are relative to the data, but shown relative to the
+        // whole report.
+        let base = report_id.is_some() as usize * 8;
+        if let Some(id) = report_id {
+            out.push_str(&format!(
+                "  [{:>3}..{:>4}) {:02x}          report id\n",
+                0, 8, id
+            ));
+        }
+        let mut offset = 0;
+        for item in items {
+            let bits = match item.size {
+                Size::Bits(s) => s as usize,
+                Size::Bytes(s) => (s as usize) * 8,
+            };
+            let bytes = data[offset / 8..(offset + bits).div_ceil(8)]
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect::<Vec<_>>()
+                .join(" ");
+            let value = |bits| extract_bits(data, offset, bits);
+            let description = match item.what {
+                What::Buttons { from, to } => {
+                    let pressed: String = (0..bits.min(32))
+                        .map(|i| match extract_bits(data, offset + i, 1) {
+                            0 => '0',
+                            _ => '1',
+                        })
+                        .collect();
+                    format!("buttons {from}..={to} = {pressed}")
+                }
+                What::Dpad { min, max } if bits <= 32 => {
+                    let raw = value(bits) as i32;
+                    format!("hat = {raw} {:?}", hat_to_dpad(raw, min, max))
+                }
+                What::Axis { usage, min, .. } if bits <= 32 => {
+                    let raw = if min < 0 {
+                        sign_extend(value(bits), bits)
+                    } else {
+                        value(bits) as i32
+                    };
+                    format!("axis {:#04x} = {raw}", usage as u16)
+                }
+                What::Keys { .. } => {
+                    let pressed: Vec<_> = data[offset / 8..(offset + bits) / 8]
+                        .iter()
+                        .filter(|&&key| key != 0)
+                        .map(|key| format!("{key:#04x}"))
+                        .collect();
+                    format!("keys = [{}]", pressed.join(" "))
+                }
+                What::Const => "padding".to_owned(),
+                _ => "unknown".to_owned(),
+            };
+            out.push_str(&format!(
+                "  [{:>3}..{:>4}) {bytes:<11} {description}\n",
+                base + offset,
+                base + offset + bits
+            ));
+            offset += bits;
+        }
+        Ok(out)
+    }
+
+    /// Build a parser from a device's report descriptor, with a layout for
+    /// each numbered input report.
+    pub fn from_descriptor(desc: &ReportDescriptor) -> Result<HidReportParser> {
+        let mut reports: BTreeMap<u8, Vec<HidReportItem>> = BTreeMap::new();
+        let mut uses_report_ids = false;
+        for field in desc.fields() {
+            if field.kind != FieldKind::Input {
+                continue;
             }
+            uses_report_ids |= field.report_id.is_some();
+            let inputs = reports.entry(field.report_id.unwrap_or(0)).or_default();
+            items_for_field(field, inputs);
+        }
+        if reports.is_empty() {
+            return Err(Error::Unsupported(
+                "Descriptor has no input reports".to_owned(),
+            ));
+        }
+        let has_controls = reports
+            .values()
+            .flatten()
+            .any(|i| !matches!(i.what, What::Const | What::Unknown));
+        if !has_controls {
+            return Err(Error::Unsupported(
+                "Descriptor has no gamepad controls".to_owned(),
+            ));
+        }
+        Ok(HidReportParser {
+            reports,
+            uses_report_ids,
+            normalize: false,
+            trigger_usages: vec![],
+        })
+    }
+
+    /// Build a parser from the report descriptor of the device behind a
+    /// hidraw node.
+    pub fn from_hidraw(fd: RawFd) -> Result<HidReportParser> {
+        let data = device::read_report_descriptor(fd)?;
+        HidReportParser::from_descriptor(&descriptor::parse_hid_descriptor(&data)?)
+    }
+}
+
+/// Add items of `bits` total size, split so each fits in a `Size`.
+fn push_sized(mut bits: u32, what: What, inputs: &mut Vec<HidReportItem>) {
+    while bits > 0 {
+        let size = if bits.is_multiple_of(8) {
+            let bytes = (bits / 8).min(u8::MAX as u32);
+            bits -= bytes * 8;
+            Size::Bytes(bytes as u8)
+        } else {
+            let chunk = bits.min(u8::MAX as u32);
+            bits -= chunk;
+            Size::Bits(chunk as u8)
+        };
+        inputs.push(HidReportItem {
+            size,
+            what: what.clone(),
         });
-        bits / 8
     }
+}
+
+fn items_for_field(field: &Field, inputs: &mut Vec<HidReportItem>) {
+    // Takes no space in the report, and names no usages.
+    if field.report_count == 0 {
+        return;
+    }
+    if field.is_constant() {
+        push_sized(field.bits(), What::Const, inputs);
+        return;
+    }
+    let first_usage = field.usages.first().or(field.usage_minimum.as_ref());
+    match first_usage.map(|u| u.page) {
+        Some(UsagePage::Button) if field.is_variable() && field.report_size == 1 => {
+            let from = field.usage_minimum.or(field.usages.first().copied());
+            let from = from.map_or(1, |u| u.id);
+            let to = (from as u32).saturating_add(field.report_count - 1);
+            push_sized(
+                field.bits(),
+                What::Buttons {
+                    from: from.min(u8::MAX as u16) as u8,
+                    to: to.min(u8::MAX as u32) as u8,
+                },
+                inputs,
+            );
+        }
+        Some(UsagePage::GenericDesktop) if field.is_variable() => {
+            // Each value takes the next usage; the last one repeats if there
+            // are fewer usages than values.
+            for i in 0..field.report_count as usize {
+                let usage = field.usages.get(i).or(field.usages.last());
+                let what = match usage.map(|u| u.typed()) {
+                    Some(Usage::GenericDesktop(GenericDesktop::HatSwitch)) => What::Dpad {
+                        min: field.logical_minimum,
+                        max: field.logical_maximum,
+                    },
+                    Some(Usage::GenericDesktop(usage)) if usage.is_axis() => What::Axis {
+                        usage,
+                        min: field.logical_minimum,
+                        max: field.logical_maximum,
+                    },
+                    _ => What::Unknown,
+                };
+                push_sized(field.report_size, what, inputs);
+            }
+        }
+        _ => push_sized(field.bits(), What::Unknown, inputs),
+    }
+}
 
-    pub fn parse(&self, _report: &[u8]) {}
+/// A parser for a boot protocol keyboard report that reports `keys` as
+/// buttons 1 and up. Some handhelds send their extra buttons as keys.
+pub fn keyboard_buttons_parser(keys: &'static [u8]) -> HidReportParser {
+    HidReportParser::unnumbered(vec![
+        HidReportItem {
+            size: Size::Bytes(1),
+            what: What::Unknown,
+        },
+        HidReportItem {
+            size: Size::Bytes(1),
+            what: What::Const,
+        },
+        HidReportItem {
+            size: Size::Bytes(6),
+            what: What::Keys { keys },
+        },
+    ])
 }
 
 fn logitech_f310_parser() -> HidReportParser {
-    HidReportParser {
-        inputs: vec![
-            HidReportItem {
-                size: Size::Bytes(1),
-                what: What::Axis {
-                    usage: AXIS_X,
-                    min: 0,
-                    max: 255,
-                },
+    HidReportParser::unnumbered(vec![
+        HidReportItem {
+            size: Size::Bytes(1),
+            what: What::Axis {
+                usage: GenericDesktop::X,
+                min: 0,
+                max: 255,
             },
-            HidReportItem {
-                size: Size::Bytes(1),
-                what: What::Axis {
-                    usage: AXIS_Y,
-                    min: 0,
-                    max: 255,
-                },
+        },
+        HidReportItem {
+            size: Size::Bytes(1),
+            what: What::Axis {
+                usage: GenericDesktop::Y,
+                min: 0,
+                max: 255,
             },
-            HidReportItem {
-                size: Size::Bytes(1),
-                what: What::Axis {
-                    usage: AXIS_Z,
-                    min: 0,
-                    max: 255,
-                },
+        },
+        HidReportItem {
+            size: Size::Bytes(1),
+            what: What::Axis {
+                usage: GenericDesktop::Z,
+                min: 0,
+                max: 255,
             },
-            HidReportItem {
-                size: Size::Bytes(1),
-                what: What::Axis {
-                    usage: AXIS_RZ,
-                    min: 0,
-                    max: 255,
-                },
+        },
+        HidReportItem {
+            size: Size::Bytes(1),
+            what: What::Axis {
+                usage: GenericDesktop::Rz,
+                min: 0,
+                max: 255,
             },
-            HidReportItem {
-                size: Size::Bits(4),
-                what: What::Dpad { min: 0, max: 7 },
+        },
+        HidReportItem {
+            size: Size::Bits(4),
+            what: What::Dpad { min: 0, max: 7 },
+        },
+        HidReportItem {
+            size: Size::Bits(12),
+            what: What::Buttons {
+                from: 0x01,
+                to: 0x0C,
             },
-            HidReportItem {
-                size: Size::Bits(12),
-                what: What::Buttons {
-                    from: 0x01,
-                    to: 0x0C,
-                },
-            },
-            HidReportItem {
-                size: Size::Bytes(2),
-                what: What::Unknown,
-            },
-        ],
-    }
+        },
+        HidReportItem {
+            size: Size::Bytes(2),
+            what: What::Unknown,
+        },
+    ])
 }
 
 pub fn find_report_parser_for_device(vendor_id: u16, product_id: u16) -> Option<HidReportParser> {
+    let handheld_parser = handheld::detect_platform()
+        .and_then(|platform| handheld::find_quirk(platform, vendor_id, product_id))
+        .and_then(|quirk| quirk.parser);
+    if let Some(parser) = handheld_parser {
+        return Some(parser());
+    }
     if vendor_id == 0x046D && product_id == 0x0C216 {
         Some(logitech_f310_parser())
     } else {
         None
     }
 }
+
+/// Find a parser for a device: from a user's descriptor override if there is
+/// one, stored or in the override directory, then built-in quirks, falling back to building one from the report
+/// descriptor read through its hidraw node.
+pub fn find_report_parser(
+    vendor_id: u16,
+    product_id: u16,
+    hidraw_fd: Option<RawFd>,
+) -> Option<HidReportParser> {
+    let data = match storage::configured() {
+        Some(storage) => {
+            descriptor::load_stored_override(storage.as_ref(), vendor_id, product_id).transpose()
+        }
+        None => None,
+    };
+    let data = data.or_else(|| {
+        descriptor::load_override(&descriptor::override_dir(), vendor_id, product_id).transpose()
+    });
+    match data.transpose() {
+        Ok(Some(data)) => {
+            let parser = descriptor::parse_hid_descriptor(&data)
+                .and_then(|desc| HidReportParser::from_descriptor(&desc));
+            match parser {
+                Ok(parser) => {
+                    info!("Using descriptor override for {vendor_id:04x}:{product_id:04x}");
+                    return Some(parser);
+                }
+                Err(e) => {
+                    warn!("Ignoring descriptor override for {vendor_id:04x}:{product_id:04x}: {e}")
+                }
+            }
+        }
+        Ok(None) => {}
+        Err(e) => warn!("{e:#}"),
+    }
+    find_report_parser_for_device(vendor_id, product_id).or_else(|| {
+        let fd = hidraw_fd?;
+        match HidReportParser::from_hidraw(fd) {
+            Ok(parser) => Some(parser),
+            Err(e) => {
+                debug!("No parser from descriptor for {vendor_id:04x}:{product_id:04x}: {e}");
+                None
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eight_bit_axis_with_maximum_0xff() {
+        #[rustfmt::skip]
+        let data = [
+            0x05, 0x01, 0x09, 0x05, 0xA1, 0x01, // Gamepad application
+            0x15, 0x00, 0x25, 0xFF, 0x75, 0x08, 0x95, 0x01, // 0..=255, 8 bits
+            0x09, 0x30, 0x81, 0x02, // X
+            0xC0,
+        ];
+        let desc = descriptor::parse_hid_descriptor(&data).unwrap();
+        let parser = HidReportParserBuilder::new()
+            .normalize(true)
+            .build(&desc)
+            .unwrap();
+        let axis = |report| parser.parse(&[report]).unwrap().axes.remove(0);
+        let max = axis(0xFF);
+        assert_eq!((max.raw, max.value), (255, Some(1.0)));
+        let min = axis(0x00);
+        assert_eq!((min.raw, min.value), (0, Some(-1.0)));
+    }
+
+    #[test]
+    fn scales_ranges_wider_than_i32() {
+        assert_eq!(scale(i32::MIN, i32::MIN, i32::MAX, -1.0), -1.0);
+        assert_eq!(scale(i32::MAX, -1, i32::MAX, -1.0), 1.0);
+        assert_eq!(scale(0, -1, 1, 0.0), 0.5);
+    }
+
+    #[test]
+    fn skips_empty_button_fields() {
+        #[rustfmt::skip]
+        let data = [
+            0x05, 0x01, 0x09, 0x05, 0xA1, 0x01, // Gamepad application
+            0x05, 0x09, 0x19, 0x00, 0x29, 0x00, 0x75, 0x01, 0x95, 0x00, // No buttons
+            0x81, 0x02,
+            0x05, 0x01, 0x15, 0x00, 0x25, 0xFF, 0x75, 0x08, 0x95, 0x01, // 0..=255, 8 bits
+            0x09, 0x30, 0x81, 0x02, // X
+            0xC0,
+        ];
+        let desc = descriptor::parse_hid_descriptor(&data).unwrap();
+        let parser = HidReportParser::from_descriptor(&desc).unwrap();
+        assert_eq!(parser.parse(&[0x80]).unwrap().axes[0].raw, 0x80);
+    }
+}
@@ -0,0 +1,50 @@
+use anyhow::Result;
+use futures::future::BoxFuture;
+
+/// Motor intensities, each 0 (off) to 0xFFFF (full).
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct RumbleEffect {
+    /// The low frequency motor, usually on the left.
+    pub strong: u16,
+    /// The high frequency motor, usually on the right.
+    pub weak: u16,
+    /// Motors behind the triggers ("impulse triggers") on Xbox One and later pads.
+    pub left_trigger: u16,
+    pub right_trigger: u16,
+}
+
+impl RumbleEffect {
+    pub fn new(strong: u16, weak: u16) -> RumbleEffect {
+        RumbleEffect {
+            strong,
+            weak,
+            ..Default::default()
+        }
+    }
+
+    pub fn is_off(&self) -> bool {
+        *self == RumbleEffect::default()
+    }
+
+    /// Fold the trigger motors into the body motors on the same side, for
+    /// devices that don't have trigger motors.
+    pub fn without_triggers(&self) -> RumbleEffect {
+        RumbleEffect {
+            strong: self.strong.max(self.left_trigger),
+            weak: self.weak.max(self.right_trigger),
+            left_trigger: 0,
+            right_trigger: 0,
+        }
+    }
+}
+
+/// A device that can rumble.
+pub trait Rumble: Send {
+    /// Whether the device has separate trigger motors. If not, `rumble` plays
+    /// trigger intensities on the body motors instead.
+    fn has_trigger_motors(&self) -> bool {
+        false
+    }
+
+    fn rumble(&mut self, effect: RumbleEffect) -> BoxFuture<'_, Result<()>>;
+}
@@ -0,0 +1,126 @@
+use log::{info, warn};
+use nix::errno::Errno;
+use nix::unistd::{self, AccessFlags};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Whether we're running in a Flatpak sandbox, where device nodes and udev
+/// are usually out of reach and `/etc` is read-only.
+pub fn in_flatpak() -> bool {
+    Path::new("/.flatpak-info").exists() || std::env::var_os("FLATPAK_ID").is_some()
+}
+
+/// Where the user's own settings go: `hidraw` in `$XDG_CONFIG_HOME` or
+/// `~/.config`. Inside Flatpak that's the app's own config directory.
+pub fn config_dir() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(base.join("hidraw"))
+}
+
+/// Something we need from the system, and why we can't have it if we can't.
+#[derive(Clone, Debug)]
+pub struct Access {
+    pub what: &'static str,
+    /// Why it's missing and what would fix it, or `None` if we have it.
+    pub missing: Option<String>,
+}
+
+/// Check what we can reach: evdev and hidraw nodes, udev, uinput and uhid.
+pub fn check_access() -> Vec<Access> {
+    let flatpak = in_flatpak();
+    let check = |what, missing| Access { what, missing };
+    let udev = if Path::new("/run/udev").exists() {
+        None
+    } else if flatpak {
+        Some("/run/udev isn't shared with the sandbox, so hotplug isn't seen".to_owned())
+    } else {
+        Some("/run/udev doesn't exist, so hotplug isn't seen".to_owned())
+    };
+    vec![
+        check(
+            "read gamepads",
+            check_nodes("/dev/input", "event", AccessFlags::R_OK, flatpak),
+        ),
+        check(
+            "talk to HID devices",
+            check_nodes(
+                "/dev",
+                "hidraw",
+                AccessFlags::R_OK | AccessFlags::W_OK,
+                flatpak,
+            ),
+        ),
+        check("watch for new devices", udev),
+        check(
+            "create virtual gamepads",
+            check_node(Path::new("/dev/uinput"), AccessFlags::W_OK, flatpak),
+        ),
+        check(
+            "create virtual HID devices",
+            check_node(Path::new("/dev/uhid"), AccessFlags::W_OK, flatpak),
+        ),
+    ]
+}
+
+/// Log whether we're sandboxed and what we can't do because of it, or for
+/// lack of permissions.
+pub fn log_access() {
+    if in_flatpak() {
+        info!("Running in a Flatpak sandbox");
+    }
+    for access in check_access() {
+        if let Some(missing) = access.missing {
+            warn!("Can't {}: {missing}", access.what);
+        }
+    }
+}
+
+/// Why the nodes in `dir` starting with `prefix` can't be opened with
+/// `flags`, if none of them can. It's fine for there to be none at all.
+fn check_nodes(dir: &str, prefix: &str, flags: AccessFlags, flatpak: bool) -> Option<String> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => return Some(unreachable(Path::new(dir), &e.to_string(), flatpak)),
+    };
+    let nodes: Vec<PathBuf> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with(prefix))
+        })
+        .collect();
+    let mut missing = None;
+    for node in &nodes {
+        match check_node(node, flags, flatpak) {
+            None => return None,
+            Some(reason) => missing = Some(reason),
+        }
+    }
+    missing
+}
+
+/// Why `node` can't be opened with `flags`, if it can't.
+fn check_node(node: &Path, flags: AccessFlags, flatpak: bool) -> Option<String> {
+    match unistd::access(node, flags) {
+        Ok(()) => None,
+        Err(nix::Error::Sys(Errno::EACCES | Errno::EPERM)) => Some(format!(
+            "permission denied on {node:?}; a udev rule granting the user access is needed"
+        )),
+        Err(e) => Some(unreachable(node, &e.to_string(), flatpak)),
+    }
+}
+
+fn unreachable(path: &Path, error: &str, flatpak: bool) -> String {
+    if flatpak {
+        format!(
+            "{path:?} isn't shared with the sandbox; run with --device=all, or talk to a \
+             daemon outside it with --talk-name=org.hidraw.Daemon"
+        )
+    } else {
+        format!("{path:?}: {error}")
+    }
+}
@@ -1,5 +1,18 @@
+use anyhow::{anyhow, bail, Context as ErrorContext, Result};
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fmt;
+use std::path::{Path, PathBuf};
 use uuid::{Bytes, Uuid};
 
+use crate::device_monitor::DeviceInfo;
+use crate::report::{Button, Dpad, GamepadInput, ParsedReport};
+use crate::sandbox;
+use crate::storage;
+
+/// The storage key of mappings shared through the configured storage.
+pub const STORED_MAPPINGS_KEY: &str = "mappings/gamecontrollerdb.txt";
 
 pub fn create_sdl_controller_uuid(bus: u16, vendor: u16, product: u16, version: u16) -> Uuid {
     // These parameters come from `struct input_id` via the `EVIOCGID` ioctl:
@@ -15,4 +28,779 @@ pub fn create_sdl_controller_uuid(bus: u16, vendor: u16, product: u16, version:
     Uuid::from_bytes(bytes)
 }
 
-// 050000007e0500003003000001000000,Nintendo Wii U Pro Controller,a:b0,b:b1,back:b8,dpdown:b14,dpleft:b15,dpright:b16,dpup:b13,guide:b10,leftshoulder:b4,leftstick:b11,lefttrigger:b6,leftx:a0,lefty:a1,rightshoulder:b5,rightstick:b12,righttrigger:b7,rightx:a2,righty:a3,start:b9,x:b3,y:b2,platform:Linux,
+/// The controls of SDL's standard gamepad, named as in mapping strings.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum SdlControl {
+    A,
+    B,
+    X,
+    Y,
+    Back,
+    Guide,
+    Start,
+    LeftStick,
+    RightStick,
+    LeftShoulder,
+    RightShoulder,
+    DpadUp,
+    DpadDown,
+    DpadLeft,
+    DpadRight,
+    Misc1,
+    Paddle1,
+    Paddle2,
+    Paddle3,
+    Paddle4,
+    Touchpad,
+    LeftX,
+    LeftY,
+    RightX,
+    RightY,
+    LeftTrigger,
+    RightTrigger,
+}
+
+impl SdlControl {
+    pub const ALL: [SdlControl; 27] = [
+        SdlControl::A,
+        SdlControl::B,
+        SdlControl::X,
+        SdlControl::Y,
+        SdlControl::Back,
+        SdlControl::Guide,
+        SdlControl::Start,
+        SdlControl::LeftStick,
+        SdlControl::RightStick,
+        SdlControl::LeftShoulder,
+        SdlControl::RightShoulder,
+        SdlControl::DpadUp,
+        SdlControl::DpadDown,
+        SdlControl::DpadLeft,
+        SdlControl::DpadRight,
+        SdlControl::Misc1,
+        SdlControl::Paddle1,
+        SdlControl::Paddle2,
+        SdlControl::Paddle3,
+        SdlControl::Paddle4,
+        SdlControl::Touchpad,
+        SdlControl::LeftX,
+        SdlControl::LeftY,
+        SdlControl::RightX,
+        SdlControl::RightY,
+        SdlControl::LeftTrigger,
+        SdlControl::RightTrigger,
+    ];
+
+    /// The name used in mapping strings.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SdlControl::A => "a",
+            SdlControl::B => "b",
+            SdlControl::X => "x",
+            SdlControl::Y => "y",
+            SdlControl::Back => "back",
+            SdlControl::Guide => "guide",
+            SdlControl::Start => "start",
+            SdlControl::LeftStick => "leftstick",
+            SdlControl::RightStick => "rightstick",
+            SdlControl::LeftShoulder => "leftshoulder",
+            SdlControl::RightShoulder => "rightshoulder",
+            SdlControl::DpadUp => "dpup",
+            SdlControl::DpadDown => "dpdown",
+            SdlControl::DpadLeft => "dpleft",
+            SdlControl::DpadRight => "dpright",
+            SdlControl::Misc1 => "misc1",
+            SdlControl::Paddle1 => "paddle1",
+            SdlControl::Paddle2 => "paddle2",
+            SdlControl::Paddle3 => "paddle3",
+            SdlControl::Paddle4 => "paddle4",
+            SdlControl::Touchpad => "touchpad",
+            SdlControl::LeftX => "leftx",
+            SdlControl::LeftY => "lefty",
+            SdlControl::RightX => "rightx",
+            SdlControl::RightY => "righty",
+            SdlControl::LeftTrigger => "lefttrigger",
+            SdlControl::RightTrigger => "righttrigger",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<SdlControl> {
+        SdlControl::ALL.iter().copied().find(|c| c.as_str() == name)
+    }
+
+    pub fn is_axis(&self) -> bool {
+        matches!(
+            self,
+            SdlControl::LeftX
+                | SdlControl::LeftY
+                | SdlControl::RightX
+                | SdlControl::RightY
+                | SdlControl::LeftTrigger
+                | SdlControl::RightTrigger
+        )
+    }
+}
+
+/// Which part of an axis's range a binding uses, written as a `+` or `-`
+/// prefix in mapping strings.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AxisRange {
+    Full,
+    Positive,
+    Negative,
+}
+
+impl AxisRange {
+    fn prefix(&self) -> &'static str {
+        match self {
+            AxisRange::Full => "",
+            AxisRange::Positive => "+",
+            AxisRange::Negative => "-",
+        }
+    }
+}
+
+/// Where on the device an SDL control comes from. Any binding can drive any
+/// control, as in SDL: axes bound to buttons, or to the d-pad, count as
+/// pressed past the middle of the part of their range they use, and buttons
+/// and hats bound to axes push them to the end of the control's range, e.g.
+/// `lefttrigger:b6` or `+leftx:h0.2`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Binding {
+    /// `b0`: a button, by index.
+    Button(u8),
+    /// `a0`, `+a0`, `-a0` or `a0~`: an axis, by index, possibly only half of
+    /// it, and inverted with a trailing `~`.
+    Axis {
+        index: u8,
+        range: AxisRange,
+        inverted: bool,
+    },
+    /// `h0.1`: a hat, by index, and the direction bit that must be set: 1 is
+    /// up, 2 right, 4 down and 8 left.
+    Hat { index: u8, mask: u8 },
+}
+
+impl Binding {
+    pub fn parse(s: &str) -> Result<Binding> {
+        let (range, rest) = match s.as_bytes().first() {
+            Some(b'+') => (AxisRange::Positive, &s[1..]),
+            Some(b'-') => (AxisRange::Negative, &s[1..]),
+            _ => (AxisRange::Full, s),
+        };
+        let (inverted, rest) = match rest.strip_suffix('~') {
+            Some(rest) => (true, rest),
+            None => (false, rest),
+        };
+        let bad = || anyhow!("Bad binding `{s}`");
+        let binding = if let Some(index) = rest.strip_prefix('a') {
+            Binding::Axis {
+                index: index.parse().with_context(bad)?,
+                range,
+                inverted,
+            }
+        } else if let Some(index) = rest.strip_prefix('b') {
+            Binding::Button(index.parse().with_context(bad)?)
+        } else if let Some(hat) = rest.strip_prefix('h') {
+            let (index, mask) = hat.split_once('.').ok_or_else(bad)?;
+            let mask: u8 = mask.parse().with_context(bad)?;
+            if ![HAT_UP, HAT_RIGHT, HAT_DOWN, HAT_LEFT].contains(&mask) {
+                return Err(bad());
+            }
+            Binding::Hat {
+                index: index.parse().with_context(bad)?,
+                mask,
+            }
+        } else {
+            return Err(bad());
+        };
+        if !matches!(binding, Binding::Axis { .. }) && (range != AxisRange::Full || inverted) {
+            return Err(bad());
+        }
+        Ok(binding)
+    }
+}
+
+impl fmt::Display for Binding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Binding::Button(index) => write!(f, "b{index}"),
+            Binding::Axis {
+                index,
+                range,
+                inverted,
+            } => {
+                let tilde = if *inverted { "~" } else { "" };
+                write!(f, "{}a{index}{tilde}", range.prefix())
+            }
+            Binding::Hat { index, mask } => write!(f, "h{index}.{mask}"),
+        }
+    }
+}
+
+/// One `control:binding` pair. `output` is the part of the control's range the
+/// binding drives, e.g. `+leftx:b3` makes a button push the stick right.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct MappingEntry {
+    pub control: SdlControl,
+    pub output: AxisRange,
+    pub binding: Binding,
+}
+
+impl fmt::Display for MappingEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let prefix = self.output.prefix();
+        write!(f, "{prefix}{}:{}", self.control.as_str(), self.binding)
+    }
+}
+
+/// A line of SDL's `gamecontrollerdb.txt`, e.g.
+/// `050000007e0500003003000001000000,Nintendo Wii U Pro Controller,a:b0,b:b1,...,platform:Linux,`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ControllerMapping {
+    pub guid: Uuid,
+    pub name: String,
+    pub platform: Option<String>,
+    pub entries: Vec<MappingEntry>,
+}
+
+impl ControllerMapping {
+    pub fn parse(line: &str) -> Result<ControllerMapping> {
+        let mut fields = line.trim().split(',');
+        let guid = fields.next().context("Missing GUID")?;
+        let guid = Uuid::try_parse(guid).with_context(|| format!("Bad GUID `{guid}`"))?;
+        let name = fields.next().context("Missing name")?.to_owned();
+        let mut platform = None;
+        let mut entries = vec![];
+        for field in fields.filter(|f| !f.is_empty()) {
+            let Some((key, value)) = field.split_once(':') else {
+                bail!("Bad field `{field}`");
+            };
+            if key == "platform" {
+                platform = Some(value.to_owned());
+                continue;
+            }
+            let (output, name) = match key.as_bytes().first() {
+                Some(b'+') => (AxisRange::Positive, &key[1..]),
+                Some(b'-') => (AxisRange::Negative, &key[1..]),
+                _ => (AxisRange::Full, key),
+            };
+            let Some(control) = SdlControl::from_name(name) else {
+                // Newer SDL versions add fields like `crc` and `hint`, and
+                // controls we don't know about. SDL ignores these too.
+                debug!("Ignoring `{field}` in mapping for {name}");
+                continue;
+            };
+            if value.is_empty() {
+                continue;
+            }
+            entries.push(MappingEntry {
+                control,
+                output,
+                binding: Binding::parse(value)?,
+            });
+        }
+        Ok(ControllerMapping {
+            guid,
+            name,
+            platform,
+            entries,
+        })
+    }
+
+    /// The binding for a control, if it's mapped.
+    pub fn binding(&self, control: SdlControl) -> Option<&MappingEntry> {
+        self.entries.iter().find(|e| e.control == control)
+    }
+}
+
+/// Zero the parts of a GUID SDL doesn't match on: the name CRC, and
+/// optionally the version.
+fn match_key(guid: &Uuid, ignore_version: bool) -> Bytes {
+    let mut bytes = *guid.as_bytes();
+    bytes[2..4].fill(0);
+    if ignore_version {
+        bytes[12..14].fill(0);
+    }
+    bytes
+}
+
+/// Mappings from a `gamecontrollerdb.txt` file.
+#[derive(Clone, Debug, Default)]
+pub struct MappingDatabase {
+    pub mappings: Vec<ControllerMapping>,
+}
+
+impl MappingDatabase {
+    /// Parse a database, keeping Linux mappings and those that don't say
+    /// which platform they're for. Bad lines are skipped, as SDL does.
+    pub fn parse(text: &str) -> MappingDatabase {
+        let mut mappings = vec![];
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match ControllerMapping::parse(line) {
+                Ok(mapping) if mapping.platform.as_deref().unwrap_or("Linux") == "Linux" => {
+                    mappings.push(mapping)
+                }
+                Ok(_) => {}
+                Err(e) => debug!("Skipping line {}: {e:#}", i + 1),
+            }
+        }
+        MappingDatabase { mappings }
+    }
+
+    /// Find the mapping for a device by the UUID from
+    /// `create_sdl_controller_uuid`, falling back to a mapping for another
+    /// version of the same device like SDL does. Later mappings win.
+    pub fn find(&self, uuid: &Uuid) -> Option<&ControllerMapping> {
+        [false, true].into_iter().find_map(|ignore_version| {
+            let key = match_key(uuid, ignore_version);
+            self.mappings
+                .iter()
+                .rev()
+                .find(|m| match_key(&m.guid, ignore_version) == key)
+        })
+    }
+
+    /// The mapping for a device, by its SDL GUID.
+    pub fn find_device(&self, info: &DeviceInfo) -> Option<&ControllerMapping> {
+        self.find(&info.sdl_guid())
+    }
+
+    pub fn load_file(path: &Path) -> Result<MappingDatabase> {
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("Failed to read {path:?}"))?;
+        Ok(MappingDatabase::parse(&text))
+    }
+
+    /// Add `other`'s mappings, which win over ours.
+    pub fn extend(&mut self, other: MappingDatabase) {
+        self.mappings.extend(other.mappings);
+    }
+
+    /// The databases installed in the system's data directories, with those
+    /// of more important directories winning.
+    fn load_system(data_dirs: Option<&OsStr>) -> MappingDatabase {
+        let mut db = MappingDatabase::default();
+        for path in system_db_paths(data_dirs).iter().rev() {
+            if path.exists() {
+                match MappingDatabase::load_file(path) {
+                    Ok(system) => db.extend(system),
+                    Err(e) => warn!("{e:#}"),
+                }
+            }
+        }
+        db
+    }
+
+    /// The system's databases, then, each winning over the last,
+    /// `gamecontrollerdb.txt` in the user's config directory, the stored
+    /// `mappings/gamecontrollerdb.txt` if storage is configured, the file named
+    /// by `$SDL_GAMECONTROLLERCONFIG_FILE` and the mappings in
+    /// `$SDL_GAMECONTROLLERCONFIG`, one per line, as SDL reads them.
+    pub fn load() -> MappingDatabase {
+        let data_dirs = std::env::var_os("XDG_DATA_DIRS");
+        let mut db = MappingDatabase::load_system(data_dirs.as_deref());
+        if let Some(path) = sandbox::config_dir().map(|dir| dir.join("gamecontrollerdb.txt")) {
+            if path.exists() {
+                match MappingDatabase::load_file(&path) {
+                    Ok(user) => db.extend(user),
+                    Err(e) => warn!("{e:#}"),
+                }
+            }
+        }
+        if let Some(storage) = storage::configured() {
+            match storage.read(STORED_MAPPINGS_KEY) {
+                Ok(Some(data)) => {
+                    db.extend(MappingDatabase::parse(&String::from_utf8_lossy(&data)))
+                }
+                Ok(None) => {}
+                Err(e) => warn!("{e:#}"),
+            }
+        }
+        if let Some(path) = std::env::var_os("SDL_GAMECONTROLLERCONFIG_FILE") {
+            match MappingDatabase::load_file(Path::new(&path)) {
+                Ok(user) => db.extend(user),
+                Err(e) => warn!("{e:#}"),
+            }
+        }
+        if let Ok(text) = std::env::var("SDL_GAMECONTROLLERCONFIG") {
+            db.extend(MappingDatabase::parse(&text));
+        }
+        debug!("Loaded {} SDL mappings", db.mappings.len());
+        db
+    }
+}
+
+/// `hidraw/gamecontrollerdb.txt` in each of `$XDG_DATA_DIRS`, by default
+/// `/usr/local/share` and `/usr/share`, where packages install the database,
+/// most important first.
+fn system_db_paths(data_dirs: Option<&OsStr>) -> Vec<PathBuf> {
+    let data_dirs = data_dirs
+        .filter(|dirs| !dirs.is_empty())
+        .unwrap_or(OsStr::new("/usr/local/share:/usr/share"));
+    std::env::split_paths(data_dirs)
+        .filter(|dir| dir.is_absolute())
+        .map(|dir| dir.join("hidraw/gamecontrollerdb.txt"))
+        .collect()
+}
+
+/// Hat direction bits, as used in `h0.N` bindings.
+const HAT_UP: u8 = 1;
+const HAT_RIGHT: u8 = 2;
+const HAT_DOWN: u8 = 4;
+const HAT_LEFT: u8 = 8;
+
+/// Raw input from a device, by index as in mapping strings.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RawInput {
+    Button(u8, bool),
+    /// An axis value in the range -1.0..=1.0.
+    Axis(u8, f32),
+    /// A hat's direction bits.
+    Hat(u8, u8),
+}
+
+impl RawInput {
+    /// What the input came from: its kind and index.
+    fn source(&self) -> (u8, u8) {
+        match *self {
+            RawInput::Button(index, _) => (0, index),
+            RawInput::Axis(index, _) => (1, index),
+            RawInput::Hat(index, _) => (2, index),
+        }
+    }
+}
+
+impl SdlControl {
+    fn button(&self) -> Option<Button> {
+        match self {
+            SdlControl::A => Some(Button::South),
+            SdlControl::B => Some(Button::East),
+            SdlControl::X => Some(Button::West),
+            SdlControl::Y => Some(Button::North),
+            SdlControl::Back => Some(Button::Back),
+            SdlControl::Guide => Some(Button::Guide),
+            SdlControl::Start => Some(Button::Start),
+            SdlControl::LeftStick => Some(Button::LeftStick),
+            SdlControl::RightStick => Some(Button::RightStick),
+            SdlControl::LeftShoulder => Some(Button::LeftShoulder),
+            SdlControl::RightShoulder => Some(Button::RightShoulder),
+            SdlControl::Misc1 => Some(Button::Misc),
+            _ => None,
+        }
+    }
+
+    fn is_trigger(&self) -> bool {
+        matches!(self, SdlControl::LeftTrigger | SdlControl::RightTrigger)
+    }
+}
+
+/// Turn raw input into a value for an entry's control: 0.0 or 1.0 for
+/// buttons, or an axis value in the control's range. `None` if the input
+/// isn't what the entry is bound to.
+fn entry_value(entry: &MappingEntry, input: RawInput) -> Option<f32> {
+    // How far the input is pushed, 0.0..=1.0.
+    let amount = match (entry.binding, input) {
+        (Binding::Button(index), RawInput::Button(i, pressed)) if index == i => {
+            if pressed {
+                1.0
+            } else {
+                0.0
+            }
+        }
+        (Binding::Hat { index, mask }, RawInput::Hat(i, bits)) if index == i => {
+            if bits & mask != 0 {
+                1.0
+            } else {
+                0.0
+            }
+        }
+        (
+            Binding::Axis {
+                index,
+                range,
+                inverted,
+            },
+            RawInput::Axis(i, value),
+        ) if index == i => {
+            let value = if inverted { -value } else { value };
+            match range {
+                AxisRange::Full => (value + 1.0) / 2.0,
+                AxisRange::Positive => value.max(0.0),
+                AxisRange::Negative => (-value).max(0.0),
+            }
+        }
+        _ => return None,
+    };
+    let amount = amount.clamp(0.0, 1.0);
+    if !entry.control.is_axis() {
+        // Axes count as pressed past the halfway point.
+        return Some(if amount > 0.5 { 1.0 } else { 0.0 });
+    }
+    Some(match entry.output {
+        AxisRange::Positive => amount,
+        AxisRange::Negative => -amount,
+        AxisRange::Full if entry.control.is_trigger() => amount,
+        // A button or half-axis driving a whole stick axis sweeps it end to end.
+        AxisRange::Full => amount * 2.0 - 1.0,
+    })
+}
+
+/// A device's input translated through a `ControllerMapping` into SDL's
+/// standard gamepad layout, so every controller looks the same to consumers.
+#[derive(Clone, Debug)]
+pub struct MappedGamepad {
+    mapping: ControllerMapping,
+    state: GamepadInput,
+    /// The latest input from each button, axis and hat.
+    last: HashMap<(u8, u8), RawInput>,
+}
+
+impl MappedGamepad {
+    pub fn new(mapping: ControllerMapping) -> MappedGamepad {
+        MappedGamepad {
+            mapping,
+            state: GamepadInput::default(),
+            last: HashMap::new(),
+        }
+    }
+
+    pub fn mapping(&self) -> &ControllerMapping {
+        &self.mapping
+    }
+
+    /// The mapped state after all input so far.
+    pub fn state(&self) -> &GamepadInput {
+        &self.state
+    }
+
+    /// Apply one raw input, returning the controls it changed. Like SDL, only
+    /// bindings to what changed since the last input are applied, so e.g.
+    /// `-leftx:h0.8,+leftx:h0.2` don't undo each other.
+    pub fn update(&mut self, input: RawInput) -> Vec<SdlControl> {
+        let previous = self.last.insert(input.source(), input);
+        if previous == Some(input) {
+            return vec![];
+        }
+        let mut changed = vec![];
+        for entry in &self.mapping.entries {
+            if let (
+                Binding::Hat { mask, .. },
+                RawInput::Hat(_, bits),
+                Some(RawInput::Hat(_, old)),
+            ) = (entry.binding, input, previous)
+            {
+                if (bits ^ old) & mask == 0 {
+                    continue;
+                }
+            }
+            let Some(value) = entry_value(entry, input) else {
+                continue;
+            };
+            if set_control(&mut self.state, entry.control, value) {
+                changed.push(entry.control);
+            }
+        }
+        changed
+    }
+
+    /// Apply a parsed HID report: buttons are numbered from 0 in usage order,
+    /// axes in report order, and the hat switch is hat 0. Axes are only used
+    /// if the parser normalizes them. Triggers cover the whole -1.0..=1.0 like
+    /// every other axis, as they do for SDL, so `lefttrigger:a2` rests at 0.
+    pub fn update_from_report(&mut self, report: &ParsedReport) -> Vec<SdlControl> {
+        let mut changed = vec![];
+        for (i, pressed) in report.buttons.iter().enumerate() {
+            changed.extend(self.update(RawInput::Button(i as u8, *pressed)));
+        }
+        for (i, axis) in report.axes.iter().enumerate() {
+            if let Some(value) = axis.value {
+                let value = if axis.trigger {
+                    value * 2.0 - 1.0
+                } else {
+                    value
+                };
+                changed.extend(self.update(RawInput::Axis(i as u8, value)));
+            }
+        }
+        if let Some(dpad) = &report.dpad {
+            changed.extend(self.update(RawInput::Hat(0, hat_bits(dpad))));
+        }
+        changed
+    }
+}
+
+fn hat_bits(dpad: &Dpad) -> u8 {
+    [
+        (dpad.up, HAT_UP),
+        (dpad.right, HAT_RIGHT),
+        (dpad.down, HAT_DOWN),
+        (dpad.left, HAT_LEFT),
+    ]
+    .iter()
+    .filter(|(set, _)| *set)
+    .fold(0, |bits, (_, bit)| bits | bit)
+}
+
+/// Set a control in `input`, returning whether it changed.
+fn set_control(input: &mut GamepadInput, control: SdlControl, value: f32) -> bool {
+    let pressed = value != 0.0;
+    if let Some(button) = control.button() {
+        let changed = input.button(button) != pressed;
+        input.set_button(button, pressed);
+        return changed;
+    }
+    let slot = match control {
+        SdlControl::DpadUp => &mut input.dpad.up,
+        SdlControl::DpadDown => &mut input.dpad.down,
+        SdlControl::DpadLeft => &mut input.dpad.left,
+        SdlControl::DpadRight => &mut input.dpad.right,
+        SdlControl::LeftX => return replace(&mut input.left_stick.x, value),
+        SdlControl::LeftY => return replace(&mut input.left_stick.y, value),
+        SdlControl::RightX => return replace(&mut input.right_stick.x, value),
+        SdlControl::RightY => return replace(&mut input.right_stick.y, value),
+        SdlControl::LeftTrigger => return replace(&mut input.left_trigger, value),
+        SdlControl::RightTrigger => return replace(&mut input.right_trigger, value),
+        // Paddles and touchpad clicks have nowhere to go in `GamepadInput`.
+        _ => return false,
+    };
+    let changed = *slot != pressed;
+    *slot = pressed;
+    changed
+}
+
+fn replace(slot: &mut f32, value: f32) -> bool {
+    let changed = *slot != value;
+    *slot = value;
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const XBOX_360: &str = "030000005e0400008e02000010010000,Xbox 360 Controller,a:b0,b:b1,\
+        back:b6,dpdown:h0.4,dpleft:h0.8,dpright:h0.2,dpup:h0.1,guide:b8,leftshoulder:b4,\
+        leftstick:b9,lefttrigger:a2,leftx:a0,lefty:a1,rightshoulder:b5,rightstick:b10,\
+        righttrigger:a5,rightx:a3,righty:a4,start:b7,x:b2,y:b3,platform:Linux,";
+
+    fn binding(mapping: &ControllerMapping, control: SdlControl) -> Binding {
+        mapping.binding(control).unwrap().binding
+    }
+
+    #[test]
+    fn parses_a_database_line() {
+        let mapping = ControllerMapping::parse(XBOX_360).unwrap();
+        assert_eq!(mapping.name, "Xbox 360 Controller");
+        assert_eq!(mapping.platform.as_deref(), Some("Linux"));
+        assert_eq!(
+            mapping.guid,
+            create_sdl_controller_uuid(3, 0x045e, 0x028e, 0x0110)
+        );
+        assert_eq!(mapping.entries.len(), 21);
+        assert_eq!(binding(&mapping, SdlControl::A), Binding::Button(0));
+        assert_eq!(binding(&mapping, SdlControl::Y), Binding::Button(3));
+        assert_eq!(binding(&mapping, SdlControl::Guide), Binding::Button(8));
+        assert_eq!(
+            binding(&mapping, SdlControl::DpadLeft),
+            Binding::Hat {
+                index: 0,
+                mask: HAT_LEFT
+            }
+        );
+        assert_eq!(
+            binding(&mapping, SdlControl::RightTrigger),
+            Binding::Axis {
+                index: 5,
+                range: AxisRange::Full,
+                inverted: false
+            }
+        );
+        assert_eq!(mapping.binding(SdlControl::Paddle1), None);
+    }
+
+    #[test]
+    fn parses_half_and_inverted_axes() {
+        let line = "03000000000000000000000000000000,Test,+leftx:b3,-leftx:b2,lefty:a1~,\
+            righttrigger:+a4,crc:1234,";
+        let mapping = ControllerMapping::parse(line).unwrap();
+        let entry = mapping.entries[0];
+        assert_eq!(entry.output, AxisRange::Positive);
+        assert_eq!(entry.to_string(), "+leftx:b3");
+        assert_eq!(mapping.entries[1].output, AxisRange::Negative);
+        assert_eq!(
+            binding(&mapping, SdlControl::LeftY),
+            Binding::Axis {
+                index: 1,
+                range: AxisRange::Full,
+                inverted: true
+            }
+        );
+        assert_eq!(
+            binding(&mapping, SdlControl::RightTrigger),
+            Binding::Axis {
+                index: 4,
+                range: AxisRange::Positive,
+                inverted: false
+            }
+        );
+        assert_eq!(mapping.entries.len(), 4);
+        assert!(ControllerMapping::parse("03000000000000000000000000000000,Bad,a:b,").is_err());
+    }
+
+    #[test]
+    fn maps_raw_input_to_the_standard_layout() {
+        let mut gamepad = MappedGamepad::new(ControllerMapping::parse(XBOX_360).unwrap());
+        assert_eq!(gamepad.update(RawInput::Button(3, true)), [SdlControl::Y]);
+        assert!(gamepad.state().button(Button::North));
+        assert!(!gamepad.state().button(Button::South));
+        gamepad.update(RawInput::Axis(1, -0.5));
+        assert_eq!(gamepad.state().left_stick.y, -0.5);
+        // Triggers rest at the bottom of the axis.
+        gamepad.update(RawInput::Axis(2, -1.0));
+        assert_eq!(gamepad.state().left_trigger, 0.0);
+        gamepad.update(RawInput::Axis(2, 1.0));
+        assert_eq!(gamepad.state().left_trigger, 1.0);
+        let changed = gamepad.update(RawInput::Hat(0, HAT_UP | HAT_LEFT));
+        assert_eq!(changed, [SdlControl::DpadLeft, SdlControl::DpadUp]);
+        assert!(gamepad.state().dpad.up && gamepad.state().dpad.left);
+        assert_eq!(gamepad.update(RawInput::Hat(0, HAT_UP | HAT_LEFT)), []);
+    }
+
+    #[test]
+    fn system_databases_are_in_the_data_dirs() {
+        assert_eq!(
+            system_db_paths(None),
+            [
+                PathBuf::from("/usr/local/share/hidraw/gamecontrollerdb.txt"),
+                PathBuf::from("/usr/share/hidraw/gamecontrollerdb.txt"),
+            ]
+        );
+        assert_eq!(
+            system_db_paths(Some(OsStr::new("/opt/share:relative"))),
+            [PathBuf::from("/opt/share/hidraw/gamecontrollerdb.txt")]
+        );
+    }
+
+    #[test]
+    fn earlier_data_dirs_win() {
+        let base = std::env::temp_dir().join(format!("hidraw-data-{}", std::process::id()));
+        let local = base.join("local");
+        let system = base.join("system");
+        for (dir, name) in [(&local, "Local"), (&system, "System")] {
+            std::fs::create_dir_all(dir.join("hidraw")).unwrap();
+            let line = XBOX_360.replace("Xbox 360 Controller", name);
+            std::fs::write(dir.join("hidraw/gamecontrollerdb.txt"), line).unwrap();
+        }
+        let dirs = std::env::join_paths([&local, &system]).unwrap();
+        let db = MappingDatabase::load_system(Some(&dirs));
+        assert_eq!(db.mappings.len(), 2);
+        let guid = create_sdl_controller_uuid(3, 0x045e, 0x028e, 0x0110);
+        assert_eq!(db.find(&guid).unwrap().name, "Local");
+        let _ = std::fs::remove_dir_all(base);
+    }
+}
@@ -0,0 +1,128 @@
+use anyhow::{bail, Context as ErrorContext, Result};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::time::Duration;
+
+use crate::device::{Device, EvdevRumble};
+#[cfg(feature = "udev")]
+use crate::device_monitor::enumerate_gamepads;
+use crate::device_monitor::DeviceInfo;
+use crate::drivers::player::{IndicatorStyle, PlayerLeds, SysfsPlayerLeds};
+use crate::sysfs;
+#[cfg(not(feature = "udev"))]
+use crate::sysfs::enumerate_gamepads;
+
+// From Linux uapi/linux/input-event-codes.h
+const FF_RUMBLE: usize = 0x50;
+
+const RUMBLE_PULSE: Duration = Duration::from_millis(300);
+const LED_STEP: Duration = Duration::from_millis(250);
+
+/// How one capability fared.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Pass,
+    Fail(String),
+    /// The device or its driver doesn't support this.
+    Skipped(String),
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Pass => write!(f, "PASS"),
+            Outcome::Fail(reason) => write!(f, "FAIL ({reason})"),
+            Outcome::Skipped(reason) => write!(f, "SKIP ({reason})"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CheckResult {
+    pub capability: &'static str,
+    pub outcome: Outcome,
+}
+
+fn outcome(result: Result<Outcome>) -> Outcome {
+    result.unwrap_or_else(|e| Outcome::Fail(format!("{e:#}")))
+}
+
+/// Find a connected gamepad by its evdev node or sys path.
+fn find_device(device: &Path) -> Result<DeviceInfo> {
+    enumerate_gamepads()?
+        .into_iter()
+        .find(|info| info.device_node == device || info.sys_path == device)
+        .with_context(|| format!("{device:?} isn't a connected gamepad"))
+}
+
+/// Play a short rumble pulse through evdev force feedback.
+async fn check_rumble(info: &DeviceInfo) -> Result<Outcome> {
+    let ff = fs::read_to_string(info.sys_path.join("device/capabilities/ff")).unwrap_or_default();
+    if !sysfs::has_bit(&ff, FF_RUMBLE) {
+        return Ok(Outcome::Skipped("no rumble support".to_owned()));
+    }
+    let rumble = EvdevRumble::open(&info.device_node)?;
+    rumble
+        .rumble(0xFFFF, 0xFFFF, RUMBLE_PULSE.as_millis() as u32)
+        .await?;
+    tokio::time::sleep(RUMBLE_PULSE).await;
+    Ok(Outcome::Pass)
+}
+
+/// Step through players 1 to 4 on the player LEDs, then turn them off.
+async fn check_leds(info: &DeviceInfo) -> Result<Outcome> {
+    let Some(style) = IndicatorStyle::for_device(info.vendor_id, info.product_id) else {
+        return Ok(Outcome::Skipped("no known player indicator".to_owned()));
+    };
+    // eventN -> inputN -> the HID device.
+    let hid_sys_path = fs::canonicalize(info.sys_path.join("device/device"))?;
+    let mut leds = match SysfsPlayerLeds::find(&hid_sys_path, style) {
+        Ok(leds) => leds,
+        Err(e) => return Ok(Outcome::Skipped(format!("{e:#}"))),
+    };
+    for player in (1..=4).chain([0]) {
+        leds.set_player(player)?;
+        tokio::time::sleep(LED_STEP).await;
+    }
+    Ok(Outcome::Pass)
+}
+
+/// Read back every feature report the descriptor declares.
+fn check_feature_reports(info: &DeviceInfo) -> Result<Outcome> {
+    let Some(node) = &info.hidraw_node else {
+        return Ok(Outcome::Skipped("no hidraw node".to_owned()));
+    };
+    let hidraw = Device::open(node)?;
+    let report_ids = hidraw.feature_report_ids();
+    if report_ids.is_empty() {
+        return Ok(Outcome::Skipped("no feature reports".to_owned()));
+    }
+    for report_id in report_ids {
+        let report = hidraw.get_feature_report(report_id)?;
+        if report.is_empty() {
+            bail!("Feature report {report_id:#04x} was empty");
+        }
+    }
+    Ok(Outcome::Pass)
+}
+
+/// Run the scripted self-test on a gamepad, given its evdev node or sys path.
+/// The user should feel a rumble pulse and see the player LEDs cycle.
+pub async fn run(device: &Path) -> Result<Vec<CheckResult>> {
+    let info = find_device(device)?;
+    Ok(vec![
+        CheckResult {
+            capability: "rumble",
+            outcome: outcome(check_rumble(&info).await),
+        },
+        CheckResult {
+            capability: "leds",
+            outcome: outcome(check_leds(&info).await),
+        },
+        CheckResult {
+            capability: "feature reports",
+            outcome: outcome(check_feature_reports(&info)),
+        },
+    ])
+}
@@ -0,0 +1,75 @@
+use std::ffi::CStr;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use crate::device_monitor::DeviceInfo;
+
+/// The seat devices are on unless udev assigns them elsewhere.
+pub const DEFAULT_SEAT: &str = "seat0";
+
+/// Where logind keeps the state of each seat.
+const SEATS_DIR: &str = "/run/systemd/seats";
+/// Where udev keeps the properties of each device node.
+const UDEV_DATA_DIR: &str = "/run/udev/data";
+
+/// A logged-in user.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct User {
+    pub uid: u32,
+    pub name: String,
+}
+
+/// The seat a device node is attached to, from udev's `ID_SEAT`, which
+/// `loginctl attach` sets.
+pub fn device_seat(device_node: &Path) -> String {
+    let seat = fs::metadata(device_node).ok().and_then(|metadata| {
+        let (major, minor) = split_dev(metadata.rdev());
+        let data = fs::read_to_string(format!("{UDEV_DATA_DIR}/c{major}:{minor}")).ok()?;
+        data.lines()
+            .find_map(|line| line.strip_prefix("E:ID_SEAT="))
+            .map(str::to_owned)
+    });
+    seat.unwrap_or_else(|| DEFAULT_SEAT.to_owned())
+}
+
+/// The user of the active session on `seat`, as logind records it. `None` if
+/// nobody is logged in there, or logind isn't running.
+pub fn active_user(seat: &str) -> Option<User> {
+    let state = fs::read_to_string(Path::new(SEATS_DIR).join(seat)).ok()?;
+    let uid = state
+        .lines()
+        .find_map(|line| line.strip_prefix("ACTIVE_UID="))?
+        .parse()
+        .ok()?;
+    Some(User {
+        uid,
+        name: user_name(uid)?,
+    })
+}
+
+/// The user whose session a device belongs to right now: the active one on
+/// its seat.
+pub fn device_user(info: &DeviceInfo) -> Option<User> {
+    active_user(&device_seat(&info.device_node))
+}
+
+fn user_name(uid: u32) -> Option<String> {
+    let mut buf = vec![0 as libc::c_char; 4096];
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    let ret =
+        unsafe { libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+    if ret != 0 || result.is_null() {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr(passwd.pw_name) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+/// A device number's major and minor, as glibc encodes them.
+fn split_dev(dev: u64) -> (u64, u64) {
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    (major, minor)
+}
@@ -0,0 +1,78 @@
+use anyhow::Result;
+use std::collections::HashMap;
+
+use crate::report::{Axis, Button, GamepadInput};
+
+/// A consumer of standardized controller state, such as a virtual device or a
+/// network protocol.
+pub trait OutputSink {
+    /// A short name used in logs.
+    fn name(&self) -> &str;
+
+    /// Send the latest controller state. Sinks are expected to only emit what
+    /// changed since the previous call.
+    fn send(&mut self, input: &GamepadInput) -> Result<()>;
+}
+
+/// Controller models we can present to other software, with the `emulation`
+/// feature.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EmulationTarget {
+    Xbox360,
+    DualShock4,
+}
+
+impl EmulationTarget {
+    pub fn from_name(name: &str) -> Option<EmulationTarget> {
+        match name {
+            "xbox360" => Some(EmulationTarget::Xbox360),
+            "dualshock4" => Some(EmulationTarget::DualShock4),
+            _ => None,
+        }
+    }
+}
+
+/// A control that can be routed to a sink.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Control {
+    Button(Button),
+    Axis(Axis),
+    Dpad,
+}
+
+/// Which sinks receive which controls, applied after the transform pipeline.
+/// Routes are keyed by the id a sink was configured under, so sinks of the same
+/// type are routed separately. Sinks without a route receive every control.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RoutingMatrix {
+    routes: HashMap<String, Vec<Control>>,
+}
+
+impl RoutingMatrix {
+    pub fn new() -> RoutingMatrix {
+        RoutingMatrix::default()
+    }
+
+    /// Send only `controls` to the sink with id `sink`.
+    pub fn route(mut self, sink: &str, controls: Vec<Control>) -> RoutingMatrix {
+        self.routes.insert(sink.to_owned(), controls);
+        self
+    }
+
+    /// The part of `input` that the sink with id `sink` should see. Controls that
+    /// aren't routed to it are left at rest.
+    pub fn filter(&self, sink: &str, input: &GamepadInput) -> GamepadInput {
+        let Some(controls) = self.routes.get(sink) else {
+            return input.clone();
+        };
+        let mut output = GamepadInput::default();
+        for control in controls {
+            match *control {
+                Control::Button(button) => output.set_button(button, input.button(button)),
+                Control::Axis(axis) => output.set_axis(axis, input.axis(axis)),
+                Control::Dpad => output.dpad = input.dpad.clone(),
+            }
+        }
+        output
+    }
+}
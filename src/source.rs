@@ -0,0 +1,386 @@
+use anyhow::Result;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use log::{info, warn};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::Receiver;
+use tokio::time::MissedTickBehavior;
+
+use crate::report::{Button, GamepadInput};
+use crate::sink::{OutputSink, RoutingMatrix};
+use crate::trace::{self, Phase};
+use crate::transform::{self, AxisTransform};
+
+/// Anything that produces controller state: a physical device, a network
+/// receiver, a replay file or a script.
+pub trait InputSource: Send {
+    /// A short name used in logs.
+    fn name(&self) -> &str;
+
+    /// Wait for the next controller state. Returns `Ok(None)` once the source
+    /// has no more input.
+    fn next_input(&mut self) -> BoxFuture<'_, Result<Option<GamepadInput>>>;
+}
+
+/// An `InputSource` that plays back a fixed sequence of states, waiting the
+/// given delay before each one.
+pub struct ScriptedSource {
+    name: String,
+    steps: VecDeque<(Duration, GamepadInput)>,
+}
+
+impl ScriptedSource {
+    pub fn new(name: &str, steps: Vec<(Duration, GamepadInput)>) -> ScriptedSource {
+        ScriptedSource {
+            name: name.to_owned(),
+            steps: steps.into(),
+        }
+    }
+}
+
+impl InputSource for ScriptedSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn next_input(&mut self) -> BoxFuture<'_, Result<Option<GamepadInput>>> {
+        async move {
+            match self.steps.pop_front() {
+                Some((delay, input)) => {
+                    tokio::time::sleep(delay).await;
+                    Ok(Some(input))
+                }
+                None => Ok(None),
+            }
+        }
+        .boxed()
+    }
+}
+
+/// An `InputSource` reading the states a device's task decodes, from
+/// `TaskHandle::subscribe`. It ends when the task does.
+pub struct ChannelSource {
+    name: String,
+    inputs: Receiver<GamepadInput>,
+}
+
+impl ChannelSource {
+    pub fn new(name: &str, inputs: Receiver<GamepadInput>) -> ChannelSource {
+        ChannelSource {
+            name: name.to_owned(),
+            inputs,
+        }
+    }
+}
+
+impl InputSource for ChannelSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn next_input(&mut self) -> BoxFuture<'_, Result<Option<GamepadInput>>> {
+        async move { Ok(self.inputs.recv().await) }.boxed()
+    }
+}
+
+/// Smooths the states sent to a virtual device that's updated on a fixed tick
+/// faster than the physical device reports. Axes are interpolated between the
+/// last two reports, trailing the device by one report interval, so a dropped
+/// report shows as a brief hold instead of a stall and a jump. Buttons and the
+/// d-pad always take the latest state.
+#[derive(Clone, Debug, Default)]
+pub struct StateInterpolator {
+    previous: Option<(GamepadInput, Instant)>,
+    latest: Option<(GamepadInput, Instant)>,
+}
+
+impl StateInterpolator {
+    pub fn new() -> StateInterpolator {
+        StateInterpolator::default()
+    }
+
+    /// Record a state the physical device reported at `at`.
+    pub fn push(&mut self, input: GamepadInput, at: Instant) {
+        self.previous = self.latest.replace((input, at));
+    }
+
+    /// The state to send at `at`, or `None` before the first report.
+    pub fn sample(&self, at: Instant) -> Option<GamepadInput> {
+        let (latest, latest_at) = self.latest.as_ref()?;
+        let Some((previous, previous_at)) = &self.previous else {
+            return Some(latest.clone());
+        };
+        let interval = latest_at.saturating_duration_since(*previous_at);
+        if interval.is_zero() {
+            return Some(latest.clone());
+        }
+        // One interval behind `at` is this far from the previous report to
+        // the latest.
+        let elapsed = at.saturating_duration_since(*latest_at);
+        let t = (elapsed.as_secs_f32() / interval.as_secs_f32()).min(1.0);
+        let (from, to) = (previous.axes(), latest.axes());
+        let mut output = latest.clone();
+        output.set_axes(&std::array::from_fn(|i| from[i] + (to[i] - from[i]) * t));
+        Some(output)
+    }
+}
+
+/// When `run_source` sends to its sinks.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum OutputMode {
+    /// As soon as the source produces a state.
+    #[default]
+    OnInput,
+    /// On a fixed timer, `rate_hz` times a second, whatever the source's own
+    /// cadence, for games and latency rigs that expect a steady one. Each
+    /// tick sends the latest state, or an interpolated one if `interpolate`
+    /// is set.
+    FixedTick { rate_hz: u32, interpolate: bool },
+}
+
+/// What `run_source` sends once its source ends or fails, e.g. when a
+/// controller's battery dies.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum DisconnectPolicy {
+    /// Release every control at once.
+    #[default]
+    Zero,
+    /// Keep the last state for a while, riding out a brief dropout, then
+    /// release every control.
+    Hold(Duration),
+    /// Release every control while tapping Start, so games pause rather than
+    /// carry on without the player.
+    Pause,
+}
+
+/// How long `DisconnectPolicy::Pause` holds Start.
+const PAUSE_TAP: Duration = Duration::from_millis(100);
+
+/// A sink and the id its routes refer to it by, e.g. its name in the config.
+pub type RoutedSink = (String, Box<dyn OutputSink + Send>);
+
+/// Send `input` to every sink, filtered by `routing`.
+fn send_to_sinks(
+    source: &str,
+    sinks: &mut [RoutedSink],
+    routing: &RoutingMatrix,
+    input: &GamepadInput,
+) {
+    for (id, sink) in sinks.iter_mut() {
+        let start = Instant::now();
+        let routed = routing.filter(id, input);
+        if let Err(e) = sink.send(&routed) {
+            warn!("Sink `{}` failed: {e}", sink.name());
+        }
+        trace::record(source, Phase::Emit, sink.name(), start);
+    }
+}
+
+/// Read from `source` until it ends or `stop_rx` fires, applying `transforms` to
+/// each state and sending the result to every sink, filtered by `routing` on
+/// the sinks' ids, at
+/// the times `mode` picks. A failing sink is logged and doesn't stop the others.
+/// If the source ends or fails, the sinks get what `disconnect` says first.
+/// The sinks are borrowed, so they can go on to the next source.
+pub async fn run_source(
+    mut source: Box<dyn InputSource>,
+    mut stop_rx: Receiver<()>,
+    transforms: Vec<AxisTransform>,
+    routing: RoutingMatrix,
+    sinks: &mut [RoutedSink],
+    mode: OutputMode,
+    disconnect: DisconnectPolicy,
+) -> Result<()> {
+    info!("Starting source `{}` with {mode:?}", source.name());
+    let (mut ticker, interpolate) = match mode {
+        OutputMode::OnInput => (None, false),
+        OutputMode::FixedTick {
+            rate_hz,
+            interpolate,
+        } => {
+            let period = Duration::from_secs(1) / rate_hz.max(1);
+            let mut ticker = tokio::time::interval(period);
+            // A late tick sends the state as of now; catching up would only
+            // send the same state several times.
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            (Some(ticker), interpolate)
+        }
+    };
+    let mut interpolator = StateInterpolator::new();
+    let mut latest = None;
+    let mut result = Ok(());
+    let mut disconnected = false;
+    loop {
+        let tick = async {
+            match &mut ticker {
+                Some(ticker) => ticker.tick().await,
+                None => std::future::pending().await,
+            }
+        };
+        let input = tokio::select! {
+            _ = stop_rx.recv() => break,
+            input = source.next_input() => match input {
+                Ok(Some(input)) => Some(input),
+                Ok(None) => {
+                    disconnected = true;
+                    break;
+                }
+                Err(e) => {
+                    disconnected = true;
+                    result = Err(e);
+                    break;
+                }
+            },
+            _ = tick => None,
+        };
+        let start = Instant::now();
+        let input = match input {
+            Some(mut input) => {
+                trace::record(source.name(), Phase::Read, "input", start);
+                let mut axes = input.axes();
+                transform::apply_all(&transforms, &mut axes);
+                input.set_axes(&axes);
+                trace::record(source.name(), Phase::Decode, "transforms", start);
+                if ticker.is_some() {
+                    if interpolate {
+                        interpolator.push(input, start);
+                    } else {
+                        latest = Some(input);
+                    }
+                    continue;
+                }
+                input
+            }
+            None if interpolate => match interpolator.sample(start) {
+                Some(input) => input,
+                None => continue,
+            },
+            None => match &latest {
+                Some(input) => input.clone(),
+                None => continue,
+            },
+        };
+        send_to_sinks(source.name(), sinks, &routing, &input);
+    }
+    if disconnected {
+        info!("Source `{}` disconnected, {disconnect:?}", source.name());
+        match disconnect {
+            DisconnectPolicy::Zero => {}
+            DisconnectPolicy::Hold(duration) => {
+                tokio::select! {
+                    _ = stop_rx.recv() => {}
+                    _ = tokio::time::sleep(duration) => {}
+                }
+            }
+            DisconnectPolicy::Pause => {
+                let mut pause = GamepadInput::default();
+                pause.set_button(Button::Start, true);
+                send_to_sinks(source.name(), sinks, &routing, &pause);
+                tokio::time::sleep(PAUSE_TAP).await;
+            }
+        }
+        let rest = GamepadInput::default();
+        send_to_sinks(source.name(), sinks, &routing, &rest);
+    }
+    info!("Stopping source `{}`", source.name());
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::Control;
+    use std::sync::{Arc, Mutex};
+    use tokio::sync::mpsc;
+
+    /// A sink that keeps every state it's sent.
+    struct Recorder(Arc<Mutex<Vec<GamepadInput>>>);
+
+    impl OutputSink for Recorder {
+        fn name(&self) -> &str {
+            "recorder"
+        }
+
+        fn send(&mut self, input: &GamepadInput) -> Result<()> {
+            self.0.lock().unwrap().push(input.clone());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn routes_sinks_of_one_type_by_id() {
+        let mut pressed = GamepadInput::default();
+        pressed.set_button(Button::South, true);
+        pressed.set_button(Button::East, true);
+        let source = ScriptedSource::new("script", vec![(Duration::ZERO, pressed)]);
+        let (first, second) = (Arc::default(), Arc::default());
+        let mut sinks: Vec<RoutedSink> = vec![
+            ("one".to_owned(), Box::new(Recorder(Arc::clone(&first)))),
+            ("two".to_owned(), Box::new(Recorder(Arc::clone(&second)))),
+        ];
+        let routing = RoutingMatrix::new()
+            .route("one", vec![Control::Button(Button::South)])
+            .route("two", vec![Control::Button(Button::East)]);
+        let (_stop_tx, stop_rx) = mpsc::channel(1);
+        run_source(
+            Box::new(source),
+            stop_rx,
+            vec![],
+            routing,
+            &mut sinks,
+            OutputMode::OnInput,
+            DisconnectPolicy::Zero,
+        )
+        .await
+        .unwrap();
+
+        let first = first.lock().unwrap();
+        assert!(first[0].button(Button::South) && !first[0].button(Button::East));
+        let second = second.lock().unwrap();
+        assert!(!second[0].button(Button::South) && second[0].button(Button::East));
+        // Both are released when the source ends.
+        assert!(!first.last().unwrap().button(Button::South));
+        assert!(!second.last().unwrap().button(Button::East));
+    }
+
+    #[tokio::test]
+    async fn plays_out_the_disconnect_policy_when_input_ends() {
+        let hold = Duration::from_millis(50);
+        for disconnect in [DisconnectPolicy::Hold(hold), DisconnectPolicy::Pause] {
+            let (input_tx, input_rx) = mpsc::channel(1);
+            let mut pressed = GamepadInput::default();
+            pressed.set_button(Button::South, true);
+            input_tx.send(pressed).await.unwrap();
+            drop(input_tx);
+            let sent = Arc::new(Mutex::new(vec![]));
+            let mut sinks: Vec<RoutedSink> =
+                vec![("one".to_owned(), Box::new(Recorder(Arc::clone(&sent))))];
+            // Held open, like the daemon does for devices that go away.
+            let (_stop_tx, stop_rx) = mpsc::channel(1);
+            let start = Instant::now();
+            run_source(
+                Box::new(ChannelSource::new("device", input_rx)),
+                stop_rx,
+                vec![],
+                RoutingMatrix::new(),
+                &mut sinks,
+                OutputMode::OnInput,
+                disconnect,
+            )
+            .await
+            .unwrap();
+
+            let sent = sent.lock().unwrap();
+            assert!(sent[0].button(Button::South));
+            match disconnect {
+                DisconnectPolicy::Hold(_) => {
+                    assert!(start.elapsed() >= hold);
+                    assert_eq!(sent.len(), 2);
+                }
+                _ => assert!(sent[1].button(Button::Start)),
+            }
+            assert!(!sent.last().unwrap().button(Button::South));
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use anyhow::Result;
+use log::debug;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::device_monitor::{Bus, DeviceInfo};
+
+const SYS_DEVICES_VIRTUAL_INPUT: &str = "/sys/devices/virtual/input";
+
+pub const VALVE_VENDOR_ID: u16 = 0x28DE;
+
+// From SDL's src/joystick/SDL_joystick.c
+const STEAM_VIRTUAL_GAMEPAD_PID: u16 = 0x11FF;
+const STEAM_VIRTUAL_GAMEPAD_NAME: &str = "Steam Virtual Gamepad";
+const MICROSOFT_VENDOR_ID: u16 = 0x045E;
+const XBOX_360_PID: u16 = 0x028E;
+
+/// What to do with the virtual controllers Steam Input creates.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SteamPolicy {
+    /// Leave them alone. Steam's virtual pads mirror physical pads we may
+    /// already be handling, and if we emulate devices too, Steam may wrap
+    /// ours in turn, feeding input back and forth.
+    Ignore,
+    /// Handle them like any other gamepad.
+    Allow,
+}
+
+impl SteamPolicy {
+    pub fn from_name(name: &str) -> Option<SteamPolicy> {
+        match name {
+            "ignore" => Some(SteamPolicy::Ignore),
+            "allow" => Some(SteamPolicy::Allow),
+            _ => None,
+        }
+    }
+}
+
+/// Whether a device with these IDs and name is one of Steam Input's virtual
+/// controllers. Older Steam clients emulate a wired Xbox 360 pad, which only
+/// differs from the real thing by being virtual and having version 0.
+pub fn is_steam_virtual_ids(
+    bus: Bus,
+    vendor_id: u16,
+    product_id: u16,
+    version: u16,
+    name: &str,
+) -> bool {
+    if bus != Bus::Virtual {
+        return false;
+    }
+    match (vendor_id, product_id) {
+        (VALVE_VENDOR_ID, STEAM_VIRTUAL_GAMEPAD_PID) => true,
+        (MICROSOFT_VENDOR_ID, XBOX_360_PID) => version == 0,
+        _ => name.starts_with(STEAM_VIRTUAL_GAMEPAD_NAME),
+    }
+}
+
+pub fn is_steam_virtual(info: &DeviceInfo) -> bool {
+    is_steam_virtual_ids(
+        info.bus,
+        info.vendor_id,
+        info.product_id,
+        info.version,
+        &info.name,
+    )
+}
+
+fn read_hex_attr(path: &Path) -> Option<u16> {
+    u16::from_str_radix(fs::read_to_string(path).ok()?.trim(), 16).ok()
+}
+
+/// Find the virtual input devices Steam has created, which means Steam Input
+/// is running and handling at least one controller.
+pub fn find_steam_devices() -> Result<Vec<PathBuf>> {
+    let mut found = vec![];
+    let Ok(entries) = fs::read_dir(SYS_DEVICES_VIRTUAL_INPUT) else {
+        return Ok(found);
+    };
+    for entry in entries {
+        let dir = entry?.path();
+        let (Some(vendor_id), Some(product_id), Some(version)) = (
+            read_hex_attr(&dir.join("id/vendor")),
+            read_hex_attr(&dir.join("id/product")),
+            read_hex_attr(&dir.join("id/version")),
+        ) else {
+            continue;
+        };
+        let name = fs::read_to_string(dir.join("name")).unwrap_or_default();
+        if is_steam_virtual_ids(Bus::Virtual, vendor_id, product_id, version, name.trim()) {
+            debug!("Found Steam virtual device {:?} ({})", dir, name.trim());
+            found.push(dir);
+        }
+    }
+    Ok(found)
+}
@@ -0,0 +1,214 @@
+use anyhow::{bail, Context as ErrorContext, Result};
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Where persistent data is kept, so deployments with many users or machines
+/// can share one store. Entries are bytes under `/`-separated keys: `config`
+/// for the daemon's config, `cache/...` for data read from devices like their
+/// calibration, `descriptors/vvvv:pppp.bin` (or `.hex`) for descriptor
+/// overrides, `mappings/gamecontrollerdb.txt` for SDL mappings, and `stats`
+/// for the daemon's counters as of its last shutdown.
+pub trait Storage: Send + Sync {
+    fn read(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Replace the entry `key` whole, so readers never see part of one.
+    fn write(&self, key: &str, data: &[u8]) -> Result<()>;
+
+    /// Remove the entry `key`, if there is one.
+    fn remove(&self, key: &str) -> Result<()>;
+
+    /// The keys starting with `prefix`, sorted.
+    fn keys(&self, prefix: &str) -> Result<Vec<String>>;
+}
+
+/// Entries as files under a directory, named by their keys.
+pub struct FileStorage {
+    root: PathBuf,
+}
+
+impl FileStorage {
+    pub fn new(root: &Path) -> FileStorage {
+        FileStorage {
+            root: root.to_owned(),
+        }
+    }
+
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        let relative = Path::new(key);
+        if !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            bail!("Bad storage key `{key}`");
+        }
+        Ok(self.root.join(relative))
+    }
+}
+
+impl Storage for FileStorage {
+    fn read(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.path(key)?;
+        match fs::read(&path) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {path:?}")),
+        }
+    }
+
+    fn write(&self, key: &str, data: &[u8]) -> Result<()> {
+        let path = self.path(key)?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).with_context(|| format!("Failed to create {parent:?}"))?;
+        }
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, data).with_context(|| format!("Failed to write {tmp:?}"))?;
+        fs::rename(&tmp, &path).with_context(|| format!("Failed to write {path:?}"))?;
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) -> Result<()> {
+        let path = self.path(key)?;
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                Err(e).with_context(|| format!("Failed to remove {path:?}"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+        let mut keys = vec![];
+        let mut dirs = vec![self.root.clone()];
+        while let Some(dir) = dirs.pop() {
+            let entries = match fs::read_dir(&dir) {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e).with_context(|| format!("Failed to list {dir:?}")),
+            };
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else if let Ok(key) = path.strip_prefix(&self.root) {
+                    let key = key.to_string_lossy().into_owned();
+                    if key.starts_with(prefix) {
+                        keys.push(key);
+                    }
+                }
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+/// Entries in one table of an SQLite database, which several daemons can
+/// share.
+#[cfg(feature = "sqlite")]
+pub struct SqliteStorage {
+    connection: Mutex<rusqlite::Connection>,
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteStorage {
+    pub fn open(path: &Path) -> Result<SqliteStorage> {
+        let connection =
+            rusqlite::Connection::open(path).with_context(|| format!("Failed to open {path:?}"))?;
+        // Wait for other daemons' writes rather than failing.
+        connection.busy_timeout(std::time::Duration::from_secs(5))?;
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS entries (key TEXT PRIMARY KEY, data BLOB NOT NULL)",
+            )
+            .with_context(|| format!("Failed to set up {path:?}"))?;
+        Ok(SqliteStorage {
+            connection: Mutex::new(connection),
+        })
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl Storage for SqliteStorage {
+    fn read(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        use rusqlite::OptionalExtension;
+        let connection = self.connection.lock().unwrap();
+        let data = connection
+            .query_row("SELECT data FROM entries WHERE key = ?1", [key], |row| {
+                row.get(0)
+            })
+            .optional()
+            .with_context(|| format!("Failed to read `{key}`"))?;
+        Ok(data)
+    }
+
+    fn write(&self, key: &str, data: &[u8]) -> Result<()> {
+        let connection = self.connection.lock().unwrap();
+        connection
+            .execute(
+                "INSERT INTO entries (key, data) VALUES (?1, ?2) \
+                 ON CONFLICT(key) DO UPDATE SET data = excluded.data",
+                rusqlite::params![key, data],
+            )
+            .with_context(|| format!("Failed to write `{key}`"))?;
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) -> Result<()> {
+        let connection = self.connection.lock().unwrap();
+        connection
+            .execute("DELETE FROM entries WHERE key = ?1", [key])
+            .with_context(|| format!("Failed to remove `{key}`"))?;
+        Ok(())
+    }
+
+    fn keys(&self, prefix: &str) -> Result<Vec<String>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT key FROM entries WHERE substr(key, 1, length(?1)) = ?1 ORDER BY key",
+        )?;
+        let keys = statement
+            .query_map([prefix], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()
+            .context("Failed to list entries")?;
+        Ok(keys)
+    }
+}
+
+/// Open the storage `spec` names: `sqlite:<path>` for an SQLite database,
+/// with the `sqlite` feature, or a directory otherwise.
+pub fn open(spec: &str) -> Result<Arc<dyn Storage>> {
+    if let Some(path) = spec.strip_prefix("sqlite:") {
+        #[cfg(feature = "sqlite")]
+        return Ok(Arc::new(SqliteStorage::open(Path::new(path))?));
+        #[cfg(not(feature = "sqlite"))]
+        bail!("Can't open {path:?}: built without the sqlite feature");
+    }
+    Ok(Arc::new(FileStorage::new(Path::new(spec))))
+}
+
+static STORAGE: Mutex<Option<Arc<dyn Storage>>> = Mutex::new(None);
+
+/// Use `storage` from now on.
+pub fn set_storage(storage: Arc<dyn Storage>) {
+    *STORAGE.lock().unwrap() = Some(storage);
+}
+
+/// The storage set with `set_storage`, or else the one `$HIDRAW_STORAGE`
+/// names. Without either, everything stays where it's always been: config and
+/// overrides in their own files, and the cache in the cache directory.
+pub fn configured() -> Option<Arc<dyn Storage>> {
+    let mut storage = STORAGE.lock().unwrap();
+    if storage.is_none() {
+        let spec = std::env::var("HIDRAW_STORAGE").ok()?;
+        match open(&spec) {
+            Ok(opened) => *storage = Some(opened),
+            Err(e) => {
+                log::warn!("Not using storage `{spec}`: {e:#}");
+                return None;
+            }
+        }
+    }
+    storage.clone()
+}
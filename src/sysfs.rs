@@ -0,0 +1,287 @@
+use anyhow::{bail, Context as ErrorContext, Result};
+use futures::Future;
+use log::{debug, error, info};
+use std::fs::{self, File};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::time::SystemTime;
+use tokio::sync::mpsc::Sender;
+
+use crate::device::{self, EvdevNames};
+use crate::device_monitor::{
+    self, Bus, DeviceEvent, DeviceInfo, InputType, MonitorConfig, UsbAddress,
+};
+use crate::error::{self, IoContext};
+use crate::report;
+
+const SYS_CLASS_INPUT: &str = "/sys/class/input";
+
+// From Linux uapi/linux/input-event-codes.h
+const KEY_A: usize = 30;
+const KEY_Z: usize = 44;
+const BTN_LEFT: usize = 0x110;
+const BTN_JOYSTICK: usize = 0x120;
+const BTN_GAMEPAD: usize = 0x130;
+const BTN_TOOL_FINGER: usize = 0x145;
+const REL_X: usize = 0x00;
+const REL_Y: usize = 0x01;
+const ABS_X: usize = 0x00;
+const ABS_Y: usize = 0x01;
+
+fn read_attr(path: &Path) -> Result<String> {
+    Ok(fs::read_to_string(path)
+        .with_context(|| format!("Failed to read {path:?}"))?
+        .trim()
+        .to_owned())
+}
+
+fn read_hex_attr(path: &Path) -> Result<u16> {
+    Ok(u16::from_str_radix(&read_attr(path)?, 16)?)
+}
+
+/// Test a bit in a sysfs capability bitmap, which is printed as space-separated
+/// hex words of `usize::BITS` bits, most significant word first.
+pub fn has_bit(bitmap: &str, bit: usize) -> bool {
+    let word_bits = usize::BITS as usize;
+    bitmap
+        .split_whitespace()
+        .rev()
+        .nth(bit / word_bits)
+        .and_then(|w| usize::from_str_radix(w, 16).ok())
+        .is_some_and(|w| w & (1 << (bit % word_bits)) != 0)
+}
+
+/// Find the hidraw node of the HID device an input device belongs to, from
+/// `<hid device>/hidraw/hidrawN`.
+fn find_hidraw_node(input_dir: &Path) -> Option<PathBuf> {
+    let entry = fs::read_dir(input_dir.join("device/hidraw"))
+        .ok()?
+        .next()?
+        .ok()?;
+    Some(Path::new("/dev").join(entry.file_name()))
+}
+
+/// The bus number and address of the USB device an input device belongs to,
+/// from the `busnum` and `devnum` attributes of the first ancestor with them.
+fn find_usb_address(input_dir: &Path) -> Option<UsbAddress> {
+    let path = fs::canonicalize(input_dir).ok()?;
+    path.ancestors().find_map(|dir| {
+        Some(UsbAddress {
+            bus: read_attr(&dir.join("busnum")).ok()?.parse().ok()?,
+            address: read_attr(&dir.join("devnum")).ok()?.parse().ok()?,
+        })
+    })
+}
+
+/// Whether an input device is of this type, roughly as udev's input_id
+/// builtin decides from its capabilities.
+fn is_input_type(input_dir: &Path, input_type: InputType) -> bool {
+    let caps = |kind| read_attr(&input_dir.join("capabilities").join(kind)).unwrap_or_default();
+    let keys = caps("key");
+    match input_type {
+        InputType::Joystick => has_bit(&keys, BTN_GAMEPAD) || has_bit(&keys, BTN_JOYSTICK),
+        InputType::Keyboard => has_bit(&keys, KEY_A) && has_bit(&keys, KEY_Z),
+        InputType::Mouse => {
+            let rel = caps("rel");
+            has_bit(&keys, BTN_LEFT) && has_bit(&rel, REL_X) && has_bit(&rel, REL_Y)
+        }
+        InputType::Touchpad => {
+            let abs = caps("abs");
+            has_bit(&keys, BTN_TOOL_FINGER) && has_bit(&abs, ABS_X) && has_bit(&abs, ABS_Y)
+        }
+    }
+}
+
+/// Build a `DeviceInfo` for `/sys/class/input/eventN` without using udev.
+fn get_device_info(event_dir: &Path, config: &MonitorConfig) -> Result<DeviceInfo> {
+    let input_dir = event_dir.join("device");
+    if !InputType::ALL
+        .into_iter()
+        .any(|t| config.wants_type(t) && is_input_type(&input_dir, t))
+    {
+        bail!("Not a wanted input device: {event_dir:?}");
+    }
+    if device_monitor::is_emulated(&read_attr(&input_dir.join("phys")).unwrap_or_default()) {
+        bail!("Skipping our own virtual device: {event_dir:?}");
+    }
+    let bus = Bus::from_id(read_hex_attr(&input_dir.join("id/bustype"))?);
+    let sysname = event_dir.file_name().context("Bad sysfs path")?;
+    let vendor_id = read_hex_attr(&input_dir.join("id/vendor"))?;
+    let product_id = read_hex_attr(&input_dir.join("id/product"))?;
+    if !config.matches(bus, vendor_id, product_id) {
+        bail!("Filtered out {vendor_id:04x}:{product_id:04x} on {bus:?}: {event_dir:?}");
+    }
+    let hidraw_node = find_hidraw_node(&input_dir);
+    let hidraw = hidraw_node.as_ref().and_then(|node| {
+        File::open(node)
+            .map_err(|e| debug!("Failed to open {node:?}: {e}"))
+            .ok()
+    });
+    let parser = report::find_report_parser(
+        vendor_id,
+        product_id,
+        hidraw.as_ref().map(|f| f.as_raw_fd()),
+    );
+    let device_node = Path::new("/dev/input").join(sysname);
+    // The ioctls need the node to be readable, but the input device's
+    // attributes say the same.
+    let opened = File::open(&device_node).ok();
+    let input_id = opened
+        .as_ref()
+        .and_then(|node| device::read_input_id(node.as_raw_fd()).ok());
+    let names = match opened.and_then(|node| device::evdev_names(node.as_raw_fd()).ok()) {
+        Some(names) => names,
+        None => EvdevNames {
+            name: read_attr(&input_dir.join("name"))?,
+            uniq: read_attr(&input_dir.join("uniq")).unwrap_or_default(),
+            phys: read_attr(&input_dir.join("phys")).unwrap_or_default(),
+        },
+    };
+    let non_empty = |s: String| Some(s).filter(|s| !s.is_empty());
+    Ok(DeviceInfo {
+        sys_path: fs::canonicalize(event_dir)?,
+        device_node,
+        hidraw_node,
+        parser,
+        bus,
+        name: names.name,
+        uniq: non_empty(names.uniq),
+        phys: non_empty(names.phys),
+        version: read_hex_attr(&input_dir.join("id/version"))?,
+        vendor_id,
+        product_id,
+        input_id,
+        usb_address: find_usb_address(&input_dir),
+        connected_at: SystemTime::now(),
+    })
+}
+
+/// Find connected gamepads by scanning sysfs. Needs no tokio runtime, for
+/// tools that just want a list.
+pub fn enumerate_gamepads() -> error::Result<Vec<DeviceInfo>> {
+    enumerate_devices(&MonitorConfig::new())
+}
+
+/// Find connected devices matching `config` by scanning sysfs.
+pub fn enumerate_devices(config: &MonitorConfig) -> error::Result<Vec<DeviceInfo>> {
+    Ok(iter_devices(config)?.collect())
+}
+
+/// The connected devices matching `config`, each read from sysfs as the
+/// iterator reaches it, so callers can stop at the first one they want.
+pub fn iter_devices(
+    config: &MonitorConfig,
+) -> error::Result<impl Iterator<Item = DeviceInfo> + '_> {
+    let context = || format!("Failed to read {SYS_CLASS_INPUT}");
+    let mut paths: Vec<PathBuf> = vec![];
+    for entry in fs::read_dir(SYS_CLASS_INPUT).io_context(context)? {
+        let entry = entry.io_context(context)?;
+        if entry.file_name().to_string_lossy().starts_with("event") {
+            paths.push(entry.path());
+        }
+    }
+    Ok(paths.into_iter().filter_map(move |path| {
+        get_device_info(&path, config)
+            .map_err(|e| debug!("{e}"))
+            .ok()
+    }))
+}
+
+/// Send a DeviceEvent::Added for each device matching `config` connected at startup, or
+/// DeviceEvent::PermissionDenied if it can't be opened. Unlike the udev monitor this
+/// doesn't see hotplug or permission changes, which is fine for built-in controls.
+pub fn monitor_devices(
+    tx: Sender<DeviceEvent>,
+    config: MonitorConfig,
+) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+    info!("Starting sysfs device scan");
+    Box::pin(async move {
+        match enumerate_devices(&config) {
+            Ok(devices) => {
+                for info in devices {
+                    let event = if info.permission_denied() {
+                        DeviceEvent::PermissionDenied(info)
+                    } else {
+                        DeviceEvent::Added(info)
+                    };
+                    if tx.send(event).await.is_err() {
+                        return;
+                    }
+                }
+            }
+            Err(e) => error!("Failed to scan {SYS_CLASS_INPUT}: {e}"),
+        }
+        // Keep the sender alive like the udev monitor does.
+        std::future::pending::<()>().await;
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A fake `/sys/class/input/eventN` for a gamepad with this `phys`.
+    fn event_dir(name: &str, phys: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hidraw-{name}-{}", std::process::id()));
+        let input_dir = dir.join("device");
+        fs::create_dir_all(input_dir.join("capabilities")).unwrap();
+        // BTN_GAMEPAD, bit 0x130, is in the fifth 64-bit word.
+        let keys = format!("{:x} 0 0 0 0", 1usize << (BTN_GAMEPAD % 64));
+        fs::write(input_dir.join("capabilities/key"), keys).unwrap();
+        fs::write(input_dir.join("phys"), phys).unwrap();
+        dir
+    }
+
+    #[test]
+    fn skips_our_virtual_devices() {
+        let config = MonitorConfig::new();
+        let ours = event_dir("emulated", "hidraw-emulated/uinput");
+        let error = get_device_info(&ours, &config).unwrap_err().to_string();
+        assert!(error.contains("our own virtual device"), "{error}");
+        let theirs = event_dir("physical", "usb-0000:00:14.0-1/input0");
+        let error = get_device_info(&theirs, &config).unwrap_err().to_string();
+        assert!(!error.contains("our own virtual device"), "{error}");
+        let _ = fs::remove_dir_all(ours);
+        let _ = fs::remove_dir_all(theirs);
+    }
+
+    #[test]
+    fn devices_on_other_buses_are_on_an_unknown_bus() {
+        let dir = event_dir("i2c", "i2c-GXTP7385:00");
+        let input_dir = dir.join("device");
+        fs::create_dir_all(input_dir.join("id")).unwrap();
+        // BUS_I2C
+        let ids = [
+            ("bustype", "0018"),
+            ("vendor", "27c6"),
+            ("product", "0f90"),
+            ("version", "0100"),
+        ];
+        for (attr, value) in ids {
+            fs::write(input_dir.join("id").join(attr), value).unwrap();
+        }
+        fs::write(input_dir.join("name"), "GXTP7385:00 27C6:0F90").unwrap();
+        let info = get_device_info(&dir, &MonitorConfig::new()).unwrap();
+        assert_eq!(info.bus, Bus::Unknown);
+        assert_eq!((info.vendor_id, info.product_id), (0x27c6, 0x0f90));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn finds_the_usb_address_of_an_input_device() {
+        let usb = std::env::temp_dir().join(format!("hidraw-usb-{}", std::process::id()));
+        let input = usb.join("1-2:1.0/0003:045E:028E.0001/input/input7");
+        fs::create_dir_all(&input).unwrap();
+        fs::write(usb.join("busnum"), "1\n").unwrap();
+        fs::write(usb.join("devnum"), "12\n").unwrap();
+        assert_eq!(
+            find_usb_address(&input),
+            Some(UsbAddress {
+                bus: 1,
+                address: 12
+            })
+        );
+        let _ = fs::remove_dir_all(usb);
+    }
+}
@@ -0,0 +1,193 @@
+use anyhow::{Context as ErrorContext, Result};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use std::path::Path;
+use tokio::fs::{File, OpenOptions};
+
+use crate::device::{read_input_event, EV_ABS, EV_SYN};
+use crate::device::{ABS_MT_POSITION_X, ABS_MT_POSITION_Y, ABS_MT_SLOT, ABS_MT_TRACKING_ID};
+use crate::report::{Button, GamepadInput};
+use crate::source::InputSource;
+
+const MAX_SLOTS: usize = 10;
+
+/// An area of the screen, in coordinates normalized to 0.0..=1.0.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Shape {
+    Rect {
+        x: f32,
+        y: f32,
+        width: f32,
+        height: f32,
+    },
+    Circle {
+        x: f32,
+        y: f32,
+        radius: f32,
+    },
+}
+
+impl Shape {
+    fn contains(&self, px: f32, py: f32) -> bool {
+        match *self {
+            Shape::Rect {
+                x,
+                y,
+                width,
+                height,
+            } => px >= x && px <= x + width && py >= y && py <= y + height,
+            Shape::Circle { x, y, radius } => (px - x).hypot(py - y) <= radius,
+        }
+    }
+
+    /// The offset of a point from the shape's center, scaled so the edge is 1.0.
+    fn offset(&self, px: f32, py: f32) -> (f32, f32) {
+        let (cx, cy, rx, ry) = match *self {
+            Shape::Rect {
+                x,
+                y,
+                width,
+                height,
+            } => (x + width / 2.0, y + height / 2.0, width / 2.0, height / 2.0),
+            Shape::Circle { x, y, radius } => (x, y, radius, radius),
+        };
+        let dx = (px - cx) / rx;
+        let dy = (py - cy) / ry;
+        let len = dx.hypot(dy);
+        if len > 1.0 {
+            (dx / len, dy / len)
+        } else {
+            (dx, dy)
+        }
+    }
+}
+
+/// What touching a region does.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum RegionAction {
+    Button(Button),
+    DpadUp,
+    DpadDown,
+    DpadLeft,
+    DpadRight,
+    /// Deflect a stick by the touch's offset from the region's center.
+    LeftStick,
+    RightStick,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TouchRegion {
+    pub shape: Shape,
+    pub action: RegionAction,
+}
+
+/// Build controller state from the current touch contacts, given as normalized
+/// screen coordinates.
+pub fn map_contacts(regions: &[TouchRegion], contacts: &[(f32, f32)]) -> GamepadInput {
+    let mut input = GamepadInput::default();
+    for region in regions {
+        let Some(&(x, y)) = contacts.iter().find(|(x, y)| region.shape.contains(*x, *y)) else {
+            continue;
+        };
+        match region.action {
+            RegionAction::Button(button) => input.set_button(button, true),
+            RegionAction::DpadUp => input.dpad.up = true,
+            RegionAction::DpadDown => input.dpad.down = true,
+            RegionAction::DpadLeft => input.dpad.left = true,
+            RegionAction::DpadRight => input.dpad.right = true,
+            RegionAction::LeftStick => {
+                let (dx, dy) = region.shape.offset(x, y);
+                input.left_stick.x = dx;
+                input.left_stick.y = dy;
+            }
+            RegionAction::RightStick => {
+                let (dx, dy) = region.shape.offset(x, y);
+                input.right_stick.x = dx;
+                input.right_stick.y = dy;
+            }
+        }
+    }
+    input
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct Slot {
+    active: bool,
+    x: i32,
+    y: i32,
+}
+
+/// An `InputSource` that turns touches on a multitouch evdev device into
+/// controller input via a set of regions.
+pub struct TouchscreenSource {
+    name: String,
+    file: File,
+    regions: Vec<TouchRegion>,
+    max_x: f32,
+    max_y: f32,
+    slots: [Slot; MAX_SLOTS],
+    slot: usize,
+}
+
+impl TouchscreenSource {
+    /// Open the touchscreen at `device_node`. `max_x` and `max_y` are the
+    /// maximum values of its position axes.
+    pub async fn open(
+        device_node: &Path,
+        max_x: i32,
+        max_y: i32,
+        regions: Vec<TouchRegion>,
+    ) -> Result<TouchscreenSource> {
+        let file = OpenOptions::new()
+            .read(true)
+            .open(device_node)
+            .await
+            .with_context(|| format!("Failed to open {device_node:?}"))?;
+        Ok(TouchscreenSource {
+            name: device_node.display().to_string(),
+            file,
+            regions,
+            max_x: max_x.max(1) as f32,
+            max_y: max_y.max(1) as f32,
+            slots: Default::default(),
+            slot: 0,
+        })
+    }
+
+    fn contacts(&self) -> Vec<(f32, f32)> {
+        self.slots
+            .iter()
+            .filter(|s| s.active)
+            .map(|s| (s.x as f32 / self.max_x, s.y as f32 / self.max_y))
+            .collect()
+    }
+}
+
+impl InputSource for TouchscreenSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn next_input(&mut self) -> BoxFuture<'_, Result<Option<GamepadInput>>> {
+        async move {
+            loop {
+                let event = read_input_event(&mut self.file).await?;
+                match (event.type_, event.code) {
+                    (EV_SYN, _) => {
+                        return Ok(Some(map_contacts(&self.regions, &self.contacts())));
+                    }
+                    (EV_ABS, ABS_MT_SLOT) => {
+                        self.slot = (event.value.max(0) as usize).min(MAX_SLOTS - 1);
+                    }
+                    (EV_ABS, ABS_MT_TRACKING_ID) => {
+                        self.slots[self.slot].active = event.value >= 0;
+                    }
+                    (EV_ABS, ABS_MT_POSITION_X) => self.slots[self.slot].x = event.value,
+                    (EV_ABS, ABS_MT_POSITION_Y) => self.slots[self.slot].y = event.value,
+                    _ => {}
+                }
+            }
+        }
+        .boxed()
+    }
+}
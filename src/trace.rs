@@ -0,0 +1,156 @@
+use anyhow::{Context as ErrorContext, Result};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write as _;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// What happened in a traced event.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Data read from a device.
+    Read,
+    /// Raw data turned into controller state, including transforms.
+    Decode,
+    /// Controller state sent to a sink or virtual device.
+    Emit,
+    /// Output such as rumble or LEDs written to a device.
+    Write,
+}
+
+impl Phase {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Phase::Read => "read",
+            Phase::Decode => "decode",
+            Phase::Emit => "emit",
+            Phase::Write => "write",
+        }
+    }
+}
+
+struct Event {
+    track: usize,
+    phase: Phase,
+    name: String,
+    start_us: u128,
+    duration_us: u128,
+}
+
+/// How many events are kept. Past this the oldest are dropped, so a long
+/// session keeps its last minutes or so rather than growing without limit.
+const MAX_EVENTS: usize = 100_000;
+
+struct Buffer {
+    start: Instant,
+    tracks: HashMap<String, usize>,
+    events: VecDeque<Event>,
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static BUFFER: Mutex<Option<Buffer>> = Mutex::new(None);
+
+/// Start recording events. Until this is called recording is a no-op.
+pub fn enable() {
+    *BUFFER.lock().unwrap() = Some(Buffer {
+        start: Instant::now(),
+        tracks: HashMap::new(),
+        events: VecDeque::new(),
+    });
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Record an event on the timeline for `track`, usually a device or source
+/// name. `start` is when it began; it ends now.
+pub fn record(track: &str, phase: Phase, name: &str, start: Instant) {
+    if !is_enabled() {
+        return;
+    }
+    let mut buffer = BUFFER.lock().unwrap();
+    let Some(buffer) = buffer.as_mut() else {
+        return;
+    };
+    let next = buffer.tracks.len() + 1;
+    let track = *buffer.tracks.entry(track.to_owned()).or_insert(next);
+    if buffer.events.len() == MAX_EVENTS {
+        buffer.events.pop_front();
+    }
+    buffer.events.push_back(Event {
+        track,
+        phase,
+        name: name.to_owned(),
+        start_us: start.saturating_duration_since(buffer.start).as_micros(),
+        duration_us: start.elapsed().as_micros(),
+    });
+}
+
+fn escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Write the most recent events as a Chrome trace (JSON object format),
+/// which can be loaded in Perfetto or `chrome://tracing`. Each track becomes
+/// a named thread.
+pub fn write(path: &Path) -> Result<()> {
+    let buffer = BUFFER.lock().unwrap();
+    let Some(buffer) = buffer.as_ref() else {
+        return Ok(());
+    };
+    let pid = std::process::id();
+    let mut events = vec![];
+    for (name, tid) in &buffer.tracks {
+        events.push(format!(
+            r#"{{"ph":"M","name":"thread_name","pid":{pid},"tid":{tid},"args":{{"name":"{}"}}}}"#,
+            escape(name)
+        ));
+    }
+    for event in &buffer.events {
+        events.push(format!(
+            r#"{{"ph":"X","cat":"{}","name":"{}","pid":{pid},"tid":{},"ts":{},"dur":{}}}"#,
+            event.phase.as_str(),
+            escape(&event.name),
+            event.track,
+            event.start_us,
+            event.duration_us
+        ));
+    }
+    let json = format!("{{\"traceEvents\":[\n{}\n]}}\n", events.join(",\n"));
+    std::fs::write(path, json).with_context(|| format!("Failed to write trace to {path:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_most_recent_events() {
+        enable();
+        let start = Instant::now();
+        for i in 0..MAX_EVENTS + 10 {
+            record("device", Phase::Read, &i.to_string(), start);
+        }
+        let buffer = BUFFER.lock().unwrap();
+        let events = &buffer.as_ref().unwrap().events;
+        // Other tests may record too, so only check what they can't change.
+        assert_eq!(events.len(), MAX_EVENTS);
+        assert!(!events.iter().any(|event| event.name == "9"));
+        let last = (MAX_EVENTS + 9).to_string();
+        assert!(events.iter().any(|event| event.name == last));
+    }
+}
@@ -0,0 +1,75 @@
+/// Transforms that operate on normalized axis values.
+///
+/// Full axes are in the range -1.0..=1.0 and half axes (triggers, pedals) are
+/// in the range 0.0..=1.0. Axes are addressed by their index in the list of
+/// axis values, which for a `GamepadInput` is `Axis as usize`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AxisTransform {
+    /// Merge two half axes into one full axis. `positive` drives the output towards
+    /// 1.0 and `negative` towards -1.0, as with DirectInput-style combined triggers
+    /// or wheels that report throttle and brake on a single axis.
+    Merge {
+        positive: usize,
+        negative: usize,
+        output: usize,
+    },
+    /// Split one full axis into two half axes, the inverse of `Merge`.
+    Split {
+        input: usize,
+        positive: usize,
+        negative: usize,
+    },
+}
+
+impl AxisTransform {
+    /// Apply this transform to `axes` in place. Indices that are out of range
+    /// are ignored.
+    pub fn apply(&self, axes: &mut [f32]) {
+        match *self {
+            AxisTransform::Merge {
+                positive,
+                negative,
+                output,
+            } => {
+                if let (Some(&p), Some(&n)) = (axes.get(positive), axes.get(negative)) {
+                    if let Some(out) = axes.get_mut(output) {
+                        *out = merge_axes(p, n);
+                    }
+                }
+            }
+            AxisTransform::Split {
+                input,
+                positive,
+                negative,
+            } => {
+                if let Some(&value) = axes.get(input) {
+                    let (p, n) = split_axis(value);
+                    if let Some(out) = axes.get_mut(positive) {
+                        *out = p;
+                    }
+                    if let Some(out) = axes.get_mut(negative) {
+                        *out = n;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Combine two half axes into a single full axis.
+pub fn merge_axes(positive: f32, negative: f32) -> f32 {
+    (positive.clamp(0.0, 1.0) - negative.clamp(0.0, 1.0)).clamp(-1.0, 1.0)
+}
+
+/// Split a full axis into `(positive, negative)` half axes.
+pub fn split_axis(value: f32) -> (f32, f32) {
+    let value = value.clamp(-1.0, 1.0);
+    (value.max(0.0), (-value).max(0.0))
+}
+
+/// Apply each transform in order.
+pub fn apply_all(transforms: &[AxisTransform], axes: &mut [f32]) {
+    for transform in transforms {
+        transform.apply(axes);
+    }
+}
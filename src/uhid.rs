@@ -0,0 +1,249 @@
+use anyhow::{bail, Context as ErrorContext, Result};
+use log::debug;
+use std::path::Path;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::device_monitor::Bus;
+
+// From Linux uapi/linux/uhid.h
+const UHID_DESTROY: u32 = 1;
+const UHID_START: u32 = 2;
+const UHID_STOP: u32 = 3;
+const UHID_OPEN: u32 = 4;
+const UHID_CLOSE: u32 = 5;
+const UHID_OUTPUT: u32 = 6;
+const UHID_GET_REPORT: u32 = 9;
+const UHID_GET_REPORT_REPLY: u32 = 10;
+const UHID_CREATE2: u32 = 11;
+const UHID_INPUT2: u32 = 12;
+const UHID_SET_REPORT: u32 = 13;
+const UHID_SET_REPORT_REPLY: u32 = 14;
+
+const UHID_DATA_MAX: usize = 4096;
+const HID_MAX_DESCRIPTOR_SIZE: usize = 4096;
+/// `struct uhid_event` is packed: a u32 type followed by a union whose largest
+/// member is `struct uhid_create2_req`.
+const UHID_EVENT_SIZE: usize = 4 + 128 + 64 + 64 + 2 + 2 + 4 + 4 + 4 + 4 + HID_MAX_DESCRIPTOR_SIZE;
+
+/// The uhid character device.
+pub const UHID_PATH: &str = "/dev/uhid";
+
+/// Everything needed to create a virtual HID device.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UhidConfig {
+    pub name: String,
+    pub phys: String,
+    pub uniq: String,
+    pub bus: Bus,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub version: u16,
+    /// The raw HID report descriptor the kernel will parse for this device.
+    pub descriptor: Vec<u8>,
+}
+
+/// Report types as used by `UHID_GET_REPORT` and `UHID_SET_REPORT`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ReportType {
+    Feature,
+    Output,
+    Input,
+}
+
+impl ReportType {
+    fn from_raw(raw: u8) -> ReportType {
+        match raw {
+            0 => ReportType::Feature,
+            1 => ReportType::Output,
+            _ => ReportType::Input,
+        }
+    }
+}
+
+/// Events the kernel sends to the owner of a uhid device.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UhidEvent {
+    Start,
+    Stop,
+    Open,
+    Close,
+    /// An output report written to the virtual device by another process.
+    Output {
+        data: Vec<u8>,
+        report_type: ReportType,
+    },
+    /// A request for a report; answer with `UhidDevice::reply_get_report`.
+    GetReport {
+        id: u32,
+        report_number: u8,
+        report_type: ReportType,
+    },
+    /// A report sent to the device; answer with `UhidDevice::reply_set_report`.
+    SetReport {
+        id: u32,
+        report_number: u8,
+        report_type: ReportType,
+        data: Vec<u8>,
+    },
+    Unknown(u32),
+}
+
+/// A virtual HID device backed by `/dev/uhid`. The device is destroyed when this
+/// is dropped, since the kernel tears it down when the fd is closed.
+#[derive(Debug)]
+pub struct UhidDevice {
+    file: File,
+    /// The same fd, so waiting for an event doesn't hold up writes, which a
+    /// tokio `File` would queue behind the read.
+    reader: File,
+}
+
+fn copy_str(buf: &mut [u8], s: &str) {
+    // Leave room for a trailing NUL.
+    let len = s.len().min(buf.len() - 1);
+    buf[..len].copy_from_slice(&s.as_bytes()[..len]);
+}
+
+fn new_event(ty: u32) -> Vec<u8> {
+    let mut buf = vec![0; UHID_EVENT_SIZE];
+    buf[..4].copy_from_slice(&ty.to_ne_bytes());
+    buf
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    u16::from_ne_bytes([buf[offset], buf[offset + 1]])
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_ne_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+impl UhidDevice {
+    /// Create a new virtual HID device.
+    pub async fn create(config: &UhidConfig) -> Result<UhidDevice> {
+        UhidDevice::create_at(Path::new(UHID_PATH), config).await
+    }
+
+    pub async fn create_at(path: &Path, config: &UhidConfig) -> Result<UhidDevice> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .await
+            .with_context(|| format!("Failed to open {path:?}"))?;
+        let reader = file.try_clone().await?;
+        let mut device = UhidDevice { file, reader };
+        device.create_device(config).await?;
+        Ok(device)
+    }
+
+    /// Create the kernel device for this fd. This can be used to bring up a new
+    /// device on the same fd after `destroy`.
+    pub async fn create_device(&mut self, config: &UhidConfig) -> Result<()> {
+        if config.descriptor.len() > HID_MAX_DESCRIPTOR_SIZE {
+            bail!(
+                "Report descriptor too large: {} bytes",
+                config.descriptor.len()
+            );
+        }
+        let mut ev = new_event(UHID_CREATE2);
+        let req = &mut ev[4..];
+        copy_str(&mut req[0..128], &config.name);
+        copy_str(&mut req[128..192], &config.phys);
+        copy_str(&mut req[192..256], &config.uniq);
+        req[256..258].copy_from_slice(&(config.descriptor.len() as u16).to_ne_bytes());
+        req[258..260].copy_from_slice(&(config.bus as u16).to_ne_bytes());
+        req[260..264].copy_from_slice(&(config.vendor_id as u32).to_ne_bytes());
+        req[264..268].copy_from_slice(&(config.product_id as u32).to_ne_bytes());
+        req[268..272].copy_from_slice(&(config.version as u32).to_ne_bytes());
+        // country stays 0
+        req[276..276 + config.descriptor.len()].copy_from_slice(&config.descriptor);
+        self.write_event(&ev).await?;
+        debug!("Created uhid device `{}`", config.name);
+        Ok(())
+    }
+
+    async fn write_event(&mut self, ev: &[u8]) -> Result<()> {
+        self.file.write_all(ev).await?;
+        // Wait for the write itself, so its errors are this event's.
+        self.file.flush().await?;
+        Ok(())
+    }
+
+    /// Send an input report to the kernel as if the device had produced it.
+    pub async fn send_input(&mut self, report: &[u8]) -> Result<()> {
+        if report.len() > UHID_DATA_MAX {
+            bail!("Input report too large: {} bytes", report.len());
+        }
+        let mut ev = new_event(UHID_INPUT2);
+        ev[4..6].copy_from_slice(&(report.len() as u16).to_ne_bytes());
+        ev[6..6 + report.len()].copy_from_slice(report);
+        self.write_event(&ev).await
+    }
+
+    /// Wait for the next event from the kernel.
+    pub async fn next_event(&mut self) -> Result<UhidEvent> {
+        let mut buf = vec![0; UHID_EVENT_SIZE];
+        let read = self.reader.read(&mut buf).await?;
+        if read < 4 {
+            bail!("Short uhid event: {read} bytes");
+        }
+        let req = &buf[4..];
+        Ok(match read_u32(&buf, 0) {
+            UHID_START => UhidEvent::Start,
+            UHID_STOP => UhidEvent::Stop,
+            UHID_OPEN => UhidEvent::Open,
+            UHID_CLOSE => UhidEvent::Close,
+            UHID_OUTPUT => {
+                let size = (read_u16(req, UHID_DATA_MAX) as usize).min(UHID_DATA_MAX);
+                UhidEvent::Output {
+                    data: req[..size].to_vec(),
+                    report_type: ReportType::from_raw(req[UHID_DATA_MAX + 2]),
+                }
+            }
+            UHID_GET_REPORT => UhidEvent::GetReport {
+                id: read_u32(req, 0),
+                report_number: req[4],
+                report_type: ReportType::from_raw(req[5]),
+            },
+            UHID_SET_REPORT => {
+                let size = (read_u16(req, 6) as usize).min(UHID_DATA_MAX);
+                UhidEvent::SetReport {
+                    id: read_u32(req, 0),
+                    report_number: req[4],
+                    report_type: ReportType::from_raw(req[5]),
+                    data: req[8..8 + size].to_vec(),
+                }
+            }
+            ty => UhidEvent::Unknown(ty),
+        })
+    }
+
+    /// Answer a `UhidEvent::GetReport`. A non-zero `err` is an errno value.
+    pub async fn reply_get_report(&mut self, id: u32, err: u16, data: &[u8]) -> Result<()> {
+        if data.len() > UHID_DATA_MAX {
+            bail!("Report too large: {} bytes", data.len());
+        }
+        let mut ev = new_event(UHID_GET_REPORT_REPLY);
+        ev[4..8].copy_from_slice(&id.to_ne_bytes());
+        ev[8..10].copy_from_slice(&err.to_ne_bytes());
+        ev[10..12].copy_from_slice(&(data.len() as u16).to_ne_bytes());
+        ev[12..12 + data.len()].copy_from_slice(data);
+        self.write_event(&ev).await
+    }
+
+    /// Answer a `UhidEvent::SetReport`. A non-zero `err` is an errno value.
+    pub async fn reply_set_report(&mut self, id: u32, err: u16) -> Result<()> {
+        let mut ev = new_event(UHID_SET_REPORT_REPLY);
+        ev[4..8].copy_from_slice(&id.to_ne_bytes());
+        ev[8..10].copy_from_slice(&err.to_ne_bytes());
+        self.write_event(&ev).await
+    }
+
+    /// Explicitly destroy the device.
+    pub async fn destroy(&mut self) -> Result<()> {
+        let ev = new_event(UHID_DESTROY);
+        self.write_event(&ev).await
+    }
+}
@@ -0,0 +1,495 @@
+use anyhow::{Context as ErrorContext, Result};
+use libc::input_event;
+use log::debug;
+use nix::errno::Errno;
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::io::unix::AsyncFd;
+
+use crate::device::{AbsInfo, EventLayout, FfEffect, EV_ABS, EV_FF, EV_KEY, EV_SYN, FF_RUMBLE};
+use crate::device_monitor::{Bus, EMULATED_PHYS_PREFIX};
+use crate::emulation::EmulatedOutput;
+use crate::report::{Axis, Button, Dpad, GamepadInput};
+use crate::rumble::RumbleEffect;
+use crate::sink::OutputSink;
+
+// From Linux uapi/linux/input-event-codes.h
+const BTN_A: u16 = 0x130;
+const BTN_B: u16 = 0x131;
+const BTN_X: u16 = 0x133;
+const BTN_Y: u16 = 0x134;
+const BTN_TL: u16 = 0x136;
+const BTN_TR: u16 = 0x137;
+const BTN_SELECT: u16 = 0x13A;
+const BTN_START: u16 = 0x13B;
+const BTN_MODE: u16 = 0x13C;
+const BTN_THUMBL: u16 = 0x13D;
+const BTN_THUMBR: u16 = 0x13E;
+const ABS_X: u16 = 0x00;
+const ABS_Y: u16 = 0x01;
+const ABS_Z: u16 = 0x02;
+const ABS_RX: u16 = 0x03;
+const ABS_RY: u16 = 0x04;
+const ABS_RZ: u16 = 0x05;
+const ABS_HAT0X: u16 = 0x10;
+const ABS_HAT0Y: u16 = 0x11;
+
+// From Linux uapi/linux/uinput.h
+/// Keys from here up are gamepad, joystick and other buttons.
+const KEY_MAX_KEYBOARD: u16 = 0x100;
+
+const EV_UINPUT: u16 = 0x0101;
+const UI_FF_UPLOAD: u16 = 1;
+const UI_FF_ERASE: u16 = 2;
+const UINPUT_MAX_NAME_SIZE: usize = 80;
+
+const UINPUT_PATH: &str = "/dev/uinput";
+
+/// How many effects applications can have uploaded at once.
+const FF_EFFECTS_MAX: u32 = 16;
+
+/// From Linux uapi/linux/uinput.h
+#[repr(C)]
+struct UinputSetup {
+    bustype: u16,
+    vendor: u16,
+    product: u16,
+    version: u16,
+    name: [u8; UINPUT_MAX_NAME_SIZE],
+    ff_effects_max: u32,
+}
+
+/// From Linux uapi/linux/uinput.h
+#[repr(C)]
+struct UinputAbsSetup {
+    code: u16,
+    absinfo: AbsInfo,
+}
+
+/// From Linux uapi/linux/uinput.h
+#[repr(C)]
+struct UinputFfUpload {
+    request_id: u32,
+    retval: i32,
+    effect: FfEffect,
+    old: FfEffect,
+}
+
+/// From Linux uapi/linux/uinput.h
+#[repr(C)]
+struct UinputFfErase {
+    request_id: u32,
+    retval: i32,
+    effect_id: u32,
+}
+
+mod ioctl {
+    use super::{UinputAbsSetup, UinputFfErase, UinputFfUpload, UinputSetup};
+
+    // From Linux uapi/linux/uinput.h
+    nix::ioctl_none!(ui_dev_create, b'U', 1);
+    nix::ioctl_write_ptr!(ui_dev_setup, b'U', 3, UinputSetup);
+    nix::ioctl_write_ptr!(ui_abs_setup, b'U', 4, UinputAbsSetup);
+    nix::ioctl_write_int!(ui_set_evbit, b'U', 100);
+    nix::ioctl_write_int!(ui_set_keybit, b'U', 101);
+    nix::ioctl_write_int!(ui_set_absbit, b'U', 103);
+    nix::ioctl_write_int!(ui_set_ffbit, b'U', 107);
+    nix::ioctl_readwrite!(ui_begin_ff_upload, b'U', 200, UinputFfUpload);
+    nix::ioctl_write_ptr!(ui_end_ff_upload, b'U', 201, UinputFfUpload);
+    nix::ioctl_readwrite!(ui_begin_ff_erase, b'U', 202, UinputFfErase);
+    nix::ioctl_write_ptr!(ui_end_ff_erase, b'U', 203, UinputFfErase);
+}
+
+/// How a virtual gamepad presents itself, and which event code each control
+/// is sent as. Remap controls by changing the codes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UinputConfig {
+    pub name: String,
+    pub bus: Bus,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub version: u16,
+    /// Buttons and the `EV_KEY` codes they're sent as.
+    pub buttons: Vec<(Button, u16)>,
+    /// Axes, the `EV_ABS` codes they're sent as, and their ranges. Sticks are
+    /// scaled from -1.0..=1.0 and triggers from 0.0..=1.0 onto the range.
+    pub axes: Vec<(Axis, u16, AbsInfo)>,
+    /// Whether the d-pad is sent as `ABS_HAT0X` and `ABS_HAT0Y`.
+    pub dpad: bool,
+    /// Whether applications can upload rumble effects, which `next_output`
+    /// returns.
+    pub rumble: bool,
+}
+
+impl UinputConfig {
+    /// An Xbox 360 controller as xpad presents it, which games and SDL
+    /// already know how to map.
+    pub fn xbox360() -> UinputConfig {
+        let stick = AbsInfo {
+            minimum: -32768,
+            maximum: 32767,
+            fuzz: 16,
+            flat: 128,
+            ..Default::default()
+        };
+        let trigger = AbsInfo {
+            maximum: 255,
+            ..Default::default()
+        };
+        UinputConfig {
+            name: "Microsoft X-Box 360 pad".to_owned(),
+            bus: Bus::Usb,
+            vendor_id: 0x045E,
+            product_id: 0x028E,
+            version: 0x0114,
+            // xpad sends the X and Y buttons as BTN_X and BTN_Y, whatever
+            // their positions.
+            buttons: vec![
+                (Button::South, BTN_A),
+                (Button::East, BTN_B),
+                (Button::West, BTN_X),
+                (Button::North, BTN_Y),
+                (Button::LeftShoulder, BTN_TL),
+                (Button::RightShoulder, BTN_TR),
+                (Button::Back, BTN_SELECT),
+                (Button::Start, BTN_START),
+                (Button::Guide, BTN_MODE),
+                (Button::LeftStick, BTN_THUMBL),
+                (Button::RightStick, BTN_THUMBR),
+            ],
+            axes: vec![
+                (Axis::LeftX, ABS_X, stick),
+                (Axis::LeftY, ABS_Y, stick),
+                (Axis::RightX, ABS_RX, stick),
+                (Axis::RightY, ABS_RY, stick),
+                (Axis::LeftTrigger, ABS_Z, trigger),
+                (Axis::RightTrigger, ABS_RZ, trigger),
+            ],
+            dpad: true,
+            rumble: true,
+        }
+    }
+}
+
+/// Scale an axis value onto `info`'s range.
+fn scale(axis: Axis, value: f32, info: &AbsInfo) -> i32 {
+    let fraction = if axis.is_trigger() {
+        value.clamp(0.0, 1.0)
+    } else {
+        (value.clamp(-1.0, 1.0) + 1.0) / 2.0
+    };
+    info.minimum + (fraction * (info.maximum - info.minimum) as f32).round() as i32
+}
+
+/// A d-pad as hat values, with up and left negative.
+fn hat(dpad: &Dpad) -> (i32, i32) {
+    (
+        dpad.right as i32 - dpad.left as i32,
+        dpad.down as i32 - dpad.up as i32,
+    )
+}
+
+fn setup(fd: RawFd, config: &UinputConfig) -> nix::Result<()> {
+    unsafe {
+        ioctl::ui_set_evbit(fd, EV_KEY as _)?;
+        for (_, code) in &config.buttons {
+            ioctl::ui_set_keybit(fd, *code as _)?;
+        }
+        ioctl::ui_set_evbit(fd, EV_ABS as _)?;
+        let hat = AbsInfo {
+            minimum: -1,
+            maximum: 1,
+            ..Default::default()
+        };
+        let hats = [(ABS_HAT0X, hat), (ABS_HAT0Y, hat)];
+        let hats = hats.iter().filter(|_| config.dpad);
+        let axes = config.axes.iter().map(|(_, code, info)| (*code, *info));
+        for (code, absinfo) in axes.chain(hats.copied()) {
+            ioctl::ui_set_absbit(fd, code as _)?;
+            ioctl::ui_abs_setup(fd, &UinputAbsSetup { code, absinfo })?;
+        }
+        if config.rumble {
+            ioctl::ui_set_evbit(fd, EV_FF as _)?;
+            ioctl::ui_set_ffbit(fd, FF_RUMBLE as _)?;
+        }
+        let mut setup = UinputSetup {
+            bustype: config.bus as u16,
+            vendor: config.vendor_id,
+            product: config.product_id,
+            version: config.version,
+            name: [0; UINPUT_MAX_NAME_SIZE],
+            ff_effects_max: if config.rumble { FF_EFFECTS_MAX } else { 0 },
+        };
+        set_name(&mut setup, &config.name);
+        create(fd, &setup)
+    }
+}
+
+fn set_name(setup: &mut UinputSetup, name: &str) {
+    // Leave room for a trailing NUL.
+    let len = name.len().min(UINPUT_MAX_NAME_SIZE - 1);
+    setup.name[..len].copy_from_slice(&name.as_bytes()[..len]);
+}
+
+/// Create the device once its capabilities are set.
+unsafe fn create(fd: RawFd, setup: &UinputSetup) -> nix::Result<()> {
+    // Mark the device as ours so the monitor skips it. UI_SET_PHYS takes a
+    // pointer, so it can't be declared with `ioctl_write_ptr!`.
+    let phys = CString::new(format!("{EMULATED_PHYS_PREFIX}/uinput")).unwrap();
+    let request = nix::request_code_write!(b'U', 108, std::mem::size_of::<*const libc::c_char>());
+    Errno::result(libc::ioctl(fd, request as _, phys.as_ptr()))?;
+    ioctl::ui_dev_setup(fd, setup)?;
+    ioctl::ui_dev_create(fd)?;
+    Ok(())
+}
+
+/// Read one event from a non-blocking uinput fd.
+async fn read_event(fd: &AsyncFd<File>) -> Result<input_event> {
+    let mut buf = [0; EventLayout::NATIVE.size()];
+    loop {
+        let mut guard = fd.readable().await?;
+        match guard.try_io(|fd| fd.get_ref().read_exact(&mut buf)) {
+            Ok(result) => {
+                result?;
+                return Ok(EventLayout::NATIVE.decode(&buf));
+            }
+            Err(_would_block) => continue,
+        }
+    }
+}
+
+/// A rumble effect an application has uploaded.
+#[derive(Copy, Clone, Debug)]
+struct UploadedEffect {
+    effect: RumbleEffect,
+    /// How long it plays for, or `None` until stopped.
+    length: Option<Duration>,
+}
+
+/// A virtual evdev gamepad backed by `/dev/uinput`, for presenting any
+/// controller to games as a standard one. The device is removed when this is
+/// dropped, since the kernel tears it down when the fd is closed.
+#[derive(Debug)]
+pub struct UinputGamepad {
+    fd: AsyncFd<File>,
+    config: UinputConfig,
+    /// The state last sent, so only changes are sent.
+    last: GamepadInput,
+    effects: HashMap<i16, UploadedEffect>,
+    /// When the playing effect runs out, if it has a length.
+    stop_at: Option<Instant>,
+}
+
+impl UinputGamepad {
+    /// Create a virtual gamepad. Must be called within a tokio runtime.
+    pub fn create(config: &UinputConfig) -> Result<UinputGamepad> {
+        UinputGamepad::create_at(Path::new(UINPUT_PATH), config)
+    }
+
+    pub fn create_at(path: &Path, config: &UinputConfig) -> Result<UinputGamepad> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)
+            .with_context(|| format!("Failed to open {path:?}"))?;
+        setup(file.as_raw_fd(), config)
+            .with_context(|| format!("Failed to create uinput device `{}`", config.name))?;
+        debug!("Created uinput device `{}`", config.name);
+        Ok(UinputGamepad {
+            fd: AsyncFd::new(file)?,
+            config: config.clone(),
+            last: GamepadInput::default(),
+            effects: HashMap::new(),
+            stop_at: None,
+        })
+    }
+
+    /// Send the current controller state, as events for what changed since
+    /// the last call.
+    pub fn send_state(&mut self, input: &GamepadInput) -> Result<()> {
+        let layout = EventLayout::NATIVE;
+        let mut events = vec![];
+        for (button, code) in &self.config.buttons {
+            let pressed = input.button(*button);
+            if pressed != self.last.button(*button) {
+                events.extend(layout.encode(EV_KEY, *code, pressed as i32));
+            }
+        }
+        for (axis, code, info) in &self.config.axes {
+            let value = scale(*axis, input.axis(*axis), info);
+            if value != scale(*axis, self.last.axis(*axis), info) {
+                events.extend(layout.encode(EV_ABS, *code, value));
+            }
+        }
+        if self.config.dpad {
+            let ((x, y), (last_x, last_y)) = (hat(&input.dpad), hat(&self.last.dpad));
+            if x != last_x {
+                events.extend(layout.encode(EV_ABS, ABS_HAT0X, x));
+            }
+            if y != last_y {
+                events.extend(layout.encode(EV_ABS, ABS_HAT0Y, y));
+            }
+        }
+        self.last = input.clone();
+        if events.is_empty() {
+            return Ok(());
+        }
+        events.extend(layout.encode(EV_SYN, 0, 0));
+        self.fd
+            .get_ref()
+            .write_all(&events)
+            .context("Failed to write to uinput device")
+    }
+
+    /// Wait for an application to start or stop rumble, handling effect
+    /// uploads along the way. Only the most recently played effect is passed
+    /// through, and returned outputs are always `EmulatedOutput::Rumble`, for
+    /// forwarding with an `OutputLoopback`.
+    pub async fn next_output(&mut self) -> Result<EmulatedOutput> {
+        loop {
+            let stop_at = self.stop_at;
+            let event = tokio::select! {
+                event = read_event(&self.fd) => Some(event?),
+                _ = tokio::time::sleep_until(stop_at.unwrap_or_else(Instant::now).into()),
+                    if stop_at.is_some() => None,
+            };
+            let Some(event) = event else {
+                self.stop_at = None;
+                return Ok(EmulatedOutput::Rumble(RumbleEffect::default()));
+            };
+            match (event.type_, event.code) {
+                (EV_UINPUT, UI_FF_UPLOAD) => self.upload(event.value as u32)?,
+                (EV_UINPUT, UI_FF_ERASE) => self.erase(event.value as u32)?,
+                // The value is how many times to play the effect, or 0 to
+                // stop it.
+                (EV_FF, id) => {
+                    let Some(uploaded) = self.effects.get(&(id as i16)) else {
+                        continue;
+                    };
+                    if event.value == 0 {
+                        self.stop_at = None;
+                        return Ok(EmulatedOutput::Rumble(RumbleEffect::default()));
+                    }
+                    self.stop_at = uploaded.length.map(|length| Instant::now() + length);
+                    return Ok(EmulatedOutput::Rumble(uploaded.effect));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn upload(&mut self, request_id: u32) -> Result<()> {
+        let fd = self.fd.as_raw_fd();
+        // Safe: the struct is plain data, and the kernel fills it in.
+        let mut upload: UinputFfUpload = unsafe { std::mem::zeroed() };
+        upload.request_id = request_id;
+        unsafe { ioctl::ui_begin_ff_upload(fd, &mut upload) }
+            .context("Failed to begin effect upload")?;
+        let effect = upload.effect;
+        upload.retval = if effect.type_ == FF_RUMBLE {
+            let rumble = unsafe { effect.u.rumble };
+            let uploaded = UploadedEffect {
+                effect: RumbleEffect::new(rumble.strong_magnitude, rumble.weak_magnitude),
+                length: (effect.replay_length > 0)
+                    .then(|| Duration::from_millis(effect.replay_length as u64)),
+            };
+            self.effects.insert(effect.id, uploaded);
+            0
+        } else {
+            debug!("Rejecting force feedback effect type {:#x}", effect.type_);
+            -libc::EINVAL
+        };
+        unsafe { ioctl::ui_end_ff_upload(fd, &upload) }
+            .context("Failed to finish effect upload")?;
+        Ok(())
+    }
+
+    fn erase(&mut self, request_id: u32) -> Result<()> {
+        let fd = self.fd.as_raw_fd();
+        let mut erase = UinputFfErase {
+            request_id,
+            retval: 0,
+            effect_id: 0,
+        };
+        unsafe { ioctl::ui_begin_ff_erase(fd, &mut erase) }
+            .context("Failed to begin effect erase")?;
+        self.effects.remove(&(erase.effect_id as i16));
+        unsafe { ioctl::ui_end_ff_erase(fd, &erase) }.context("Failed to finish effect erase")?;
+        Ok(())
+    }
+}
+
+impl OutputSink for UinputGamepad {
+    fn name(&self) -> &str {
+        "uinput"
+    }
+
+    fn send(&mut self, input: &GamepadInput) -> Result<()> {
+        self.send_state(input)
+    }
+}
+
+/// A virtual keyboard with every key below the gamepad buttons, for passing
+/// through keys from a grabbed keyboard that aren't bound to anything.
+#[derive(Debug)]
+pub struct UinputKeyboard {
+    file: File,
+}
+
+impl UinputKeyboard {
+    pub fn create(name: &str) -> Result<UinputKeyboard> {
+        UinputKeyboard::create_at(Path::new(UINPUT_PATH), name)
+    }
+
+    pub fn create_at(path: &Path, name: &str) -> Result<UinputKeyboard> {
+        let file = OpenOptions::new()
+            .write(true)
+            .open(path)
+            .with_context(|| format!("Failed to open {path:?}"))?;
+        let fd = file.as_raw_fd();
+        let mut setup = UinputSetup {
+            bustype: Bus::Virtual as u16,
+            vendor: 0,
+            product: 0,
+            version: 0,
+            name: [0; UINPUT_MAX_NAME_SIZE],
+            ff_effects_max: 0,
+        };
+        set_name(&mut setup, name);
+        unsafe {
+            ioctl::ui_set_evbit(fd, EV_KEY as _)?;
+            for key in 1..KEY_MAX_KEYBOARD {
+                ioctl::ui_set_keybit(fd, key as _)?;
+            }
+            create(fd, &setup)
+        }
+        .with_context(|| format!("Failed to create uinput device `{name}`"))?;
+        debug!("Created uinput device `{name}`");
+        Ok(UinputKeyboard { file })
+    }
+
+    /// Press, repeat or release `key`.
+    pub fn send_key(&mut self, key: u16, value: i32) -> Result<()> {
+        let layout = EventLayout::NATIVE;
+        let mut events = layout.encode(EV_KEY, key, value);
+        events.extend(layout.encode(EV_SYN, 0, 0));
+        self.file
+            .write_all(&events)
+            .context("Failed to write to uinput device")
+    }
+}
+
+#[cfg(test)]
+impl UinputKeyboard {
+    /// A keyboard that writes its events to `file`.
+    pub fn for_test(file: File) -> UinputKeyboard {
+        UinputKeyboard { file }
+    }
+}
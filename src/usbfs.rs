@@ -0,0 +1,266 @@
+use anyhow::{bail, Context as ErrorContext, Result};
+use log::{debug, info};
+use rusb::{
+    DeviceHandle, Direction, GlobalContext, Recipient, RequestType, TransferType, UsbContext,
+};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::{self, Receiver};
+
+use crate::device_monitor::UsbAddress;
+
+// From the USB HID specification.
+const USB_CLASS_HID: u8 = 0x03;
+const HID_REPORT_DESCRIPTOR: u16 = 0x22;
+const HID_GET_REPORT: u8 = 0x01;
+const HID_SET_REPORT: u8 = 0x09;
+const HID_OUTPUT_REPORT: u16 = 0x02;
+const HID_FEATURE_REPORT: u16 = 0x03;
+// From the USB specification.
+const GET_DESCRIPTOR: u8 = 0x06;
+
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How to send output reports.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OutputMethod {
+    /// Use the interrupt out endpoint if there is one, falling back to
+    /// SET_REPORT if that fails. This is what usbhid does, minus the fallback.
+    Auto,
+    /// The interrupt out endpoint only.
+    Interrupt,
+    /// SET_REPORT control transfers only.
+    Control,
+}
+
+impl OutputMethod {
+    /// The method a device needs, for devices known to be picky.
+    pub fn for_device(vendor_id: u16, product_id: u16) -> OutputMethod {
+        match (vendor_id, product_id) {
+            // From Linux drivers/hid/hid-sony.c: the Sixaxis has an interrupt
+            // out endpoint but ignores output reports sent to it.
+            (0x054C, 0x0268) => OutputMethod::Control,
+            _ => OutputMethod::Auto,
+        }
+    }
+}
+
+/// A USB HID device accessed directly through usbfs with libusb, for kernels
+/// built without hidraw. Claiming the interface detaches the kernel's usbhid
+/// driver, so the device's evdev node goes away while we hold it.
+pub struct UsbHidDevice {
+    handle: DeviceHandle<GlobalContext>,
+    interface: u8,
+    in_endpoint: u8,
+    out_endpoint: Option<u8>,
+    max_packet_size: usize,
+    output_method: Mutex<OutputMethod>,
+}
+
+impl UsbHidDevice {
+    /// Open the first HID interface of the device with these IDs, at `address`
+    /// if given, or else the first one found.
+    pub fn open(
+        vendor_id: u16,
+        product_id: u16,
+        address: Option<UsbAddress>,
+    ) -> Result<UsbHidDevice> {
+        let device = GlobalContext::default()
+            .devices()?
+            .iter()
+            .find(|d| {
+                let at_address =
+                    address.is_none_or(|a| d.bus_number() == a.bus && d.address() == a.address);
+                at_address
+                    && d.device_descriptor().is_ok_and(|desc| {
+                        desc.vendor_id() == vendor_id && desc.product_id() == product_id
+                    })
+            })
+            .with_context(|| format!("No USB device {vendor_id:04x}:{product_id:04x}"))?;
+        let config = device.active_config_descriptor()?;
+        for interface in config.interfaces() {
+            for desc in interface.descriptors() {
+                if desc.class_code() != USB_CLASS_HID {
+                    continue;
+                }
+                let interrupt = |direction| {
+                    desc.endpoint_descriptors().find(|e| {
+                        e.transfer_type() == TransferType::Interrupt && e.direction() == direction
+                    })
+                };
+                let Some(in_endpoint) = interrupt(Direction::In) else {
+                    continue;
+                };
+                let handle = device.open()?;
+                handle.set_auto_detach_kernel_driver(true)?;
+                handle
+                    .claim_interface(desc.interface_number())
+                    .context("Failed to claim HID interface")?;
+                info!(
+                    "Claimed HID interface {} of {vendor_id:04x}:{product_id:04x} through usbfs",
+                    desc.interface_number()
+                );
+                return Ok(UsbHidDevice {
+                    handle,
+                    interface: desc.interface_number(),
+                    in_endpoint: in_endpoint.address(),
+                    out_endpoint: interrupt(Direction::Out).map(|e| e.address()),
+                    max_packet_size: in_endpoint.max_packet_size() as usize,
+                    output_method: Mutex::new(OutputMethod::for_device(vendor_id, product_id)),
+                });
+            }
+        }
+        bail!("{vendor_id:04x}:{product_id:04x} has no HID interface with an input endpoint");
+    }
+
+    /// Read the report descriptor, as hidraw's `HIDIOCGRDESC` would.
+    pub fn read_report_descriptor(&self) -> Result<Vec<u8>> {
+        let mut buf = vec![0; 4096];
+        let len = self.handle.read_control(
+            rusb::request_type(Direction::In, RequestType::Standard, Recipient::Interface),
+            GET_DESCRIPTOR,
+            HID_REPORT_DESCRIPTOR << 8,
+            self.interface as u16,
+            &mut buf,
+            TIMEOUT,
+        )?;
+        buf.truncate(len);
+        Ok(buf)
+    }
+
+    /// Wait up to `timeout` for an input report. Returns `Ok(None)` on timeout.
+    pub fn read_report(&self, timeout: Duration) -> Result<Option<Vec<u8>>> {
+        let mut buf = vec![0; self.max_packet_size];
+        match self
+            .handle
+            .read_interrupt(self.in_endpoint, &mut buf, timeout)
+        {
+            Ok(len) => {
+                buf.truncate(len);
+                Ok(Some(buf))
+            }
+            Err(rusb::Error::Timeout) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn output_method(&self) -> OutputMethod {
+        *self.output_method.lock().unwrap()
+    }
+
+    /// Override how output reports are sent, for drivers that know better
+    /// than `OutputMethod::for_device`.
+    pub fn set_output_method(&self, method: OutputMethod) {
+        *self.output_method.lock().unwrap() = method;
+    }
+
+    fn write_control(&self, report_id: u8, data: &[u8]) -> rusb::Result<usize> {
+        self.handle.write_control(
+            rusb::request_type(Direction::Out, RequestType::Class, Recipient::Interface),
+            HID_SET_REPORT,
+            HID_OUTPUT_REPORT << 8 | report_id as u16,
+            self.interface as u16,
+            data,
+            TIMEOUT,
+        )
+    }
+
+    /// Send an output report per the output method. The first byte is the
+    /// report ID, or 0 for unnumbered reports.
+    pub fn write_report(&self, data: &[u8]) -> Result<()> {
+        let Some((&report_id, payload)) = data.split_first() else {
+            bail!("Empty report");
+        };
+        // The report ID is only sent if the device uses numbered reports.
+        let data = if report_id == 0 { payload } else { data };
+        match (self.output_method(), self.out_endpoint) {
+            (OutputMethod::Interrupt, None) => bail!("No interrupt out endpoint"),
+            (OutputMethod::Interrupt, Some(endpoint)) => {
+                self.handle.write_interrupt(endpoint, data, TIMEOUT)?;
+            }
+            (OutputMethod::Auto, Some(endpoint)) => {
+                match self.handle.write_interrupt(endpoint, data, TIMEOUT) {
+                    Ok(_) => {}
+                    Err(rusb::Error::NoDevice) => bail!(rusb::Error::NoDevice),
+                    Err(e) => {
+                        // Stick with whatever works from now on.
+                        info!("Interrupt out failed ({e}), switching to SET_REPORT");
+                        self.write_control(report_id, data)?;
+                        self.set_output_method(OutputMethod::Control);
+                    }
+                }
+            }
+            (OutputMethod::Auto | OutputMethod::Control, _) => {
+                self.write_control(report_id, data)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Read feature report `report_id` with room for `len` bytes, including
+    /// the report ID, which the result starts with as with hidraw.
+    pub fn get_feature_report(&self, report_id: u8, len: usize) -> Result<Vec<u8>> {
+        let mut buf = vec![0; len.max(1)];
+        buf[0] = report_id;
+        // Unnumbered reports come back without an ID byte.
+        let start = if report_id == 0 { 1 } else { 0 };
+        let read = self.handle.read_control(
+            rusb::request_type(Direction::In, RequestType::Class, Recipient::Interface),
+            HID_GET_REPORT,
+            HID_FEATURE_REPORT << 8 | report_id as u16,
+            self.interface as u16,
+            &mut buf[start..],
+            TIMEOUT,
+        )?;
+        buf.truncate(start + read);
+        Ok(buf)
+    }
+
+    /// Send a feature report. The first byte is the report ID, or 0 for
+    /// unnumbered reports.
+    pub fn send_feature_report(&self, data: &[u8]) -> Result<()> {
+        let Some((&report_id, payload)) = data.split_first() else {
+            bail!("Empty report");
+        };
+        let data = if report_id == 0 { payload } else { data };
+        self.handle.write_control(
+            rusb::request_type(Direction::Out, RequestType::Class, Recipient::Interface),
+            HID_SET_REPORT,
+            HID_FEATURE_REPORT << 8 | report_id as u16,
+            self.interface as u16,
+            data,
+            TIMEOUT,
+        )?;
+        Ok(())
+    }
+
+    /// Read input reports on a blocking thread until the device goes away or
+    /// the receiver is dropped. The device can still be written to meanwhile.
+    pub fn spawn_reader(self: &Arc<Self>) -> Receiver<Vec<u8>> {
+        let (tx, rx) = mpsc::channel(64);
+        let device = self.clone();
+        tokio::task::spawn_blocking(move || loop {
+            match device.read_report(TIMEOUT) {
+                Ok(Some(report)) => {
+                    if tx.blocking_send(report).is_err() {
+                        break;
+                    }
+                }
+                Ok(None) if tx.is_closed() => break,
+                Ok(None) => {}
+                Err(e) => {
+                    debug!("Stopped reading USB interface {}: {e}", device.interface);
+                    break;
+                }
+            }
+        });
+        rx
+    }
+}
+
+impl Drop for UsbHidDevice {
+    fn drop(&mut self) {
+        // Auto-detach reattaches the kernel driver once the interface is released.
+        let _ = self.handle.release_interface(self.interface);
+    }
+}
@@ -0,0 +1,413 @@
+use anyhow::{Context as ErrorContext, Result};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use libc::input_event;
+use log::{debug, info};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use tokio::fs::OpenOptions;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::task::JoinHandle;
+#[cfg(feature = "udev")]
+use tokio_udev::{Device, Enumerator};
+
+use crate::device::{read_input_event, EV_ABS, EV_KEY, EV_SYN};
+use crate::report::{Axis, Button, Capabilities, GamepadInput};
+use crate::source::InputSource;
+
+pub const NINTENDO_VENDOR_ID: u16 = 0x057E;
+pub const WIIMOTE_PRODUCT_IDS: &[u16] = &[0x0306, 0x0330];
+
+/// The kernel's hid-wiimote driver names every node it creates with this prefix.
+const NAME_PREFIX: &str = "Nintendo Wii Remote";
+
+// Key codes used by hid-wiimote.
+const KEY_UP: u16 = 103;
+const KEY_LEFT: u16 = 105;
+const KEY_RIGHT: u16 = 106;
+const KEY_DOWN: u16 = 108;
+const KEY_NEXT: u16 = 407;
+const KEY_PREVIOUS: u16 = 412;
+const BTN_1: u16 = 0x101;
+const BTN_2: u16 = 0x102;
+const BTN_A: u16 = 0x130;
+const BTN_B: u16 = 0x131;
+const BTN_C: u16 = 0x132;
+const BTN_Z: u16 = 0x135;
+const BTN_MODE: u16 = 0x13C;
+const ABS_HAT0X: u16 = 0x10;
+const ABS_HAT0Y: u16 = 0x11;
+
+/// The nunchuk stick range reported by hid-wiimote.
+const NUNCHUK_STICK_MAX: f32 = 120.0;
+
+/// The evdev nodes hid-wiimote creates for a single remote. Everything but
+/// `Core` comes and goes as extensions are plugged in.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum WiimoteNode {
+    Core,
+    Accelerometer,
+    Ir,
+    MotionPlus,
+    Nunchuk,
+    ClassicController,
+    BalanceBoard,
+    ProController,
+    Unknown,
+}
+
+impl WiimoteNode {
+    /// Classify a node from its kernel input device name.
+    pub fn from_name(name: &str) -> Option<WiimoteNode> {
+        let suffix = name.strip_prefix(NAME_PREFIX)?.trim();
+        Some(match suffix {
+            "" => WiimoteNode::Core,
+            "Accelerometer" => WiimoteNode::Accelerometer,
+            "IR" => WiimoteNode::Ir,
+            "Motion Plus" => WiimoteNode::MotionPlus,
+            "Nunchuk" => WiimoteNode::Nunchuk,
+            "Classic Controller" => WiimoteNode::ClassicController,
+            "Balance Board" => WiimoteNode::BalanceBoard,
+            "Pro Controller" => WiimoteNode::ProController,
+            _ => WiimoteNode::Unknown,
+        })
+    }
+}
+
+impl WiimoteNode {
+    /// The controls an extension node adds, as mapped by `WiimoteProfile::Nunchuk`.
+    pub fn capabilities(&self) -> Capabilities {
+        match self {
+            WiimoteNode::Core => Capabilities {
+                buttons: vec![
+                    Button::South,
+                    Button::East,
+                    Button::West,
+                    Button::North,
+                    Button::Start,
+                    Button::Back,
+                    Button::Guide,
+                ],
+                dpad: true,
+                ..Default::default()
+            },
+            WiimoteNode::Nunchuk => Capabilities {
+                buttons: vec![Button::LeftShoulder],
+                axes: vec![Axis::LeftX, Axis::LeftY, Axis::LeftTrigger],
+                motion: true,
+                ..Default::default()
+            },
+            WiimoteNode::Accelerometer | WiimoteNode::MotionPlus => Capabilities {
+                motion: true,
+                ..Default::default()
+            },
+            _ => Capabilities::default(),
+        }
+    }
+}
+
+pub fn is_wiimote(vendor_id: u16, product_id: u16) -> bool {
+    vendor_id == NINTENDO_VENDOR_ID && WIIMOTE_PRODUCT_IDS.contains(&product_id)
+}
+
+/// Classify an evdev device as a wiimote node, if it is one.
+#[cfg(feature = "udev")]
+pub fn classify(device: &Device) -> Option<WiimoteNode> {
+    let name = device
+        .parent()?
+        .attribute_value("name")?
+        .to_str()?
+        .to_owned();
+    WiimoteNode::from_name(&name)
+}
+
+/// If `device` is a wiimote extension node, find the sys path of the core
+/// remote node it belongs to.
+#[cfg(feature = "udev")]
+pub fn find_core(device: &Device) -> Result<Option<(WiimoteNode, PathBuf)>> {
+    let Some(kind) = classify(device) else {
+        return Ok(None);
+    };
+    if kind == WiimoteNode::Core {
+        return Ok(None);
+    }
+    let hid = device
+        .parent_with_subsystem("hid")?
+        .context("Wiimote without a HID parent")?;
+    let mut enumerator = Enumerator::new()?;
+    enumerator.match_parent(&hid)?;
+    enumerator.match_subsystem("input")?;
+    for sibling in enumerator.scan_devices()? {
+        let is_event_node = sibling
+            .devnode()
+            .is_some_and(|n| n.starts_with("/dev/input/event"));
+        if is_event_node && classify(&sibling) == Some(WiimoteNode::Core) {
+            return Ok(Some((kind, sibling.syspath().to_owned())));
+        }
+    }
+    Ok(None)
+}
+
+/// Find all evdev nodes belonging to the same remote as the evdev device at
+/// `sys_path`.
+#[cfg(feature = "udev")]
+pub fn find_nodes(sys_path: &Path) -> Result<Vec<(WiimoteNode, PathBuf)>> {
+    let device = Device::from_syspath(sys_path)?;
+    let hid = device
+        .parent_with_subsystem("hid")?
+        .context("Wiimote without a HID parent")?;
+    let mut enumerator = Enumerator::new()?;
+    enumerator.match_parent(&hid)?;
+    enumerator.match_subsystem("input")?;
+    let mut nodes = vec![];
+    for device in enumerator.scan_devices()? {
+        let Some(node) = device.devnode() else {
+            continue;
+        };
+        if !node.starts_with("/dev/input/event") {
+            continue;
+        }
+        if let Some(kind) = classify(&device) {
+            nodes.push((kind, node.to_owned()));
+        }
+    }
+    Ok(nodes)
+}
+
+/// How to turn remote and extension inputs into standard controls.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WiimoteProfile {
+    /// The remote held sideways, NES-style, with the dpad on the left.
+    Sideways,
+    /// The remote held upright with a nunchuk in the other hand.
+    Nunchuk,
+}
+
+impl WiimoteProfile {
+    fn button(&self, code: u16) -> Option<Button> {
+        match (self, code) {
+            (WiimoteProfile::Sideways, BTN_2) => Some(Button::South),
+            (WiimoteProfile::Sideways, BTN_1) => Some(Button::West),
+            (WiimoteProfile::Sideways, BTN_A) => Some(Button::North),
+            (WiimoteProfile::Sideways, BTN_B) => Some(Button::East),
+            (WiimoteProfile::Nunchuk, BTN_A) => Some(Button::South),
+            (WiimoteProfile::Nunchuk, BTN_B) => Some(Button::East),
+            (WiimoteProfile::Nunchuk, BTN_1) => Some(Button::West),
+            (WiimoteProfile::Nunchuk, BTN_2) => Some(Button::North),
+            (WiimoteProfile::Nunchuk, BTN_C) => Some(Button::LeftShoulder),
+            (_, KEY_NEXT) => Some(Button::Start),
+            (_, KEY_PREVIOUS) => Some(Button::Back),
+            (_, BTN_MODE) => Some(Button::Guide),
+            _ => None,
+        }
+    }
+
+    fn apply(&self, event: &input_event, state: &mut GamepadInput) {
+        let pressed = event.value != 0;
+        match (event.type_, event.code) {
+            (EV_KEY, BTN_Z) if *self == WiimoteProfile::Nunchuk => {
+                state.left_trigger = if pressed { 1.0 } else { 0.0 };
+            }
+            (EV_KEY, code @ (KEY_UP | KEY_DOWN | KEY_LEFT | KEY_RIGHT)) => {
+                // Held sideways, the remote's dpad is rotated a quarter turn.
+                let code = match (self, code) {
+                    (WiimoteProfile::Sideways, KEY_UP) => KEY_LEFT,
+                    (WiimoteProfile::Sideways, KEY_LEFT) => KEY_DOWN,
+                    (WiimoteProfile::Sideways, KEY_DOWN) => KEY_RIGHT,
+                    (WiimoteProfile::Sideways, KEY_RIGHT) => KEY_UP,
+                    (_, code) => code,
+                };
+                match code {
+                    KEY_UP => state.dpad.up = pressed,
+                    KEY_DOWN => state.dpad.down = pressed,
+                    KEY_LEFT => state.dpad.left = pressed,
+                    _ => state.dpad.right = pressed,
+                }
+            }
+            (EV_KEY, code) => {
+                if let Some(button) = self.button(code) {
+                    state.set_button(button, pressed);
+                }
+            }
+            (EV_ABS, ABS_HAT0X) if *self == WiimoteProfile::Nunchuk => {
+                state.left_stick.x = (event.value as f32 / NUNCHUK_STICK_MAX).clamp(-1.0, 1.0);
+            }
+            (EV_ABS, ABS_HAT0Y) if *self == WiimoteProfile::Nunchuk => {
+                // Nunchuk up is positive, gamepad up is negative.
+                state.left_stick.y = (-event.value as f32 / NUNCHUK_STICK_MAX).clamp(-1.0, 1.0);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// An event from one of a remote's nodes, or `None` once the node is gone.
+type NodeEvent = (WiimoteNode, Option<input_event>);
+
+/// An `InputSource` that merges all of a remote's evdev nodes into one
+/// controller state. It ends when the core node goes away.
+pub struct WiimoteSource {
+    name: String,
+    profile: WiimoteProfile,
+    state: GamepadInput,
+    tx: Sender<NodeEvent>,
+    rx: Receiver<NodeEvent>,
+    readers: HashMap<WiimoteNode, JoinHandle<()>>,
+}
+
+impl WiimoteSource {
+    pub async fn open(
+        name: &str,
+        nodes: &[(WiimoteNode, PathBuf)],
+        profile: WiimoteProfile,
+    ) -> Result<WiimoteSource> {
+        let (tx, rx) = mpsc::channel(64);
+        let mut source = WiimoteSource {
+            name: name.to_owned(),
+            profile,
+            state: GamepadInput::default(),
+            tx,
+            rx,
+            readers: HashMap::new(),
+        };
+        for (kind, path) in nodes {
+            source.add_node(*kind, path).await?;
+        }
+        Ok(source)
+    }
+
+    /// Start reading from a node, e.g. when an extension is plugged in.
+    pub async fn add_node(&mut self, kind: WiimoteNode, path: &Path) -> Result<()> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .open(path)
+            .await
+            .with_context(|| format!("Failed to open {path:?}"))?;
+        debug!("Reading wiimote {kind:?} node {path:?}");
+        let tx = self.tx.clone();
+        let reader = tokio::spawn(async move {
+            while let Ok(event) = read_input_event(&mut file).await {
+                if tx.send((kind, Some(event))).await.is_err() {
+                    return;
+                }
+            }
+            let _ = tx.send((kind, None)).await;
+        });
+        if let Some(old) = self.readers.insert(kind, reader) {
+            old.abort();
+        }
+        Ok(())
+    }
+
+    /// Stop reading from a node, e.g. when an extension is unplugged.
+    pub fn remove_node(&mut self, kind: WiimoteNode) {
+        if let Some(reader) = self.readers.remove(&kind) {
+            info!("Wiimote {kind:?} removed");
+            reader.abort();
+        }
+        if kind == WiimoteNode::Nunchuk {
+            self.state.left_stick = Default::default();
+            self.state.left_trigger = 0.0;
+            self.state.set_button(Button::LeftShoulder, false);
+        }
+    }
+}
+
+impl Drop for WiimoteSource {
+    fn drop(&mut self) {
+        for reader in self.readers.values() {
+            reader.abort();
+        }
+    }
+}
+
+impl InputSource for WiimoteSource {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn next_input(&mut self) -> BoxFuture<'_, Result<Option<GamepadInput>>> {
+        async move {
+            while let Some((kind, event)) = self.rx.recv().await {
+                match event {
+                    Some(event) if event.type_ == EV_SYN => return Ok(Some(self.state.clone())),
+                    Some(event) => self.profile.apply(&event, &mut self.state),
+                    // We hold a sender ourselves for `add_node`, so the channel
+                    // never closes by itself.
+                    None if kind == WiimoteNode::Core => {
+                        info!("Wiimote {} disconnected", self.name);
+                        for (_, reader) in self.readers.drain() {
+                            reader.abort();
+                        }
+                        self.rx.close();
+                        return Ok(None);
+                    }
+                    None => {
+                        self.remove_node(kind);
+                        return Ok(Some(self.state.clone()));
+                    }
+                }
+            }
+            Ok(None)
+        }
+        .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::EventLayout;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    fn node_file(name: &str, events: &[(u16, u16, i32)]) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("hidraw-wiimote-{name}-{}", std::process::id()));
+        let data: Vec<u8> = events
+            .iter()
+            .flat_map(|&(type_, code, value)| EventLayout::NATIVE.encode(type_, code, value))
+            .collect();
+        std::fs::write(&path, data).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn ends_when_core_node_goes_away() {
+        let core = node_file("core", &[(EV_KEY, BTN_A, 1), (EV_SYN, 0, 0)]);
+        let nodes = [(WiimoteNode::Core, core.clone())];
+        let mut source = WiimoteSource::open("Wiimote", &nodes, WiimoteProfile::Nunchuk)
+            .await
+            .unwrap();
+        let state = source.next_input().await.unwrap().unwrap();
+        assert!(state.button(Button::South));
+        // The file ends like a node that was removed.
+        let end = timeout(Duration::from_secs(5), source.next_input()).await;
+        assert!(end.expect("source didn't end").unwrap().is_none());
+        assert!(source.readers.is_empty());
+        std::fs::remove_file(core).unwrap();
+    }
+
+    #[tokio::test]
+    async fn releases_nunchuk_controls_when_unplugged() {
+        let core = node_file("core2", &[]);
+        let nunchuk = node_file("nunchuk", &[(EV_KEY, BTN_C, 1), (EV_SYN, 0, 0)]);
+        let mut source = WiimoteSource::open("Wiimote", &[], WiimoteProfile::Nunchuk)
+            .await
+            .unwrap();
+        source
+            .add_node(WiimoteNode::Nunchuk, &nunchuk)
+            .await
+            .unwrap();
+        let state = source.next_input().await.unwrap().unwrap();
+        assert!(state.button(Button::LeftShoulder));
+        let state = source.next_input().await.unwrap().unwrap();
+        assert!(!state.button(Button::LeftShoulder));
+        assert!(!source.readers.contains_key(&WiimoteNode::Nunchuk));
+        source.add_node(WiimoteNode::Core, &core).await.unwrap();
+        let end = timeout(Duration::from_secs(5), source.next_input()).await;
+        assert!(end.expect("source didn't end").unwrap().is_none());
+        std::fs::remove_file(core).unwrap();
+        std::fs::remove_file(nunchuk).unwrap();
+    }
+}